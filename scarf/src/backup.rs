@@ -0,0 +1,425 @@
+use std::{
+    fmt::Debug, fs, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc}, thread::{self, JoinHandle}, time::Duration
+};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use redb::{ReadableTable, TableDefinition, TableHandle};
+
+use crate::{
+    database::{Database, DatabaseLocation}, meta, options::DatabaseOptions, timeseries::SeriesKey, Error
+};
+
+/// Path of the `index`th rotated backup of `path`, where `1` is the newest.
+pub fn rotated_path(path: impl AsRef<Path>, index: usize) -> PathBuf {
+    let path = path.as_ref();
+    let mut name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+    name.push(format!(".backup.{index}"));
+    path.with_file_name(name)
+}
+
+/// Copies `path` to `{path}.backup.1`, shifting older backups up and dropping any past `keep`.
+pub(crate) fn rotate(path: impl AsRef<Path>, keep: usize) -> crate::Result<()> {
+    let path = path.as_ref();
+    if keep == 0 || !path.exists() {
+        return Ok(());
+    }
+
+    let oldest = rotated_path(path, keep);
+    if oldest.exists() {
+        fs::remove_file(&oldest)?;
+    }
+    for index in (1..keep).rev() {
+        let current = rotated_path(path, index);
+        if current.exists() {
+            fs::rename(&current, rotated_path(path, index + 1))?;
+        }
+    }
+    fs::copy(path, rotated_path(path, 1))?;
+    tracing::info!(path = %path.display(), keep, "rotated database backups");
+    Ok(())
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SnapshotConfig {
+    pub directory: PathBuf,
+    pub interval: Duration,
+    /// Number of snapshots kept in `directory`; older ones are deleted after each new snapshot.
+    pub keep: usize
+}
+
+impl SnapshotConfig {
+    pub fn new(directory: impl AsRef<Path>) -> Self {
+        Self {
+            directory: directory.as_ref().to_path_buf(),
+            interval: Duration::from_secs(60 * 60),
+            keep: 24
+        }
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn keep(mut self, keep: usize) -> Self {
+        self.keep = keep.max(1);
+        self
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    pub id: String,
+    pub path: PathBuf,
+    pub created_at: DateTime<Utc>,
+    pub size: u64
+}
+
+const SNAPSHOT_EXTENSION: &str = "snapshot";
+const SNAPSHOT_ID_FORMAT: &str = "%Y%m%dT%H%M%S%.6fZ";
+
+fn snapshot_info(path: PathBuf) -> crate::Result<Option<SnapshotInfo>> {
+    if path.extension().is_none_or(|extension| extension != SNAPSHOT_EXTENSION) {
+        return Ok(None);
+    }
+    let Some(id) = path.file_stem().and_then(|stem| stem.to_str()).map(|stem| stem.to_string()) else {
+        return Ok(None);
+    };
+    let Ok(created_at) = NaiveDateTime::parse_from_str(&id, SNAPSHOT_ID_FORMAT) else {
+        return Ok(None);
+    };
+    Ok(Some(SnapshotInfo {
+        id,
+        size: fs::metadata(&path)?.len(),
+        path,
+        created_at: created_at.and_utc()
+    }))
+}
+
+/// Lists the snapshots in `directory`, oldest first.
+pub(crate) fn list(directory: impl AsRef<Path>) -> crate::Result<Vec<SnapshotInfo>> {
+    if !directory.as_ref().exists() {
+        return Ok(Vec::new());
+    }
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        if let Some(info) = snapshot_info(entry?.path())? {
+            snapshots.push(info);
+        }
+    }
+    snapshots.sort_by_key(|info| info.created_at);
+    Ok(snapshots)
+}
+
+/// Copies the database file to `destination` while holding a write transaction, so no commit can land
+/// halfway through the copy.
+fn copy_consistent(db: &Database, operation: &str, destination: &Path) -> crate::Result<()> {
+    let DatabaseLocation::Filesystem(source) = db.location() else {
+        return Err(Error::NotFileBacked(operation.to_string()));
+    };
+    let txn = db.begin_write(operation, destination.display().to_string())?;
+    let copied = fs::copy(&source, destination);
+    txn.abort()?;
+    copied?;
+    Ok(())
+}
+
+/// Copies the database file into `config.directory` and deletes snapshots beyond `config.keep`.
+pub(crate) fn take(db: &Database, config: &SnapshotConfig) -> crate::Result<SnapshotInfo> {
+    fs::create_dir_all(&config.directory)?;
+    let id = Utc::now().format(SNAPSHOT_ID_FORMAT).to_string();
+    let path = config.directory.join(format!("{id}.{SNAPSHOT_EXTENSION}"));
+    copy_consistent(db, "snapshot", &path)?;
+
+    let snapshots = list(&config.directory)?;
+    for expired in snapshots.iter().take(snapshots.len().saturating_sub(config.keep)) {
+        fs::remove_file(&expired.path)?;
+    }
+    snapshot_info(path.clone())?.ok_or(Error::UnknownSnapshot(id))
+}
+
+/// A place backups can be pushed to and fetched back from, e.g. a local directory or a blob store.
+///
+/// Backups are addressed by name; implementations decide how names map onto their storage.
+pub trait BackupTarget: Debug + Send + Sync {
+    /// Uploads the file at `source` as `name`, replacing any existing backup with that name.
+    fn put(&self, name: &str, source: &Path) -> crate::Result<()>;
+
+    /// Downloads backup `name` into the file at `destination`.
+    fn fetch(&self, name: &str, destination: &Path) -> crate::Result<()>;
+
+    fn list(&self) -> crate::Result<Vec<String>>;
+
+    fn remove(&self, name: &str) -> crate::Result<()>;
+}
+
+/// Stores backups as plain files in a directory.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LocalDirectory {
+    pub path: PathBuf
+}
+
+impl LocalDirectory {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self { path: path.as_ref().to_path_buf() }
+    }
+}
+
+impl BackupTarget for LocalDirectory {
+    fn put(&self, name: &str, source: &Path) -> crate::Result<()> {
+        fs::create_dir_all(&self.path)?;
+        fs::copy(source, self.path.join(name))?;
+        Ok(())
+    }
+
+    fn fetch(&self, name: &str, destination: &Path) -> crate::Result<()> {
+        let path = self.path.join(name);
+        if !path.exists() {
+            return Err(Error::UnknownSnapshot(name.to_string()));
+        }
+        fs::copy(path, destination)?;
+        Ok(())
+    }
+
+    fn list(&self) -> crate::Result<Vec<String>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() && let Some(name) = entry.file_name().to_str() {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn remove(&self, name: &str) -> crate::Result<()> {
+        fs::remove_file(self.path.join(name))?;
+        Ok(())
+    }
+}
+
+static STAGING_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A temp file path for staging `name` that no other call in this process returns, so concurrent backups,
+/// restores and verifies don't overwrite each other's copies.
+fn staging_path(name: &str) -> PathBuf {
+    let unique = STAGING_COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("scarf-{}-{unique}-{name}", std::process::id()))
+}
+
+/// Takes a consistent copy of the database and pushes it to `target` as `name`.
+pub(crate) fn push(db: &Database, target: &dyn BackupTarget, name: &str) -> crate::Result<()> {
+    let staging = staging_path(name);
+    let pushed = copy_consistent(db, "backup", &staging).and_then(|_| target.put(name, &staging));
+    let _ = fs::remove_file(&staging);
+    pushed
+}
+
+/// Fetches backup `name` from `target` into a temporary file and passes its path to `restore`.
+pub(crate) fn pull(target: &dyn BackupTarget, name: &str, restore: impl FnOnce(&Path) -> crate::Result<()>) -> crate::Result<()> {
+    let staging = staging_path(name);
+    let restored = target.fetch(name, &staging).and_then(|_| restore(&staging));
+    let _ = fs::remove_file(&staging);
+    restored
+}
+
+/// Background thread that takes a snapshot every [SnapshotConfig::interval]. Stops when dropped.
+#[derive(Debug)]
+pub struct SnapshotScheduler {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>
+}
+
+impl SnapshotScheduler {
+    pub(crate) fn spawn(db: Database, config: SnapshotConfig) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handle = thread::spawn(move || {
+            loop {
+                thread::park_timeout(config.interval);
+                if stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                match take(&db, &config) {
+                    Ok(info) => tracing::info!(id = info.id.as_str(), size = info.size, "took scheduled snapshot"),
+                    Err(e) => tracing::warn!(error = %e, "scheduled snapshot failed")
+                }
+            }
+        });
+        Self {
+            stop,
+            handle: Some(handle)
+        }
+    }
+
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for SnapshotScheduler {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Result of [verify]. `errors` lists every value that failed to decode.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub format_version: u32,
+    /// Whether redb had to repair the copy before it could be checked.
+    pub repaired: bool,
+    pub tables: usize,
+    pub values_checked: u64,
+    pub errors: Vec<String>,
+    /// Stored collections whose documents and indexes weren't checked because no type was registered for them,
+    /// see [verify_with].
+    pub unchecked: Vec<String>
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+fn decode_values<K: redb::Key + 'static>(txn: &redb::ReadTransaction, name: &str, report: &mut VerifyReport) -> crate::Result<()> {
+    let table = txn.open_table(TableDefinition::<K, &[u8]>::new(name))?;
+    for entry in table.iter()? {
+        let (_, value) = entry?;
+        report.values_checked += 1;
+        if let Err(e) = rmp_serde::from_slice::<rmpv::Value>(value.value()) {
+            report.errors.push(format!("{name}: {e}"));
+        }
+    }
+    Ok(())
+}
+
+/// Checks a database file or backup without touching it: the file is copied aside, opened, run through
+/// redb's integrity check, and every msgpack value in scarf-owned tables is decoded. Collection tables are
+/// keyed by their document's primary key type, so checking them needs [verify_with]; here they're listed in
+/// [VerifyReport::unchecked].
+pub fn verify(path: impl AsRef<Path>) -> crate::Result<VerifyReport> {
+    verify_with(path, |_| Ok(()))
+}
+
+/// Like [verify], but `register` first registers document types on the copy (`|db| db.register::<User>("users")`)
+/// so every registered collection's documents are decoded as their type and its indexes are checked.
+pub fn verify_with(path: impl AsRef<Path>, register: impl FnOnce(&Database) -> crate::Result<()>) -> crate::Result<VerifyReport> {
+    let staging = staging_path("verify");
+    fs::copy(path.as_ref(), &staging)?;
+    let report = verify_copy(&staging, register);
+    let _ = fs::remove_file(&staging);
+    report
+}
+
+fn verify_copy(path: &Path, register: impl FnOnce(&Database) -> crate::Result<()>) -> crate::Result<VerifyReport> {
+    let mut raw = redb::Database::create(path)?;
+    let repaired = !raw.check_integrity()?;
+    let db = Database::wrap(raw, DatabaseLocation::file(path), DatabaseOptions::default());
+    let mut report = VerifyReport {
+        format_version: meta::format_version(&db)?,
+        repaired,
+        ..Default::default()
+    };
+
+    let txn = db.db()?.read()?.begin_read()?;
+    let names: Vec<String> = txn.list_tables()?.map(|handle| handle.name().to_string()).collect();
+    report.tables = names.len() + txn.list_multimap_tables()?.count();
+    for name in names {
+        if name == meta::META_TABLE {
+            decode_values::<&str>(&txn, &name, &mut report)?;
+        } else if name.starts_with("logs/") {
+            decode_values::<u64>(&txn, &name, &mut report)?;
+        } else if name.starts_with("timeseries/") {
+            decode_values::<SeriesKey>(&txn, &name, &mut report)?;
+        }
+    }
+    drop(txn);
+
+    register(&db)?;
+    for info in db.collections()? {
+        let Some(collection) = db.erased_collection(&info.name)? else {
+            report.unchecked.push(info.name);
+            continue;
+        };
+        let (documents, problems) = collection.verify()?;
+        report.values_checked += documents;
+        report.errors.extend(problems.into_iter().map(|problem| format!("collections/{}: {problem}", info.name)));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use redb::MultimapTableDefinition;
+
+    use super::*;
+    use crate::testing::{fixtures::Note, temp_path};
+
+    #[test]
+    fn verify_checks_registered_collections() -> crate::Result<()> {
+        let path = temp_path("scarf-verify");
+        let db = Database::open(&path)?;
+        let notes = db.collection::<Note>("notes");
+        notes.insert_many(["a", "b"].map(|id| Note { id: id.to_string(), title: format!("title {id}") }))?;
+
+        assert_eq!(verify(&path)?.unchecked, ["notes"]);
+        let report = verify_with(&path, |db| db.register::<Note>("notes").map(|_| ()))?;
+        assert!(report.is_ok() && report.unchecked.is_empty(), "{report:?}");
+
+        let txn = db.writer()?;
+        txn.write_guard("test", "notes")?.delete_multimap_table(MultimapTableDefinition::<&[u8], String>::new("collections/notes/index/title"))?;
+        txn.commit()?;
+        let report = verify_with(&path, |db| db.register::<Note>("notes").map(|_| ()))?;
+        assert_eq!(report.errors.len(), 2, "{report:?}");
+
+        drop(db);
+        let _ = fs::remove_file(path);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_restores_keep_the_original_database() -> crate::Result<()> {
+        let (path, directory) = (temp_path("scarf-restore"), temp_path("scarf-restore-target"));
+        let db = Database::open(&path)?;
+        let notes = db.collection::<Note>("notes");
+        let note = |id: &str| Note { id: id.to_string(), title: format!("title {id}") };
+        notes.insert(&note("a"))?;
+        let target = LocalDirectory::new(&directory);
+        db.backup_to(&target, "good")?;
+
+        notes.insert(&note("b"))?;
+        db.restore_from(&target, "good")?;
+        assert_eq!(notes.get(&"a".to_string())?, Some(note("a")));
+        assert_eq!(notes.get(&"b".to_string())?, None);
+
+        fs::write(directory.join("bad"), b"not a database")?;
+        assert!(db.restore_from(&target, "bad").is_err());
+        assert_eq!(notes.get(&"a".to_string())?, Some(note("a")));
+        notes.insert(&note("c"))?;
+        assert_eq!(notes.get(&"c".to_string())?, Some(note("c")));
+        for suffix in ["restoring", "previous"] {
+            assert!(!PathBuf::from(format!("{}.{suffix}", path.display())).exists());
+        }
+
+        drop(db);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_dir_all(directory);
+        Ok(())
+    }
+}
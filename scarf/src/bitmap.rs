@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+
+/// The low 12 bits of a row are its place in its [Container], the rest pick the container. Containers are
+/// smaller than roaring's 65,536 rows so that a write rewrites at most 512 bytes per indexed value.
+pub const CONTAINER_BITS: u32 = 12;
+const WORDS: usize = 1 << (CONTAINER_BITS - 6);
+/// Containers holding this many rows switch from a sorted array to a bitset, which is then no larger.
+const ARRAY_LIMIT: usize = WORDS * 4;
+
+/// One 4,096-row slice of a [Bitmap], stored the roaring way: a sorted array of the set rows while there
+/// are few of them, a bitset once that would be larger.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Container {
+    Array(Vec<u16>),
+    Bits(Vec<u64>)
+}
+
+impl Default for Container {
+    fn default() -> Self {
+        Self::Array(Vec::new())
+    }
+}
+
+impl Container {
+    /// Reads a container written by [Container::as_bytes], or `None` if `bytes` isn't one.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() == WORDS * 8 {
+            return Some(Self::Bits(bytes.chunks_exact(8).map(|word| u64::from_be_bytes(word.try_into().unwrap_or_default())).collect()));
+        }
+        let chunks = bytes.chunks_exact(2);
+        if !chunks.remainder().is_empty() || bytes.len() / 2 >= ARRAY_LIMIT {
+            return None;
+        }
+        let rows: Vec<u16> = chunks.map(|row| u16::from_be_bytes(row.try_into().unwrap_or_default())).collect();
+        rows.windows(2).all(|pair| pair.first() < pair.get(1)).then_some(Self::Array(rows))
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Array(rows) => rows.iter().flat_map(|row| row.to_be_bytes()).collect(),
+            Self::Bits(words) => words.iter().flat_map(|word| word.to_be_bytes()).collect()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Array(rows) => rows.len(),
+            Self::Bits(words) => words.iter().map(|word| word.count_ones() as usize).sum()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Self::Array(rows) => rows.is_empty(),
+            Self::Bits(words) => words.iter().all(|word| *word == 0)
+        }
+    }
+
+    pub fn contains(&self, row: u16) -> bool {
+        match self {
+            Self::Array(rows) => rows.binary_search(&row).is_ok(),
+            Self::Bits(words) => words.get(usize::from(row >> 6)).is_some_and(|word| word & (1 << (row & 63)) != 0)
+        }
+    }
+
+    /// Sets `row`, returning `false` if it was already set.
+    pub fn insert(&mut self, row: u16) -> bool {
+        match self {
+            Self::Array(rows) => {
+                let Err(at) = rows.binary_search(&row) else {
+                    return false;
+                };
+                rows.insert(at, row);
+                if rows.len() >= ARRAY_LIMIT {
+                    *self = Self::bits(rows);
+                }
+                true
+            },
+            Self::Bits(words) => match words.get_mut(usize::from(row >> 6)) {
+                Some(word) if *word & (1 << (row & 63)) == 0 => {
+                    *word |= 1 << (row & 63);
+                    true
+                },
+                _ => false
+            }
+        }
+    }
+
+    /// Clears `row`, returning `false` if it wasn't set.
+    pub fn remove(&mut self, row: u16) -> bool {
+        let removed = match self {
+            Self::Array(rows) => match rows.binary_search(&row) {
+                Ok(at) => {
+                    rows.remove(at);
+                    true
+                },
+                Err(_) => false
+            },
+            Self::Bits(words) => match words.get_mut(usize::from(row >> 6)) {
+                Some(word) if *word & (1 << (row & 63)) != 0 => {
+                    *word &= !(1 << (row & 63));
+                    true
+                },
+                _ => false
+            }
+        };
+        if matches!(self, Self::Bits(_)) && self.len() < ARRAY_LIMIT {
+            *self = Self::Array(self.iter().collect());
+        }
+        removed
+    }
+
+    /// The rows set in both containers.
+    pub fn intersection(&self, other: &Self) -> Self {
+        match (self, other) {
+            (Self::Bits(ours), Self::Bits(theirs)) => {
+                let words: Vec<u64> = ours.iter().zip(theirs).map(|(ours, theirs)| ours & theirs).collect();
+                let joined = Self::Bits(words);
+                match joined.len() >= ARRAY_LIMIT {
+                    true => joined,
+                    false => Self::Array(joined.iter().collect())
+                }
+            },
+            (Self::Array(rows), other) | (other, Self::Array(rows)) => Self::Array(rows.iter().copied().filter(|row| other.contains(*row)).collect())
+        }
+    }
+
+    /// The set rows, in ascending order.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = u16> + '_> {
+        match self {
+            Self::Array(rows) => Box::new(rows.iter().copied()),
+            Self::Bits(words) => Box::new((0..1 << CONTAINER_BITS).filter(move |row| words.get(usize::from(row >> 6)).is_some_and(|word| word & (1 << (row & 63)) != 0)))
+        }
+    }
+
+    fn bits(rows: &[u16]) -> Self {
+        let mut words = vec![0u64; WORDS];
+        for row in rows {
+            if let Some(word) = words.get_mut(usize::from(row >> 6)) {
+                *word |= 1 << (row & 63);
+            }
+        }
+        Self::Bits(words)
+    }
+}
+
+/// A compressed set of `u32` rows made of one [Container] per 4,096 rows, as kept by
+/// [IndexSpec::bitmap](crate::document::IndexSpec::bitmap) indexes. Intersections only visit the containers
+/// both sides have.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Bitmap {
+    containers: BTreeMap<u32, Container>
+}
+
+impl Bitmap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Splits `row` into the key of its container and its place in that container.
+    pub fn split(row: u32) -> (u32, u16) {
+        (row >> CONTAINER_BITS, (row & ((1 << CONTAINER_BITS) - 1)) as u16)
+    }
+
+    /// Adds the container with key `chunk`, replacing any held under it.
+    pub fn insert_container(&mut self, chunk: u32, container: Container) {
+        if !container.is_empty() {
+            self.containers.insert(chunk, container);
+        }
+    }
+
+    pub fn insert(&mut self, row: u32) -> bool {
+        let (chunk, low) = Self::split(row);
+        self.containers.entry(chunk).or_default().insert(low)
+    }
+
+    pub fn contains(&self, row: u32) -> bool {
+        let (chunk, low) = Self::split(row);
+        self.containers.get(&chunk).is_some_and(|container| container.contains(low))
+    }
+
+    pub fn len(&self) -> usize {
+        self.containers.values().map(Container::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.containers.values().all(Container::is_empty)
+    }
+
+    pub fn intersection(&self, other: &Self) -> Self {
+        let containers = self.containers.iter()
+            .filter_map(|(chunk, ours)| Some((*chunk, ours.intersection(other.containers.get(chunk)?))))
+            .filter(|(_, container)| !container.is_empty())
+            .collect();
+        Self { containers }
+    }
+
+    /// The set rows, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.containers.iter().flat_map(|(chunk, container)| container.iter().map(move |low| (chunk << CONTAINER_BITS) | u32::from(low)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn containers_switch_between_arrays_and_bitsets() {
+        let mut container = Container::default();
+        for row in (0..1_000u16).map(|row| row * 3) {
+            assert!(container.insert(row));
+        }
+        assert!(matches!(container, Container::Bits(_)));
+        assert_eq!(container.len(), 1_000);
+        assert!(!container.insert(3));
+        assert_eq!(Container::from_bytes(&container.as_bytes()), Some(container.clone()));
+
+        for row in (200..1_000u16).map(|row| row * 3) {
+            assert!(container.remove(row));
+        }
+        assert!(matches!(container, Container::Array(_)));
+        assert_eq!(container.len(), 200);
+        assert!(container.contains(3) && !container.contains(600));
+        assert!(!container.remove(601));
+        assert_eq!(Container::from_bytes(&container.as_bytes()), Some(container.clone()));
+        assert_eq!(Container::from_bytes(&[0, 2, 0, 1]), None);
+    }
+
+    #[test]
+    fn intersections_match_a_brute_force_filter() {
+        let (mut evens, mut thirds) = (Bitmap::new(), Bitmap::new());
+        for row in (0..300_000u32).step_by(2) {
+            evens.insert(row);
+        }
+        for row in (0..300_000u32).step_by(3).chain([1 << 30]) {
+            thirds.insert(row);
+        }
+        let joined = evens.intersection(&thirds);
+        let expected: Vec<u32> = (0..300_000u32).step_by(6).collect();
+        assert_eq!(joined.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(joined.len(), expected.len());
+        assert!(joined.contains(6) && !joined.contains(1 << 30));
+        assert!(evens.intersection(&Bitmap::new()).is_empty());
+    }
+}
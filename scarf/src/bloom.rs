@@ -0,0 +1,158 @@
+use std::{
+    collections::HashMap, f64::consts::LN_2, sync::{Arc, RwLock}
+};
+
+use crate::{
+    database::{Database, Transaction}, document::{fnv1a_128, mix64}
+};
+
+/// A fixed-size bloom filter over byte strings: [BloomFilter::may_contain] is `false` only for items that were
+/// never inserted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32
+}
+
+impl BloomFilter {
+    /// Sized to hold `expected_items` at a false-positive rate of about `false_positive_rate`.
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        let (items, rate) = (expected_items.max(1) as f64, false_positive_rate.clamp(1e-9, 0.5));
+        let bits = (-items * rate.ln() / (LN_2 * LN_2)).ceil().max(64.0);
+        Self {
+            bits: vec![0; (bits as usize).div_ceil(64)],
+            hashes: (bits / items * LN_2).round().clamp(1.0, 32.0) as u32
+        }
+    }
+
+    pub fn insert(&mut self, item: &[u8]) {
+        for (word, mask) in self.positions(item) {
+            if let Some(word) = self.bits.get_mut(word) {
+                *word |= mask;
+            }
+        }
+    }
+
+    pub fn may_contain(&self, item: &[u8]) -> bool {
+        self.positions(item).all(|(word, mask)| self.bits.get(word).is_some_and(|word| word & mask != 0))
+    }
+
+    /// Size of the bit array in bytes.
+    pub fn size(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    /// The word and bit mask of each of the item's probes, by double hashing.
+    fn positions(&self, item: &[u8]) -> impl Iterator<Item = (usize, u64)> + use<> {
+        let hash = fnv1a_128(item);
+        let (first, step) = (mix64(hash as u64), mix64((hash >> 64) as u64) | 1);
+        let bits = self.bits.len() as u64 * 64;
+        (0..u64::from(self.hashes)).map(move |probe| {
+            let bit = first.wrapping_add(probe.wrapping_mul(step)) % bits;
+            ((bit / 64) as usize, 1 << (bit % 64))
+        })
+    }
+}
+
+/// Scans a collection's primary keys into a new filter and installs it, see [KeyFilters::rebuild].
+pub(crate) type BuildKeyFilter = fn(&Database, &str, KeyFilterConfig) -> crate::Result<()>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct KeyFilterConfig {
+    pub expected_keys: usize,
+    pub false_positive_rate: f64
+}
+
+#[derive(Clone, Debug)]
+struct KeyFilter {
+    /// `None` while the stored keys may have changed behind the filter's back, until it's rebuilt.
+    filter: Option<BloomFilter>,
+    /// Transactions opened before the filter was built may see keys it doesn't hold, so only transactions with
+    /// at least this id consult it.
+    since: u64,
+    config: KeyFilterConfig,
+    build: BuildKeyFilter
+}
+
+/// The in-memory primary key filters of a database's collections, see
+/// [crate::database::Collection::enable_key_filter].
+#[derive(Clone, Debug, Default)]
+pub(crate) struct KeyFilters {
+    filters: Arc<RwLock<HashMap<String, KeyFilter>>>
+}
+
+impl KeyFilters {
+    pub(crate) fn install(&self, collection: impl AsRef<str>, filter: BloomFilter, since: u64, config: KeyFilterConfig, build: BuildKeyFilter) -> crate::Result<()> {
+        self.filters.write()?.insert(collection.as_ref().to_string(), KeyFilter { filter: Some(filter), since, config, build });
+        Ok(())
+    }
+
+    pub(crate) fn remove(&self, collection: impl AsRef<str>) -> crate::Result<bool> {
+        Ok(self.filters.write()?.remove(collection.as_ref()).is_some())
+    }
+
+    /// Size in bytes of the filter of `collection`, if it has a usable one.
+    pub(crate) fn size(&self, collection: impl AsRef<str>) -> crate::Result<Option<usize>> {
+        Ok(self.filters.read()?.get(collection.as_ref()).and_then(|filter| filter.filter.as_ref()).map(BloomFilter::size))
+    }
+
+    /// Whether `key` is certainly not stored in `collection` as seen by `txn`.
+    pub(crate) fn excludes(&self, collection: impl AsRef<str>, txn: &Transaction, key: &[u8]) -> crate::Result<bool> {
+        let filters = self.filters.read()?;
+        let Some(KeyFilter { filter: Some(filter), since, .. }) = filters.get(collection.as_ref()) else {
+            return Ok(false);
+        };
+        Ok(txn.id() >= *since && !filter.may_contain(key))
+    }
+
+    /// Records a key about to be written to `collection`.
+    pub(crate) fn insert(&self, collection: impl AsRef<str>, key: &[u8]) -> crate::Result<()> {
+        if let Some(KeyFilter { filter: Some(filter), .. }) = self.filters.write()?.get_mut(collection.as_ref()) {
+            filter.insert(key);
+        }
+        Ok(())
+    }
+
+    /// Stops consulting every filter until [KeyFilters::rebuild], for when the whole file is replaced.
+    pub(crate) fn invalidate_all(&self) -> crate::Result<()> {
+        for filter in self.filters.write()?.values_mut() {
+            filter.filter = None;
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the filters of `collections` that have one, or of every collection if `None`.
+    pub(crate) fn rebuild(&self, db: &Database, collections: Option<&[String]>) -> crate::Result<()> {
+        let builds: Vec<(String, KeyFilterConfig, BuildKeyFilter)> = {
+            let mut filters = self.filters.write()?;
+            filters
+                .iter_mut()
+                .filter(|(name, _)| collections.is_none_or(|collections| collections.contains(name)))
+                .map(|(name, filter)| {
+                    filter.filter = None;
+                    (name.clone(), filter.config, filter.build)
+                })
+                .collect()
+        };
+        for (name, config, build) in builds {
+            build(db, &name, config)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserted_items_are_always_found() {
+        let mut filter = BloomFilter::new(1000, 0.01);
+        for item in 0u32..1000 {
+            filter.insert(&item.to_be_bytes());
+        }
+        assert!((0u32..1000).all(|item| filter.may_contain(&item.to_be_bytes())));
+        let false_positives = (1000u32..11000).filter(|item| filter.may_contain(&item.to_be_bytes())).count();
+        assert!(false_positives < 300, "{false_positives} false positives in 10000 lookups");
+    }
+}
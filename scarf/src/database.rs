@@ -1,11 +1,13 @@
-use either::Either;
-use redb::{backends::InMemoryBackend, TableDefinition, TableHandle};
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use redb::{backends::InMemoryBackend, Durability, Key, MultimapTableDefinition, MultimapTableHandle, ReadableMultimapTable, ReadableTable, ReadableTableMetadata, TableDefinition, TableHandle, Value};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
-    borrow::Borrow, collections::HashMap, convert::Infallible, fmt::Display, marker::PhantomData, ops::Deref, path::{Path, PathBuf}, sync::{Arc, Mutex, MutexGuard, RwLock}
+    any::Any, borrow::Borrow, cell::{OnceCell, RefCell}, collections::{BTreeMap, HashMap, HashSet}, fs, hash::Hash, marker::PhantomData, ops::{Bound, RangeBounds}, panic::{self, AssertUnwindSafe}, path::{Path, PathBuf}, sync::{Arc, Mutex, MutexGuard, OnceLock, RwLock}, time::{Duration, Instant}
 };
 
-use crate::document::Document;
+use crate::{
+    backup::{self, BackupTarget, SnapshotConfig, SnapshotInfo, SnapshotScheduler}, bitmap::{Bitmap, Container}, bloom::{BloomFilter, KeyFilterConfig, KeyFilters}, sketch::HyperLogLog, document::{base64_index_key, decode_ordered_string, encode_index_value, encode_ordered_prefix, encode_ordered_value, fill_missing, index_names, index_spec, index_values, named_value, read_pointer, value_at_mut, Document, IndexKeyFormat, OwnedKey}, erased::ErasedCollection, graph::Edges, keys::KeySet, log::Log, meta::{self, AppMeta}, multi_get::MultiGet, options::{DatabaseBuilder, DatabaseOptions}, query::Query, registry::CollectionRegistry, relation::{DeleteHooks, Relation}, timeseries::{Sample, TimeSeries}, tracking::{ChangeKind, CheckpointScheduler, DryRun, DurableCheckpoint, TableChanges, TransactionGuard, TransactionInfo, TransactionKind, TransactionMetrics, TransactionTracker, Watchdog, WriteAmplificationReport, WriteContext}, tree::Tree, Error
+};
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
@@ -24,134 +26,3927 @@ impl DatabaseLocation {
     }
 }
 
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WarmupReport {
+    pub tables: usize,
+    pub pages: u64,
+    pub bytes: u64,
+    pub elapsed: Duration
+}
+
+/// A collection found in the database file by [Database::collections].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CollectionInfo {
+    pub name: String,
+    pub indexes: Vec<String>,
+    pub documents: u64,
+    pub index_format: IndexKeyFormat
+}
+
+/// Size of one table as reported by redb; `bytes` covers stored data, metadata and fragmentation.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TableSize {
+    pub entries: u64,
+    pub bytes: u64
+}
+
+impl From<(u64, redb::TableStats)> for TableSize {
+    fn from((entries, stats): (u64, redb::TableStats)) -> Self {
+        Self { entries, bytes: stats.stored_bytes() + stats.metadata_bytes() + stats.fragmented_bytes() }
+    }
+}
+
+/// Storage used by a collection, see [Collection::stats].
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CollectionStats {
+    pub documents: TableSize,
+    pub indexes: BTreeMap<String, TableSize>
+}
+
+impl CollectionStats {
+    pub fn total_bytes(&self) -> u64 {
+        self.documents.bytes + self.indexes.values().map(|size| size.bytes).sum::<u64>()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Database {
     database: Arc<RwLock<redb::Database>>,
-    location: DatabaseLocation
+    location: DatabaseLocation,
+    tracker: TransactionTracker,
+    options: DatabaseOptions,
+    registry: CollectionRegistry,
+    delete_hooks: DeleteHooks,
+    key_filters: KeyFilters,
+    /// Why the handle can't be used any more, set when a restore couldn't reopen any database file.
+    unusable: Arc<OnceLock<String>>
 }
 
 impl Database {
+    pub fn builder() -> DatabaseBuilder {
+        DatabaseBuilder::default()
+    }
+
     pub fn open(path: impl AsRef<Path>) -> crate::Result<Self> {
-        let db = redb::Database::create(path.as_ref().to_path_buf())?;
-        Ok(Self {
-            database: Arc::new(RwLock::new(db)),
-            location: DatabaseLocation::file(path)
-        })
+        Self::builder().open(path)
     }
-    
+
     pub fn open_in_memory() -> crate::Result<Self> {
-        let db = redb::Database::builder().create_with_backend(InMemoryBackend::new())?;
-        Ok(Self {
+        Self::builder().open_in_memory()
+    }
+
+    pub(crate) fn initialize(db: redb::Database, location: DatabaseLocation, options: DatabaseOptions) -> crate::Result<Self> {
+        let database = Self::wrap(db, location, options);
+        meta::ensure_format(&database, &database.options)?;
+        Ok(database)
+    }
+
+    /// Wraps an open redb database without checking its format.
+    pub(crate) fn wrap(db: redb::Database, location: DatabaseLocation, options: DatabaseOptions) -> Self {
+        Self {
             database: Arc::new(RwLock::new(db)),
-            location: DatabaseLocation::memory()
-        })
+            location,
+            tracker: TransactionTracker::new(options.ingest.clone()),
+            options,
+            registry: CollectionRegistry::default(),
+            delete_hooks: DeleteHooks::default(),
+            key_filters: KeyFilters::default(),
+            unusable: Arc::new(OnceLock::new())
+        }
     }
 
     pub fn location(&self) -> DatabaseLocation {
         self.location.clone()
     }
 
-    pub(crate) fn db(&self) -> Arc<RwLock<redb::Database>> {
-        self.database.clone()
+    /// The scarf format version stamped in this database, see [meta::FORMAT_VERSION].
+    pub fn format_version(&self) -> crate::Result<u32> {
+        meta::format_version(self)
+    }
+
+    /// Describes the table naming, key encodings and metadata layout of this database, so other tools can
+    /// read scarf files without linking scarf.
+    pub fn describe_format(&self) -> crate::Result<meta::FormatDescription> {
+        meta::describe(self)
+    }
+
+    pub(crate) fn db(&self) -> crate::Result<Arc<RwLock<redb::Database>>> {
+        self.usable()?;
+        Ok(self.database.clone())
+    }
+
+    pub(crate) fn tracker(&self) -> TransactionTracker {
+        self.tracker.clone()
     }
 
     pub fn reader(&self) -> crate::Result<Transaction> {
-        Transaction::reader(self.clone())
+        Transaction::reader(self.clone(), "reader", None)
     }
 
     pub fn writer(&self) -> crate::Result<Transaction> {
-        Transaction::writer(self.clone())
+        Transaction::writer(self.clone(), "writer", None)
+    }
+
+    /// A write transaction carrying `context`, for `_in` writes whose provenance should be recorded.
+    pub fn writer_with(&self, context: WriteContext) -> crate::Result<Transaction> {
+        let txn = self.writer()?;
+        txn.set_context(context)?;
+        Ok(txn)
+    }
+
+    /// Runs `run` in a write transaction and rolls it back, returning what it would have changed per table, e.g.
+    /// to preview or validate a request. Reads inside `run` see its own writes.
+    pub fn dry_run<R>(&self, run: impl FnOnce(&Transaction) -> crate::Result<R>) -> crate::Result<DryRun<R>> {
+        let txn = Transaction::writer(self.clone(), "dry_run", None)?;
+        let result = run(&txn);
+        let tables = txn.changes()?;
+        txn.abort()?;
+        Ok(DryRun { result: result?, tables })
+    }
+
+    pub(crate) fn begin_read(&self, operation: impl AsRef<str>, target: impl AsRef<str>) -> crate::Result<Transaction> {
+        Transaction::reader(self.clone(), operation, Some(target.as_ref().to_string()))
+    }
+
+    pub(crate) fn begin_write(&self, operation: impl AsRef<str>, target: impl AsRef<str>) -> crate::Result<Transaction> {
+        Transaction::writer(self.clone(), operation, Some(target.as_ref().to_string()))
+    }
+
+    /// Lists every transaction opened through this handle (or its clones) that hasn't been dropped yet, oldest first.
+    pub fn active_transactions(&self) -> crate::Result<Vec<TransactionInfo>> {
+        self.tracker.active()
+    }
+
+    pub fn transaction_metrics(&self) -> crate::Result<TransactionMetrics> {
+        self.tracker.metrics()
+    }
+
+    /// Bytes written per table by every operation committed through this handle since it was opened.
+    pub fn write_amplification_report(&self) -> crate::Result<WriteAmplificationReport> {
+        self.tracker.write_amplification()
+    }
+
+    /// The newest durable commit. Under [crate::options::DatabaseOptions::ingest] later commits may be lost on crash.
+    pub fn last_durable_checkpoint(&self) -> crate::Result<Option<DurableCheckpoint>> {
+        self.tracker.last_durable_checkpoint()
+    }
+
+    /// Blocks new write transactions until [Database::unfreeze], then waits for open ones to finish, so the file can
+    /// be copied or maintained without racing writers. Readers are unaffected. Calling this while holding a
+    /// write transaction on this thread deadlocks.
+    pub fn freeze(&self) -> crate::Result<()> {
+        self.tracker.freeze()
+    }
+
+    /// Lets write transactions blocked by [Database::freeze] proceed.
+    pub fn unfreeze(&self) -> crate::Result<()> {
+        self.tracker.unfreeze()
+    }
+
+    pub fn is_frozen(&self) -> crate::Result<bool> {
+        self.tracker.is_frozen()
+    }
+
+    /// Forces a durable commit now, persisting every earlier eventual-durability commit.
+    pub fn checkpoint(&self) -> crate::Result<Option<DurableCheckpoint>> {
+        let txn = self.begin_write("checkpoint", "database")?;
+        if let Transaction::Write(_, guard) = &txn {
+            guard.require_durable();
+        }
+        txn.commit()?;
+        self.last_durable_checkpoint()
+    }
+
+    /// Starts a [CheckpointScheduler] checking every [interval](crate::tracking::CheckpointPolicy::interval) of the
+    /// [ingest](crate::options::DatabaseOptions::ingest) policy for commits left without a durable checkpoint.
+    /// Without an ingest policy every commit is durable and the scheduler never checkpoints.
+    pub fn schedule_checkpoints(&self) -> crate::Result<CheckpointScheduler> {
+        let interval = self.tracker.checkpoint_policy()?.map_or(Duration::from_secs(1), |policy| policy.interval);
+        Ok(CheckpointScheduler::spawn(self.clone(), interval))
+    }
+
+    /// Enables or disables emitting one `tracing` event per committed write transaction,
+    /// summarising the tables touched and the rows inserted, updated and deleted.
+    pub fn set_commit_logging(&self, enabled: bool) {
+        self.tracker.set_commit_logging(enabled);
+    }
+
+    /// Starts a [Watchdog] that checks every `interval` for read transactions older than `threshold`.
+    pub fn watchdog(&self, threshold: Duration, interval: Duration) -> Watchdog {
+        Watchdog::spawn(self.tracker(), threshold, interval)
+    }
+
+    fn snapshot_config(&self) -> crate::Result<SnapshotConfig> {
+        self.options.snapshots.clone().ok_or(Error::SnapshotsNotConfigured)
+    }
+
+    /// Takes a snapshot into the configured snapshot directory now.
+    pub fn snapshot(&self) -> crate::Result<SnapshotInfo> {
+        backup::take(self, &self.snapshot_config()?)
+    }
+
+    /// Lists the snapshots in the configured snapshot directory, oldest first.
+    pub fn snapshots(&self) -> crate::Result<Vec<SnapshotInfo>> {
+        backup::list(self.snapshot_config()?.directory)
+    }
+
+    /// Starts a [SnapshotScheduler] taking snapshots at the configured interval.
+    pub fn schedule_snapshots(&self) -> crate::Result<SnapshotScheduler> {
+        Ok(SnapshotScheduler::spawn(self.clone(), self.snapshot_config()?))
+    }
+
+    /// Replaces the database file with snapshot `id` and reopens it. Every clone of this handle sees the
+    /// restored data; fails if any transaction is still open.
+    pub fn restore_snapshot(&self, id: impl AsRef<str>) -> crate::Result<()> {
+        let DatabaseLocation::Filesystem(path) = self.location() else {
+            return Err(Error::NotFileBacked("restore".to_string()));
+        };
+        let snapshot = self.snapshots()?.into_iter().find(|info| info.id == id.as_ref()).ok_or_else(|| Error::UnknownSnapshot(id.as_ref().to_string()))?;
+        self.restore_file(&snapshot.path, &path)?;
+        tracing::info!(id = snapshot.id.as_str(), "restored snapshot");
+        Ok(())
+    }
+
+    /// Pushes a consistent copy of the database file to `target` as `name`.
+    pub fn backup_to(&self, target: &dyn BackupTarget, name: impl AsRef<str>) -> crate::Result<()> {
+        backup::push(self, target, name.as_ref())
+    }
+
+    /// Replaces the database file with backup `name` from `target`, with the same caveats as [Database::restore_snapshot].
+    pub fn restore_from(&self, target: &dyn BackupTarget, name: impl AsRef<str>) -> crate::Result<()> {
+        let DatabaseLocation::Filesystem(path) = self.location() else {
+            return Err(Error::NotFileBacked("restore".to_string()));
+        };
+        backup::pull(target, name.as_ref(), |source| self.restore_file(source, &path))?;
+        tracing::info!(name = name.as_ref(), "restored backup");
+        Ok(())
+    }
+
+    /// Swaps the file at `path` for a copy of `source`. The copy is staged beside `path` and opened once
+    /// before the current file is closed, so a backup redb can't read leaves the database as it was. If the
+    /// swap itself fails, the original file is put back and reopened; only when that fails too is the handle
+    /// left [Error::Unusable].
+    fn restore_file(&self, source: &Path, path: &Path) -> crate::Result<()> {
+        let mut database = self.database.write()?;
+        self.usable()?;
+        let active = self.tracker.active()?.len();
+        if active > 0 {
+            return Err(Error::TransactionsActive(active));
+        }
+        let (staging, previous) = (sibling_path(path, "restoring"), sibling_path(path, "previous"));
+        let staged = fs::copy(source, &staging).map_err(Error::from).and_then(|_| Ok(redb::Database::open(&staging).map(drop)?));
+        if let Err(e) = staged {
+            let _ = fs::remove_file(&staging);
+            return Err(e);
+        }
+
+        self.key_filters.invalidate_all()?;
+        drop(std::mem::replace(&mut *database, redb::Database::builder().create_with_backend(InMemoryBackend::new())?));
+        let set_aside = fs::rename(path, &previous).is_ok();
+        let restored = match set_aside {
+            true => fs::rename(&staging, path).map_err(Error::from).and_then(|_| Ok(redb::Database::open(path)?)),
+            false => Err(Error::Io(std::io::Error::other(format!("couldn't move {} aside", path.display()))))
+        };
+        let result = match restored {
+            Ok(restored) => {
+                *database = restored;
+                let _ = fs::remove_file(&previous);
+                Ok(())
+            },
+            Err(e) => {
+                let _ = fs::remove_file(&staging);
+                let put_back = match set_aside {
+                    true => fs::rename(&previous, path).map_err(Error::from),
+                    false => Ok(())
+                };
+                match put_back.and_then(|_| Ok(redb::Database::open(path)?)) {
+                    Ok(original) => *database = original,
+                    Err(reopen) => {
+                        let _ = self.unusable.set(format!("restoring {} failed ({e}) and reopening it failed ({reopen})", path.display()));
+                    }
+                }
+                Err(e)
+            }
+        };
+        drop(database);
+        self.usable()?;
+        self.key_filters.rebuild(self, None)?;
+        result
+    }
+
+    /// Fails with [Error::Unusable] once a failed restore has left this handle without a database file.
+    fn usable(&self) -> crate::Result<()> {
+        match self.unusable.get() {
+            Some(reason) => Err(Error::Unusable(reason.clone())),
+            None => Ok(())
+        }
+    }
+
+    /// Compacts the file, returning whether any space was reclaimed, then rebuilds every key filter (see
+    /// [Collection::enable_key_filter]) so deleted keys stop passing them. Fails if any transaction is open.
+    pub fn compact(&self) -> crate::Result<bool> {
+        self.usable()?;
+        let compacted = self.database.write()?.compact()?;
+        self.key_filters.rebuild(self, None)?;
+        Ok(compacted)
+    }
+
+    /// Reads every page of the tables matching `prefixes` (a table name, or a prefix such as
+    /// `collections/users` covering its index tables) so later queries hit redb's page cache.
+    pub fn warmup(&self, prefixes: impl IntoIterator<Item = impl AsRef<str>>) -> crate::Result<WarmupReport> {
+        let prefixes: Vec<String> = prefixes.into_iter().map(|prefix| prefix.as_ref().to_string()).collect();
+        let matches = |name: &str| prefixes.iter().any(|prefix| name == prefix || name.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.starts_with('/')));
+        let started = Instant::now();
+        let txn = self.begin_read("warmup", prefixes.join(", "))?;
+        let Transaction::Read(inner, _) = &txn else {
+            return Ok(WarmupReport::default());
+        };
+        let txn = inner.read()?;
+        let mut report = WarmupReport::default();
+        let mut record = |stats: redb::TableStats| {
+            report.tables += 1;
+            report.pages += stats.leaf_pages() + stats.branch_pages();
+            report.bytes += stats.stored_bytes() + stats.metadata_bytes() + stats.fragmented_bytes();
+        };
+        for handle in txn.list_tables()? {
+            if matches(handle.name()) {
+                record(txn.open_untyped_table(handle)?.stats()?);
+            }
+        }
+        for handle in txn.list_multimap_tables()? {
+            if matches(handle.name()) {
+                record(txn.open_untyped_multimap_table(handle)?.stats()?);
+            }
+        }
+        report.elapsed = started.elapsed();
+        Ok(report)
     }
 
     pub fn collection<T: Document>(&self, name: impl AsRef<str>) -> Collection<T> {
         Collection::<T>::new(self.clone(), name.as_ref().to_string())
     }
+
+    /// Deletes collection `name`'s main table and every index table in one write transaction, returning `false`
+    /// if nothing was stored under that name.
+    pub fn drop_collection(&self, name: impl AsRef<str>) -> crate::Result<bool> {
+        let txn = self.begin_write("drop_collection", format!("collections/{}", name.as_ref()))?;
+        let dropped = self.drop_collection_in(&txn, name)?;
+        txn.commit()?;
+        Ok(dropped)
+    }
+
+    pub fn drop_collection_in(&self, txn: &Transaction, name: impl AsRef<str>) -> crate::Result<bool> {
+        let main_name = format!("collections/{}", name.as_ref());
+        let index_prefix = format!("{main_name}/index/");
+        let guard = txn.write_guard("drop_collection", &main_name)?;
+        let owned = [
+            lock_table_name(name.as_ref()),
+            idempotency_table_name(name.as_ref()),
+            sketch_table_name(name.as_ref()),
+            bitmap_table_name(name.as_ref()),
+            bitmap_rows_table_name(name.as_ref()),
+            bitmap_keys_table_name(name.as_ref())
+        ];
+        let tables: Vec<_> = guard.list_tables()?.filter(|handle| handle.name() == main_name || owned.iter().any(|owned| handle.name() == owned)).collect();
+        let indexes: Vec<_> = guard.list_multimap_tables()?.filter(|handle| handle.name().starts_with(&index_prefix)).collect();
+        let mut dropped = Vec::new();
+        for handle in tables {
+            if guard.delete_table(handle.clone())? {
+                dropped.push(handle.name().to_string());
+            }
+        }
+        for handle in indexes {
+            if guard.delete_multimap_table(handle.clone())? {
+                dropped.push(handle.name().to_string());
+            }
+        }
+        drop(guard);
+        for table in &dropped {
+            txn.record_change(table, ChangeKind::Delete, 0)?;
+        }
+        meta::set_index_format(txn, name.as_ref(), IndexKeyFormat::default())?;
+        meta::move_maintenance_cursors(txn, name.as_ref(), None)?;
+        if !dropped.is_empty() {
+            self.delete_hooks.run(self, txn, name.as_ref(), None)?;
+        }
+        Ok(!dropped.is_empty())
+    }
+
+    /// Every collection stored in the file, sorted by name, discovered from table names so no document types
+    /// are needed.
+    pub fn collections(&self) -> crate::Result<Vec<CollectionInfo>> {
+        let txn = self.begin_read("collections", "collections")?;
+        let Transaction::Read(inner, _) = &txn else {
+            return Ok(Vec::new());
+        };
+        let mut found: BTreeMap<String, CollectionInfo> = BTreeMap::new();
+        {
+            let inner = inner.read()?;
+            for handle in inner.list_tables()? {
+                if let Some(name) = handle.name().strip_prefix("collections/") {
+                    let documents = inner.open_untyped_table(handle.clone())?.len()?;
+                    found.insert(name.to_string(), CollectionInfo {
+                        name: name.to_string(),
+                        indexes: Vec::new(),
+                        documents,
+                        index_format: IndexKeyFormat::default()
+                    });
+                }
+            }
+            for handle in inner.list_multimap_tables()? {
+                if let Some((name, key)) = handle.name().strip_prefix("collections/").and_then(|name| name.rsplit_once("/index/"))
+                    && let Some(info) = found.get_mut(name)
+                {
+                    info.indexes.push(key.to_string());
+                }
+            }
+        }
+        for info in found.values_mut() {
+            info.indexes.sort();
+            info.index_format = meta::index_format(&txn, &info.name)?;
+        }
+        Ok(found.into_values().collect())
+    }
+
+    /// Moves collection `old` to `new`, copying its main table and every index table and deleting the originals
+    /// in one write transaction. Fails with [Error::CollectionExists] if `new` is taken, or with
+    /// [Error::CollectionConflict] if `new` is registered to another document type, and returns `false` if
+    /// nothing was stored under `old`. A registration of `old` moves to `new` once the rename happened.
+    pub fn rename_collection<T: Document + 'static>(&self, old: impl AsRef<str>, new: impl AsRef<str>) -> crate::Result<bool> {
+        let (source, target) = (self.collection::<T>(old), self.collection::<T>(new));
+        self.registry.ensure_available::<T>(target.name())?;
+        let operation = CollectionOperation::new_writer("rename_collection", &source)?;
+        let renamed = operation.rename_to(&target)?;
+        operation.commit()?;
+        if renamed {
+            self.registry.rename(source.name(), target.name())?;
+            self.delete_hooks.rename(source.name(), target.name())?;
+            self.key_filters.rebuild(self, Some(&[source.name(), target.name()]))?;
+        }
+        Ok(renamed)
+    }
+
+    /// Registers `T` as the document type stored in collection `name`. A name can only be registered to
+    /// one type and a type to one name; registering the same pair again is a no-op.
+    pub fn register<T: Document + Send + Sync + 'static>(&self, name: impl AsRef<str>) -> crate::Result<Collection<T>> {
+        self.registry.register::<T>(name.as_ref())?;
+        Ok(self.collection::<T>(name))
+    }
+
+    /// Opens the collection registered for `T` with [Database::register].
+    pub fn get_collection<T: Document + 'static>(&self) -> crate::Result<Collection<T>> {
+        Ok(self.collection::<T>(self.registry.name_of::<T>()?))
+    }
+
+    /// Opens a registered collection by name without knowing its document type.
+    pub fn erased_collection(&self, name: impl AsRef<str>) -> crate::Result<Option<Box<dyn ErasedCollection>>> {
+        self.registry.erased(self, name)
+    }
+
+    /// Every registered collection, sorted by name.
+    pub fn registered_collections(&self) -> crate::Result<Vec<Box<dyn ErasedCollection>>> {
+        self.registry.all(self)
+    }
+
+    /// Starts a batch of typed lookups across collections, resolved in one read transaction:
+    ///
+    /// ```ignore
+    /// let mut batch = db.multi_get();
+    /// let (user, posts) = (batch.get(&users, user_id), batch.get_many(&posts, post_ids));
+    /// let mut results = batch.execute()?;
+    /// let user: Option<User> = results.take(user).flatten();
+    /// ```
+    pub fn multi_get(&self) -> MultiGet {
+        MultiGet::new(self.clone())
+    }
+
+    /// Small application-owned metadata stored beside scarf's own, e.g. `db.app_meta().set("last_sync", &now)?`.
+    pub fn app_meta(&self) -> AppMeta {
+        AppMeta::new(self.clone())
+    }
+
+    pub fn timeseries<T: Sample>(&self, name: impl AsRef<str>) -> TimeSeries<T> {
+        TimeSeries::<T>::new(self.clone(), name.as_ref().to_string())
+    }
+
+    pub fn log<T: Serialize + DeserializeOwned>(&self, name: impl AsRef<str>) -> Log<T> {
+        Log::<T>::new(self.clone(), name.as_ref().to_string())
+    }
+
+    pub(crate) fn delete_hooks(&self) -> DeleteHooks {
+        self.delete_hooks.clone()
+    }
+
+    pub fn relation<A: Document, B: Document>(&self, name: impl AsRef<str>) -> Relation<A, B> {
+        Relation::<A, B>::new(self.clone(), name.as_ref().to_string())
+    }
+
+    pub fn edges<N: OwnedKey + Hash + Eq>(&self, name: impl AsRef<str>) -> Edges<N> {
+        Edges::<N>::new(self.clone(), name.as_ref().to_string())
+    }
+
+    /// A collection of untyped JSON documents keyed by the value at JSON pointer `id_pointer`, see
+    /// [JsonCollection](crate::json::JsonCollection).
+    #[cfg(feature = "json")]
+    pub fn json_collection(&self, name: impl AsRef<str>, id_pointer: impl AsRef<str>) -> crate::json::JsonCollection {
+        crate::json::JsonCollection::new(self.clone(), name.as_ref().to_string(), id_pointer.as_ref().to_string())
+    }
+}
+
+/// Opens `$definition` on either kind of transaction and evaluates `$body` with it bound to `$table`.
+/// Read transactions evaluate `$missing` instead if the table was never created.
+macro_rules! with_table {
+    (multimap $txn:expr, $definition:expr, $table:ident => $body:expr, $missing:expr) => {
+        match $txn {
+            $crate::database::Transaction::Read(txn, _) => match txn.read()?.open_multimap_table($definition) {
+                Ok($table) => $body,
+                Err(redb::TableError::TableDoesNotExist(_)) => $missing,
+                Err(e) => Err(e.into())
+            },
+            $crate::database::Transaction::Write(txn, _) => {
+                let txn = txn.lock()?;
+                let $table = txn.open_multimap_table($definition)?;
+                $body
+            }
+        }
+    };
+    ($txn:expr, $definition:expr, $table:ident => $body:expr, $missing:expr) => {
+        match $txn {
+            $crate::database::Transaction::Read(txn, _) => match txn.read()?.open_table($definition) {
+                Ok($table) => $body,
+                Err(redb::TableError::TableDoesNotExist(_)) => $missing,
+                Err(e) => Err(e.into())
+            },
+            $crate::database::Transaction::Write(txn, _) => {
+                let txn = txn.lock()?;
+                let $table = txn.open_table($definition)?;
+                $body
+            }
+        }
+    };
 }
 
+pub(crate) use with_table;
+
 #[derive(Clone)]
 pub enum Transaction {
-    Read(Arc<RwLock<redb::ReadTransaction>>),
-    Write(Arc<Mutex<redb::WriteTransaction>>)
+    Read(Arc<RwLock<redb::ReadTransaction>>, Arc<TransactionGuard>),
+    Write(Arc<Mutex<redb::WriteTransaction>>, Arc<TransactionGuard>)
 }
 
 impl Transaction {
-    pub(crate) fn reader(db: Database) -> crate::Result<Self> {
-        let txn = db.db().read()?.begin_read()?;
-        Ok(Self::Read(Arc::new(RwLock::new(txn))))
+    pub(crate) fn reader(db: Database, operation: impl AsRef<str>, target: Option<String>) -> crate::Result<Self> {
+        // Tracked before the snapshot is taken, so a transaction id orders it after everything that happened
+        // before it was assigned (see KeyFilters).
+        let guard = db.tracker().track(TransactionKind::Read, operation, target)?;
+        let txn = db.db()?.read()?.begin_read()?;
+        Ok(Self::Read(Arc::new(RwLock::new(txn)), guard))
     }
 
-    pub(crate) fn writer(db: Database) -> crate::Result<Self> {
-        let txn = db.db().read()?.begin_write()?;
-        Ok(Self::Write(Arc::new(Mutex::new(txn))))
+    pub(crate) fn writer(db: Database, operation: impl AsRef<str>, target: Option<String>) -> crate::Result<Self> {
+        let guard = db.tracker().track(TransactionKind::Write, operation, target)?;
+        let txn = db.db()?.read()?.begin_write()?;
+        Ok(Self::Write(Arc::new(Mutex::new(txn)), guard))
     }
 
-    
-}
+    pub fn is_writable(&self) -> bool {
+        matches!(self, Self::Write(..))
+    }
 
-#[derive(Clone, Debug)]
-pub struct Collection<T: Document> {
-    database: Database,
-    collection_name: String,
-    doctype: PhantomData<T>,
-    confirmed_existence: bool
-}
+    pub(crate) fn id(&self) -> u64 {
+        match self {
+            Self::Read(_, guard) | Self::Write(_, guard) => guard.id()
+        }
+    }
 
-impl<T: Document> Collection<T> {
-    pub(crate) fn new(db: Database, name: String) -> Self {
-        Self {
-            database: db,
-            collection_name: name,
-            doctype: PhantomData,
-            confirmed_existence: false
+    pub fn info(&self) -> crate::Result<Option<TransactionInfo>> {
+        match self {
+            Self::Read(_, guard) | Self::Write(_, guard) => guard.info()
         }
     }
 
-    pub fn name(&self) -> String {
-        self.collection_name.clone()
+    /// Attaches `context` to this transaction, replacing any set before, see [WriteContext].
+    pub fn set_context(&self, context: WriteContext) -> crate::Result<()> {
+        match self {
+            Self::Read(_, guard) | Self::Write(_, guard) => guard.set_context(context)
+        }
     }
 
-    fn index_table_names(&self) -> HashMap<String, String> {
-        let mut results = HashMap::new();
+    pub fn context(&self) -> crate::Result<Option<WriteContext>> {
+        Ok(self.info()?.and_then(|info| info.context))
+    }
 
-        for key in T::index_keys() {
-            results.insert(key.clone(), format!("collections/{}/index/{}", self.name(), key.clone()));
+    /// Commits the transaction, or rolls it back and fails with [Error::HookPanicked] if a callback panicked in it.
+    pub fn commit(self) -> crate::Result<()> {
+        match self {
+            Self::Read(..) => Ok(()),
+            Self::Write(txn, guard) => {
+                if let Some((hook, message)) = guard.panicked()? {
+                    Self::Write(txn, guard).abort()?;
+                    return Err(Error::HookPanicked { hook, message });
+                }
+                let started = Instant::now();
+                let mut txn = Arc::try_unwrap(txn).map_err(Error::arc_refs)?.into_inner()?;
+                let durable = guard.wants_durable()?;
+                if !durable {
+                    txn.set_durability(Durability::Eventual);
+                }
+                txn.commit()?;
+                guard.committed(started.elapsed(), durable)
+            }
+        }
+    }
+
+    pub fn abort(self) -> crate::Result<()> {
+        match self {
+            Self::Read(..) => Ok(()),
+            Self::Write(txn, guard) => {
+                Arc::try_unwrap(txn).map_err(Error::arc_refs)?.into_inner()?.abort()?;
+                guard.aborted()
+            }
         }
+    }
 
-        results
+    /// Runs user callback `hook`, turning a panic into [Error::HookPanicked] instead of unwinding through scarf with
+    /// this transaction open. A panicked write transaction can't be committed afterwards.
+    pub(crate) fn run_hook<R>(&self, hook: &str, callback: impl FnOnce() -> R) -> crate::Result<R> {
+        let payload = match panic::catch_unwind(AssertUnwindSafe(callback)) {
+            Ok(result) => return Ok(result),
+            Err(payload) => payload
+        };
+        let message = payload.downcast_ref::<&str>().map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        if let Self::Write(_, guard) = self {
+            guard.hook_panicked(hook, &message)?;
+        }
+        Err(Error::HookPanicked { hook: hook.to_string(), message })
     }
 
-    fn main_table_name(&self) -> String {
-        format!("collections/{}", self.name())
+    /// Notes a row-level change against `table` for commit logging.
+    pub(crate) fn record_change(&self, table: impl AsRef<str>, kind: ChangeKind, bytes: usize) -> crate::Result<()> {
+        match self {
+            Self::Read(..) => Ok(()),
+            Self::Write(_, guard) => guard.record_change(table, kind, bytes)
+        }
     }
 
-    pub(crate) fn database(&self) -> Database {
-        self.database.clone()
+    pub(crate) fn changes(&self) -> crate::Result<BTreeMap<String, TableChanges>> {
+        match self {
+            Self::Read(..) => Ok(BTreeMap::new()),
+            Self::Write(_, guard) => guard.changes()
+        }
+    }
+
+    /// Names of every multimap table visible to this transaction.
+    pub(crate) fn multimap_table_names(&self) -> crate::Result<Vec<String>> {
+        Ok(match self {
+            Self::Read(txn, _) => txn.read()?.list_multimap_tables()?.map(|handle| handle.name().to_string()).collect(),
+            Self::Write(txn, _) => txn.lock()?.list_multimap_tables()?.map(|handle| handle.name().to_string()).collect()
+        })
+    }
+
+    pub(crate) fn write_guard(&self, operation: impl AsRef<str>, target: impl AsRef<str>) -> crate::Result<MutexGuard<'_, redb::WriteTransaction>> {
+        match self {
+            Self::Read(..) => Err(Error::read_only(operation, target)),
+            Self::Write(txn, _) => Ok(txn.lock()?)
+        }
     }
 }
 
-#[derive(Clone)]
-pub(crate) struct CollectionOperation<T: Document> {
-    operation: String,
-    transaction: Transaction,
-    database: Database,
-    collection: Collection<T>
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpsertResult<T> {
+    Inserted,
+    Replaced(T)
 }
 
-impl<T: Document> CollectionOperation<T> {
-    pub fn new(operation: impl AsRef<str>, collection: &Collection<T>, transaction: &Transaction) -> Self {
-        Self {
-            operation: operation.as_ref().to_string(),
-            transaction: transaction.clone(),
-            database: collection.database(),
-            collection: collection.clone()
+/// Outcome of [Collection::insert_idempotent], holding the primary key of the document inserted for the
+/// idempotency key.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IdempotentInsert<K> {
+    Inserted(K),
+    /// The idempotency key was already used within its TTL, so nothing was written.
+    Duplicate(K)
+}
+
+/// What [Collection::sweep_expired] did, for tuning how often it runs.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SweepStats {
+    /// Expiry index entries read. Only entries up to the cutoff are read.
+    pub scanned: u64,
+    /// Documents deleted.
+    pub purged: u64,
+    pub elapsed: Duration
+}
+
+/// Progress of [Collection::rebuild_indexes_chunked], saved in the meta table after every chunk.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RebuildCursor<K> {
+    format: IndexKeyFormat,
+    /// Primary key of the last document indexed.
+    after: Option<K>,
+    written: u64
+}
+
+/// Progress of [Collection::check_indexes_chunked], saved in the meta table after every chunk.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CheckCursor<K> {
+    /// Primary key of the last document checked.
+    after: Option<K>,
+    /// Index entries found so far per index, compared with each index table's size after the last chunk.
+    found: BTreeMap<String, u64>,
+    problems: Vec<String>
+}
+
+/// A used idempotency key: the encoded primary key it inserted, and when the key may be reused.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct IdempotencyRecord {
+    id: Vec<u8>,
+    expires_at: DateTime<Utc>
+}
+
+fn idempotency_table_name(collection: &str) -> String {
+    format!("idempotency/{collection}")
+}
+
+/// `path` with `.{suffix}` appended, in the same directory so it can be renamed over `path`.
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{suffix}"));
+    PathBuf::from(name)
+}
+
+/// Holds the [HyperLogLog] sketch of each sketched index of `collection`, keyed by index name.
+fn sketch_table_name(collection: &str) -> String {
+    format!("sketches/{collection}")
+}
+
+/// Adds the stored index keys `values` to the sketch of `index`, returning the bytes written.
+fn add_to_sketch<'a>(txn: &redb::WriteTransaction, table: &str, index: &str, values: impl IntoIterator<Item = &'a Vec<u8>>) -> crate::Result<usize> {
+    let mut sketches = txn.open_table(TableDefinition::<&str, &[u8]>::new(table))?;
+    let mut sketch = match sketches.get(index)? {
+        Some(stored) => HyperLogLog::from_bytes(stored.value()).unwrap_or_default(),
+        None => HyperLogLog::new()
+    };
+    for value in values {
+        sketch.insert(value);
+    }
+    sketches.insert(index, sketch.as_bytes())?;
+    Ok(index.len() + sketch.as_bytes().len())
+}
+
+/// Holds the [Bitmap] containers of each bitmap index of `collection`, keyed by index name, stored index key
+/// and container number.
+fn bitmap_table_name(collection: &str) -> String {
+    format!("bitmaps/{collection}")
+}
+
+/// Maps primary keys to the rows `collection`'s bitmaps hold them under.
+fn bitmap_rows_table_name(collection: &str) -> String {
+    format!("bitmaps/{collection}/rows")
+}
+
+/// Maps bitmap rows back to primary keys.
+fn bitmap_keys_table_name(collection: &str) -> String {
+    format!("bitmaps/{collection}/keys")
+}
+
+/// The bitmap row of document `id`, and whether it was just numbered. Unless `allocate` is unset, a document
+/// without one gets the row after the last in use.
+fn bitmap_row<K: OwnedKey>(txn: &redb::WriteTransaction, collection: &str, id: &K, allocate: bool) -> crate::Result<Option<(u32, bool)>> {
+    let (rows_name, keys_name) = (bitmap_rows_table_name(collection), bitmap_keys_table_name(collection));
+    let mut rows = txn.open_table(TableDefinition::<K, u32>::new(&rows_name))?;
+    if let Some(row) = rows.get(id)? {
+        return Ok(Some((row.value(), false)));
+    }
+    if !allocate {
+        return Ok(None);
+    }
+    let mut keys = txn.open_table(TableDefinition::<u32, K>::new(&keys_name))?;
+    let row = match keys.last()? {
+        Some((last, _)) => last.value().checked_add(1).ok_or_else(|| Error::BitmapRowsExhausted(collection.to_string()))?,
+        None => 0
+    };
+    rows.insert(id, row)?;
+    keys.insert(row, id)?;
+    Ok(Some((row, true)))
+}
+
+/// Forgets the bitmap row of deleted document `id`, returning `false` if it had none.
+fn release_bitmap_row<K: OwnedKey>(txn: &redb::WriteTransaction, collection: &str, id: &K) -> crate::Result<bool> {
+    let (rows_name, keys_name) = (bitmap_rows_table_name(collection), bitmap_keys_table_name(collection));
+    let mut rows = txn.open_table(TableDefinition::<K, u32>::new(&rows_name))?;
+    let Some(row) = rows.remove(id)?.map(|row| row.value()) else {
+        return Ok(false);
+    };
+    txn.open_table(TableDefinition::<u32, K>::new(&keys_name))?.remove(row)?;
+    Ok(true)
+}
+
+/// Clears `row` in the bitmap of `index` for each stored key in `removed` and sets it for each in `added`,
+/// returning the bytes written.
+fn update_bitmaps<'a>(txn: &redb::WriteTransaction, collection: &str, index: &str, row: u32, removed: impl IntoIterator<Item = &'a Vec<u8>>, added: impl IntoIterator<Item = &'a Vec<u8>>) -> crate::Result<usize> {
+    let name = bitmap_table_name(collection);
+    let mut bitmaps = txn.open_table(TableDefinition::<(&str, &[u8], u32), &[u8]>::new(&name))?;
+    let (chunk, low) = Bitmap::split(row);
+    let mut written = 0;
+    for (value, set) in removed.into_iter().map(|value| (value, false)).chain(added.into_iter().map(|value| (value, true))) {
+        let key = (index, value.as_slice(), chunk);
+        let mut container = bitmaps.get(key)?.and_then(|stored| Container::from_bytes(stored.value())).unwrap_or_default();
+        let changed = match set {
+            true => container.insert(low),
+            false => container.remove(low)
+        };
+        if !changed {
+            continue;
         }
+        if container.is_empty() {
+            bitmaps.remove(key)?;
+        } else {
+            let bytes = container.as_bytes();
+            bitmaps.insert(key, bytes.as_slice())?;
+            written += index.len() + value.len() + 4 + bytes.len();
+        }
+    }
+    Ok(written)
+}
+
+fn bitmap_conditions<K: AsRef<str>, V: Into<rmpv::Value>>(conditions: impl IntoIterator<Item = (K, V)>) -> Vec<(String, rmpv::Value)> {
+    conditions.into_iter().map(|(key, value)| (key.as_ref().to_string(), value.into())).collect()
+}
+
+/// What a write operation changed. Returned by [Collection::insert], [Collection::insert_many],
+/// [Collection::insert_many_chunked], [Collection::delete_where], [Collection::update_where],
+/// [Collection::delete_matching] and [Collection::update_matching] (and their `_in` variants), the writes that touch keys the caller doesn't already hold.
+///
+/// Single-document writes such as [Collection::replace], [Collection::upsert], [Collection::delete] and
+/// [Collection::modify] return the document they replaced or removed (or the field result) instead, which
+/// already answers whether a previous value was present; they don't report index or byte counts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WriteReceipt<K> {
+    /// Primary keys written or deleted, in the order they were written.
+    pub keys: Vec<K>,
+    pub index_entries_added: u64,
+    pub index_entries_removed: u64,
+    /// Bytes of keys, documents and index entries written.
+    pub bytes: u64,
+    /// Writes that replaced or deleted an existing document.
+    pub previous: u64
+}
+
+impl<K> Default for WriteReceipt<K> {
+    fn default() -> Self {
+        Self { keys: Vec::new(), index_entries_added: 0, index_entries_removed: 0, bytes: 0, previous: 0 }
     }
+}
 
-    pub fn new_reader(operation: impl AsRef<str>, collection: &Collection<T>) -> crate::Result<Self> {
-        Ok(Self::new(operation, collection, &Transaction::reader(collection.database())?))
+impl<K> WriteReceipt<K> {
+    pub fn had_previous(&self) -> bool {
+        self.previous > 0
     }
 
-    pub fn new_writer(operation: impl AsRef<str>, collection: &Collection<T>) -> crate::Result<Self> {
-        Ok(Self::new(operation, collection, &Transaction::writer(collection.database())?))
+    pub fn merge(&mut self, other: Self) {
+        self.keys.extend(other.keys);
+        self.index_entries_added += other.index_entries_added;
+        self.index_entries_removed += other.index_entries_removed;
+        self.bytes += other.bytes;
+        self.previous += other.previous;
+    }
+}
+
+/// An advisory lock on one document, see [Collection::lock]. Locks don't block writes; applications check them.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DocumentLock {
+    pub owner: String,
+    pub acquired_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>
+}
+
+impl DocumentLock {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now()
+    }
+}
+
+/// `ttl` from now, saturating at the latest representable time.
+fn expires_after(now: DateTime<Utc>, ttl: Duration) -> DateTime<Utc> {
+    chrono::Duration::from_std(ttl).ok().and_then(|ttl| now.checked_add_signed(ttl)).unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+fn lock_table_name(collection: &str) -> String {
+    format!("locks/{collection}")
+}
+
+/// A collection declared once as a `static`, see [crate::collection!].
+#[derive(Debug)]
+pub struct CollectionDef<T: Document> {
+    name: &'static str,
+    doctype: PhantomData<fn() -> T>
+}
+
+impl<T: Document> CollectionDef<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self { name, doctype: PhantomData }
     }
-}
\ No newline at end of file
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn indexes(&self) -> Vec<String> {
+        index_names::<T>()
+    }
+
+    pub fn open(&self, db: &Database) -> Collection<T> {
+        db.collection::<T>(self.name)
+    }
+}
+
+impl<T: Document + Send + Sync + 'static> CollectionDef<T> {
+    /// Opens the collection through [Database::register], so a conflicting definition elsewhere fails.
+    pub fn register(&self, db: &Database) -> crate::Result<Collection<T>> {
+        db.register::<T>(self.name)
+    }
+}
+
+/// Declares a typed static [CollectionDef]:
+///
+/// ```ignore
+/// scarf::collection!(pub static USERS: User = "users");
+/// let users = USERS.open(&db);
+/// ```
+#[macro_export]
+macro_rules! collection {
+    ($(#[$meta:meta])* $vis:vis static $ident:ident: $doctype:ty = $name:expr;) => {
+        $(#[$meta])*
+        $vis static $ident: $crate::database::CollectionDef<$doctype> = $crate::database::CollectionDef::new($name);
+    };
+    ($(#[$meta:meta])* $vis:vis static $ident:ident: $doctype:ty = $name:expr) => {
+        $crate::collection!($(#[$meta])* $vis static $ident: $doctype = $name;);
+    };
+}
+
+pub(crate) type FieldProvider = Arc<dyn Fn() -> rmpv::Value + Send + Sync>;
+
+/// Values filled into unset fields of inserted documents, see [Collection::with_default].
+#[derive(Clone, Default)]
+pub(crate) struct FieldDefaults(pub(crate) Vec<(String, FieldProvider)>);
+
+impl std::fmt::Debug for FieldDefaults {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.0.iter().map(|(field, _)| field)).finish()
+    }
+}
+
+impl FieldDefaults {
+    /// `document` with every unset field filled in, or `None` if nothing was filled.
+    fn apply<T: Document>(&self, document: &T) -> crate::Result<Option<T>> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+        let mut value = named_value(document)?;
+        let mut filled = false;
+        for (field, provider) in &self.0 {
+            filled |= fill_missing(&mut value, field, || provider());
+        }
+        match filled {
+            true => Ok(Some(rmp_serde::from_slice::<T>(&rmp_serde::to_vec_named(&value)?)?)),
+            false => Ok(None)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Collection<T: Document> {
+    database: Database,
+    collection_name: String,
+    defaults: FieldDefaults,
+    doctype: PhantomData<fn() -> T>
+}
+
+impl<T: Document> Clone for Collection<T> {
+    fn clone(&self) -> Self {
+        Self {
+            database: self.database.clone(),
+            collection_name: self.collection_name.clone(),
+            defaults: self.defaults.clone(),
+            doctype: PhantomData
+        }
+    }
+}
+
+impl<T: Document> Collection<T> {
+    pub(crate) fn new(db: Database, name: String) -> Self {
+        Self {
+            database: db,
+            collection_name: name,
+            defaults: FieldDefaults::default(),
+            doctype: PhantomData
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.collection_name.clone()
+    }
+
+    /// Fills dot path `field` with `provider()` in documents inserted through this handle (and its clones) that
+    /// leave it unset, before they're encoded, e.g. a `tenant_id` taken from request context or
+    /// `source = "import"`. A field counts as unset when it's nil or missing from the serialized document.
+    ///
+    /// For a collection registered with [Database::register], the default is kept with the registration
+    /// instead, so every handle opened for it afterwards fills it in too, such as those from
+    /// [Database::get_collection] and [CollectionDef::open].
+    pub fn with_default(mut self, field: impl AsRef<str>, provider: impl Fn() -> rmpv::Value + Send + Sync + 'static) -> Self {
+        let provider: FieldProvider = Arc::new(provider);
+        if !matches!(self.database.registry.add_default::<T>(&self.collection_name, field.as_ref(), provider.clone()), Ok(true)) {
+            self.defaults.0.push((field.as_ref().to_string(), provider));
+        }
+        self
+    }
+
+    /// The defaults inserts through this handle fill in: its own, then those of its registration.
+    fn defaults(&self) -> crate::Result<FieldDefaults> {
+        let mut defaults = self.defaults.clone();
+        defaults.0.extend(self.database.registry.defaults(&self.collection_name)?);
+        Ok(defaults)
+    }
+
+    fn index_table_names(&self) -> HashMap<String, String> {
+        let mut results = HashMap::new();
+
+        for key in index_names::<T>() {
+            results.insert(key.clone(), format!("collections/{}/index/{}", self.name(), key.clone()));
+        }
+
+        results
+    }
+
+    pub(crate) fn main_table_name(&self) -> String {
+        format!("collections/{}", self.name())
+    }
+
+    pub(crate) fn database(&self) -> Database {
+        self.database.clone()
+    }
+
+    /// Inserts a new document, failing with [Error::DocumentExists] if its primary key is taken.
+    pub fn insert(&self, document: &T) -> crate::Result<WriteReceipt<T::PrimaryKey>> {
+        let operation = CollectionOperation::new_writer("insert", self)?;
+        operation.insert(document)?;
+        let receipt = operation.receipt();
+        operation.commit()?;
+        Ok(receipt)
+    }
+
+    pub fn insert_in(&self, txn: &Transaction, document: &T) -> crate::Result<WriteReceipt<T::PrimaryKey>> {
+        let operation = CollectionOperation::new("insert", self, txn);
+        operation.insert(document)?;
+        Ok(operation.receipt())
+    }
+
+    /// Inserts every document in one write transaction. Nothing is written if any primary key is already taken.
+    pub fn insert_many<D: Borrow<T>>(&self, documents: impl IntoIterator<Item = D>) -> crate::Result<WriteReceipt<T::PrimaryKey>> {
+        let operation = CollectionOperation::new_writer("insert_many", self)?;
+        operation.insert_many(documents)?;
+        let receipt = operation.receipt();
+        operation.commit()?;
+        Ok(receipt)
+    }
+
+    /// Like [Collection::insert_many], but commits after every `chunk_size` documents so huge imports don't
+    /// hold one enormous transaction. Chunks committed before a failure stay committed.
+    pub fn insert_many_chunked<D: Borrow<T>>(&self, documents: impl IntoIterator<Item = D>, chunk_size: usize) -> crate::Result<WriteReceipt<T::PrimaryKey>> {
+        let mut documents = documents.into_iter().peekable();
+        let mut receipt = WriteReceipt::default();
+        while documents.peek().is_some() {
+            receipt.merge(self.insert_many(documents.by_ref().take(chunk_size.max(1)))?);
+        }
+        Ok(receipt)
+    }
+
+    pub fn insert_many_in<D: Borrow<T>>(&self, txn: &Transaction, documents: impl IntoIterator<Item = D>) -> crate::Result<WriteReceipt<T::PrimaryKey>> {
+        let operation = CollectionOperation::new("insert_many", self, txn);
+        operation.insert_many(documents)?;
+        Ok(operation.receipt())
+    }
+
+    /// Inserts `document` unless `idempotency_key` was already used on this collection within the last `ttl`,
+    /// so a retried client request doesn't insert twice. Used keys are kept in a dedup table beside the
+    /// collection, and expired ones are purged on every call. Fails like [Collection::insert] otherwise.
+    pub fn insert_idempotent(&self, idempotency_key: impl AsRef<str>, document: &T, ttl: Duration) -> crate::Result<IdempotentInsert<T::PrimaryKey>> {
+        let operation = CollectionOperation::new_writer("insert_idempotent", self)?;
+        let result = operation.insert_idempotent(idempotency_key.as_ref(), document, ttl)?;
+        operation.commit()?;
+        Ok(result)
+    }
+
+    pub fn insert_idempotent_in(&self, txn: &Transaction, idempotency_key: impl AsRef<str>, document: &T, ttl: Duration) -> crate::Result<IdempotentInsert<T::PrimaryKey>> {
+        CollectionOperation::new("insert_idempotent", self, txn).insert_idempotent(idempotency_key.as_ref(), document, ttl)
+    }
+
+    /// The first of `base`, `base-2`, `base-3`, ... that no document holds in index `key`. The check runs inside
+    /// the write transaction `txn`, so a document inserted with the slug in the same transaction can't collide
+    /// with another writer. `key` should be [unique](crate::document::IndexSpec::unique) so the slug stays taken.
+    pub fn unique_slug_in(&self, txn: &Transaction, key: impl AsRef<str>, base: impl AsRef<str>) -> crate::Result<String> {
+        CollectionOperation::new("unique_slug", self, txn).unique_slug(key.as_ref(), base.as_ref())
+    }
+
+    /// Builds a document from a free slug for `base` (see [Collection::unique_slug_in]) and inserts it, in one
+    /// write transaction.
+    pub fn insert_with_slug(&self, key: impl AsRef<str>, base: impl AsRef<str>, build: impl FnOnce(String) -> T) -> crate::Result<T> {
+        let operation = CollectionOperation::new_writer("insert_with_slug", self)?;
+        let slug = operation.unique_slug(key.as_ref(), base.as_ref())?;
+        let document = operation.transaction().run_hook("insert_with_slug", || build(slug))?;
+        operation.insert(&document)?;
+        operation.commit()?;
+        Ok(document)
+    }
+
+    pub fn get(&self, id: &T::PrimaryKey) -> crate::Result<Option<T>> {
+        CollectionOperation::new_reader("get", self)?.get(id)
+    }
+
+    /// Keeps an in-memory bloom filter of this collection's primary keys, shared by every handle to it, so
+    /// lookups such as [Collection::get] and [Collection::contains] answer most missing keys without a B-tree
+    /// descent. The filter is sized for `expected_keys` at about `false_positive_rate` and is built now by
+    /// scanning the keys inside a write transaction, so don't call this while holding one. Deleted keys stay
+    /// in the filter, costing a lookup each, until [Database::compact] rebuilds it. Filters aren't persisted.
+    pub fn enable_key_filter(&self, expected_keys: usize, false_positive_rate: f64) -> crate::Result<()> {
+        build_key_filter::<T>(&self.database, &self.name(), KeyFilterConfig { expected_keys, false_positive_rate })
+    }
+
+    /// Stops filtering lookups, returning `false` if no key filter was enabled.
+    pub fn disable_key_filter(&self) -> crate::Result<bool> {
+        self.database.key_filters.remove(self.name())
+    }
+
+    /// Size in bytes of the key filter, if one is enabled and built.
+    pub fn key_filter_size(&self) -> crate::Result<Option<usize>> {
+        self.database.key_filters.size(self.name())
+    }
+
+    /// The value at JSON pointer `pointer` (such as `"/profile/email"`) of the document under `id`, decoding only
+    /// that value from the stored bytes. `None` if the document doesn't exist or holds nothing at the pointer.
+    pub fn get_path(&self, id: &T::PrimaryKey, pointer: impl AsRef<str>) -> crate::Result<Option<rmpv::Value>> {
+        CollectionOperation::new_reader("get_path", self)?.get_path(id, pointer.as_ref())
+    }
+
+    pub fn get_path_in(&self, txn: &Transaction, id: &T::PrimaryKey, pointer: impl AsRef<str>) -> crate::Result<Option<rmpv::Value>> {
+        CollectionOperation::new("get_path", self, txn).get_path(id, pointer.as_ref())
+    }
+
+    /// Lazily iterates over every document in key order, holding a read transaction open until dropped.
+    pub fn iter(&self) -> crate::Result<Documents<T>> {
+        Documents::new(self, false)
+    }
+
+    /// Like [Collection::iter], in reverse key order.
+    pub fn iter_rev(&self) -> crate::Result<Documents<T>> {
+        Documents::new(self, true)
+    }
+
+    /// Copies every document into the collection of the same name in `target`, streaming them from one read
+    /// transaction into one write transaction and rebuilding the indexes there. Documents already stored in
+    /// `target` under the same primary key are replaced. Returns how many documents were copied.
+    pub fn copy_to(&self, target: &Database) -> crate::Result<u64> {
+        let destination = target.collection::<T>(self.name());
+        let operation = CollectionOperation::new_writer("copy_to", &destination)?;
+        let mut copied = 0;
+        for entry in self.iter()? {
+            operation.upsert(&entry?.1)?;
+            copied += 1;
+        }
+        operation.commit()?;
+        Ok(copied)
+    }
+
+    /// Removes every document and index entry in one write transaction, returning how many documents were
+    /// removed. Unlike [Collection::drop], the collection keeps its index key format.
+    pub fn clear(&self) -> crate::Result<u64> {
+        let operation = CollectionOperation::new_writer("clear", self)?;
+        let cleared = operation.clear()?;
+        operation.commit()?;
+        Ok(cleared)
+    }
+
+    pub fn clear_in(&self, txn: &Transaction) -> crate::Result<u64> {
+        CollectionOperation::new("clear", self, txn).clear()
+    }
+
+    /// Deletes the collection and its index tables, see [Database::drop_collection].
+    pub fn drop(self) -> crate::Result<bool> {
+        self.database.drop_collection(&self.collection_name)
+    }
+
+    /// Fetches every id in one read transaction, returning the documents in the same order as `ids`.
+    pub fn get_many<K: Borrow<T::PrimaryKey>>(&self, ids: impl IntoIterator<Item = K>) -> crate::Result<Vec<Option<T>>> {
+        CollectionOperation::new_reader("get_many", self)?.get_many(ids)
+    }
+
+    pub fn get_many_in<K: Borrow<T::PrimaryKey>>(&self, txn: &Transaction, ids: impl IntoIterator<Item = K>) -> crate::Result<Vec<Option<T>>> {
+        CollectionOperation::new("get_many", self, txn).get_many(ids)
+    }
+
+    /// Whether a document is stored under `id`, without decoding it.
+    pub fn contains(&self, id: &T::PrimaryKey) -> crate::Result<bool> {
+        CollectionOperation::new_reader("contains", self)?.contains(id)
+    }
+
+    pub fn contains_in(&self, txn: &Transaction, id: &T::PrimaryKey) -> crate::Result<bool> {
+        CollectionOperation::new("contains", self, txn).contains(id)
+    }
+
+    /// Document count and approximate on-disk size of the main table and each index table.
+    pub fn stats(&self) -> crate::Result<CollectionStats> {
+        let txn = self.database.begin_read("stats", self.main_table_name())?;
+        let Transaction::Read(inner, _) = &txn else {
+            return Ok(CollectionStats::default());
+        };
+        let inner = inner.read()?;
+        let mut stats = CollectionStats::default();
+        let index_tables: HashMap<String, String> = self.index_table_names().into_iter().map(|(key, name)| (name, key)).collect();
+        for handle in inner.list_tables()? {
+            if handle.name() == self.main_table_name() {
+                let table = inner.open_untyped_table(handle)?;
+                stats.documents = (table.len()?, table.stats()?).into();
+            }
+        }
+        for handle in inner.list_multimap_tables()? {
+            if let Some(key) = index_tables.get(handle.name()) {
+                let table = inner.open_untyped_multimap_table(handle)?;
+                stats.indexes.insert(key.clone(), (table.len()?, table.stats()?).into());
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Number of stored documents, read from the table length without decoding anything.
+    pub fn count(&self) -> crate::Result<u64> {
+        CollectionOperation::new_reader("count", self)?.len()
+    }
+
+    pub fn is_empty(&self) -> crate::Result<bool> {
+        Ok(self.count()? == 0)
+    }
+
+    /// Starts a [Query] over this collection.
+    pub fn query(&self) -> Query<T> {
+        Query::new(self.clone())
+    }
+
+    /// Navigates this collection as a tree whose documents hold their parent's primary key in index `parent`.
+    pub fn tree(&self, parent: impl AsRef<str>) -> Tree<T> {
+        Tree::new(self.clone(), parent.as_ref().to_string())
+    }
+
+    /// Every stored primary key, without decoding any documents.
+    pub fn keys(&self) -> crate::Result<KeySet<T::PrimaryKey>> {
+        CollectionOperation::new_reader("keys", self)?.keys()
+    }
+
+    /// Primary keys of the documents whose index `key` holds `value`, read from the index table alone.
+    /// Combine the results with `&`, `|` and `-` before fetching documents.
+    pub fn keys_where(&self, key: impl AsRef<str>, value: impl Into<rmpv::Value>) -> crate::Result<KeySet<T::PrimaryKey>> {
+        CollectionOperation::new_reader("keys_where", self)?.keys_where(key, &value.into())
+    }
+
+    pub fn keys_where_in(&self, txn: &Transaction, key: impl AsRef<str>, value: impl Into<rmpv::Value>) -> crate::Result<KeySet<T::PrimaryKey>> {
+        CollectionOperation::new("keys_where", self, txn).keys_where(key, &value.into())
+    }
+
+    /// Primary keys matching `query`, read from the index tables without opening the main table, see
+    /// [Query::index_keys].
+    pub fn keys_matching(&self, query: &Query<T>) -> crate::Result<KeySet<T::PrimaryKey>> {
+        query.index_keys()
+    }
+
+    pub fn keys_matching_in(&self, txn: &Transaction, query: &Query<T>) -> crate::Result<KeySet<T::PrimaryKey>> {
+        query.index_keys_in(txn)
+    }
+
+    /// Primary keys of the documents matching every `(key, value)` condition, found by intersecting the
+    /// bitmaps of [IndexSpec::bitmap](crate::document::IndexSpec::bitmap) indexes, so checks such as
+    /// `status = active AND archived = false` read neither the index tables nor the main table. Only conditions
+    /// on hashed or truncated indexes are verified against their documents. Fails with [Error::UnbitmappedIndex]
+    /// for a condition on any other index; no conditions match nothing.
+    pub fn bitmap_keys<K: AsRef<str>, V: Into<rmpv::Value>>(&self, conditions: impl IntoIterator<Item = (K, V)>) -> crate::Result<KeySet<T::PrimaryKey>> {
+        CollectionOperation::new_reader("bitmap_keys", self)?.bitmap_keys(&bitmap_conditions(conditions))
+    }
+
+    pub fn bitmap_keys_in<K: AsRef<str>, V: Into<rmpv::Value>>(&self, txn: &Transaction, conditions: impl IntoIterator<Item = (K, V)>) -> crate::Result<KeySet<T::PrimaryKey>> {
+        CollectionOperation::new("bitmap_keys", self, txn).bitmap_keys(&bitmap_conditions(conditions))
+    }
+
+    /// Documents whose index `key` holds `value`, in primary key order.
+    pub fn find_by(&self, key: impl AsRef<str>, value: impl Into<rmpv::Value>) -> crate::Result<Vec<T>> {
+        CollectionOperation::new_reader("find_by", self)?.find_by(key, &value.into())
+    }
+
+    pub fn find_by_in(&self, txn: &Transaction, key: impl AsRef<str>, value: impl Into<rmpv::Value>) -> crate::Result<Vec<T>> {
+        CollectionOperation::new("find_by", self, txn).find_by(key, &value.into())
+    }
+
+    /// Number of documents whose index `key` holds `value`, read from the index table alone.
+    pub fn count_by_index(&self, key: impl AsRef<str>, value: impl Into<rmpv::Value>) -> crate::Result<u64> {
+        CollectionOperation::new_reader("count_by_index", self)?.count_by_index(key, &value.into())
+    }
+
+    /// About how many distinct values index `key` has held, read from its [HyperLogLog] sketch instead of the
+    /// index; within a few percent on large collections. Values no document holds any more keep counting until
+    /// [Collection::rebuild_indexes] rebuilds the sketch. Fails with [Error::UnsketchedIndex] unless the index is
+    /// declared [IndexSpec::sketched](crate::document::IndexSpec::sketched).
+    pub fn approx_distinct(&self, key: impl AsRef<str>) -> crate::Result<u64> {
+        CollectionOperation::new_reader("approx_distinct", self)?.approx_distinct(key.as_ref())
+    }
+
+    pub fn approx_distinct_in(&self, txn: &Transaction, key: impl AsRef<str>) -> crate::Result<u64> {
+        CollectionOperation::new("approx_distinct", self, txn).approx_distinct(key.as_ref())
+    }
+
+    /// Documents whose index `key` falls in `range`, in index order, read through the index table. The index
+    /// must be declared [IndexSpec::ordered](crate::document::IndexSpec::ordered).
+    pub fn find_range<V: Into<rmpv::Value> + Clone>(&self, key: impl AsRef<str>, range: impl RangeBounds<V>) -> crate::Result<Vec<T>> {
+        CollectionOperation::new_reader("find_range", self)?.find_range(key.as_ref(), value_bounds(range))
+    }
+
+    pub fn find_range_in<V: Into<rmpv::Value> + Clone>(&self, txn: &Transaction, key: impl AsRef<str>, range: impl RangeBounds<V>) -> crate::Result<Vec<T>> {
+        CollectionOperation::new("find_range", self, txn).find_range(key.as_ref(), value_bounds(range))
+    }
+
+    /// Documents whose string value in ordered index `key` is within `max_distance` single-character edits
+    /// (insertions, deletions or substitutions) of `term`, closest first and in index order among equals, for
+    /// typo-tolerant lookups of names and titles. Reads each distinct string in the index once, sharing the
+    /// edit-distance work of common prefixes between neighbours, and only the matching documents.
+    pub fn search_fuzzy(&self, key: impl AsRef<str>, term: impl AsRef<str>, max_distance: usize) -> crate::Result<Vec<T>> {
+        CollectionOperation::new_reader("search_fuzzy", self)?.search_fuzzy(key.as_ref(), term.as_ref(), max_distance)
+    }
+
+    pub fn search_fuzzy_in(&self, txn: &Transaction, key: impl AsRef<str>, term: impl AsRef<str>, max_distance: usize) -> crate::Result<Vec<T>> {
+        CollectionOperation::new("search_fuzzy", self, txn).search_fuzzy(key.as_ref(), term.as_ref(), max_distance)
+    }
+
+    /// Documents whose compound index `key` starts with the values in `prefix`, in index order. With a full
+    /// prefix this is an equality lookup; [Collection::find_range] with array bounds also works on compound indexes.
+    pub fn find_prefix<V: Into<rmpv::Value>>(&self, key: impl AsRef<str>, prefix: impl IntoIterator<Item = V>) -> crate::Result<Vec<T>> {
+        let prefix: Vec<rmpv::Value> = prefix.into_iter().map(Into::into).collect();
+        CollectionOperation::new_reader("find_prefix", self)?.find_prefix(key.as_ref(), &prefix)
+    }
+
+    pub fn find_prefix_in<V: Into<rmpv::Value>>(&self, txn: &Transaction, key: impl AsRef<str>, prefix: impl IntoIterator<Item = V>) -> crate::Result<Vec<T>> {
+        let prefix: Vec<rmpv::Value> = prefix.into_iter().map(Into::into).collect();
+        CollectionOperation::new("find_prefix", self, txn).find_prefix(key.as_ref(), &prefix)
+    }
+
+    /// Checks that the index tables match the stored documents, returning a description of every
+    /// missing or stale index entry. An empty list means the indexes are consistent.
+    pub fn check_indexes(&self) -> crate::Result<Vec<String>> {
+        CollectionOperation::new_reader("check_indexes", self)?.index_problems()
+    }
+
+    pub fn check_indexes_in(&self, txn: &Transaction) -> crate::Result<Vec<String>> {
+        CollectionOperation::new("check_indexes", self, txn).index_problems()
+    }
+
+    /// Like [Collection::check_indexes], but reads `chunk_size` documents per transaction and keeps only
+    /// counts between chunks, saving its progress in the meta table so an interrupted check resumes where it
+    /// stopped. Stale entries are reported as a count per index rather than one by one. Writes landing between
+    /// chunks can show up as problems; check again before repairing.
+    pub fn check_indexes_chunked(&self, chunk_size: usize) -> crate::Result<Vec<String>> {
+        loop {
+            let operation = CollectionOperation::new_writer("check_indexes_chunked", self)?;
+            let finished = operation.check_indexes_chunk(chunk_size.max(1))?;
+            operation.commit()?;
+            if let Some(problems) = finished {
+                return Ok(problems);
+            }
+        }
+    }
+
+    /// How this collection's index tables store index values. Collections created since format version 2 use
+    /// [IndexKeyFormat::Raw]; ones indexed before that keep [IndexKeyFormat::Base64] until migrated.
+    pub fn index_format(&self) -> crate::Result<IndexKeyFormat> {
+        CollectionOperation::new_reader("index_format", self)?.index_format()
+    }
+
+    /// Switches the collection to `format`, rebuilding every index table from the stored documents in one write
+    /// transaction. Returns the number of index entries written, or zero if the format was already in use.
+    pub fn set_index_format(&self, format: IndexKeyFormat) -> crate::Result<usize> {
+        let operation = CollectionOperation::new_writer("set_index_format", self)?;
+        let written = operation.set_index_format(format)?;
+        operation.commit()?;
+        Ok(written)
+    }
+
+    pub fn set_index_format_in(&self, txn: &Transaction, format: IndexKeyFormat) -> crate::Result<usize> {
+        CollectionOperation::new("set_index_format", self, txn).set_index_format(format)
+    }
+
+    /// Rebuilds every index table from the stored documents, e.g. after changing [Document::index_spec].
+    /// Returns the number of index entries written.
+    pub fn rebuild_indexes(&self) -> crate::Result<usize> {
+        let operation = CollectionOperation::new_writer("rebuild_indexes", self)?;
+        let written = operation.rebuild_indexes()?;
+        operation.commit()?;
+        Ok(written)
+    }
+
+    pub fn rebuild_indexes_in(&self, txn: &Transaction) -> crate::Result<usize> {
+        CollectionOperation::new("rebuild_indexes", self, txn).rebuild_indexes()
+    }
+
+    /// Fails with [Error::IndexMismatch] if the stored index tables don't match the indexes `T` declares.
+    pub fn check_index_layout(&self) -> crate::Result<()> {
+        CollectionOperation::new_reader("check_index_layout", self)?.check_index_layout()
+    }
+
+    pub fn check_index_layout_in(&self, txn: &Transaction) -> crate::Result<()> {
+        CollectionOperation::new("check_index_layout", self, txn).check_index_layout()
+    }
+
+    /// Like [Collection::rebuild_indexes], but indexes `chunk_size` documents per write transaction so memory
+    /// stays bounded on large collections. Progress is saved in the meta table with every chunk, so a rebuild
+    /// cut short by a crash resumes where it stopped the next time this (or [Collection::set_index_format_chunked])
+    /// is called. Until it finishes, index lookups miss the documents not reindexed yet.
+    pub fn rebuild_indexes_chunked(&self, chunk_size: usize) -> crate::Result<usize> {
+        self.write_indexes_chunked(None, chunk_size)
+    }
+
+    /// Like [Collection::set_index_format], in resumable chunks like [Collection::rebuild_indexes_chunked]. An
+    /// unfinished run into a different format is abandoned and started over.
+    pub fn set_index_format_chunked(&self, format: IndexKeyFormat, chunk_size: usize) -> crate::Result<usize> {
+        self.write_indexes_chunked(Some(format), chunk_size)
+    }
+
+    fn write_indexes_chunked(&self, format: Option<IndexKeyFormat>, chunk_size: usize) -> crate::Result<usize> {
+        loop {
+            let operation = CollectionOperation::new_writer("rebuild_indexes_chunked", self)?;
+            let finished = operation.write_indexes_chunk(format, chunk_size.max(1))?;
+            operation.commit()?;
+            if let Some(written) = finished {
+                return Ok(written);
+            }
+        }
+    }
+
+    pub fn get_in(&self, txn: &Transaction, id: &T::PrimaryKey) -> crate::Result<Option<T>> {
+        CollectionOperation::new("get", self, txn).get(id)
+    }
+
+    /// Replaces an existing document, returning the previous version. Fails with [Error::DocumentNotFound]
+    /// if nothing is stored under its primary key.
+    pub fn replace(&self, document: &T) -> crate::Result<T> {
+        let operation = CollectionOperation::new_writer("replace", self)?;
+        let previous = operation.replace(document)?;
+        operation.commit()?;
+        Ok(previous)
+    }
+
+    pub fn replace_in(&self, txn: &Transaction, document: &T) -> crate::Result<T> {
+        CollectionOperation::new("replace", self, txn).replace(document)
+    }
+
+    /// Inserts the document, or replaces the one stored under the same primary key.
+    pub fn upsert(&self, document: &T) -> crate::Result<UpsertResult<T>> {
+        let operation = CollectionOperation::new_writer("upsert", self)?;
+        let result = operation.upsert(document)?;
+        operation.commit()?;
+        Ok(result)
+    }
+
+    pub fn upsert_in(&self, txn: &Transaction, document: &T) -> crate::Result<UpsertResult<T>> {
+        CollectionOperation::new("upsert", self, txn).upsert(document)
+    }
+
+    /// Reads the document, lets `modify` change it in place and writes it back, all in one write transaction.
+    /// `modify` returns `false` to skip the write. Returns the resulting document, or `None` if it doesn't exist.
+    pub fn modify(&self, id: &T::PrimaryKey, modify: impl FnOnce(&mut T) -> bool) -> crate::Result<Option<T>> {
+        let operation = CollectionOperation::new_writer("modify", self)?;
+        let modified = operation.modify(id, modify)?;
+        operation.commit()?;
+        Ok(modified)
+    }
+
+    pub fn modify_in(&self, txn: &Transaction, id: &T::PrimaryKey, modify: impl FnOnce(&mut T) -> bool) -> crate::Result<Option<T>> {
+        CollectionOperation::new("modify", self, txn).modify(id, modify)
+    }
+
+    /// Replaces the document stored under `id` with `document` only if the stored one satisfies `predicate`,
+    /// checked inside the write transaction. Returns whether the write was applied.
+    pub fn update_if(&self, id: &T::PrimaryKey, predicate: impl FnOnce(&T) -> bool, document: &T) -> crate::Result<bool> {
+        let operation = CollectionOperation::new_writer("update_if", self)?;
+        let applied = operation.update_if(id, predicate, document)?;
+        operation.commit()?;
+        Ok(applied)
+    }
+
+    pub fn update_if_in(&self, txn: &Transaction, id: &T::PrimaryKey, predicate: impl FnOnce(&T) -> bool, document: &T) -> crate::Result<bool> {
+        CollectionOperation::new("update_if", self, txn).update_if(id, predicate, document)
+    }
+
+    /// Adds `delta` to the integer at dot path `field` of the document stored under `id` in one write transaction,
+    /// returning the new value, or `None` if the document doesn't exist.
+    pub fn increment(&self, id: &T::PrimaryKey, field: impl AsRef<str>, delta: i64) -> crate::Result<Option<i64>> {
+        let operation = CollectionOperation::new_writer("increment", self)?;
+        let value = operation.increment(id, field.as_ref(), delta)?;
+        operation.commit()?;
+        Ok(value)
+    }
+
+    pub fn increment_in(&self, txn: &Transaction, id: &T::PrimaryKey, field: impl AsRef<str>, delta: i64) -> crate::Result<Option<i64>> {
+        CollectionOperation::new("increment", self, txn).increment(id, field.as_ref(), delta)
+    }
+
+    /// Appends `value` to the array at dot path `field` of the document under `id` in one write transaction,
+    /// updating multikey index entries. Returns the new length, or `None` if the document doesn't exist.
+    pub fn push(&self, id: &T::PrimaryKey, field: impl AsRef<str>, value: impl Into<rmpv::Value>) -> crate::Result<Option<usize>> {
+        let operation = CollectionOperation::new_writer("push", self)?;
+        let length = operation.push(id, field.as_ref(), value.into())?;
+        operation.commit()?;
+        Ok(length)
+    }
+
+    pub fn push_in(&self, txn: &Transaction, id: &T::PrimaryKey, field: impl AsRef<str>, value: impl Into<rmpv::Value>) -> crate::Result<Option<usize>> {
+        CollectionOperation::new("push", self, txn).push(id, field.as_ref(), value.into())
+    }
+
+    /// Removes every element equal to `value` from the array at dot path `field`, like [Collection::push].
+    /// Returns how many were removed, or `None` if the document doesn't exist.
+    pub fn pull(&self, id: &T::PrimaryKey, field: impl AsRef<str>, value: impl Into<rmpv::Value>) -> crate::Result<Option<usize>> {
+        let operation = CollectionOperation::new_writer("pull", self)?;
+        let removed = operation.pull(id, field.as_ref(), &value.into())?;
+        operation.commit()?;
+        Ok(removed)
+    }
+
+    pub fn pull_in(&self, txn: &Transaction, id: &T::PrimaryKey, field: impl AsRef<str>, value: impl Into<rmpv::Value>) -> crate::Result<Option<usize>> {
+        CollectionOperation::new("pull", self, txn).pull(id, field.as_ref(), &value.into())
+    }
+
+    /// Deletes every document matching `predicate` in one write transaction.
+    pub fn delete_where(&self, predicate: impl FnMut(&T) -> bool) -> crate::Result<WriteReceipt<T::PrimaryKey>> {
+        let operation = CollectionOperation::new_writer("delete_where", self)?;
+        operation.delete_where(predicate)?;
+        let receipt = operation.receipt();
+        operation.commit()?;
+        Ok(receipt)
+    }
+
+    pub fn delete_where_in(&self, txn: &Transaction, predicate: impl FnMut(&T) -> bool) -> crate::Result<WriteReceipt<T::PrimaryKey>> {
+        let operation = CollectionOperation::new("delete_where", self, txn);
+        operation.delete_where(predicate)?;
+        Ok(operation.receipt())
+    }
+
+    /// Deletes every document matching `query` in one write transaction. Unlike [Collection::delete_where], only
+    /// the documents the query's index conditions point at are read.
+    pub fn delete_matching(&self, query: &Query<T>) -> crate::Result<WriteReceipt<T::PrimaryKey>> {
+        let operation = CollectionOperation::new_writer("delete_matching", self)?;
+        operation.delete_matching(query)?;
+        let receipt = operation.receipt();
+        operation.commit()?;
+        Ok(receipt)
+    }
+
+    pub fn delete_matching_in(&self, txn: &Transaction, query: &Query<T>) -> crate::Result<WriteReceipt<T::PrimaryKey>> {
+        let operation = CollectionOperation::new("delete_matching", self, txn);
+        operation.delete_matching(query)?;
+        Ok(operation.receipt())
+    }
+
+    /// Applies `mutator` to every document matching `predicate` in one write transaction.
+    pub fn update_where(&self, predicate: impl FnMut(&T) -> bool, mutator: impl FnMut(&mut T)) -> crate::Result<WriteReceipt<T::PrimaryKey>> {
+        let operation = CollectionOperation::new_writer("update_where", self)?;
+        operation.update_where(predicate, mutator)?;
+        let receipt = operation.receipt();
+        operation.commit()?;
+        Ok(receipt)
+    }
+
+    pub fn update_where_in(&self, txn: &Transaction, predicate: impl FnMut(&T) -> bool, mutator: impl FnMut(&mut T)) -> crate::Result<WriteReceipt<T::PrimaryKey>> {
+        let operation = CollectionOperation::new("update_where", self, txn);
+        operation.update_where(predicate, mutator)?;
+        Ok(operation.receipt())
+    }
+
+    /// Applies `mutator` to every document matching `query` in one write transaction, reading only the documents
+    /// the query's index conditions point at.
+    pub fn update_matching(&self, query: &Query<T>, mutator: impl FnMut(&mut T)) -> crate::Result<WriteReceipt<T::PrimaryKey>> {
+        let operation = CollectionOperation::new_writer("update_matching", self)?;
+        operation.update_matching(query, mutator)?;
+        let receipt = operation.receipt();
+        operation.commit()?;
+        Ok(receipt)
+    }
+
+    pub fn update_matching_in(&self, txn: &Transaction, query: &Query<T>, mutator: impl FnMut(&mut T)) -> crate::Result<WriteReceipt<T::PrimaryKey>> {
+        let operation = CollectionOperation::new("update_matching", self, txn);
+        operation.update_matching(query, mutator)?;
+        Ok(operation.receipt())
+    }
+
+    /// Deletes and returns the document stored under `id` in one write transaction, for queue-like consumers.
+    pub fn take(&self, id: &T::PrimaryKey) -> crate::Result<Option<T>> {
+        let operation = CollectionOperation::new_writer("take", self)?;
+        let taken = operation.delete(id)?;
+        operation.commit()?;
+        Ok(taken)
+    }
+
+    /// Deletes and returns the document with the lowest value in index `key`, which must be
+    /// [ordered](crate::document::IndexSpec::ordered). Ties go to the lowest primary key.
+    pub fn pop_first_by(&self, key: impl AsRef<str>) -> crate::Result<Option<T>> {
+        let operation = CollectionOperation::new_writer("pop_first_by", self)?;
+        let popped = operation.pop_first_by(key.as_ref())?;
+        operation.commit()?;
+        Ok(popped)
+    }
+
+    pub fn pop_first_by_in(&self, txn: &Transaction, key: impl AsRef<str>) -> crate::Result<Option<T>> {
+        CollectionOperation::new("pop_first_by", self, txn).pop_first_by(key.as_ref())
+    }
+
+    /// Deletes every document whose expiry index `key` holds a value at or before `now`, walking the index
+    /// from its start instead of scanning the collection. The index must be
+    /// [ordered](crate::document::IndexSpec::ordered) and hold values that sort by time, such as Unix
+    /// timestamps. Documents are deleted like [Collection::delete], so relation cascades still run.
+    pub fn sweep_expired(&self, key: impl AsRef<str>, now: impl Into<rmpv::Value>) -> crate::Result<SweepStats> {
+        let operation = CollectionOperation::new_writer("sweep_expired", self)?;
+        let stats = operation.sweep_expired(key.as_ref(), &now.into())?;
+        operation.commit()?;
+        Ok(stats)
+    }
+
+    pub fn sweep_expired_in(&self, txn: &Transaction, key: impl AsRef<str>, now: impl Into<rmpv::Value>) -> crate::Result<SweepStats> {
+        CollectionOperation::new("sweep_expired", self, txn).sweep_expired(key.as_ref(), &now.into())
+    }
+
+    /// Like [Collection::sweep_expired], but reads at most `chunk_size` expiry values per write transaction so
+    /// a large backlog doesn't hold one long write. Purged documents leave the index, so an interrupted sweep
+    /// needs no saved cursor: calling this again picks up the rest.
+    pub fn sweep_expired_chunked(&self, key: impl AsRef<str>, now: impl Into<rmpv::Value>, chunk_size: usize) -> crate::Result<SweepStats> {
+        let (started, now) = (Instant::now(), now.into());
+        let mut stats = SweepStats { scanned: 0, purged: 0, elapsed: Duration::ZERO };
+        let mut after = None;
+        loop {
+            let operation = CollectionOperation::new_writer("sweep_expired_chunked", self)?;
+            let (chunk, last) = operation.sweep_expired_chunk(key.as_ref(), &now, after.take(), chunk_size.max(1))?;
+            operation.commit()?;
+            stats.scanned += chunk.scanned;
+            stats.purged += chunk.purged;
+            match last {
+                Some(last) => after = Some(last),
+                None => break
+            }
+        }
+        stats.elapsed = started.elapsed();
+        Ok(stats)
+    }
+
+    /// Takes an advisory lock on `id` for `owner` until `ttl` from now, renewing it if `owner` already holds it.
+    /// Fails with [Error::DocumentLocked] while another owner's lock is unexpired.
+    pub fn lock(&self, id: &T::PrimaryKey, owner: impl AsRef<str>, ttl: Duration) -> crate::Result<DocumentLock> {
+        let operation = CollectionOperation::new_writer("lock", self)?;
+        let lock = operation.lock(id, owner.as_ref(), ttl)?;
+        operation.commit()?;
+        Ok(lock)
+    }
+
+    /// Releases the lock on `id` if `owner` holds it, returning whether it did.
+    pub fn unlock(&self, id: &T::PrimaryKey, owner: impl AsRef<str>) -> crate::Result<bool> {
+        let operation = CollectionOperation::new_writer("unlock", self)?;
+        let unlocked = operation.unlock(id, owner.as_ref())?;
+        operation.commit()?;
+        Ok(unlocked)
+    }
+
+    /// The unexpired lock on `id`, if any.
+    pub fn lock_info(&self, id: &T::PrimaryKey) -> crate::Result<Option<DocumentLock>> {
+        CollectionOperation::new_reader("lock_info", self)?.lock_info(id)
+    }
+
+    /// Deletes a document and its index entries, returning it if it existed.
+    pub fn delete(&self, id: &T::PrimaryKey) -> crate::Result<Option<T>> {
+        let operation = CollectionOperation::new_writer("delete", self)?;
+        let deleted = operation.delete(id)?;
+        operation.commit()?;
+        Ok(deleted)
+    }
+
+    pub fn delete_in(&self, txn: &Transaction, id: &T::PrimaryKey) -> crate::Result<Option<T>> {
+        CollectionOperation::new("delete", self, txn).delete(id)
+    }
+}
+
+/// Lazy iterator over a [Collection], decoding each document as it is reached.
+pub struct Documents<T: Document> {
+    _transaction: Transaction,
+    range: Option<redb::Range<'static, T::PrimaryKey, &'static [u8]>>,
+    reverse: bool,
+    doctype: PhantomData<fn() -> T>
+}
+
+impl<T: Document> Documents<T> {
+    fn new(collection: &Collection<T>, reverse: bool) -> crate::Result<Self> {
+        let name = collection.main_table_name();
+        let transaction = collection.database().begin_read(if reverse { "iter_rev" } else { "iter" }, &name)?;
+        let range = match &transaction {
+            Transaction::Read(txn, _) => match txn.read()?.open_table(TableDefinition::<T::PrimaryKey, &[u8]>::new(&name)) {
+                Ok(table) => Some(table.range::<T::PrimaryKey>(..)?),
+                Err(redb::TableError::TableDoesNotExist(_)) => None,
+                Err(e) => return Err(e.into())
+            },
+            Transaction::Write(..) => None
+        };
+        Ok(Self { _transaction: transaction, range, reverse, doctype: PhantomData })
+    }
+}
+
+impl<T: Document> Iterator for Documents<T> {
+    type Item = crate::Result<(T::PrimaryKey, T)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let range = self.range.as_mut()?;
+        let entry = if self.reverse { range.next_back() } else { range.next() }?;
+        Some(entry.map_err(Error::from).and_then(|(key, value)| Ok((key.value(), rmp_serde::from_slice::<T>(value.value())?))))
+    }
+}
+
+pub(crate) struct CollectionOperation<T: Document> {
+    operation: String,
+    transaction: Transaction,
+    collection: Collection<T>,
+    index_format: OnceCell<IndexKeyFormat>,
+    receipt: RefCell<WriteReceipt<T::PrimaryKey>>
+}
+
+impl<T: Document> CollectionOperation<T> {
+    pub fn new(operation: impl AsRef<str>, collection: &Collection<T>, transaction: &Transaction) -> Self {
+        Self {
+            operation: operation.as_ref().to_string(),
+            transaction: transaction.clone(),
+            collection: collection.clone(),
+            index_format: OnceCell::new(),
+            receipt: RefCell::new(WriteReceipt::default())
+        }
+    }
+
+    pub(crate) fn transaction(&self) -> &Transaction {
+        &self.transaction
+    }
+
+    /// Everything written through this operation so far.
+    pub fn receipt(&self) -> WriteReceipt<T::PrimaryKey> {
+        self.receipt.borrow().clone()
+    }
+
+    /// Read from the meta table once per operation. Must not be called while holding a write guard.
+    pub fn index_format(&self) -> crate::Result<IndexKeyFormat> {
+        if let Some(format) = self.index_format.get() {
+            return Ok(*format);
+        }
+        let format = meta::index_format(&self.transaction, self.collection.name())?;
+        Ok(*self.index_format.get_or_init(|| format))
+    }
+
+    pub fn new_reader(operation: impl AsRef<str>, collection: &Collection<T>) -> crate::Result<Self> {
+        let transaction = collection.database().begin_read(operation.as_ref(), collection.main_table_name())?;
+        Ok(Self::new(operation, collection, &transaction))
+    }
+
+    pub fn new_writer(operation: impl AsRef<str>, collection: &Collection<T>) -> crate::Result<Self> {
+        let transaction = collection.database().begin_write(operation.as_ref(), collection.main_table_name())?;
+        Ok(Self::new(operation, collection, &transaction))
+    }
+
+    /// Commits the underlying transaction. Fails if the transaction is shared with another handle.
+    pub fn commit(self) -> crate::Result<()> {
+        self.transaction.commit()
+    }
+
+    fn not_found(&self, id: &T::PrimaryKey) -> Error {
+        Error::DocumentNotFound { collection: self.collection.name(), id: format!("{id:?}") }
+    }
+
+    /// Whether the collection's key filter proves `id` isn't stored, so the table needn't be read.
+    fn filtered_out(&self, id: &T::PrimaryKey) -> crate::Result<bool> {
+        self.collection.database.key_filters.excludes(self.collection.name(), &self.transaction, T::PrimaryKey::as_bytes(id).as_ref())
+    }
+
+    pub fn get(&self, id: &T::PrimaryKey) -> crate::Result<Option<T>> {
+        if self.filtered_out(id)? {
+            return Ok(None);
+        }
+        let name = self.collection.main_table_name();
+        with_table!(&self.transaction, TableDefinition::<T::PrimaryKey, &[u8]>::new(&name), table => {
+            match table.get(id)? {
+                Some(value) => Ok(Some(rmp_serde::from_slice::<T>(value.value())?)),
+                None => Ok(None)
+            }
+        }, Ok(None))
+    }
+
+    pub fn get_many<K: Borrow<T::PrimaryKey>>(&self, ids: impl IntoIterator<Item = K>) -> crate::Result<Vec<Option<T>>> {
+        let name = self.collection.main_table_name();
+        let ids = ids.into_iter();
+        with_table!(&self.transaction, TableDefinition::<T::PrimaryKey, &[u8]>::new(&name), table => {
+            let mut results = Vec::with_capacity(ids.size_hint().0);
+            for id in ids {
+                if self.filtered_out(id.borrow())? {
+                    results.push(None);
+                    continue;
+                }
+                results.push(match table.get(id.borrow())? {
+                    Some(value) => Some(rmp_serde::from_slice::<T>(value.value())?),
+                    None => None
+                });
+            }
+            Ok(results)
+        }, Ok(ids.map(|_| None).collect()))
+    }
+
+    pub fn contains(&self, id: &T::PrimaryKey) -> crate::Result<bool> {
+        if self.filtered_out(id)? {
+            return Ok(false);
+        }
+        let name = self.collection.main_table_name();
+        with_table!(&self.transaction, TableDefinition::<T::PrimaryKey, &[u8]>::new(&name), table => {
+            Ok(table.get(id)?.is_some())
+        }, Ok(false))
+    }
+
+    pub fn get_path(&self, id: &T::PrimaryKey, pointer: &str) -> crate::Result<Option<rmpv::Value>> {
+        if self.filtered_out(id)? {
+            return Ok(None);
+        }
+        let name = self.collection.main_table_name();
+        with_table!(&self.transaction, TableDefinition::<T::PrimaryKey, &[u8]>::new(&name), table => {
+            match table.get(id)? {
+                Some(value) => read_pointer(value.value(), pointer),
+                None => Ok(None)
+            }
+        }, Ok(None))
+    }
+
+    pub fn get_raw(&self, id: &T::PrimaryKey) -> crate::Result<Option<Vec<u8>>> {
+        if self.filtered_out(id)? {
+            return Ok(None);
+        }
+        let name = self.collection.main_table_name();
+        with_table!(&self.transaction, TableDefinition::<T::PrimaryKey, &[u8]>::new(&name), table => {
+            Ok(table.get(id)?.map(|value| value.value().to_vec()))
+        }, Ok(None))
+    }
+
+    /// Every stored document as `(primary key, encoded document)`, in key order.
+    pub fn scan_raw(&self) -> crate::Result<Vec<(T::PrimaryKey, Vec<u8>)>> {
+        let name = self.collection.main_table_name();
+        with_table!(&self.transaction, TableDefinition::<T::PrimaryKey, &[u8]>::new(&name), table => {
+            let mut results = Vec::new();
+            for entry in table.iter()? {
+                let (key, value) = entry?;
+                results.push((key.value(), value.value().to_vec()));
+            }
+            Ok(results)
+        }, Ok(Vec::new()))
+    }
+
+    /// Every `(serialized value, primary key)` pair in the index table for `key`, with values in the base64 form
+    /// of [serialize_index_value] whatever the table's key format.
+    pub fn index_entries(&self, key: impl AsRef<str>) -> crate::Result<Vec<(String, T::PrimaryKey)>> {
+        let name = self.index_table_name(key.as_ref())?;
+        match self.index_format()? {
+            IndexKeyFormat::Raw => with_table!(multimap &self.transaction, MultimapTableDefinition::<&[u8], T::PrimaryKey>::new(&name), table => {
+                let mut results = Vec::new();
+                for entry in table.iter()? {
+                    let (value, ids) = entry?;
+                    for id in ids {
+                        results.push((base64_index_key(value.value()), id?.value()));
+                    }
+                }
+                Ok(results)
+            }, Ok(Vec::new())),
+            IndexKeyFormat::Base64 => with_table!(multimap &self.transaction, MultimapTableDefinition::<&str, T::PrimaryKey>::new(&name), table => {
+                let mut results = Vec::new();
+                for entry in table.iter()? {
+                    let (value, ids) = entry?;
+                    for id in ids {
+                        results.push((value.value().to_string(), id?.value()));
+                    }
+                }
+                Ok(results)
+            }, Ok(Vec::new()))
+        }
+    }
+
+    /// Compares every index table against the stored documents, describing each missing or stale entry.
+    pub fn index_problems(&self) -> crate::Result<Vec<String>> {
+        let mut expected = HashSet::new();
+        for (id, encoded) in self.scan_raw()? {
+            let id_bytes = T::PrimaryKey::as_bytes(&id).as_ref().to_vec();
+            for (key, values) in stored_indices(&rmp_serde::from_slice::<T>(&encoded)?)? {
+                for value in values {
+                    expected.insert((key.clone(), base64_index_key(&value), id_bytes.clone()));
+                }
+            }
+        }
+
+        let mut problems = Vec::new();
+        for key in index_names::<T>() {
+            for (value, id) in self.index_entries(&key)? {
+                let entry = (key.clone(), value, T::PrimaryKey::as_bytes(&id).as_ref().to_vec());
+                if !expected.remove(&entry) {
+                    problems.push(format!("stale entry in index {key}: {} -> {id:?}", entry.1));
+                }
+            }
+        }
+        for (key, value, _) in expected {
+            if index_names::<T>().contains(&key) {
+                problems.push(format!("missing entry in index {key}: {value}"));
+            }
+        }
+        Ok(problems)
+    }
+
+    pub fn set_index_format(&self, format: IndexKeyFormat) -> crate::Result<usize> {
+        if self.index_format()? == format {
+            return Ok(0);
+        }
+        self.write_indexes(format)
+    }
+
+    pub fn rebuild_indexes(&self) -> crate::Result<usize> {
+        self.write_indexes(self.index_format()?)
+    }
+
+    /// Index names with a table under this collection, sorted.
+    fn stored_index_names(&self) -> crate::Result<Vec<String>> {
+        let prefix = format!("{}/index/", self.collection.main_table_name());
+        let mut names: Vec<String> = self.transaction.multimap_table_names()?.iter().filter_map(|name| name.strip_prefix(&prefix)).map(str::to_string).collect();
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn check_index_layout(&self) -> crate::Result<()> {
+        let stored = self.stored_index_names()?;
+        let mut declared = index_names::<T>();
+        declared.sort();
+        if stored == declared || (stored.is_empty() && self.len()? == 0) {
+            return Ok(());
+        }
+        Err(Error::IndexMismatch { collection: self.collection.name(), stored, declared })
+    }
+
+    /// Replaces every index table with entries in `format` computed from the stored documents, dropping index
+    /// tables `T` no longer declares.
+    fn write_indexes(&self, format: IndexKeyFormat) -> crate::Result<usize> {
+        let mut indices = Vec::new();
+        for (id, document) in self.scan()? {
+            indices.push((id, stored_indices(&document)?));
+        }
+        self.delete_indexes(format)?;
+        meta::remove_maintenance_cursor(&self.transaction, &self.collection.name(), meta::REBUILD_INDEXES)?;
+        self.insert_index_entries(format, &indices)
+    }
+
+    /// One chunk of a resumable index rebuild into `format` (the current format if `None`): the first chunk
+    /// deletes the index tables, then each one indexes the next `chunk_size` documents. Returns the entries
+    /// written by the whole rebuild once it finishes.
+    fn write_indexes_chunk(&self, format: Option<IndexKeyFormat>, chunk_size: usize) -> crate::Result<Option<usize>> {
+        let (name, current) = (self.collection.name(), self.index_format()?);
+        let stored = meta::maintenance_cursor::<RebuildCursor<T::PrimaryKey>>(&self.transaction, &name, meta::REBUILD_INDEXES)?;
+        let cursor = match (stored, format) {
+            (Some(cursor), None) => cursor,
+            (Some(cursor), Some(format)) if cursor.format == format => cursor,
+            (None, Some(format)) if format == current => return Ok(Some(0)),
+            (_, format) => {
+                let format = format.unwrap_or(current);
+                self.delete_indexes(format)?;
+                RebuildCursor { format, after: None, written: 0 }
+            }
+        };
+
+        let documents = self.scan_raw_after(cursor.after, chunk_size)?;
+        let mut indices = Vec::new();
+        for (id, encoded) in &documents {
+            indices.push((id.clone(), stored_indices(&rmp_serde::from_slice::<T>(encoded)?)?));
+        }
+        let written = cursor.written + self.insert_index_entries(cursor.format, &indices)? as u64;
+        if documents.len() < chunk_size {
+            meta::remove_maintenance_cursor(&self.transaction, &name, meta::REBUILD_INDEXES)?;
+            return Ok(Some(written as usize));
+        }
+        let after = documents.last().map(|(id, _)| id.clone());
+        meta::set_maintenance_cursor(&self.transaction, &name, meta::REBUILD_INDEXES, &RebuildCursor { format: cursor.format, after, written })?;
+        Ok(None)
+    }
+
+    /// One chunk of a resumable index check, looking up the entries of the next `chunk_size` documents. Returns
+    /// every problem found once the last chunk is checked.
+    fn check_indexes_chunk(&self, chunk_size: usize) -> crate::Result<Option<Vec<String>>> {
+        let name = self.collection.name();
+        let mut cursor = meta::maintenance_cursor::<CheckCursor<T::PrimaryKey>>(&self.transaction, &name, meta::CHECK_INDEXES)?
+            .unwrap_or_else(|| CheckCursor { after: None, found: BTreeMap::new(), problems: Vec::new() });
+        let tables = self.collection.index_table_names();
+        let documents = self.scan_raw_after(cursor.after.take(), chunk_size)?;
+        for (id, encoded) in &documents {
+            let document = match rmp_serde::from_slice::<T>(encoded) {
+                Ok(document) => document,
+                Err(e) => {
+                    cursor.problems.push(format!("document {id:?}: {e}"));
+                    continue;
+                }
+            };
+            for (key, values) in stored_indices(&document)? {
+                let Some(index_name) = tables.get(&key) else {
+                    continue;
+                };
+                for value in values {
+                    if self.has_index_entry(index_name, &value, id)? {
+                        *cursor.found.entry(key.clone()).or_default() += 1;
+                    } else {
+                        cursor.problems.push(format!("missing entry in index {key}: {}", base64_index_key(&value)));
+                    }
+                }
+            }
+        }
+
+        if documents.len() == chunk_size {
+            cursor.after = documents.last().map(|(id, _)| id.clone());
+            meta::set_maintenance_cursor(&self.transaction, &name, meta::CHECK_INDEXES, &cursor)?;
+            return Ok(None);
+        }
+        for (key, index_name) in tables {
+            let (stored, found) = (self.index_len(&index_name)?, cursor.found.get(&key).copied().unwrap_or_default());
+            if stored > found {
+                cursor.problems.push(format!("{} stale entries in index {key}", stored - found));
+            }
+        }
+        meta::remove_maintenance_cursor(&self.transaction, &name, meta::CHECK_INDEXES)?;
+        Ok(Some(cursor.problems))
+    }
+
+    /// Up to `limit` stored documents after primary key `after`, in key order.
+    fn scan_raw_after(&self, after: Option<T::PrimaryKey>, limit: usize) -> crate::Result<Vec<(T::PrimaryKey, Vec<u8>)>> {
+        let name = self.collection.main_table_name();
+        let range = (after.map_or(Bound::Unbounded, Bound::Excluded), Bound::Unbounded);
+        with_table!(&self.transaction, TableDefinition::<T::PrimaryKey, &[u8]>::new(&name), table => {
+            let mut results = Vec::new();
+            for entry in table.range::<T::PrimaryKey>(range)?.take(limit) {
+                let (key, value) = entry?;
+                results.push((key.value(), value.value().to_vec()));
+            }
+            crate::Result::Ok(results)
+        }, Ok(Vec::new()))
+    }
+
+    /// Whether index table `index_name` maps stored value `value` to `id`.
+    fn has_index_entry(&self, index_name: &str, value: &[u8], id: &T::PrimaryKey) -> crate::Result<bool> {
+        let id = T::PrimaryKey::as_bytes(id).as_ref().to_vec();
+        let mut found = false;
+        match self.index_format()? {
+            IndexKeyFormat::Raw => with_table!(multimap &self.transaction, MultimapTableDefinition::<&[u8], T::PrimaryKey>::new(index_name), table => {
+                for entry in table.get(value)? {
+                    found |= T::PrimaryKey::as_bytes(&entry?.value()).as_ref() == id.as_slice();
+                }
+                crate::Result::Ok(found)
+            }, Ok(false)),
+            IndexKeyFormat::Base64 => with_table!(multimap &self.transaction, MultimapTableDefinition::<&str, T::PrimaryKey>::new(index_name), table => {
+                for entry in table.get(base64_index_key(value).as_str())? {
+                    found |= T::PrimaryKey::as_bytes(&entry?.value()).as_ref() == id.as_slice();
+                }
+                crate::Result::Ok(found)
+            }, Ok(false))
+        }
+    }
+
+    /// Number of entries in index table `index_name`.
+    fn index_len(&self, index_name: &str) -> crate::Result<u64> {
+        match self.index_format()? {
+            IndexKeyFormat::Raw => with_table!(multimap &self.transaction, MultimapTableDefinition::<&[u8], T::PrimaryKey>::new(index_name), table => {
+                crate::Result::Ok(table.len()?)
+            }, Ok(0)),
+            IndexKeyFormat::Base64 => with_table!(multimap &self.transaction, MultimapTableDefinition::<&str, T::PrimaryKey>::new(index_name), table => {
+                crate::Result::Ok(table.len()?)
+            }, Ok(0))
+        }
+    }
+
+    /// Deletes every index table of the collection, including those of indexes `T` no longer declares, and the
+    /// index sketches, then records `format` for the tables written next.
+    fn delete_indexes(&self, format: IndexKeyFormat) -> crate::Result<()> {
+        let current = self.index_format()?;
+        let stored = self.stored_index_names()?;
+        let main_name = self.collection.main_table_name();
+        let guard = self.transaction.write_guard(&self.operation, &main_name)?;
+        let mut deleted = Vec::new();
+        for name in stored {
+            let index_name = format!("{main_name}/index/{name}");
+            let existed = match current {
+                IndexKeyFormat::Raw => guard.delete_multimap_table(MultimapTableDefinition::<&[u8], T::PrimaryKey>::new(&index_name))?,
+                IndexKeyFormat::Base64 => guard.delete_multimap_table(MultimapTableDefinition::<&str, T::PrimaryKey>::new(&index_name))?
+            };
+            if existed {
+                deleted.push(index_name);
+            }
+        }
+        let collection = self.collection.name();
+        for name in [sketch_table_name(&collection), bitmap_table_name(&collection), bitmap_rows_table_name(&collection), bitmap_keys_table_name(&collection)] {
+            if guard.delete_table(TableDefinition::<&str, &[u8]>::new(&name))? {
+                deleted.push(name);
+            }
+        }
+        drop(guard);
+
+        for table in deleted {
+            self.transaction.record_change(table, ChangeKind::Delete, 0)?;
+        }
+        meta::set_index_format(&self.transaction, self.collection.name(), format)
+    }
+
+    /// Adds the index entries of `indices` in `format`, their values to the sketches of sketched indexes and
+    /// their documents to the bitmaps of bitmap indexes, returning the number of entries written.
+    fn insert_index_entries(&self, format: IndexKeyFormat, indices: &[(T::PrimaryKey, StoredIndices)]) -> crate::Result<usize> {
+        let main_name = self.collection.main_table_name();
+        let sketch_name = sketch_table_name(&self.collection.name());
+        let guard = self.transaction.write_guard(&self.operation, &main_name)?;
+        let mut changes = Vec::new();
+        for (key, index_name) in self.collection.index_table_names() {
+            let entries = indices.iter().filter_map(|(id, values)| Some(values.get(&key)?.iter().map(move |value| (id, value)))).flatten();
+            match format {
+                IndexKeyFormat::Raw => {
+                    let mut index = guard.open_multimap_table(MultimapTableDefinition::<&[u8], T::PrimaryKey>::new(&index_name))?;
+                    for (id, value) in entries {
+                        index.insert(value.as_slice(), id)?;
+                        changes.push((index_name.clone(), ChangeKind::Insert, value.len() + T::PrimaryKey::as_bytes(id).as_ref().len()));
+                    }
+                },
+                IndexKeyFormat::Base64 => {
+                    let mut index = guard.open_multimap_table(MultimapTableDefinition::<&str, T::PrimaryKey>::new(&index_name))?;
+                    for (id, value) in entries {
+                        let value = base64_index_key(value);
+                        index.insert(value.as_str(), id)?;
+                        changes.push((index_name.clone(), ChangeKind::Insert, value.len() + T::PrimaryKey::as_bytes(id).as_ref().len()));
+                    }
+                }
+            }
+            if index_spec::<T>(&key).sketch {
+                let values = indices.iter().filter_map(|(_, values)| values.get(&key)).flatten();
+                changes.push((sketch_name.clone(), ChangeKind::Update, add_to_sketch(&guard, &sketch_name, &key, values)?));
+            }
+            if index_spec::<T>(&key).bitmap {
+                let collection = self.collection.name();
+                for (id, values) in indices.iter().filter_map(|(id, values)| Some((id, values.get(&key)?))) {
+                    let Some((row, allocated)) = bitmap_row(&guard, &collection, id, true)? else {
+                        continue;
+                    };
+                    if allocated {
+                        let bytes = T::PrimaryKey::as_bytes(id).as_ref().len() + 4;
+                        changes.push((bitmap_rows_table_name(&collection), ChangeKind::Update, bytes));
+                        changes.push((bitmap_keys_table_name(&collection), ChangeKind::Update, bytes));
+                    }
+                    changes.push((bitmap_table_name(&collection), ChangeKind::Update, update_bitmaps(&guard, &collection, &key, row, [], values)?));
+                }
+            }
+        }
+        drop(guard);
+
+        let written = changes.iter().filter(|(_, kind, _)| *kind == ChangeKind::Insert).count();
+        for (table, kind, bytes) in changes {
+            self.transaction.record_change(table, kind, bytes)?;
+        }
+        Ok(written)
+    }
+
+    pub fn clear(&self) -> crate::Result<u64> {
+        let (cleared, format) = (self.len()?, self.index_format()?);
+        let main_name = self.collection.main_table_name();
+        let guard = self.transaction.write_guard(&self.operation, &main_name)?;
+        let mut deleted = Vec::new();
+        if guard.delete_table(TableDefinition::<T::PrimaryKey, &[u8]>::new(&main_name))? {
+            deleted.push(main_name.clone());
+        }
+        let lock_name = lock_table_name(&self.collection.name());
+        if guard.delete_table(TableDefinition::<T::PrimaryKey, &[u8]>::new(&lock_name))? {
+            deleted.push(lock_name);
+        }
+        let collection = self.collection.name();
+        let owned = [idempotency_table_name(&collection), sketch_table_name(&collection), bitmap_table_name(&collection), bitmap_rows_table_name(&collection), bitmap_keys_table_name(&collection)];
+        for name in owned {
+            if guard.delete_table(TableDefinition::<&str, &[u8]>::new(&name))? {
+                deleted.push(name);
+            }
+        }
+        for index_name in self.collection.index_table_names().into_values() {
+            let existed = match format {
+                IndexKeyFormat::Raw => guard.delete_multimap_table(MultimapTableDefinition::<&[u8], T::PrimaryKey>::new(&index_name))?,
+                IndexKeyFormat::Base64 => guard.delete_multimap_table(MultimapTableDefinition::<&str, T::PrimaryKey>::new(&index_name))?
+            };
+            if existed {
+                deleted.push(index_name);
+            }
+        }
+        drop(guard);
+        for table in deleted {
+            self.transaction.record_change(table, ChangeKind::Delete, 0)?;
+        }
+        meta::move_maintenance_cursors(&self.transaction, &self.collection.name(), None)?;
+        self.run_delete_hooks(None)?;
+        Ok(cleared)
+    }
+
+    pub fn rename_to(&self, target: &Collection<T>) -> crate::Result<bool> {
+        let format = self.index_format()?;
+        let (source_name, target_name) = (self.collection.main_table_name(), target.main_table_name());
+        let target_prefix = format!("{target_name}/");
+        let guard = self.transaction.write_guard(&self.operation, &source_name)?;
+        if guard.list_tables()?.any(|handle| handle.name() == target_name) || guard.list_multimap_tables()?.any(|handle| handle.name().starts_with(&target_prefix)) {
+            return Err(Error::CollectionExists(target.name()));
+        }
+
+        let mut tables = vec![(source_name.clone(), target_name.clone())];
+        let mut moved = copy_table(&guard, TableDefinition::<T::PrimaryKey, &[u8]>::new(&source_name), TableDefinition::new(&target_name))?;
+        let target_indexes = target.index_table_names();
+        for (key, index_name) in self.collection.index_table_names() {
+            let Some(target_index) = target_indexes.get(&key) else {
+                continue;
+            };
+            moved |= match format {
+                IndexKeyFormat::Raw => copy_multimap_table(&guard, MultimapTableDefinition::<&[u8], T::PrimaryKey>::new(&index_name), MultimapTableDefinition::new(target_index))?,
+                IndexKeyFormat::Base64 => copy_multimap_table(&guard, MultimapTableDefinition::<&str, T::PrimaryKey>::new(&index_name), MultimapTableDefinition::new(target_index))?
+            };
+            tables.push((index_name, target_index.clone()));
+        }
+        let (source_locks, target_locks) = (lock_table_name(&self.collection.name()), lock_table_name(&target.name()));
+        if copy_table(&guard, TableDefinition::<T::PrimaryKey, &[u8]>::new(&source_locks), TableDefinition::new(&target_locks))? {
+            tables.push((source_locks, target_locks));
+        }
+        for table_name in [idempotency_table_name, sketch_table_name] {
+            let (source_table, target_table) = (table_name(&self.collection.name()), table_name(&target.name()));
+            if copy_table(&guard, TableDefinition::<&str, &[u8]>::new(&source_table), TableDefinition::new(&target_table))? {
+                tables.push((source_table, target_table));
+            }
+        }
+        let (source_bitmaps, target_bitmaps) = (bitmap_table_name(&self.collection.name()), bitmap_table_name(&target.name()));
+        if copy_table(&guard, TableDefinition::<(&str, &[u8], u32), &[u8]>::new(&source_bitmaps), TableDefinition::new(&target_bitmaps))? {
+            tables.push((source_bitmaps, target_bitmaps));
+        }
+        let (source_rows, target_rows) = (bitmap_rows_table_name(&self.collection.name()), bitmap_rows_table_name(&target.name()));
+        if copy_table(&guard, TableDefinition::<T::PrimaryKey, u32>::new(&source_rows), TableDefinition::new(&target_rows))? {
+            tables.push((source_rows, target_rows));
+        }
+        let (source_keys, target_keys) = (bitmap_keys_table_name(&self.collection.name()), bitmap_keys_table_name(&target.name()));
+        if copy_table(&guard, TableDefinition::<u32, T::PrimaryKey>::new(&source_keys), TableDefinition::new(&target_keys))? {
+            tables.push((source_keys, target_keys));
+        }
+        drop(guard);
+
+        if moved {
+            for (source, target) in tables {
+                self.transaction.record_change(source, ChangeKind::Delete, 0)?;
+                self.transaction.record_change(target, ChangeKind::Insert, 0)?;
+            }
+            meta::set_index_format(&self.transaction, target.name(), format)?;
+            meta::set_index_format(&self.transaction, self.collection.name(), IndexKeyFormat::default())?;
+            meta::move_maintenance_cursors(&self.transaction, &self.collection.name(), Some(&target.name()))?;
+        }
+        Ok(moved)
+    }
+
+    fn index_table_name(&self, key: &str) -> crate::Result<String> {
+        self.collection.index_table_names().remove(key).ok_or_else(|| Error::UnknownIndex(key.to_string()))
+    }
+
+    pub fn approx_distinct(&self, key: &str) -> crate::Result<u64> {
+        self.index_table_name(key)?;
+        if !index_spec::<T>(key).sketch {
+            return Err(Error::UnsketchedIndex(key.to_string()));
+        }
+        let name = sketch_table_name(&self.collection.name());
+        with_table!(&self.transaction, TableDefinition::<&str, &[u8]>::new(&name), table => {
+            Ok(table.get(key)?.and_then(|stored| HyperLogLog::from_bytes(stored.value())).map(|sketch| sketch.estimate()).unwrap_or(0))
+        }, Ok(0))
+    }
+
+    pub fn bitmap_keys(&self, conditions: &[(String, rmpv::Value)]) -> crate::Result<KeySet<T::PrimaryKey>> {
+        let name = bitmap_table_name(&self.collection.name());
+        let (mut matched, mut lossy) = (None::<Bitmap>, Vec::new());
+        for (key, value) in conditions {
+            self.index_table_name(key)?;
+            let spec = index_spec::<T>(key);
+            if !spec.bitmap {
+                return Err(Error::UnbitmappedIndex(key.clone()));
+            }
+            let (stored, verify) = spec.stored_key(value)?;
+            if verify {
+                lossy.push((key, value));
+            }
+            if matched.as_ref().is_some_and(Bitmap::is_empty) {
+                continue;
+            }
+            let bitmap = with_table!(&self.transaction, TableDefinition::<(&str, &[u8], u32), &[u8]>::new(&name), table => {
+                let mut bitmap = Bitmap::new();
+                for entry in table.range((key.as_str(), stored.as_slice(), 0)..=(key.as_str(), stored.as_slice(), u32::MAX))? {
+                    let (chunk, container) = entry?;
+                    if let Some(container) = Container::from_bytes(container.value()) {
+                        bitmap.insert_container(chunk.value().2, container);
+                    }
+                }
+                crate::Result::Ok(bitmap)
+            }, Ok(Bitmap::new()))?;
+            matched = Some(match matched {
+                Some(matched) => matched.intersection(&bitmap),
+                None => bitmap
+            });
+        }
+
+        let keys_name = bitmap_keys_table_name(&self.collection.name());
+        let rows = matched.unwrap_or_default();
+        let mut keys: KeySet<T::PrimaryKey> = with_table!(&self.transaction, TableDefinition::<u32, T::PrimaryKey>::new(&keys_name), table => {
+            let mut keys = Vec::new();
+            for row in rows.iter() {
+                if let Some(id) = table.get(row)? {
+                    keys.push(id.value());
+                }
+            }
+            crate::Result::Ok(keys.into_iter().collect())
+        }, Ok(KeySet::default()))?;
+        for (key, value) in lossy {
+            if keys.is_empty() {
+                break;
+            }
+            keys = keys & self.keys_where(key, value)?;
+        }
+        Ok(keys)
+    }
+
+    pub fn count_by_index(&self, key: impl AsRef<str>, value: &rmpv::Value) -> crate::Result<u64> {
+        let name = self.index_table_name(key.as_ref())?;
+        let (stored, lossy) = index_spec::<T>(key.as_ref()).stored_key(value)?;
+        if lossy {
+            return Ok(self.keys_where(key, value)?.len() as u64);
+        }
+        match self.index_format()? {
+            IndexKeyFormat::Raw => with_table!(multimap &self.transaction, MultimapTableDefinition::<&[u8], T::PrimaryKey>::new(&name), table => {
+                Ok(table.get(stored.as_slice())?.len())
+            }, Ok(0)),
+            IndexKeyFormat::Base64 => with_table!(multimap &self.transaction, MultimapTableDefinition::<&str, T::PrimaryKey>::new(&name), table => {
+                Ok(table.get(base64_index_key(&stored).as_str())?.len())
+            }, Ok(0))
+        }
+    }
+
+    pub fn find_range(&self, key: &str, range: (Bound<rmpv::Value>, Bound<rmpv::Value>)) -> crate::Result<Vec<T>> {
+        let encode = |bound: Bound<rmpv::Value>| -> crate::Result<Bound<Vec<u8>>> {
+            Ok(match bound {
+                Bound::Included(value) => Bound::Included(encode_ordered_value(&value)?),
+                Bound::Excluded(value) => Bound::Excluded(encode_ordered_value(&value)?),
+                Bound::Unbounded => Bound::Unbounded
+            })
+        };
+        let ids = self.ordered_ids(key, (encode(range.0)?, encode(range.1)?), |_| true)?;
+        Ok(self.get_many(first_occurrences(ids))?.into_iter().flatten().collect())
+    }
+
+    pub fn find_prefix(&self, key: &str, prefix: &[rmpv::Value]) -> crate::Result<Vec<T>> {
+        let prefix = encode_ordered_prefix(prefix)?;
+        let ids = self.ordered_ids(key, (Bound::Included(prefix.clone()), Bound::Unbounded), |stored| stored.starts_with(&prefix))?;
+        Ok(self.get_many(first_occurrences(ids))?.into_iter().flatten().collect())
+    }
+
+    pub fn search_fuzzy(&self, key: &str, term: &str, max_distance: usize) -> crate::Result<Vec<T>> {
+        let mut distances = EditDistances::new(term, max_distance);
+        // The last stored value seen and its distance, shared by every entry holding it.
+        let (mut matches, mut last) = (Vec::new(), None::<(Vec<u8>, Option<usize>)>);
+        for (stored, id) in self.ordered_range(key, (Bound::Included(vec![0x20]), Bound::Excluded(vec![0x21])), |_| true)? {
+            let distance = match &last {
+                Some((previous, distance)) if *previous == stored => *distance,
+                _ => {
+                    let Some(value) = decode_ordered_string(&stored) else {
+                        continue;
+                    };
+                    let distance = distances.to(&value);
+                    last = Some((stored, distance));
+                    distance
+                }
+            };
+            if let Some(distance) = distance {
+                matches.push((distance, id));
+            }
+        }
+        matches.sort_by_key(|(distance, _)| *distance);
+        let ids = first_occurrences(matches.into_iter().map(|(_, id)| id).collect());
+        Ok(self.get_many(ids)?.into_iter().flatten().collect())
+    }
+
+    pub fn keys_with_prefix(&self, key: &str, prefix: &[rmpv::Value]) -> crate::Result<KeySet<T::PrimaryKey>> {
+        let prefix = encode_ordered_prefix(prefix)?;
+        Ok(self.ordered_ids(key, (Bound::Included(prefix.clone()), Bound::Unbounded), |stored| stored.starts_with(&prefix))?.into_iter().collect())
+    }
+
+    /// Deletes and returns the document with the lowest value in ordered index `key`, the lowest primary key
+    /// among ties.
+    pub fn pop_first_by(&self, key: &str) -> crate::Result<Option<T>> {
+        let mut first = true;
+        match self.ordered_ids(key, (Bound::Unbounded, Bound::Unbounded), |_| std::mem::take(&mut first))?.first() {
+            Some(id) => self.delete(id),
+            None => Ok(None)
+        }
+    }
+
+    pub fn sweep_expired(&self, key: &str, now: &rmpv::Value) -> crate::Result<SweepStats> {
+        Ok(self.sweep_expired_chunk(key, now, None, usize::MAX)?.0)
+    }
+
+    /// Purges the documents under the first `limit` values of expiry index `key` after stored value `after`,
+    /// also returning the last value read if more may follow.
+    fn sweep_expired_chunk(&self, key: &str, now: &rmpv::Value, after: Option<Vec<u8>>, limit: usize) -> crate::Result<(SweepStats, Option<Vec<u8>>)> {
+        let started = Instant::now();
+        let mut values = 0;
+        let lower = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let entries = self.ordered_range(key, (lower, Bound::Included(encode_ordered_value(now)?)), |_| {
+            values += 1;
+            values <= limit
+        })?;
+        let last = entries.last().map(|(stored, _)| stored.clone()).filter(|_| values > limit);
+        let scanned = entries.len() as u64;
+        let mut purged = 0;
+        for id in first_occurrences(entries.into_iter().map(|(_, id)| id).collect()) {
+            if self.delete(&id)?.is_some() {
+                purged += 1;
+            }
+        }
+        Ok((SweepStats { scanned, purged, elapsed: started.elapsed() }, last))
+    }
+
+    /// `(stored value, primary key)` for every document in ordered index `key`, in index order or its reverse.
+    /// Documents with several entries appear at their first one, or at every one if `duplicates` is set.
+    pub fn ordered_entries(&self, key: &str, descending: bool, duplicates: bool) -> crate::Result<Vec<(Vec<u8>, T::PrimaryKey)>> {
+        let mut entries = self.ordered_range(key, (Bound::Unbounded, Bound::Unbounded), |_| true)?;
+        if descending {
+            entries.reverse();
+        }
+        if duplicates {
+            return Ok(entries);
+        }
+        let mut seen = HashSet::new();
+        Ok(entries.into_iter().filter(|(_, id)| seen.insert(T::PrimaryKey::as_bytes(id).as_ref().to_vec())).collect())
+    }
+
+    /// Primary keys of the entries of ordered index `key` within `range`, in index order, stopping at the
+    /// first stored value outside `within`.
+    fn ordered_ids(&self, key: &str, range: (Bound<Vec<u8>>, Bound<Vec<u8>>), within: impl FnMut(&[u8]) -> bool) -> crate::Result<Vec<T::PrimaryKey>> {
+        Ok(self.ordered_range(key, range, within)?.into_iter().map(|(_, id)| id).collect())
+    }
+
+    fn ordered_range(&self, key: &str, range: (Bound<Vec<u8>>, Bound<Vec<u8>>), mut within: impl FnMut(&[u8]) -> bool) -> crate::Result<Vec<(Vec<u8>, T::PrimaryKey)>> {
+        let name = self.index_table_name(key)?;
+        if !index_spec::<T>(key).is_ordered() || self.index_format()? != IndexKeyFormat::Raw {
+            return Err(Error::UnorderedIndex(key.to_string()));
+        }
+        with_table!(multimap &self.transaction, MultimapTableDefinition::<&[u8], T::PrimaryKey>::new(&name), table => {
+            let mut ids = Vec::new();
+            for entry in table.range::<&[u8]>((range.0.as_ref().map(Vec::as_slice), range.1.as_ref().map(Vec::as_slice)))? {
+                let (stored, entries) = entry?;
+                if !within(stored.value()) {
+                    break;
+                }
+                for id in entries {
+                    ids.push((stored.value().to_vec(), id?.value()));
+                }
+            }
+            crate::Result::Ok(ids)
+        }, Ok(Vec::new()))
+    }
+
+    pub fn keys(&self) -> crate::Result<KeySet<T::PrimaryKey>> {
+        let name = self.collection.main_table_name();
+        with_table!(&self.transaction, TableDefinition::<T::PrimaryKey, &[u8]>::new(&name), table => {
+            let mut keys = Vec::new();
+            for entry in table.iter()? {
+                keys.push(entry?.0.value());
+            }
+            Ok(KeySet::from_sorted(keys))
+        }, Ok(KeySet::default()))
+    }
+
+    pub fn keys_where(&self, key: impl AsRef<str>, value: &rmpv::Value) -> crate::Result<KeySet<T::PrimaryKey>> {
+        let key = key.as_ref();
+        let (stored, lossy) = index_spec::<T>(key).stored_key(value)?;
+        let candidates = self.index_candidates(key, &stored)?;
+        if !lossy {
+            return Ok(KeySet::from_sorted(candidates));
+        }
+        let spec = index_spec::<T>(key);
+        let encoded = encode_index_value(&spec.compared(value))?;
+        let mut keys = Vec::new();
+        for id in candidates {
+            let Some(document) = self.get(&id)? else {
+                continue;
+            };
+            let Some(stored) = index_values(&document)?.remove(key) else {
+                continue;
+            };
+            for entry in spec.entry_values(stored) {
+                if encode_index_value(&spec.compared(&entry))? == encoded {
+                    keys.push(id);
+                    break;
+                }
+            }
+        }
+        Ok(KeySet::from_sorted(keys))
+    }
+
+    pub fn unique_slug(&self, key: &str, base: &str) -> crate::Result<String> {
+        if !self.transaction.is_writable() {
+            return Err(Error::read_only(&self.operation, self.collection.name()));
+        }
+        let mut suffix = 1u64;
+        loop {
+            let slug = match suffix {
+                1 => base.to_string(),
+                suffix => format!("{base}-{suffix}")
+            };
+            if self.keys_where(key, &slug.as_str().into())?.is_empty() {
+                return Ok(slug);
+            }
+            suffix += 1;
+        }
+    }
+
+    pub fn find_by(&self, key: impl AsRef<str>, value: &rmpv::Value) -> crate::Result<Vec<T>> {
+        let keys = self.keys_where(key, value)?;
+        Ok(self.get_many(keys)?.into_iter().flatten().collect())
+    }
+
+    /// Primary keys stored under `stored` in index `key`, in key order and unverified.
+    fn index_candidates(&self, key: &str, stored: &[u8]) -> crate::Result<Vec<T::PrimaryKey>> {
+        let name = self.index_table_name(key)?;
+        match self.index_format()? {
+            IndexKeyFormat::Raw => with_table!(multimap &self.transaction, MultimapTableDefinition::<&[u8], T::PrimaryKey>::new(&name), table => {
+                let mut keys = Vec::new();
+                for id in table.get(stored)? {
+                    keys.push(id?.value());
+                }
+                Ok(keys)
+            }, Ok(Vec::new())),
+            IndexKeyFormat::Base64 => with_table!(multimap &self.transaction, MultimapTableDefinition::<&str, T::PrimaryKey>::new(&name), table => {
+                let mut keys = Vec::new();
+                for id in table.get(base64_index_key(stored).as_str())? {
+                    keys.push(id?.value());
+                }
+                Ok(keys)
+            }, Ok(Vec::new()))
+        }
+    }
+
+    pub fn len(&self) -> crate::Result<u64> {
+        let name = self.collection.main_table_name();
+        with_table!(&self.transaction, TableDefinition::<T::PrimaryKey, &[u8]>::new(&name), table => {
+            Ok(table.len()?)
+        }, Ok(0))
+    }
+
+    pub fn insert(&self, document: &T) -> crate::Result<()> {
+        let filled = self.transaction.run_hook("with_default", || self.collection.defaults()?.apply(document))??;
+        let document = filled.as_ref().unwrap_or(document);
+        let id = document.id();
+        if self.contains(&id)? {
+            return Err(Error::DocumentExists { collection: self.collection.name(), id: format!("{id:?}") });
+        }
+        self.write(&id, None, Some(document))
+    }
+
+    pub fn insert_idempotent(&self, idempotency_key: &str, document: &T, ttl: Duration) -> crate::Result<IdempotentInsert<T::PrimaryKey>> {
+        self.purge_expired_idempotency_keys()?;
+        let name = idempotency_table_name(&self.collection.name());
+        let used: crate::Result<Option<IdempotencyRecord>> = with_table!(&self.transaction, TableDefinition::<&str, &[u8]>::new(&name), table => {
+            match table.get(idempotency_key)? {
+                Some(value) => Ok(Some(rmp_serde::from_slice(value.value())?)),
+                None => Ok(None)
+            }
+        }, Ok(None));
+        if let Some(used) = used? {
+            return Ok(IdempotentInsert::Duplicate(T::PrimaryKey::from_bytes(&used.id)));
+        }
+
+        let id = document.id();
+        self.insert(document)?;
+        let record = IdempotencyRecord { id: T::PrimaryKey::as_bytes(&id).as_ref().to_vec(), expires_at: expires_after(Utc::now(), ttl) };
+        let encoded = rmp_serde::to_vec_named(&record)?;
+        let guard = self.transaction.write_guard(&self.operation, &name)?;
+        guard.open_table(TableDefinition::<&str, &[u8]>::new(&name))?.insert(idempotency_key, encoded.as_slice())?;
+        drop(guard);
+        self.transaction.record_change(&name, ChangeKind::Insert, idempotency_key.len() + encoded.len())?;
+        Ok(IdempotentInsert::Inserted(id))
+    }
+
+    /// Deletes every idempotency key of the collection whose TTL has passed.
+    fn purge_expired_idempotency_keys(&self) -> crate::Result<usize> {
+        let (name, now) = (idempotency_table_name(&self.collection.name()), Utc::now());
+        let guard = self.transaction.write_guard(&self.operation, &name)?;
+        let mut table = guard.open_table(TableDefinition::<&str, &[u8]>::new(&name))?;
+        let mut purged = 0;
+        for entry in table.extract_if(|_, value| rmp_serde::from_slice::<IdempotencyRecord>(value).is_ok_and(|record| record.expires_at <= now))? {
+            entry?;
+            purged += 1;
+        }
+        drop(table);
+        drop(guard);
+        for _ in 0..purged {
+            self.transaction.record_change(&name, ChangeKind::Delete, 0)?;
+        }
+        Ok(purged)
+    }
+
+    pub fn insert_many<D: Borrow<T>>(&self, documents: impl IntoIterator<Item = D>) -> crate::Result<usize> {
+        let mut inserted = 0;
+        for document in documents {
+            self.insert(document.borrow())?;
+            inserted += 1;
+        }
+        Ok(inserted)
+    }
+
+    pub fn replace(&self, document: &T) -> crate::Result<T> {
+        let id = document.id();
+        let previous = self.get(&id)?.ok_or_else(|| self.not_found(&id))?;
+        self.write(&id, Some(&stored_indices(&previous)?), Some(document))?;
+        Ok(previous)
+    }
+
+    pub fn upsert(&self, document: &T) -> crate::Result<UpsertResult<T>> {
+        let id = document.id();
+        let previous = self.get(&id)?;
+        let indices = previous.as_ref().map(stored_indices).transpose()?;
+        self.write(&id, indices.as_ref(), Some(document))?;
+        Ok(match previous {
+            Some(previous) => UpsertResult::Replaced(previous),
+            None => UpsertResult::Inserted
+        })
+    }
+
+    pub fn modify(&self, id: &T::PrimaryKey, modify: impl FnOnce(&mut T) -> bool) -> crate::Result<Option<T>> {
+        let Some(mut document) = self.get(id)? else {
+            return Ok(None);
+        };
+        let indices = stored_indices(&document)?;
+        if !self.transaction.run_hook("modify", || modify(&mut document))? {
+            return Ok(Some(document));
+        }
+        self.ensure_same_key(id, &document)?;
+        self.write(id, Some(&indices), Some(&document))?;
+        Ok(Some(document))
+    }
+
+    pub fn update_if(&self, id: &T::PrimaryKey, predicate: impl FnOnce(&T) -> bool, document: &T) -> crate::Result<bool> {
+        self.ensure_same_key(id, document)?;
+        let Some(stored) = self.get(id)? else {
+            return Ok(false);
+        };
+        if !self.transaction.run_hook("update_if", || predicate(&stored))? {
+            return Ok(false);
+        }
+        self.write(id, Some(&stored_indices(&stored)?), Some(document))?;
+        Ok(true)
+    }
+
+    pub fn increment(&self, id: &T::PrimaryKey, field: &str, delta: i64) -> crate::Result<Option<i64>> {
+        self.edit_field(id, field, |value| {
+            let next = value.as_i64().and_then(|current| current.checked_add(delta)).ok_or("an integer that can take the increment")?;
+            *value = next.into();
+            Ok(next)
+        })
+    }
+
+    pub fn push(&self, id: &T::PrimaryKey, field: &str, value: rmpv::Value) -> crate::Result<Option<usize>> {
+        self.edit_field(id, field, |array| {
+            let rmpv::Value::Array(values) = array else {
+                return Err("an array");
+            };
+            values.push(value);
+            Ok(values.len())
+        })
+    }
+
+    pub fn pull(&self, id: &T::PrimaryKey, field: &str, value: &rmpv::Value) -> crate::Result<Option<usize>> {
+        let encoded = encode_index_value(value)?;
+        self.edit_field(id, field, |array| {
+            let rmpv::Value::Array(values) = array else {
+                return Err("an array");
+            };
+            let before = values.len();
+            values.retain(|element| encode_index_value(element).ok().as_ref() != Some(&encoded));
+            Ok(before - values.len())
+        })
+    }
+
+    pub fn lock_info(&self, id: &T::PrimaryKey) -> crate::Result<Option<DocumentLock>> {
+        let name = lock_table_name(&self.collection.name());
+        with_table!(&self.transaction, TableDefinition::<T::PrimaryKey, &[u8]>::new(&name), table => {
+            match table.get(id)? {
+                Some(value) => Ok(Some(rmp_serde::from_slice::<DocumentLock>(value.value())?).filter(|lock| !lock.is_expired())),
+                None => Ok(None)
+            }
+        }, Ok(None))
+    }
+
+    pub fn lock(&self, id: &T::PrimaryKey, owner: &str, ttl: Duration) -> crate::Result<DocumentLock> {
+        self.purge_expired_locks()?;
+        let held = self.lock_info(id)?;
+        if let Some(held) = &held && held.owner != owner {
+            return Err(Error::DocumentLocked { collection: self.collection.name(), id: format!("{id:?}"), owner: held.owner.clone(), expires_at: held.expires_at });
+        }
+        let now = Utc::now();
+        let lock = DocumentLock {
+            owner: owner.to_string(),
+            acquired_at: held.map_or(now, |held| held.acquired_at),
+            expires_at: expires_after(now, ttl)
+        };
+        let (name, encoded) = (lock_table_name(&self.collection.name()), rmp_serde::to_vec_named(&lock)?);
+        let guard = self.transaction.write_guard(&self.operation, &name)?;
+        let kind = match guard.open_table(TableDefinition::<T::PrimaryKey, &[u8]>::new(&name))?.insert(id, encoded.as_slice())? {
+            Some(_) => ChangeKind::Update,
+            None => ChangeKind::Insert
+        };
+        drop(guard);
+        self.transaction.record_change(&name, kind, encoded.len())?;
+        Ok(lock)
+    }
+
+    /// Deletes every expired lock row of the collection, so the lock table only holds live locks.
+    fn purge_expired_locks(&self) -> crate::Result<usize> {
+        let name = lock_table_name(&self.collection.name());
+        let guard = self.transaction.write_guard(&self.operation, &name)?;
+        let mut table = guard.open_table(TableDefinition::<T::PrimaryKey, &[u8]>::new(&name))?;
+        let mut purged = 0;
+        for entry in table.extract_if(|_, value| rmp_serde::from_slice::<DocumentLock>(value).is_ok_and(|lock| lock.is_expired()))? {
+            entry?;
+            purged += 1;
+        }
+        drop(table);
+        drop(guard);
+        for _ in 0..purged {
+            self.transaction.record_change(&name, ChangeKind::Delete, 0)?;
+        }
+        Ok(purged)
+    }
+
+    pub fn unlock(&self, id: &T::PrimaryKey, owner: &str) -> crate::Result<bool> {
+        if self.lock_info(id)?.is_none_or(|held| held.owner != owner) {
+            return Ok(false);
+        }
+        let name = lock_table_name(&self.collection.name());
+        let guard = self.transaction.write_guard(&self.operation, &name)?;
+        guard.open_table(TableDefinition::<T::PrimaryKey, &[u8]>::new(&name))?.remove(id)?;
+        drop(guard);
+        self.transaction.record_change(&name, ChangeKind::Delete, 0)?;
+        Ok(true)
+    }
+
+    /// Rewrites the value at dot path `field` of the document under `id` with `edit`, which names the expected
+    /// kind of value when it doesn't apply. Returns `None` if the document doesn't exist.
+    fn edit_field<R>(&self, id: &T::PrimaryKey, field: &str, edit: impl FnOnce(&mut rmpv::Value) -> Result<R, &'static str>) -> crate::Result<Option<R>> {
+        let Some(document) = self.get(id)? else {
+            return Ok(None);
+        };
+        let mut value = named_value(&document)?;
+        let field_error = |expected| Error::FieldType { collection: self.collection.name(), id: format!("{id:?}"), field: field.to_string(), expected };
+        let result = edit(value_at_mut(&mut value, field).ok_or_else(|| field_error("present"))?).map_err(field_error)?;
+        let updated = rmp_serde::from_slice::<T>(&rmp_serde::to_vec_named(&value)?)?;
+        self.ensure_same_key(id, &updated)?;
+        self.write(id, Some(&stored_indices(&document)?), Some(&updated))?;
+        Ok(Some(result))
+    }
+
+    /// Decodes every stored document, in key order.
+    pub fn scan(&self) -> crate::Result<Vec<(T::PrimaryKey, T)>> {
+        let mut results = Vec::new();
+        for (id, encoded) in self.scan_raw()? {
+            results.push((id, rmp_serde::from_slice::<T>(&encoded)?));
+        }
+        Ok(results)
+    }
+
+    pub fn delete_where(&self, predicate: impl FnMut(&T) -> bool) -> crate::Result<usize> {
+        self.delete_entries(self.scan_where("delete_where", predicate)?)
+    }
+
+    pub fn delete_matching(&self, query: &Query<T>) -> crate::Result<usize> {
+        self.delete_entries(query.entries_in(&self.transaction)?)
+    }
+
+    fn delete_entries(&self, entries: impl IntoIterator<Item = (T::PrimaryKey, T)>) -> crate::Result<usize> {
+        let mut deleted = 0;
+        for (id, document) in entries {
+            self.write(&id, Some(&stored_indices(&document)?), None)?;
+            self.run_delete_hooks(Some(&id))?;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+
+    pub fn update_where(&self, predicate: impl FnMut(&T) -> bool, mutator: impl FnMut(&mut T)) -> crate::Result<usize> {
+        self.update_entries("update_where", self.scan_where("update_where", predicate)?, mutator)
+    }
+
+    pub fn update_matching(&self, query: &Query<T>, mutator: impl FnMut(&mut T)) -> crate::Result<usize> {
+        self.update_entries("update_matching", query.entries_in(&self.transaction)?, mutator)
+    }
+
+    /// Every stored document `predicate` accepts, in key order, with panics in it caught as hook `hook`.
+    fn scan_where(&self, hook: &str, mut predicate: impl FnMut(&T) -> bool) -> crate::Result<Vec<(T::PrimaryKey, T)>> {
+        let mut matched = Vec::new();
+        for (id, document) in self.scan()? {
+            if self.transaction.run_hook(hook, || predicate(&document))? {
+                matched.push((id, document));
+            }
+        }
+        Ok(matched)
+    }
+
+    fn update_entries(&self, hook: &str, entries: impl IntoIterator<Item = (T::PrimaryKey, T)>, mut mutator: impl FnMut(&mut T)) -> crate::Result<usize> {
+        let mut updated = 0;
+        for (id, mut document) in entries {
+            let indices = stored_indices(&document)?;
+            self.transaction.run_hook(hook, || mutator(&mut document))?;
+            self.ensure_same_key(&id, &document)?;
+            self.write(&id, Some(&indices), Some(&document))?;
+            updated += 1;
+        }
+        Ok(updated)
+    }
+
+    fn ensure_same_key(&self, id: &T::PrimaryKey, document: &T) -> crate::Result<()> {
+        if T::PrimaryKey::compare(T::PrimaryKey::as_bytes(id).as_ref(), T::PrimaryKey::as_bytes(&document.id()).as_ref()).is_ne() {
+            return Err(Error::PrimaryKeyChanged { collection: self.collection.name(), id: format!("{id:?}") });
+        }
+        Ok(())
+    }
+
+    pub fn delete(&self, id: &T::PrimaryKey) -> crate::Result<Option<T>> {
+        let previous = self.get(id)?;
+        if let Some(previous) = &previous {
+            self.write(id, Some(&stored_indices(previous)?), None)?;
+            self.run_delete_hooks(Some(id))?;
+        }
+        Ok(previous)
+    }
+
+    /// Runs the [DeleteHooks] registered for this collection, e.g. by [Relation::cascade].
+    fn run_delete_hooks(&self, id: Option<&T::PrimaryKey>) -> crate::Result<()> {
+        let database = self.collection.database();
+        database.delete_hooks.run(&database, &self.transaction, self.collection.name(), id.map(|id| id as &dyn Any))
+    }
+
+    /// Fails with [Error::DuplicateKey] if `document` would share the value of a unique index with another document.
+    fn ensure_unique(&self, id: &T::PrimaryKey, previous: Option<&StoredIndices>, document: &T) -> crate::Result<()> {
+        let id_bytes = T::PrimaryKey::as_bytes(id);
+        for (key, value) in index_values(document)? {
+            let spec = index_spec::<T>(&key);
+            if !spec.unique || !index_names::<T>().contains(&key) {
+                continue;
+            }
+            let held = previous.and_then(|values| values.get(&key)).map(Vec::as_slice).unwrap_or_default();
+            for value in spec.entry_values(value) {
+                if held.contains(&spec.stored_key(&value)?.0) {
+                    continue;
+                }
+                if self.keys_where(&key, &value)?.iter().any(|other| T::PrimaryKey::as_bytes(other).as_ref() != id_bytes.as_ref()) {
+                    return Err(Error::DuplicateKey { collection: self.collection.name(), index: key, value: value.to_string() });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the stored document under `id` to `next`, updating only the index entries whose values differ from
+    /// `previous`, the serialized indices of the document currently stored (if any).
+    fn write(&self, id: &T::PrimaryKey, previous: Option<&StoredIndices>, next: Option<&T>) -> crate::Result<()> {
+        let main_name = self.collection.main_table_name();
+        let format = if index_names::<T>().is_empty() { IndexKeyFormat::default() } else { self.index_format()? };
+        if let Some(document) = next {
+            self.ensure_unique(id, previous, document)?;
+        }
+        // Everything fallible about encoding happens before the first table write, so a failed write leaves
+        // nothing behind inside a longer transaction. Measuring the document here also sizes the value it's
+        // encoded into below.
+        let length = next.map(encoded_length).transpose()?;
+        let next_indices = next.map(stored_indices).transpose()?.unwrap_or_default();
+        let guard = self.transaction.write_guard(&self.operation, &main_name)?;
+        let id_bytes = T::PrimaryKey::as_bytes(id).as_ref().len();
+        let (collection, mut row) = (self.collection.name(), None);
+
+        let mut receipt = self.receipt.borrow_mut();
+        receipt.keys.push(id.clone());
+        receipt.previous += u64::from(previous.is_some());
+
+        let mut main = guard.open_table(TableDefinition::<T::PrimaryKey, &[u8]>::new(&main_name))?;
+        match next.zip(length) {
+            Some((document, length)) => {
+                self.collection.database.key_filters.insert(self.collection.name(), T::PrimaryKey::as_bytes(id).as_ref())?;
+                let length = encode_into(&mut main, id, document, length)?;
+                let kind = if previous.is_some() { ChangeKind::Update } else { ChangeKind::Insert };
+                self.transaction.record_change(&main_name, kind, id_bytes + length)?;
+                receipt.bytes += (id_bytes + length) as u64;
+            },
+            None => {
+                main.remove(id)?;
+                self.transaction.record_change(&main_name, ChangeKind::Delete, 0)?;
+            }
+        }
+
+        for (key, index_name) in self.collection.index_table_names() {
+            let old_values = previous.and_then(|values| values.get(&key)).map(Vec::as_slice).unwrap_or_default();
+            let new_values = next_indices.get(&key).map(Vec::as_slice).unwrap_or_default();
+            let removed: Vec<&Vec<u8>> = old_values.iter().filter(|value| !new_values.contains(value)).collect();
+            let added: Vec<&Vec<u8>> = new_values.iter().filter(|value| !old_values.contains(value)).collect();
+            if removed.is_empty() && added.is_empty() {
+                continue;
+            }
+            let stored = match format {
+                IndexKeyFormat::Raw => {
+                    let mut index = guard.open_multimap_table(MultimapTableDefinition::<&[u8], T::PrimaryKey>::new(&index_name))?;
+                    for value in &removed {
+                        index.remove(value.as_slice(), id)?;
+                    }
+                    let mut stored = Vec::new();
+                    for value in &added {
+                        index.insert(value.as_slice(), id)?;
+                        stored.push(value.len());
+                    }
+                    stored
+                },
+                IndexKeyFormat::Base64 => {
+                    let mut index = guard.open_multimap_table(MultimapTableDefinition::<&str, T::PrimaryKey>::new(&index_name))?;
+                    for value in &removed {
+                        index.remove(base64_index_key(value).as_str(), id)?;
+                    }
+                    let mut stored = Vec::new();
+                    for value in added.iter().map(|value| base64_index_key(value)) {
+                        index.insert(value.as_str(), id)?;
+                        stored.push(value.len());
+                    }
+                    stored
+                }
+            };
+            for _ in &removed {
+                self.transaction.record_change(&index_name, ChangeKind::Delete, 0)?;
+                receipt.index_entries_removed += 1;
+            }
+            for bytes in stored {
+                self.transaction.record_change(&index_name, ChangeKind::Insert, bytes + id_bytes)?;
+                receipt.index_entries_added += 1;
+                receipt.bytes += (bytes + id_bytes) as u64;
+            }
+            if !added.is_empty() && index_spec::<T>(&key).sketch {
+                let sketch_name = sketch_table_name(&self.collection.name());
+                let bytes = add_to_sketch(&guard, &sketch_name, &key, added.iter().copied())?;
+                self.transaction.record_change(&sketch_name, ChangeKind::Update, bytes)?;
+            }
+            if index_spec::<T>(&key).bitmap {
+                if row.is_none() {
+                    row = Some(bitmap_row(&guard, &collection, id, next.is_some())?);
+                    if let Some(Some((_, true))) = row {
+                        self.transaction.record_change(bitmap_rows_table_name(&collection), ChangeKind::Insert, id_bytes + 4)?;
+                        self.transaction.record_change(bitmap_keys_table_name(&collection), ChangeKind::Insert, id_bytes + 4)?;
+                    }
+                }
+                if let Some(Some((row, _))) = row {
+                    let bytes = update_bitmaps(&guard, &collection, &key, row, removed, added)?;
+                    self.transaction.record_change(bitmap_table_name(&collection), ChangeKind::Update, bytes)?;
+                }
+            }
+        }
+        let bitmapped = || self.collection.index_table_names().keys().any(|key| index_spec::<T>(key).bitmap);
+        if next.is_none() && bitmapped() && release_bitmap_row(&guard, &collection, id)? {
+            self.transaction.record_change(bitmap_rows_table_name(&collection), ChangeKind::Delete, 0)?;
+            self.transaction.record_change(bitmap_keys_table_name(&collection), ChangeKind::Delete, 0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copies every entry of `source` into `target` and deletes `source`, returning `false` if it didn't exist.
+fn copy_table<K: Key + 'static, V: Value + 'static>(txn: &redb::WriteTransaction, source: TableDefinition<K, V>, target: TableDefinition<K, V>) -> crate::Result<bool> {
+    if !txn.list_tables()?.any(|handle| handle.name() == source.name()) {
+        return Ok(false);
+    }
+    {
+        let (from, mut to) = (txn.open_table(source)?, txn.open_table(target)?);
+        for entry in from.iter()? {
+            let (key, value) = entry?;
+            to.insert(key.value(), value.value())?;
+        }
+    }
+    Ok(txn.delete_table(source)?)
+}
+
+fn copy_multimap_table<K: Key + 'static, V: Key + 'static>(txn: &redb::WriteTransaction, source: MultimapTableDefinition<K, V>, target: MultimapTableDefinition<K, V>) -> crate::Result<bool> {
+    if !txn.list_multimap_tables()?.any(|handle| handle.name() == source.name()) {
+        return Ok(false);
+    }
+    {
+        let (from, mut to) = (txn.open_multimap_table(source)?, txn.open_multimap_table(target)?);
+        for entry in from.iter()? {
+            let (key, values) = entry?;
+            for value in values {
+                to.insert(key.value(), value?.value())?;
+            }
+        }
+    }
+    Ok(txn.delete_multimap_table(source)?)
+}
+
+/// An [std::io::Write] sink that only counts bytes, used to size a reserved value before encoding into it.
+#[derive(Default)]
+struct ByteCount(usize);
+
+impl std::io::Write for ByteCount {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The length of `document` encoded as named msgpack, measured without buffering it.
+fn encoded_length<T: Serialize>(document: &T) -> crate::Result<usize> {
+    let mut length = ByteCount::default();
+    rmp_serde::encode::write_named(&mut length, document)?;
+    Ok(length.0)
+}
+
+/// Encodes `document` straight into a value of `length` bytes (from [encoded_length]) reserved in `table`,
+/// returning the length stored. If the second encoding doesn't fill the reservation exactly, as with a
+/// `Serialize` impl that isn't deterministic, the value is rewritten from a buffer instead.
+fn encode_into<K: Key + 'static, T: Serialize>(table: &mut redb::Table<K, &'static [u8]>, key: &K::SelfType<'_>, document: &T, length: usize) -> crate::Result<usize> {
+    let reserved = u32::try_from(length).map_err(|_| redb::StorageError::ValueTooLarge(length))?;
+    let filled = {
+        let mut value = table.insert_reserve(key, reserved)?;
+        let mut buffer: &mut [u8] = value.as_mut();
+        rmp_serde::encode::write_named(&mut buffer, document).is_ok() && buffer.is_empty()
+    };
+    if filled {
+        return Ok(length);
+    }
+    let encoded = rmp_serde::to_vec_named(document)?;
+    table.insert(key, encoded.as_slice())?;
+    Ok(encoded.len())
+}
+
+fn value_bounds<V: Into<rmpv::Value> + Clone>(range: impl RangeBounds<V>) -> (Bound<rmpv::Value>, Bound<rmpv::Value>) {
+    (range.start_bound().cloned().map(Into::into), range.end_bound().cloned().map(Into::into))
+}
+
+/// The index table keys of a document per index, sorted and free of duplicates.
+pub(crate) type StoredIndices = HashMap<String, Vec<Vec<u8>>>;
+
+/// The index table keys of every index value of `document`, after applying each index's [crate::document::IndexSpec].
+pub(crate) fn stored_indices<T: Document>(document: &T) -> crate::Result<StoredIndices> {
+    let mut stored = HashMap::new();
+    for (key, value) in index_values(document)? {
+        let spec = index_spec::<T>(&key);
+        let mut values = Vec::new();
+        for value in spec.entry_values(value) {
+            values.push(spec.stored_key(&value)?.0);
+        }
+        values.sort();
+        values.dedup();
+        stored.insert(key, values);
+    }
+    Ok(stored)
+}
+
+/// Scans the primary keys of collection `name` into a new key filter and installs it before the write transaction
+/// it scans in ends, so no write can land between the scan and the filter taking over.
+fn build_key_filter<T: Document>(db: &Database, name: &str, config: KeyFilterConfig) -> crate::Result<()> {
+    let collection = db.collection::<T>(name);
+    let main_name = collection.main_table_name();
+    let txn = db.begin_write("build_key_filter", &main_name)?;
+    let mut filter = BloomFilter::new(config.expected_keys, config.false_positive_rate);
+    let scanned: crate::Result<()> = with_table!(&txn, TableDefinition::<T::PrimaryKey, &[u8]>::new(&main_name), table => {
+        for entry in table.iter()? {
+            filter.insert(T::PrimaryKey::as_bytes(&entry?.0.value()).as_ref());
+        }
+        Ok(())
+    }, Ok(()));
+    scanned?;
+    db.key_filters.install(name, filter, db.tracker.next_id(), config, build_key_filter::<T>)?;
+    txn.abort()
+}
+
+/// Levenshtein distances from a sequence of values to one term, bounded by `max_distance`, see
+/// [CollectionOperation::search_fuzzy]. Fed values in sorted order, each reuses the rows computed for the prefix
+/// it shares with the one before.
+struct EditDistances {
+    term: Vec<char>,
+    max_distance: usize,
+    /// `rows[i]` holds the distances between the first `i` characters of `previous` and every prefix of `term`.
+    rows: Vec<Vec<usize>>,
+    previous: Vec<char>
+}
+
+impl EditDistances {
+    fn new(term: &str, max_distance: usize) -> Self {
+        let term: Vec<char> = term.chars().collect();
+        Self { rows: vec![(0..=term.len()).collect()], term, max_distance, previous: Vec::new() }
+    }
+
+    /// The distance from `value` to the term, or `None` if it's more than `max_distance`.
+    fn to(&mut self, value: &str) -> Option<usize> {
+        let value: Vec<char> = value.chars().collect();
+        let common = value.iter().zip(&self.previous).take_while(|(a, b)| a == b).count().min(self.rows.len() - 1);
+        self.rows.truncate(common + 1);
+        for &character in value.iter().skip(common) {
+            // Once every distance in a row is past the bound, no longer value can come back under it.
+            let Some(last) = self.rows.last().filter(|row| row.iter().min().is_some_and(|&min| min <= self.max_distance)) else {
+                break;
+            };
+            let mut row = vec![last.first().copied().unwrap_or_default() + 1];
+            for (i, &expected) in self.term.iter().enumerate() {
+                let substitution = last.get(i).copied().unwrap_or_default() + usize::from(character != expected);
+                let deletion = last.get(i + 1).copied().unwrap_or_default() + 1;
+                let insertion = row.get(i).copied().unwrap_or_default() + 1;
+                row.push(substitution.min(deletion).min(insertion));
+            }
+            self.rows.push(row);
+        }
+        let complete = self.rows.len() == value.len() + 1;
+        self.previous = value;
+        self.rows.last().and_then(|row| row.last()).copied().filter(|&distance| complete && distance <= self.max_distance)
+    }
+}
+
+/// `ids` without repeats, keeping the first occurrence of each.
+fn first_occurrences<K: OwnedKey>(ids: Vec<K>) -> Vec<K> {
+    let mut seen = HashSet::new();
+    ids.into_iter().filter(|id| seen.insert(K::as_bytes(id).as_ref().to_vec())).collect()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::panic)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::{document::IndexSpec, testing::fixtures::{Item, Note}};
+
+    /// [Note] stored under the same name, with its `title` index swapped for `length`.
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    struct LengthNote {
+        id: String,
+        title: String
+    }
+
+    impl Document for LengthNote {
+        type PrimaryKey = String;
+
+        fn id(&self) -> String {
+            self.id.clone()
+        }
+
+        fn id_field() -> String {
+            "id".to_string()
+        }
+
+        fn index_keys() -> Vec<String> {
+            vec!["length".to_string()]
+        }
+
+        fn index_vals(&self) -> HashMap<String, rmpv::Value> {
+            HashMap::from([("length".to_string(), (self.title.len() as u64).into())])
+        }
+    }
+
+    #[test]
+    fn changed_indexes_are_reported_and_rebuilt() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        db.collection::<Note>("notes").insert(&Note { id: "a".to_string(), title: "abc".to_string() })?;
+        let notes = db.collection::<LengthNote>("notes");
+        match notes.check_index_layout() {
+            Err(Error::IndexMismatch { stored, declared, .. }) => assert_eq!((stored, declared), (vec!["title".to_string()], vec!["length".to_string()])),
+            other => panic!("expected an index mismatch, got {other:?}")
+        }
+        notes.rebuild_indexes()?;
+        notes.check_index_layout()?;
+        assert_eq!(notes.keys_where("length", 3u64)?.into_vec(), ["a"]);
+        Ok(())
+    }
+
+    #[test]
+    fn rename_keeps_registrations_consistent() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        db.register::<Note>("notes")?;
+        db.register::<LengthNote>("lengths")?;
+        assert!(!db.rename_collection::<Note>("notes", "renamed")?);
+        assert_eq!(db.get_collection::<Note>()?.name(), "notes");
+
+        db.get_collection::<Note>()?.insert(&Note { id: "a".to_string(), title: "abc".to_string() })?;
+        assert!(matches!(db.rename_collection::<Note>("notes", "lengths"), Err(Error::CollectionConflict { .. })));
+        assert!(db.rename_collection::<Note>("notes", "renamed")?);
+        assert_eq!(db.get_collection::<Note>()?.name(), "renamed");
+        Ok(())
+    }
+
+    /// Serializes to a longer string every time, so no two encodings agree.
+    struct Growing(std::cell::Cell<usize>);
+
+    impl Serialize for Growing {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            self.0.set(self.0.get() + 1);
+            serializer.serialize_str(&"x".repeat(self.0.get()))
+        }
+    }
+
+    #[test]
+    fn documents_encode_straight_into_reserved_values() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let notes = db.collection::<Note>("notes");
+        let note = Note { id: "a".to_string(), title: "reserved".to_string() };
+        notes.insert(&note)?;
+        assert_eq!(notes.get_raw(&"a".into())?, Some(rmp_serde::to_vec_named(&note)?));
+        assert_eq!(notes.get(&"a".to_string())?, Some(note));
+
+        let txn = db.writer()?;
+        let guard = txn.write_guard("encode_into", "growing")?;
+        let mut table = guard.open_table(TableDefinition::<u64, &[u8]>::new("growing"))?;
+        let growing = Growing(std::cell::Cell::new(0));
+        let length = encoded_length(&growing)?;
+        assert_eq!(encode_into(&mut table, &1, &growing, length)?, length + 2);
+        assert_eq!(table.get(1)?.map(|value| rmp_serde::from_slice::<String>(value.value())).transpose()?, Some("xxx".to_string()));
+        Ok(())
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    struct Post {
+        id: u64,
+        slug: String
+    }
+
+    impl Document for Post {
+        type PrimaryKey = u64;
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn id_field() -> String {
+            "id".to_string()
+        }
+
+        fn index_keys() -> Vec<String> {
+            vec!["slug".to_string()]
+        }
+
+        fn index_vals(&self) -> HashMap<String, rmpv::Value> {
+            HashMap::from([("slug".to_string(), self.slug.clone().into())])
+        }
+
+        fn index_spec(_key: &str) -> IndexSpec {
+            IndexSpec::new().unique()
+        }
+    }
+
+    #[test]
+    fn slugs_skip_taken_values() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let posts = db.collection::<Post>("posts");
+        let slugs: Vec<String> = (1..=3)
+            .map(|id| posts.insert_with_slug("slug", "hello", |slug| Post { id, slug }).map(|post| post.slug))
+            .collect::<crate::Result<_>>()?;
+        assert_eq!(slugs, ["hello", "hello-2", "hello-3"]);
+
+        posts.delete(&2)?;
+        let txn = db.writer()?;
+        assert_eq!(posts.unique_slug_in(&txn, "slug", "hello")?, "hello-2");
+        txn.abort()?;
+        assert!(matches!(posts.unique_slug_in(&db.reader()?, "slug", "hello"), Err(Error::ReadOnlyTransaction { .. })));
+        assert!(matches!(posts.unique_slug_in(&db.writer()?, "title", "hello"), Err(Error::UnknownIndex(_))));
+        Ok(())
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    struct Visit {
+        id: u64,
+        user: u64,
+        page: String
+    }
+
+    impl Document for Visit {
+        type PrimaryKey = u64;
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn id_field() -> String {
+            "id".to_string()
+        }
+
+        fn index_keys() -> Vec<String> {
+            vec!["user".to_string(), "page".to_string()]
+        }
+
+        fn index_vals(&self) -> HashMap<String, rmpv::Value> {
+            HashMap::from([("user".to_string(), self.user.into()), ("page".to_string(), self.page.clone().into())])
+        }
+
+        fn index_spec(key: &str) -> IndexSpec {
+            match key {
+                "user" => IndexSpec::new().sketched(),
+                _ => IndexSpec::new()
+            }
+        }
+    }
+
+    #[test]
+    fn sketches_estimate_distinct_index_values() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let visits = db.collection::<Visit>("visits");
+        visits.insert_many((0..4000).map(|id| Visit { id, user: id % 1000, page: format!("/{}", id % 7) }))?;
+        let estimate = visits.approx_distinct("user")?;
+        assert!((950..=1050).contains(&estimate), "estimated {estimate} distinct users");
+        assert!(matches!(visits.approx_distinct("page"), Err(Error::UnsketchedIndex(_))));
+        assert!(matches!(visits.approx_distinct("referrer"), Err(Error::UnknownIndex(_))));
+
+        visits.delete_where(|visit| visit.user >= 10)?;
+        assert_eq!(visits.approx_distinct("user")?, estimate);
+        visits.rebuild_indexes()?;
+        assert!((9..=11).contains(&visits.approx_distinct("user")?));
+        visits.clear()?;
+        assert_eq!(visits.approx_distinct("user")?, 0);
+        Ok(())
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    struct Ticket {
+        id: u64,
+        status: String,
+        archived: bool,
+        owner: u64
+    }
+
+    impl Document for Ticket {
+        type PrimaryKey = u64;
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn id_field() -> String {
+            "id".to_string()
+        }
+
+        fn index_keys() -> Vec<String> {
+            vec!["status".to_string(), "archived".to_string(), "owner".to_string()]
+        }
+
+        fn index_vals(&self) -> HashMap<String, rmpv::Value> {
+            HashMap::from([
+                ("status".to_string(), self.status.clone().into()),
+                ("archived".to_string(), self.archived.into()),
+                ("owner".to_string(), self.owner.into())
+            ])
+        }
+
+        fn index_spec(key: &str) -> IndexSpec {
+            match key {
+                "status" => IndexSpec::new().bitmap(),
+                "archived" => IndexSpec::new().hashed(crate::document::HashWidth::Bits64).bitmap(),
+                _ => IndexSpec::new()
+            }
+        }
+    }
+
+    #[test]
+    fn bitmap_indexes_intersect_conditions() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let tickets = db.collection::<Ticket>("tickets");
+        let statuses = ["open", "closed", "pending"];
+        let ticket = |id: u64| Ticket { id, status: statuses.get(id as usize % 3).unwrap().to_string(), archived: id.is_multiple_of(2), owner: id % 10 };
+        tickets.insert_many((0..3_000).map(ticket))?;
+        let open_active = [("status", rmpv::Value::from("open")), ("archived", false.into())];
+        let expected = |tickets: &Collection<Ticket>| -> crate::Result<Vec<u64>> {
+            Ok(tickets.query().collect()?.into_iter().filter(|ticket| ticket.status == "open" && !ticket.archived).map(|ticket| ticket.id).collect())
+        };
+        let found = |tickets: &Collection<Ticket>| -> crate::Result<Vec<u64>> {
+            Ok(tickets.bitmap_keys(open_active.clone())?.into_vec())
+        };
+        assert_eq!(found(&tickets)?.len(), 500);
+        assert_eq!(found(&tickets)?, expected(&tickets)?);
+        assert_eq!(tickets.bitmap_keys([("archived", true)])?.len(), 1_500);
+        let queried: Vec<u64> = tickets.query().eq("status", "open").eq("archived", false).collect()?.into_iter().map(|ticket| ticket.id).collect();
+        assert_eq!(queried, expected(&tickets)?);
+
+        tickets.upsert(&Ticket { status: "closed".to_string(), ..ticket(3) })?;
+        tickets.upsert(&Ticket { archived: false, ..ticket(6) })?;
+        tickets.delete(&9)?;
+        tickets.delete_where(|ticket| ticket.id >= 2_700)?;
+        assert!(!found(&tickets)?.contains(&3) && found(&tickets)?.contains(&6));
+        assert_eq!(found(&tickets)?, expected(&tickets)?);
+
+        tickets.rebuild_indexes()?;
+        assert_eq!(found(&tickets)?, expected(&tickets)?);
+        tickets.insert(&ticket(5_001))?;
+        assert_eq!(found(&tickets)?, expected(&tickets)?);
+
+        assert!(matches!(tickets.bitmap_keys([("owner", 1)]), Err(Error::UnbitmappedIndex(_))));
+        assert!(matches!(tickets.bitmap_keys([("priority", 1)]), Err(Error::UnknownIndex(_))));
+        assert!(tickets.bitmap_keys([("status", "missing")])?.is_empty());
+
+        let renamed = db.collection::<Ticket>("renamed_tickets");
+        assert!(db.rename_collection::<Ticket>("tickets", "renamed_tickets")?);
+        assert!(found(&tickets)?.is_empty());
+        assert_eq!(found(&renamed)?, expected(&renamed)?);
+        renamed.clear()?;
+        assert!(found(&renamed)?.is_empty());
+        Ok(())
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    struct Session {
+        id: u64,
+        expires_at: u64
+    }
+
+    impl Document for Session {
+        type PrimaryKey = u64;
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn id_field() -> String {
+            "id".to_string()
+        }
+
+        fn index_keys() -> Vec<String> {
+            vec!["expires_at".to_string()]
+        }
+
+        fn index_vals(&self) -> HashMap<String, rmpv::Value> {
+            HashMap::from([("expires_at".to_string(), self.expires_at.into())])
+        }
+
+        fn index_spec(_key: &str) -> IndexSpec {
+            IndexSpec::new().ordered()
+        }
+    }
+
+    #[test]
+    fn sweeps_read_only_expired_index_entries() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let sessions = db.collection::<Session>("sessions");
+        sessions.insert_many((0..10).map(|id| Session { id, expires_at: 1000 + id * 100 }))?;
+
+        let stats = sessions.sweep_expired("expires_at", 1400u64)?;
+        assert_eq!((stats.scanned, stats.purged), (5, 5));
+        assert_eq!(sessions.keys()?.into_iter().collect::<Vec<_>>(), (5..10).collect::<Vec<_>>());
+        let stats = sessions.sweep_expired("expires_at", 1400u64)?;
+        assert_eq!((stats.scanned, stats.purged), (0, 0));
+        let stats = sessions.sweep_expired_chunked("expires_at", 1700u64, 2)?;
+        assert_eq!((stats.scanned, stats.purged), (3, 3));
+        assert_eq!(sessions.keys()?.into_iter().collect::<Vec<_>>(), (8..10).collect::<Vec<_>>());
+        assert!(matches!(sessions.sweep_expired("created_at", 1400u64), Err(Error::UnknownIndex(_))));
+        assert!(matches!(db.collection::<Visit>("visits").sweep_expired("page", "/"), Err(Error::UnorderedIndex(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn ordered_indexes_return_documents_in_value_order() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let items = db.collection::<Item>("items");
+        let inserted = [(1, "pear", 3.5), (2, "apple", -1.0), (3, "fig", 0.0), (4, "apple", 10.25), (5, "kiwi", 2.0)].map(|(id, name, score)| Item::new(id, name, score));
+        items.insert_many(&inserted)?;
+        let ids = |found: Vec<Item>| found.into_iter().map(|item| item.id).collect::<Vec<_>>();
+
+        let by_score = items.find_range::<f64>("score", ..)?;
+        assert_eq!(by_score.iter().map(|item| item.id).collect::<Vec<_>>(), [2, 3, 5, 1, 4]);
+        assert!(by_score.iter().all(|item| inserted.contains(item)));
+        assert_eq!(ids(items.find_range("score", 0.0..3.5)?), [3, 5]);
+        assert_eq!(ids(items.find_range("score", 0.0..=3.5)?), [3, 5, 1]);
+        assert_eq!(ids(items.find_range("name", "b".."m")?), [3, 5]);
+        assert_eq!(ids(items.find_range("name", ..="apple")?), [2, 4]);
+        assert_eq!(items.pop_first_by("score")?.map(|item| item.id), Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn fuzzy_search_tolerates_typos() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let items = db.collection::<Item>("items");
+        let names = ["apple", "apply", "ample", "maple", "banana", "appel", "apple", "café"];
+        items.insert_many(names.iter().zip(1..).map(|(name, id)| Item::new(id, name, 0.0)))?;
+        let ids = |term: &str, max_distance| -> crate::Result<Vec<u64>> { Ok(items.search_fuzzy("name", term, max_distance)?.into_iter().map(|item| item.id).collect()) };
+
+        assert_eq!(ids("apple", 0)?, [1, 7]);
+        assert_eq!(ids("apple", 1)?, [1, 7, 3, 2]);
+        assert_eq!(ids("apple", 2)?, [1, 7, 3, 2, 6, 4]);
+        assert_eq!(ids("cafe", 1)?, [8]);
+        assert!(ids("kiwi", 1)?.is_empty());
+        assert!(matches!(items.search_fuzzy("tags", "red", 1), Err(Error::UnorderedIndex(_))));
+
+        let levenshtein = |a: &str, b: &str| {
+            let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+            let mut row: Vec<usize> = (0..=b.len()).collect();
+            for (i, x) in a.iter().enumerate() {
+                let mut next = vec![i + 1];
+                for (j, y) in b.iter().enumerate() {
+                    let at = |row: &Vec<usize>, j: usize| *row.get(j).unwrap();
+                    next.push((at(&row, j) + usize::from(x != y)).min(at(&row, j + 1) + 1).min(at(&next, j) + 1));
+                }
+                row = next;
+            }
+            *row.last().unwrap()
+        };
+        let mut words = ["", "a", "ab", "abc", "abd", "abcd", "b", "ba", "bab", "cab", "cabbage", "cable", "zz"];
+        words.sort();
+        for term in ["", "ab", "cab", "abcde", "bb"] {
+            for max_distance in 0..4 {
+                let mut distances = EditDistances::new(term, max_distance);
+                for word in words {
+                    let expected = Some(levenshtein(word, term)).filter(|&distance| distance <= max_distance);
+                    assert_eq!(distances.to(word), expected, "{word} {term} {max_distance}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unique_violations_roll_back_the_whole_write() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let items = db.collection::<Item>("items");
+        let with_email = |id, email: &str| Item { email: Some(email.to_string()), ..Item::new(id, "item", 1.0) };
+        items.insert(&with_email(1, "ada@example.com"))?;
+        items.insert(&Item::new(2, "no email", 1.0))?;
+
+        let batch = [with_email(3, "grace@example.com"), Item::new(4, "no email", 1.0), with_email(5, "ada@example.com")];
+        assert!(matches!(items.insert_many(&batch), Err(Error::DuplicateKey { index, .. }) if index == "email"));
+        assert!(matches!(items.upsert(&with_email(2, "ada@example.com")), Err(Error::DuplicateKey { .. })));
+        assert_eq!(items.count()?, 2);
+        assert!(items.find_by("email", "grace@example.com")?.is_empty());
+        assert_eq!(items.get(&2)?, Some(Item::new(2, "no email", 1.0)));
+        assert!(items.check_indexes()?.is_empty());
+
+        items.upsert(&with_email(1, "ada@example.org"))?;
+        items.insert(&with_email(5, "ada@example.com"))?;
+        assert_eq!(items.find_by("email", "ada@example.com")?.into_iter().map(|item| item.id).collect::<Vec<_>>(), [5]);
+        Ok(())
+    }
+
+    #[test]
+    fn push_and_pull_keep_multikey_entries_in_step() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let items = db.collection::<Item>("items");
+        items.insert(&Item { tags: vec!["red".to_string()], ..Item::new(1, "apple", 1.0) })?;
+        items.insert(&Item { tags: vec!["red".to_string(), "green".to_string()], ..Item::new(2, "pear", 1.0) })?;
+        let tagged = |tag: &str| -> crate::Result<Vec<u64>> { Ok(items.find_by("tags", tag)?.into_iter().map(|item| item.id).collect()) };
+
+        assert_eq!(items.push(&1, "tags", "green")?, Some(2));
+        assert_eq!(items.push(&1, "tags", "green")?, Some(3));
+        assert_eq!(tagged("green")?, [1, 2]);
+        assert_eq!(items.pull(&1, "tags", "green")?, Some(2));
+        assert_eq!(tagged("green")?, [2]);
+        assert_eq!(items.pull(&2, "tags", "red")?, Some(1));
+        assert_eq!(tagged("red")?, [1]);
+        assert_eq!(items.pull(&2, "tags", "blue")?, Some(0));
+        assert_eq!(items.push(&9, "tags", "red")?, None);
+        assert!(items.check_indexes()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_writes_by_predicate_and_by_query() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let items = db.collection::<Item>("items");
+        items.insert_many([(1, "a", 1.0), (2, "b", 2.0), (3, "a", 3.0), (4, "c", 4.0), (5, "b", 5.0)].map(|(id, name, score)| Item::new(id, name, score)))?;
+        let named = |name: &str| -> crate::Result<Vec<u64>> { Ok(items.find_by("name", name)?.into_iter().map(|item| item.id).collect()) };
+
+        assert_eq!(items.update_where(|item| item.score >= 4.0, |item| item.name = "a".to_string())?.keys, [4, 5]);
+        assert_eq!(named("a")?, [1, 3, 4, 5]);
+        assert_eq!(items.delete_where(|item| item.name == "b")?.keys, [2]);
+        assert!(items.check_indexes()?.is_empty());
+
+        let txn = db.writer()?;
+        let guard = txn.write_guard("test", items.main_table_name())?;
+        guard.open_table(TableDefinition::<u64, &[u8]>::new(&items.main_table_name()))?.insert(1, [0xc1].as_slice())?;
+        drop(guard);
+        txn.commit()?;
+        assert!(items.delete_where(|_| false).is_err());
+
+        assert_eq!(items.update_matching(&items.query().eq("name", "a").range(4..), |item| item.score *= 10.0)?.keys, [4, 5]);
+        assert_eq!(items.get(&5)?.map(|item| item.score), Some(50.0));
+        assert_eq!(items.find_range::<f64>("score", 40.0..)?.into_iter().map(|item| item.id).collect::<Vec<_>>(), [4, 5]);
+        assert_eq!(items.delete_matching(&items.query().eq("name", "a").range(3..4))?.keys, [3]);
+        assert_eq!(items.keys_matching(&items.query().eq("name", "a"))?.into_iter().collect::<Vec<_>>(), [1, 4, 5]);
+        Ok(())
+    }
+
+    fn visits(db: &Database) -> crate::Result<Collection<Visit>> {
+        let visits = db.collection::<Visit>("visits");
+        visits.insert_many((0..25).map(|id| Visit { id, user: id, page: format!("/{}", id % 3) }))?;
+        Ok(visits)
+    }
+
+    #[test]
+    fn chunked_rebuilds_resume_after_interruption() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let visits = visits(&db)?;
+        let operation = CollectionOperation::new_writer("interrupted", &visits)?;
+        assert_eq!(operation.write_indexes_chunk(Some(IndexKeyFormat::Base64), 10)?, None);
+        operation.commit()?;
+        assert_eq!(visits.count_by_index("page", "/1")?, 3);
+        assert!(meta::maintenance_cursor::<rmpv::Value>(&db.reader()?, "visits", meta::REBUILD_INDEXES)?.is_some());
+
+        assert_eq!(visits.set_index_format_chunked(IndexKeyFormat::Base64, 10)?, 50);
+        assert_eq!(visits.index_format()?, IndexKeyFormat::Base64);
+        assert_eq!(visits.count_by_index("page", "/1")?, 8);
+        assert!((24..=26).contains(&visits.approx_distinct("user")?));
+        assert!(meta::maintenance_cursor::<rmpv::Value>(&db.reader()?, "visits", meta::REBUILD_INDEXES)?.is_none());
+        assert_eq!(visits.set_index_format_chunked(IndexKeyFormat::Base64, 10)?, 0);
+        assert_eq!(visits.rebuild_indexes_chunked(7)?, 50);
+        assert!(visits.check_indexes()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn chunked_checks_report_missing_and_stale_entries() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let visits = visits(&db)?;
+        assert!(visits.check_indexes_chunked(4)?.is_empty());
+
+        let name = visits.index_table_names().remove("page").unwrap();
+        let (missing, stale) = (index_spec::<Visit>("page").stored_key(&"/0".into())?.0, index_spec::<Visit>("page").stored_key(&"/9".into())?.0);
+        let txn = db.writer()?;
+        let guard = txn.write_guard("corrupt", &name)?;
+        {
+            let mut index = guard.open_multimap_table(MultimapTableDefinition::<&[u8], u64>::new(&name))?;
+            index.remove(missing.as_slice(), 3)?;
+            index.insert(stale.as_slice(), 99)?;
+        }
+        drop(guard);
+        txn.commit()?;
+
+        let problems = visits.check_indexes_chunked(4)?;
+        assert_eq!(problems, [format!("missing entry in index page: {}", base64_index_key(&missing)), "1 stale entries in index page".to_string()]);
+        assert!(meta::maintenance_cursor::<rmpv::Value>(&db.reader()?, "visits", meta::CHECK_INDEXES)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn retried_idempotent_inserts_write_once() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let notes = db.collection::<Note>("notes");
+        let note = |id: &str| Note { id: id.to_string(), title: id.to_string() };
+        assert_eq!(notes.insert_idempotent("request-1", &note("a"), Duration::from_secs(60))?, IdempotentInsert::Inserted("a".to_string()));
+        assert_eq!(notes.insert_idempotent("request-1", &note("b"), Duration::from_secs(60))?, IdempotentInsert::Duplicate("a".to_string()));
+        assert_eq!(notes.count()?, 1);
+
+        notes.insert_idempotent("request-2", &note("c"), Duration::from_millis(1))?;
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(notes.insert_idempotent("request-2", &note("d"), Duration::from_secs(60))?, IdempotentInsert::Inserted("d".to_string()));
+        assert!(matches!(notes.insert_idempotent("request-3", &note("a"), Duration::from_secs(60)), Err(Error::DocumentExists { .. })));
+
+        notes.clear()?;
+        assert_eq!(notes.insert_idempotent("request-1", &note("a"), Duration::from_secs(60))?, IdempotentInsert::Inserted("a".to_string()));
+        Ok(())
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    struct Import {
+        id: String,
+        tenant_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        source: Option<String>
+    }
+
+    impl Document for Import {
+        type PrimaryKey = String;
+
+        fn id(&self) -> String {
+            self.id.clone()
+        }
+
+        fn id_field() -> String {
+            "id".to_string()
+        }
+
+        fn index_keys() -> Vec<String> {
+            vec!["tenant_id".to_string()]
+        }
+
+        fn index_vals(&self) -> HashMap<String, rmpv::Value> {
+            HashMap::from([("tenant_id".to_string(), self.tenant_id.clone().map_or(rmpv::Value::Nil, Into::into))])
+        }
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    struct Contact {
+        id: u64,
+        name: String,
+        aliases: Vec<String>
+    }
+
+    impl Document for Contact {
+        type PrimaryKey = u64;
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn id_field() -> String {
+            "id".to_string()
+        }
+
+        fn index_keys() -> Vec<String> {
+            vec!["name".to_string(), "aliases".to_string()]
+        }
+
+        fn index_vals(&self) -> HashMap<String, rmpv::Value> {
+            HashMap::from([
+                ("name".to_string(), self.name.clone().into()),
+                ("aliases".to_string(), rmpv::Value::Array(self.aliases.iter().map(|alias| alias.as_str().into()).collect()))
+            ])
+        }
+
+        fn index_spec(key: &str) -> IndexSpec {
+            match key {
+                "name" => IndexSpec::new().phonetic(),
+                _ => IndexSpec::new().phonetic().multikey().hashed(crate::document::HashWidth::Bits64)
+            }
+        }
+    }
+
+    #[test]
+    fn phonetic_indexes_find_names_that_sound_alike() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let contacts = db.collection::<Contact>("contacts");
+        let contact = |id, name: &str, aliases: &[&str]| Contact { id, name: name.to_string(), aliases: aliases.iter().map(|alias| alias.to_string()).collect() };
+        contacts.insert_many([contact(1, "Robert", &["Bob"]), contact(2, "Rupert", &[]), contact(3, "Ruben", &["Rob", "Bobby"])])?;
+        let named = |key: &str, name: &str| -> crate::Result<Vec<u64>> { Ok(contacts.find_by(key, name)?.into_iter().map(|contact| contact.id).collect()) };
+
+        assert_eq!(named("name", "Rubert")?, [1, 2]);
+        assert_eq!(named("name", "Rubin")?, [3]);
+        assert_eq!(named("aliases", "Bop")?, [1, 3]);
+        assert_eq!(named("aliases", "Rab")?, [3]);
+        contacts.upsert(&contact(2, "Reuben", &[]))?;
+        assert_eq!(named("name", "Rubin")?, [2, 3]);
+        assert!(contacts.check_indexes()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn panicking_callbacks_roll_back_their_transaction() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let items = db.collection::<Item>("items");
+        items.insert_many([Item::new(1, "a", 1.0), Item::new(2, "b", 2.0)])?;
+        let panicked = |result: crate::Result<_>, expected: &str| matches!(result, Err(Error::HookPanicked { hook, message }) if hook == expected && message == "boom");
+
+        assert!(panicked(items.modify(&1, |_| panic!("boom")).map(|_| ()), "modify"));
+        assert!(panicked(items.update_if(&1, |_| panic!("boom"), &Item::new(1, "z", 0.0)).map(|_| ()), "update_if"));
+        let mut seen = 0;
+        let result = items.update_where(|_| true, |item| {
+            seen += 1;
+            assert!(seen < 2, "boom");
+            item.score = 0.0;
+        });
+        assert!(panicked(result.map(|_| ()), "update_where"));
+        assert!(panicked(items.delete_where(|item| item.id == 2 && panic!("boom")).map(|_| ()), "delete_where"));
+        assert_eq!(items.find_by("score", 0.0)?, []);
+        assert_eq!(items.count()?, 2);
+
+        let txn = db.writer()?;
+        items.insert_in(&txn, &Item::new(3, "c", 3.0))?;
+        assert!(panicked(items.modify_in(&txn, &1, |_| panic!("boom")).map(|_| ()), "modify"));
+        assert!(panicked(txn.commit(), "modify"));
+        assert_eq!(items.get(&3)?, None);
+
+        let defaulted = db.collection::<Item>("items").with_default("email", || panic!("boom"));
+        assert!(panicked(defaulted.insert(&Item::new(4, "d", 4.0)).map(|_| ()), "with_default"));
+        items.insert(&Item::new(4, "d", 4.0))?;
+        assert_eq!(items.count()?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn defaults_fill_unset_fields_on_insert() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let imports = db.collection::<Import>("imports").with_default("tenant_id", || "acme".into()).with_default("source", || "import".into());
+        let import = |id: &str, tenant_id: Option<&str>| Import { id: id.to_string(), tenant_id: tenant_id.map(str::to_string), source: None };
+        imports.insert(&import("a", None))?;
+        imports.insert_many([import("b", Some("globex"))])?;
+
+        let a = imports.get(&"a".to_string())?.unwrap();
+        assert_eq!((a.tenant_id.as_deref(), a.source.as_deref()), (Some("acme"), Some("import")));
+        assert_eq!(imports.get(&"b".to_string())?.unwrap().tenant_id.as_deref(), Some("globex"));
+        assert_eq!(imports.keys_where("tenant_id", "acme")?.into_vec(), ["a"]);
+
+        db.collection::<Import>("imports").insert(&import("c", None))?;
+        assert_eq!(imports.get(&"c".to_string())?.unwrap().tenant_id, None);
+        Ok(())
+    }
+
+    #[test]
+    fn registered_defaults_reach_every_handle() -> crate::Result<()> {
+        crate::collection!(static IMPORTS: Import = "registered_imports");
+        let db = Database::open_in_memory()?;
+        let import = |id: &str| Import { id: id.to_string(), tenant_id: None, source: None };
+        let tenant = |imports: &Collection<Import>, id: &str| -> crate::Result<Option<String>> {
+            Ok(imports.get(&id.to_string())?.and_then(|import| import.tenant_id))
+        };
+        let local = db.collection::<Import>("registered_imports").with_default("source", || "local".into());
+        IMPORTS.register(&db)?.with_default("tenant_id", || "acme".into());
+
+        db.get_collection::<Import>()?.insert(&import("a"))?;
+        IMPORTS.open(&db).insert(&import("b"))?;
+        local.insert(&import("c"))?;
+        let imports = IMPORTS.open(&db);
+        assert_eq!((tenant(&imports, "a")?, tenant(&imports, "b")?, tenant(&imports, "c")?), (Some("acme".to_string()), Some("acme".to_string()), Some("acme".to_string())));
+        assert_eq!(imports.get(&"c".to_string())?.unwrap().source.as_deref(), Some("local"));
+        assert_eq!(imports.get(&"a".to_string())?.unwrap().source, None);
+
+        assert!(db.rename_collection::<Import>("registered_imports", "renamed_imports")?);
+        let renamed = db.get_collection::<Import>()?;
+        renamed.insert(&import("d"))?;
+        assert_eq!(tenant(&renamed, "d")?, Some("acme".to_string()));
+        db.collection::<Import>("registered_imports").insert(&import("e"))?;
+        assert_eq!(tenant(&db.collection::<Import>("registered_imports"), "e")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn paths_read_single_fields() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let notes = db.collection::<Note>("notes");
+        notes.insert(&Note { id: "a".to_string(), title: "first".to_string() })?;
+        assert_eq!(notes.get_path(&"a".to_string(), "/title")?, Some("first".into()));
+        assert_eq!(notes.get_path(&"a".to_string(), "/body")?, None);
+        assert_eq!(notes.get_path(&"b".to_string(), "/title")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn key_filters_skip_missing_keys_only() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let notes = db.collection::<Note>("notes");
+        let note = |id: &str| Note { id: id.to_string(), title: id.to_string() };
+        notes.insert(&note("a"))?;
+        let earlier = db.reader()?;
+        notes.enable_key_filter(100, 0.01)?;
+        assert!(notes.key_filter_size()?.is_some());
+        let excluded = |id: &str, txn: &Transaction| db.key_filters.excludes("notes", txn, String::as_bytes(&id.to_string()).as_ref());
+        assert!(excluded("missing", &db.reader()?)? && !excluded("a", &db.reader()?)?);
+        assert!(!excluded("missing", &earlier)?);
+        drop(earlier);
+
+        notes.insert(&note("b"))?;
+        assert_eq!(notes.get(&"b".to_string())?, Some(note("b")));
+        let txn = db.writer()?;
+        notes.insert_in(&txn, &note("c"))?;
+        assert!(notes.contains_in(&txn, &"c".to_string())?);
+        txn.abort()?;
+        assert!(!notes.contains(&"c".to_string())?);
+
+        notes.delete(&"a".to_string())?;
+        assert!(!excluded("a", &db.reader()?)?);
+        db.compact()?;
+        assert!(excluded("a", &db.reader()?)?);
+
+        assert!(db.rename_collection::<Note>("notes", "moved")?);
+        assert_eq!(db.collection::<Note>("moved").get(&"b".to_string())?, Some(note("b")));
+        assert!(notes.disable_key_filter()?);
+        Ok(())
+    }
+
+    fn lock_rows(notes: &Collection<Note>) -> crate::Result<u64> {
+        let txn = notes.database().begin_read("test", "locks")?;
+        let name = lock_table_name(&notes.name());
+        with_table!(&txn, TableDefinition::<String, &[u8]>::new(&name), table => Ok(table.len()?), Ok(0))
+    }
+
+    #[test]
+    fn expired_locks_are_purged() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let notes = db.collection::<Note>("notes");
+        for id in ["a", "b", "c"] {
+            notes.lock(&id.to_string(), "alice", Duration::from_millis(1))?;
+        }
+        std::thread::sleep(Duration::from_millis(5));
+        notes.lock(&"d".to_string(), "bob", Duration::from_secs(60))?;
+        assert_eq!(lock_rows(&notes)?, 1);
+
+        assert!(matches!(notes.lock(&"d".to_string(), "alice", Duration::from_secs(60)), Err(Error::DocumentLocked { .. })));
+        notes.clear()?;
+        assert_eq!(lock_rows(&notes)?, 0);
+        assert_eq!(notes.lock_info(&"d".to_string())?, None);
+        Ok(())
+    }
+}
@@ -1,12 +1,35 @@
-use std::{collections::HashMap, fmt::Debug};
+use std::{borrow::Cow, collections::HashMap, fmt::Debug, sync::Arc};
 
-use redb::TypeName;
+use redb::{TypeName, Value};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use base64::prelude::*;
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Id(uuid::Uuid);
 
+impl Id {
+    /// A new random (v4) id.
+    pub fn new() -> Self {
+        Self(uuid::Uuid::new_v4())
+    }
+
+    pub fn uuid(&self) -> uuid::Uuid {
+        self.0
+    }
+}
+
+impl Default for Id {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<uuid::Uuid> for Id {
+    fn from(value: uuid::Uuid) -> Self {
+        Self(value)
+    }
+}
+
 impl redb::Value for Id {
     type SelfType<'a> = Id;
     type AsBytes<'a> = [u8; 16];
@@ -38,23 +61,796 @@ impl redb::Key for Id {
     }
 }
 
-pub trait Document: Serialize + DeserializeOwned + Clone + Debug {
-    type PrimaryKey: redb::Key + Serialize + DeserializeOwned;
+/// A redb key type that reads back as itself, so owned keys can be passed straight to tables.
+pub trait OwnedKey: redb::Key + for<'a> redb::Value<SelfType<'a> = Self> + Clone + Debug + 'static {}
+
+impl<K> OwnedKey for K where K: redb::Key + for<'a> redb::Value<SelfType<'a> = K> + Clone + Debug + 'static {}
+
+/// Decodes key bytes that didn't come from a table (such as a query cursor), returning `None` where redb's
+/// own decoding would panic. Common key types are checked up front; any other type is decoded behind
+/// [std::panic::catch_unwind] and must re-encode to the same bytes.
+pub(crate) fn decode_key<K: OwnedKey>(bytes: &[u8]) -> Option<K> {
+    if K::fixed_width().is_some_and(|width| width != bytes.len()) {
+        return None;
+    }
+    let name = K::type_name();
+    let valid = if name == String::type_name() || name == <&str>::type_name() {
+        std::str::from_utf8(bytes).is_ok()
+    } else if name == char::type_name() {
+        matches!(bytes, [a, b, c] if char::from_u32(u32::from_le_bytes([*a, *b, *c, 0])).is_some())
+    } else {
+        true
+    };
+    if !valid {
+        return None;
+    }
+    std::panic::catch_unwind(|| {
+        let key = K::from_bytes(bytes);
+        (K::as_bytes(&key).as_ref() == bytes && K::compare(bytes, bytes).is_eq()).then_some(key)
+    })
+    .ok()
+    .flatten()
+}
+
+/// Encodes an index value as the raw msgpack bytes stored as its index table key.
+pub fn encode_index_value(value: &rmpv::Value) -> crate::Result<Vec<u8>> {
+    let mut writer = Vec::<u8>::new();
+    rmpv::encode::write_value(&mut writer, value).map_err(rmp_serde::encode::Error::InvalidValueWrite)?;
+    Ok(writer)
+}
+
+/// The base64 form of [encode_index_value], as stored by index tables written before format version 2.
+pub fn serialize_index_value(value: &rmpv::Value) -> crate::Result<String> {
+    Ok(BASE64_URL_SAFE_NO_PAD.encode(encode_index_value(value)?))
+}
+
+/// Encodes an index value so that byte order matches value order, as stored by [IndexSpec::ordered] indexes.
+///
+/// Values sort by type first (nil, booleans, integers, floats, strings, binary, arrays, then maps and
+/// extensions), then by value within the type. Integers and floats are not ordered relative to each other.
+pub fn encode_ordered_value(value: &rmpv::Value) -> crate::Result<Vec<u8>> {
+    let mut writer = Vec::new();
+    write_ordered(&mut writer, value)?;
+    Ok(writer)
+}
+
+fn write_ordered(writer: &mut Vec<u8>, value: &rmpv::Value) -> crate::Result<()> {
+    // Strings and binary escape zero bytes as 0x00 0xff and end with 0x00 0x00, so a shorter value
+    // sorts before any value it is a prefix of, also inside arrays.
+    let escaped = |writer: &mut Vec<u8>, bytes: &[u8]| {
+        for byte in bytes {
+            writer.push(*byte);
+            if *byte == 0 {
+                writer.push(0xff);
+            }
+        }
+        writer.extend_from_slice(&[0, 0]);
+    };
+    match value {
+        rmpv::Value::Nil => writer.push(0x01),
+        rmpv::Value::Boolean(value) => writer.push(0x02 + u8::from(*value)),
+        rmpv::Value::Integer(value) => {
+            let value = value.as_u64().map(i128::from).or(value.as_i64().map(i128::from)).unwrap_or_default();
+            writer.push(0x10);
+            writer.extend_from_slice(&((value as u128) ^ (1 << 127)).to_be_bytes());
+        },
+        rmpv::Value::F32(_) | rmpv::Value::F64(_) => {
+            let bits = value.as_f64().unwrap_or_default().to_bits();
+            writer.push(0x11);
+            writer.extend_from_slice(&(if bits >> 63 == 1 { !bits } else { bits | (1 << 63) }).to_be_bytes());
+        },
+        rmpv::Value::String(value) => {
+            writer.push(0x20);
+            escaped(writer, value.as_bytes());
+        },
+        rmpv::Value::Binary(value) => {
+            writer.push(0x21);
+            escaped(writer, value);
+        },
+        rmpv::Value::Array(values) => {
+            writer.push(0x30);
+            for value in values {
+                write_ordered(writer, value)?;
+            }
+            writer.push(0x00);
+        },
+        rmpv::Value::Map(_) | rmpv::Value::Ext(..) => {
+            writer.push(0x40);
+            writer.extend_from_slice(&encode_index_value(value)?);
+        }
+    }
+    Ok(())
+}
+
+/// The string held by an [encode_ordered_value] key, or `None` if it holds another type.
+pub(crate) fn decode_ordered_string(encoded: &[u8]) -> Option<String> {
+    let (&0x20, rest) = encoded.split_first()? else {
+        return None;
+    };
+    let (mut bytes, mut rest) = (Vec::new(), rest.iter());
+    while let Some(&byte) = rest.next() {
+        if byte != 0 {
+            bytes.push(byte);
+            continue;
+        }
+        match rest.next()? {
+            0xff => bytes.push(0),
+            0 => return String::from_utf8(bytes).ok(),
+            _ => return None
+        }
+    }
+    None
+}
+
+pub(crate) fn base64_index_key(encoded: &[u8]) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(encoded)
+}
+
+/// 64-bit FNV-1a, used where index keys need a hash that stays stable across builds and platforms.
+/// The four-character American Soundex code of `text`, e.g. `R163` for both "Robert" and "Rupert", as stored
+/// by [IndexSpec::phonetic] indexes. Characters other than ASCII letters are ignored; text without any letters
+/// codes as an empty string.
+pub fn soundex(text: &str) -> String {
+    let digit = |letter: char| match letter {
+        'B' | 'F' | 'P' | 'V' => Some(b'1'),
+        'C' | 'G' | 'J' | 'K' | 'Q' | 'S' | 'X' | 'Z' => Some(b'2'),
+        'D' | 'T' => Some(b'3'),
+        'L' => Some(b'4'),
+        'M' | 'N' => Some(b'5'),
+        'R' => Some(b'6'),
+        _ => None
+    };
+    let mut letters = text.chars().filter(char::is_ascii_alphabetic).map(|letter| letter.to_ascii_uppercase());
+    let Some(first) = letters.next() else {
+        return String::new();
+    };
+    let (mut code, mut last) = (vec![first as u8], digit(first));
+    for letter in letters {
+        let current = digit(letter);
+        if current.is_some() && current != last {
+            code.extend(current);
+        }
+        // H and W don't separate two letters with the same code; vowels do.
+        if !matches!(letter, 'H' | 'W') {
+            last = current;
+        }
+        if code.len() == 4 {
+            break;
+        }
+    }
+    code.resize(4, b'0');
+    String::from_utf8_lossy(&code).into_owned()
+}
+
+pub(crate) fn fnv1a_64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3))
+}
+
+/// 128-bit FNV-1a, the wide variant of [fnv1a_64].
+pub(crate) fn fnv1a_128(bytes: &[u8]) -> u128 {
+    bytes.iter().fold(0x6c62_272e_07bb_0142_62b8_2175_6295_c58d, |hash, byte| (hash ^ u128::from(*byte)).wrapping_mul(0x0000_0000_0100_0000_0000_0000_0000_013b))
+}
+
+/// The splitmix64 finalizer, spreading FNV's weak low bits across the whole word for bloom filters and sketches.
+pub(crate) fn mix64(mut value: u64) -> u64 {
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
+
+/// Width of the hash stored by a hashed index.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HashWidth {
+    Bits64,
+    Bits128
+}
+
+/// A hash function for hashed indexes, see [IndexSpec::hashed_with]. The output must be stable across runs
+/// and builds, since it is stored in the index tables.
+pub trait IndexHasher: Debug + Send + Sync {
+    fn hash(&self, encoded: &[u8]) -> Vec<u8>;
+}
+
+/// Keyed SipHash-2-4, for hashed indexes over values an attacker might choose to force collisions.
+#[derive(Clone)]
+pub struct KeyedHasher {
+    k0: u64,
+    k1: u64
+}
+
+impl Debug for KeyedHasher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyedHasher").finish_non_exhaustive()
+    }
+}
+
+impl KeyedHasher {
+    pub fn new(key: [u8; 16]) -> Self {
+        let (k0, k1) = key.split_at(8);
+        Self {
+            k0: u64::from_le_bytes(k0.try_into().unwrap_or_default()),
+            k1: u64::from_le_bytes(k1.try_into().unwrap_or_default())
+        }
+    }
+
+    fn siphash(&self, bytes: &[u8]) -> u64 {
+        let mut v = [self.k0 ^ 0x736f_6d65_7073_6575, self.k1 ^ 0x646f_7261_6e64_6f6d, self.k0 ^ 0x6c79_6765_6e65_7261, self.k1 ^ 0x7465_6462_7974_6573];
+        let round = |[mut v0, mut v1, mut v2, mut v3]: [u64; 4]| {
+            v0 = v0.wrapping_add(v1);
+            v1 = v1.rotate_left(13) ^ v0;
+            v0 = v0.rotate_left(32);
+            v2 = v2.wrapping_add(v3);
+            v3 = v3.rotate_left(16) ^ v2;
+            v0 = v0.wrapping_add(v3);
+            v3 = v3.rotate_left(21) ^ v0;
+            v2 = v2.wrapping_add(v1);
+            v1 = v1.rotate_left(17) ^ v2;
+            v2 = v2.rotate_left(32);
+            [v0, v1, v2, v3]
+        };
+        let mut compress = |block: u64| {
+            v[3] ^= block;
+            v = round(round(v));
+            v[0] ^= block;
+        };
+
+        let chunks = bytes.chunks_exact(8);
+        let mut last = [0u8; 8];
+        for (slot, byte) in last.iter_mut().zip(chunks.remainder()) {
+            *slot = *byte;
+        }
+        for chunk in chunks {
+            compress(u64::from_le_bytes(chunk.try_into().unwrap_or_default()));
+        }
+        compress(u64::from_le_bytes(last) | ((bytes.len() as u64) << 56));
+
+        v[2] ^= 0xff;
+        v = round(round(round(round(v))));
+        v[0] ^ v[1] ^ v[2] ^ v[3]
+    }
+}
+
+impl IndexHasher for KeyedHasher {
+    fn hash(&self, encoded: &[u8]) -> Vec<u8> {
+        self.siphash(encoded).to_be_bytes().to_vec()
+    }
+}
+
+/// How a single index stores its values, returned by [Document::index_spec].
+#[derive(Clone, Debug, Default)]
+pub struct IndexSpec {
+    /// Encoded values longer than this many bytes are stored as their leading bytes plus a hash of the whole
+    /// value, so long strings like URLs don't bloat the index table. Lookups verify candidates against the
+    /// stored documents.
+    pub truncate: Option<usize>,
+    /// Store only a fixed-width hash of each value. Hashed indexes are much smaller and cheaper to write, but
+    /// only support equality lookups, which verify candidates against the stored documents.
+    pub hashed: Option<HashWidth>,
+    /// Replaces the built-in FNV-1a hash of a hashed index, e.g. with a [KeyedHasher].
+    pub hasher: Option<Arc<dyn IndexHasher>>,
+    /// Store values with [encode_ordered_value] so the index can be range-scanned with
+    /// [crate::database::Collection::find_range]. Ordered values are never truncated.
+    pub ordered: bool,
+    /// Reject writes that would store a value already held by another document, see [crate::Error::DuplicateKey].
+    pub unique: bool,
+    /// Leave documents whose value is nil out of the index, e.g. an `Option` field that is usually unset.
+    pub sparse: bool,
+    /// Index every element of an array value as its own entry, so a `tags: Vec<String>` field can be looked up
+    /// by any one tag. Non-array values are indexed as usual.
+    pub multikey: bool,
+    /// Keep a [HyperLogLog](crate::sketch::HyperLogLog) sketch of the index's values beside it, so
+    /// [crate::database::Collection::approx_distinct] answers without reading the index.
+    pub sketch: bool,
+    /// Store string values by their [soundex] code, so lookups such as
+    /// [crate::database::Collection::find_by] match every value that sounds alike ("Rupert" finds "Robert").
+    /// Phonetic indexes aren't ordered.
+    pub phonetic: bool,
+    /// Also keep a [Bitmap](crate::bitmap::Bitmap) of the documents holding each value, for low-cardinality
+    /// fields such as statuses and flags, so [crate::database::Collection::bitmap_keys] intersects several
+    /// conditions without reading the index tables or documents.
+    pub bitmap: bool
+}
+
+impl IndexSpec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn truncate(mut self, max_bytes: usize) -> Self {
+        self.truncate = Some(max_bytes);
+        self
+    }
+
+    pub fn hashed(mut self, width: HashWidth) -> Self {
+        self.hashed = Some(width);
+        self
+    }
+
+    /// Hashes values with `hasher` instead of FNV-1a. Candidates are still verified against their documents,
+    /// so collisions only cost extra reads.
+    pub fn hashed_with(mut self, hasher: impl IndexHasher + 'static) -> Self {
+        self.hasher = Some(Arc::new(hasher));
+        self
+    }
+
+    pub fn ordered(mut self) -> Self {
+        self.ordered = true;
+        self
+    }
+
+    pub fn unique(mut self) -> Self {
+        self.unique = true;
+        self
+    }
+
+    pub fn sparse(mut self) -> Self {
+        self.sparse = true;
+        self
+    }
+
+    pub fn multikey(mut self) -> Self {
+        self.multikey = true;
+        self
+    }
+
+    pub fn sketched(mut self) -> Self {
+        self.sketch = true;
+        self
+    }
+
+    pub fn phonetic(mut self) -> Self {
+        self.phonetic = true;
+        self
+    }
+
+    pub fn bitmap(mut self) -> Self {
+        self.bitmap = true;
+        self
+    }
+
+    /// `value` as this index compares it: its [soundex] code for a [IndexSpec::phonetic] index's strings.
+    pub(crate) fn compared<'a>(&self, value: &'a rmpv::Value) -> Cow<'a, rmpv::Value> {
+        match value.as_str() {
+            Some(text) if self.phonetic => Cow::Owned(soundex(text).into()),
+            _ => Cow::Borrowed(value)
+        }
+    }
+
+    /// The values that get an index entry for one document's `value`.
+    pub(crate) fn entry_values(&self, value: rmpv::Value) -> Vec<rmpv::Value> {
+        match value {
+            rmpv::Value::Array(values) if self.multikey => values,
+            value => vec![value]
+        }
+    }
+
+    /// Whether stored keys sort in value order. Hashing and [IndexSpec::phonetic] take precedence over
+    /// [IndexSpec::ordered].
+    pub fn is_ordered(&self) -> bool {
+        self.ordered && !self.phonetic && self.hashed.is_none() && self.hasher.is_none()
+    }
+
+    /// The index table key for `value`, and whether matches on it must be verified against documents.
+    pub(crate) fn stored_key(&self, value: &rmpv::Value) -> crate::Result<(Vec<u8>, bool)> {
+        let value = self.compared(value);
+        let value = value.as_ref();
+        if self.is_ordered() {
+            return Ok((encode_ordered_value(value)?, false));
+        }
+        let encoded = encode_index_value(value)?;
+        if let Some(hasher) = &self.hasher {
+            return Ok((hasher.hash(&encoded), true));
+        }
+        match self.hashed {
+            Some(HashWidth::Bits64) => return Ok((fnv1a_64(&encoded).to_be_bytes().to_vec(), true)),
+            Some(HashWidth::Bits128) => return Ok((fnv1a_128(&encoded).to_be_bytes().to_vec(), true)),
+            None => {}
+        }
+        Ok(match self.truncate {
+            Some(max) if encoded.len() > max => {
+                let mut stored = encoded.get(..max).unwrap_or(&encoded).to_vec();
+                stored.extend_from_slice(&fnv1a_64(&encoded).to_be_bytes());
+                (stored, true)
+            },
+            _ => (encoded, false)
+        })
+    }
+}
+
+/// How a collection's index tables store index values as keys. [IndexKeyFormat::Raw] is smaller and
+/// avoids an encoding step; [IndexKeyFormat::Base64] is kept for collections indexed by older versions.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexKeyFormat {
+    #[default]
+    Raw,
+    Base64
+}
+
+pub trait Document: Serialize + DeserializeOwned + Debug {
+    type PrimaryKey: OwnedKey + Serialize + DeserializeOwned;
 
     fn id(&self) -> Self::PrimaryKey;
     fn id_field() -> String;
     fn index_keys() -> Vec<String>;
+    /// The value of each index for this document. Keys left out get no entry in their index table, so a
+    /// document can opt out of an index; see also [IndexSpec::sparse].
     fn index_vals(&self) -> HashMap<String, rmpv::Value>;
 
-    fn serialized_indices(&self) -> HashMap<String, String> {
+    /// Storage options for index `key`. By default every value is stored in full.
+    fn index_spec(_key: &str) -> IndexSpec {
+        IndexSpec::default()
+    }
+
+    /// Indexes on fields of the serialized document, named by their dot path such as `"address.city"`. Array
+    /// elements are addressed by position, e.g. `"phones.0"`. Documents with no value at the path aren't indexed.
+    fn path_indexes() -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Compound indexes as `(name, fields)`, e.g. `("tenant_created", ["tenant_id", "created_at"])`. Each stores
+    /// the listed fields (dot paths as in [Document::path_indexes]) as one array, always with [IndexSpec::ordered],
+    /// so [crate::database::Collection::find_prefix] can match the leading fields.
+    fn compound_indexes() -> Vec<(String, Vec<String>)> {
+        Vec::new()
+    }
+
+    fn encoded_indices(&self) -> crate::Result<HashMap<String, Vec<u8>>> {
+        let mut result = HashMap::new();
+
+        for (key, val) in index_values(self)? {
+            result.insert(key, encode_index_value(&val)?);
+        }
+
+        Ok(result)
+    }
+
+    fn serialized_indices(&self) -> crate::Result<HashMap<String, String>> {
         let mut result = HashMap::new();
 
-        for (key, val) in self.index_vals() {
-            let mut writer = Vec::<u8>::new();
-            rmpv::encode::write_value(&mut writer, &val).unwrap();
-            result.insert(key, BASE64_URL_SAFE_NO_PAD.encode(writer.as_slice()));
+        for (key, val) in index_values(self)? {
+            result.insert(key, serialize_index_value(&val)?);
+        }
+
+        Ok(result)
+    }
+}
+
+/// Every index of `T`, single-field and compound.
+pub(crate) fn index_names<T: Document>() -> Vec<String> {
+    let mut names = T::index_keys();
+    names.extend(T::path_indexes());
+    names.extend(T::compound_indexes().into_iter().map(|(name, _)| name));
+    names
+}
+
+pub(crate) fn index_spec<T: Document>(key: &str) -> IndexSpec {
+    match T::compound_indexes().iter().any(|(name, _)| name == key) {
+        true => IndexSpec::new().ordered(),
+        false => T::index_spec(key)
+    }
+}
+
+/// [Document::index_vals] plus the value of every path and compound index.
+pub(crate) fn index_values<T: Document>(document: &T) -> crate::Result<HashMap<String, rmpv::Value>> {
+    let mut values = document.index_vals();
+    let (paths, compound) = (T::path_indexes(), T::compound_indexes());
+    if !paths.is_empty() || !compound.is_empty() {
+        let root = named_value(document)?;
+        for path in paths {
+            if let Some(value) = value_at(&root, &path) {
+                values.insert(path, value.clone());
+            }
+        }
+        for (name, fields) in compound {
+            values.insert(name, rmpv::Value::Array(fields.iter().map(|field| value_at(&root, field).cloned().unwrap_or(rmpv::Value::Nil)).collect()));
         }
+    }
+    values.retain(|key, value| !value.is_nil() || !index_spec::<T>(key).sparse);
+    Ok(values)
+}
+
+/// `value` as stored, with structs as maps keyed by field name, for walking with [value_at].
+pub fn named_value<V: Serialize>(value: &V) -> crate::Result<rmpv::Value> {
+    Ok(rmp_serde::from_slice(&rmp_serde::to_vec_named(value)?)?)
+}
+
+pub(crate) fn value_at_mut<'a>(value: &'a mut rmpv::Value, path: &str) -> Option<&'a mut rmpv::Value> {
+    path.split('.').try_fold(value, |value, segment| match value {
+        rmpv::Value::Map(entries) => entries.iter_mut().find(|(key, _)| key.as_str() == Some(segment)).map(|(_, value)| value),
+        rmpv::Value::Array(values) => values.get_mut(segment.parse::<usize>().ok()?),
+        _ => None
+    })
+}
 
-        result
+/// Sets dot path `path` inside `value` to `default()` if it's nil, or absent from a map that exists. Returns
+/// whether it was set.
+pub(crate) fn fill_missing(value: &mut rmpv::Value, path: &str, default: impl FnOnce() -> rmpv::Value) -> bool {
+    let (parent, field) = match path.rsplit_once('.') {
+        Some((parent, field)) => (value_at_mut(value, parent), field),
+        None => (Some(value), path)
+    };
+    let Some(rmpv::Value::Map(entries)) = parent else {
+        return false;
+    };
+    match entries.iter_mut().find(|(key, _)| key.as_str() == Some(field)) {
+        Some((_, existing)) if existing.is_nil() => *existing = default(),
+        Some(_) => return false,
+        None => entries.push((field.into(), default()))
+    }
+    true
+}
+
+/// The value at dot path `path` inside `value`, e.g. `"address.city"` or `"phones.0.number"`.
+pub fn value_at<'a>(value: &'a rmpv::Value, path: &str) -> Option<&'a rmpv::Value> {
+    path.split('.').try_fold(value, |value, segment| match value {
+        rmpv::Value::Map(entries) => entries.iter().find(|(key, _)| key.as_str() == Some(segment)).map(|(_, value)| value),
+        rmpv::Value::Array(values) => values.get(segment.parse::<usize>().ok()?),
+        _ => None
+    })
+}
+
+/// The value at JSON pointer `pointer` (such as `"/profile/email"` or `"/phones/0"`) inside the msgpack
+/// document `encoded`. Only that value is decoded: every map key on the way is compared in place and every
+/// other entry is skipped over token by token. `None` if nothing is stored at the pointer.
+pub fn read_pointer(encoded: &[u8], pointer: &str) -> crate::Result<Option<rmpv::Value>> {
+    let segments: Vec<String> = match pointer.strip_prefix('/') {
+        Some(rest) => rest.split('/').map(|segment| segment.replace("~1", "/").replace("~0", "~")).collect(),
+        None if pointer.is_empty() => Vec::new(),
+        None => return Err(crate::Error::InvalidPointer(pointer.to_string()))
+    };
+    let mut input = encoded;
+    for segment in &segments {
+        let mut peek = input;
+        let found = match rmp::decode::read_marker(&mut peek).map_err(|_| malformed())? {
+            rmp::Marker::FixMap(_) | rmp::Marker::Map16 | rmp::Marker::Map32 => {
+                let entries = rmp::decode::read_map_len(&mut input).map_err(|_| malformed())?;
+                let mut found = false;
+                for _ in 0..entries {
+                    if matches!(read_str(&mut input)?, Some(key) if key == segment.as_bytes()) {
+                        found = true;
+                        break;
+                    }
+                    skip_value(&mut input)?;
+                }
+                found
+            },
+            rmp::Marker::FixArray(_) | rmp::Marker::Array16 | rmp::Marker::Array32 => {
+                let length = rmp::decode::read_array_len(&mut input).map_err(|_| malformed())?;
+                match segment.parse::<u32>() {
+                    Ok(position) if position < length => {
+                        for _ in 0..position {
+                            skip_value(&mut input)?;
+                        }
+                        true
+                    },
+                    _ => false
+                }
+            },
+            _ => false
+        };
+        if !found {
+            return Ok(None);
+        }
+    }
+    Ok(Some(rmpv::decode::read_value(&mut input).map_err(|e| crate::Error::Decode(rmp_serde::decode::Error::Syntax(e.to_string())))?))
+}
+
+fn malformed() -> crate::Error {
+    crate::Error::Decode(rmp_serde::decode::Error::Syntax("malformed msgpack document".to_string()))
+}
+
+fn take<'a>(input: &mut &'a [u8], length: usize) -> crate::Result<&'a [u8]> {
+    let (taken, rest) = input.split_at_checked(length).ok_or_else(malformed)?;
+    *input = rest;
+    Ok(taken)
+}
+
+fn read_length(input: &mut &[u8], width: usize) -> crate::Result<usize> {
+    Ok(take(input, width)?.iter().fold(0, |length, byte| (length << 8) | usize::from(*byte)))
+}
+
+/// Reads a map key, returning its bytes if it's a string and skipping it otherwise.
+fn read_str<'a>(input: &mut &'a [u8]) -> crate::Result<Option<&'a [u8]>> {
+    let mut peek = *input;
+    let length = match rmp::decode::read_marker(&mut peek).map_err(|_| malformed())? {
+        rmp::Marker::FixStr(length) => usize::from(length),
+        rmp::Marker::Str8 => read_length(&mut peek, 1)?,
+        rmp::Marker::Str16 => read_length(&mut peek, 2)?,
+        rmp::Marker::Str32 => read_length(&mut peek, 4)?,
+        _ => {
+            skip_value(input)?;
+            return Ok(None);
+        }
+    };
+    let key = take(&mut peek, length)?;
+    *input = peek;
+    Ok(Some(key))
+}
+
+/// Advances `input` past one msgpack value, nested values included, without decoding it.
+fn skip_value(input: &mut &[u8]) -> crate::Result<()> {
+    use rmp::Marker::*;
+    let mut pending = 1usize;
+    while pending > 0 {
+        pending -= 1;
+        let (data, values) = match rmp::decode::read_marker(input).map_err(|_| malformed())? {
+            FixPos(_) | FixNeg(_) | Null | True | False | Reserved => (0, 0),
+            U8 | I8 => (1, 0),
+            U16 | I16 => (2, 0),
+            U32 | I32 | F32 => (4, 0),
+            U64 | I64 | F64 => (8, 0),
+            FixStr(length) => (usize::from(length), 0),
+            Str8 | Bin8 => (read_length(input, 1)?, 0),
+            Str16 | Bin16 => (read_length(input, 2)?, 0),
+            Str32 | Bin32 => (read_length(input, 4)?, 0),
+            FixArray(length) => (0, usize::from(length)),
+            Array16 => (0, read_length(input, 2)?),
+            Array32 => (0, read_length(input, 4)?),
+            FixMap(length) => (0, 2 * usize::from(length)),
+            Map16 => (0, 2 * read_length(input, 2)?),
+            Map32 => (0, 2 * read_length(input, 4)?),
+            FixExt1 => (2, 0),
+            FixExt2 => (3, 0),
+            FixExt4 => (5, 0),
+            FixExt8 => (9, 0),
+            FixExt16 => (17, 0),
+            Ext8 => (read_length(input, 1)? + 1, 0),
+            Ext16 => (read_length(input, 2)? + 1, 0),
+            Ext32 => (read_length(input, 4)? + 1, 0)
+        };
+        take(input, data)?;
+        pending = pending.checked_add(values).ok_or_else(malformed)?;
+    }
+    Ok(())
+}
+
+/// The [encode_ordered_value] bytes shared by every compound value that starts with `prefix`.
+pub(crate) fn encode_ordered_prefix(prefix: &[rmpv::Value]) -> crate::Result<Vec<u8>> {
+    let mut writer = vec![0x30];
+    for value in prefix {
+        write_ordered(&mut writer, value)?;
+    }
+    Ok(writer)
+}
+
+/// A document keyed by an [Id] with no indexes. Anything implementing it is a [Document]; use
+/// [crate::simple_document!] for types with an `id: Id` field.
+pub trait SimpleDocument: Serialize + DeserializeOwned + Debug {
+    fn id(&self) -> Id;
+
+    fn id_field() -> String {
+        String::from("id")
+    }
+}
+
+impl<T: SimpleDocument> Document for T {
+    type PrimaryKey = Id;
+
+    fn id(&self) -> Id {
+        SimpleDocument::id(self)
+    }
+
+    fn id_field() -> String {
+        <T as SimpleDocument>::id_field()
+    }
+
+    fn index_keys() -> Vec<String> {
+        Vec::new()
+    }
+
+    fn index_vals(&self) -> HashMap<String, rmpv::Value> {
+        HashMap::new()
+    }
+}
+
+/// Implements [SimpleDocument] for types with an `id: Id` field.
+#[macro_export]
+macro_rules! simple_document {
+    ($($doctype:ty),+ $(,)?) => {
+        $(
+            impl $crate::document::SimpleDocument for $doctype {
+                fn id(&self) -> $crate::document::Id {
+                    self.id.clone()
+                }
+            }
+        )+
+    };
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn encoded(value: &rmpv::Value) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        rmpv::encode::write_value(&mut bytes, value).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn ordered_encoding_sorts_like_the_values() -> crate::Result<()> {
+        let ascending: Vec<rmpv::Value> = vec![
+            rmpv::Value::Nil,
+            false.into(),
+            true.into(),
+            i64::MIN.into(),
+            (-1).into(),
+            0.into(),
+            1.into(),
+            u64::MAX.into(),
+            f64::NEG_INFINITY.into(),
+            (-2.5).into(),
+            (-0.0).into(),
+            0.0.into(),
+            1.5.into(),
+            f64::INFINITY.into(),
+            "".into(),
+            "a".into(),
+            "a\0".into(),
+            "a\0b".into(),
+            "ab".into(),
+            "b".into(),
+            rmpv::Value::Binary(vec![0]),
+            rmpv::Value::Binary(vec![0, 0]),
+            rmpv::Value::Array(Vec::new()),
+            rmpv::Value::Array(vec!["a".into()]),
+            rmpv::Value::Array(vec!["a".into(), 1.into()]),
+            rmpv::Value::Array(vec!["a".into(), rmpv::Value::Array(Vec::new())]),
+            rmpv::Value::Array(vec!["ab".into()]),
+            rmpv::Value::Array(vec!["b".into()]),
+        ];
+        let encoded = ascending.iter().map(encode_ordered_value).collect::<crate::Result<Vec<_>>>()?;
+        for (pair, values) in encoded.windows(2).zip(ascending.windows(2)) {
+            assert!(pair.first() < pair.get(1), "{values:?} out of order");
+        }
+        for (value, bytes) in ascending.iter().zip(&encoded) {
+            assert_eq!(&encode_ordered_value(&value.clone())?, bytes, "{value} encodes differently twice");
+        }
+        assert_eq!(encode_ordered_value(&rmpv::Value::from(7u64))?, encode_ordered_value(&rmpv::Value::from(7i64))?);
+        Ok(())
+    }
+
+    #[test]
+    fn phonetic_indexes_store_soundex_codes() -> crate::Result<()> {
+        for (name, code) in [("Robert", "R163"), ("Rupert", "R163"), ("Ashcraft", "A261"), ("Tymczak", "T522"), ("Pfister", "P236"), ("Lee", "L000"), ("o'Brien", "O165"), ("42", "")] {
+            assert_eq!(soundex(name), code, "{name}");
+        }
+        for spec in [IndexSpec::new().phonetic(), IndexSpec::new().phonetic().hashed(HashWidth::Bits64), IndexSpec::new().phonetic().ordered()] {
+            assert!(!spec.is_ordered());
+            assert_eq!(spec.stored_key(&"Robert".into())?.0, spec.stored_key(&"rupert".into())?.0);
+            assert_ne!(spec.stored_key(&"Robert".into())?.0, spec.stored_key(&"Ruben".into())?.0);
+            assert_eq!(spec.stored_key(&7.into())?, IndexSpec { phonetic: false, ordered: false, ..spec.clone() }.stored_key(&7.into())?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pointers_read_nested_values() -> crate::Result<()> {
+        let long = "x".repeat(300);
+        let document = rmpv::Value::Map(vec![
+            ("skipped".into(), rmpv::Value::Array(vec![long.as_str().into(), rmpv::Value::Ext(3, vec![1, 2, 3]), rmpv::Value::F64(1.5)])),
+            (rmpv::Value::from(7), "integer key".into()),
+            ("a/b".into(), "escaped".into()),
+            ("profile".into(), rmpv::Value::Map(vec![
+                ("bio".into(), long.as_str().into()),
+                ("email".into(), "ada@example.com".into()),
+                ("phones".into(), rmpv::Value::Array(vec!["123".into(), "456".into()]))
+            ])),
+        ]);
+        let bytes = encoded(&document);
+
+        assert_eq!(read_pointer(&bytes, "/profile/email")?, Some("ada@example.com".into()));
+        assert_eq!(read_pointer(&bytes, "/profile/phones/1")?, Some("456".into()));
+        assert_eq!(read_pointer(&bytes, "/a~1b")?, Some("escaped".into()));
+        assert_eq!(read_pointer(&bytes, "")?, Some(document));
+        for missing in ["/profile/phones/2", "/profile/phones/first", "/profile/email/domain", "/7", "/nope"] {
+            assert_eq!(read_pointer(&bytes, missing)?, None, "{missing}");
+        }
+        assert!(matches!(read_pointer(&bytes, "profile"), Err(crate::Error::InvalidPointer(_))));
+        assert!(matches!(read_pointer(bytes.get(..bytes.len() - 4).unwrap(), "/profile/phones/1"), Err(crate::Error::Decode(_))));
+        Ok(())
     }
 }
@@ -0,0 +1,93 @@
+use std::fmt::Debug;
+
+use crate::{
+    database::{Collection, CollectionOperation}, document::{index_names, Document}
+};
+
+/// Object-safe view of a [Collection] that works on encoded documents, for tooling that doesn't know the
+/// document type at compile time. Primary keys are passed as msgpack values and documents as msgpack bytes.
+pub trait ErasedCollection: Debug + Send + Sync {
+    fn name(&self) -> String;
+
+    fn indexes(&self) -> Vec<String>;
+
+    fn id_field(&self) -> String;
+
+    fn len(&self) -> crate::Result<u64>;
+
+    fn is_empty(&self) -> crate::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    fn get_raw(&self, id: &rmpv::Value) -> crate::Result<Option<Vec<u8>>>;
+
+    /// Every document as `(primary key, encoded document)`, in key order.
+    fn scan_raw(&self) -> crate::Result<Vec<(rmpv::Value, Vec<u8>)>>;
+
+    /// Decodes `document` and stores it, replacing any document with the same primary key.
+    fn put_raw(&self, document: &[u8]) -> crate::Result<()>;
+
+    /// Deletes a document, returning its encoded form if it existed.
+    fn delete_raw(&self, id: &rmpv::Value) -> crate::Result<Option<Vec<u8>>>;
+
+    /// Decodes every stored document as the collection's type and, if they all decode, checks the indexes
+    /// against them. Returns the number of documents read and a description of each problem.
+    fn verify(&self) -> crate::Result<(u64, Vec<String>)>;
+}
+
+impl<T: Document + Send + Sync + 'static> ErasedCollection for Collection<T> {
+    fn name(&self) -> String {
+        Collection::name(self)
+    }
+
+    fn indexes(&self) -> Vec<String> {
+        index_names::<T>()
+    }
+
+    fn id_field(&self) -> String {
+        T::id_field()
+    }
+
+    fn len(&self) -> crate::Result<u64> {
+        CollectionOperation::new_reader("len", self)?.len()
+    }
+
+    fn get_raw(&self, id: &rmpv::Value) -> crate::Result<Option<Vec<u8>>> {
+        let id = rmpv::ext::from_value::<T::PrimaryKey>(id.clone())?;
+        CollectionOperation::new_reader("get_raw", self)?.get_raw(&id)
+    }
+
+    fn scan_raw(&self) -> crate::Result<Vec<(rmpv::Value, Vec<u8>)>> {
+        let mut results = Vec::new();
+        for (id, document) in CollectionOperation::new_reader("scan_raw", self)?.scan_raw()? {
+            results.push((rmpv::ext::to_value(id)?, document));
+        }
+        Ok(results)
+    }
+
+    fn put_raw(&self, document: &[u8]) -> crate::Result<()> {
+        let document = rmp_serde::from_slice::<T>(document)?;
+        self.upsert(&document).map(|_| ())
+    }
+
+    fn delete_raw(&self, id: &rmpv::Value) -> crate::Result<Option<Vec<u8>>> {
+        let id = rmpv::ext::from_value::<T::PrimaryKey>(id.clone())?;
+        let operation = CollectionOperation::new_writer("delete_raw", self)?;
+        let deleted = operation.delete(&id)?.map(|document| rmp_serde::to_vec_named(&document)).transpose()?;
+        operation.commit()?;
+        Ok(deleted)
+    }
+
+    fn verify(&self) -> crate::Result<(u64, Vec<String>)> {
+        let operation = CollectionOperation::new_reader("verify", self)?;
+        let documents = operation.scan_raw()?;
+        let mut problems: Vec<String> = documents
+            .iter()
+            .filter_map(|(id, encoded)| rmp_serde::from_slice::<T>(encoded).err().map(|e| format!("document {id:?}: {e}")))
+            .collect();
+        if problems.is_empty() {
+            problems.extend(operation.index_problems()?);
+        }
+        Ok((documents.len() as u64, problems))
+    }
+}
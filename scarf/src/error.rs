@@ -1,9 +1,43 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 #[derive(thiserror::Error, Debug)]
+#[cfg_attr(feature = "miette", derive(miette::Diagnostic))]
 pub enum Error {
     #[error("Unhandled redb error: {0:?}")]
-    Redb(#[from] redb::Error),
+    Redb(#[from] Box<redb::Error>),
+
+    #[error("Failed to commit transaction: {0}")]
+    Commit(#[from] redb::CommitError),
+
+    #[error("Failed to compact database: {0}")]
+    Compaction(#[from] redb::CompactionError),
+
+    #[error("Failed to open database: {0}")]
+    Database(#[from] redb::DatabaseError),
+
+    #[error("Savepoint error: {0}")]
+    Savepoint(#[from] redb::SavepointError),
+
+    #[error("Storage error: {0}")]
+    Storage(#[from] redb::StorageError),
+
+    #[error("Table error: {0}")]
+    Table(#[from] redb::TableError),
+
+    #[error("Failed to begin transaction: {0}")]
+    Transaction(#[from] Box<redb::TransactionError>),
+
+    #[error("Failed to upgrade database file: {0}")]
+    Upgrade(#[from] redb::UpgradeError),
+
+    #[error("Failed to encode value: {0}")]
+    Encode(#[from] rmp_serde::encode::Error),
+
+    #[error("Failed to decode value: {0}")]
+    Decode(#[from] rmp_serde::decode::Error),
+
+    #[error("Failed to convert msgpack value: {0}")]
+    Value(#[from] rmpv::ext::Error),
 
     #[error("Filesystem/memory IO error: {0:?}")]
     Io(#[from] std::io::Error),
@@ -15,16 +49,230 @@ pub enum Error {
     UnknownTableName(String),
 
     #[error("More than one strong reference to this Arc exists: {0} strong, {1} weak.")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::arc_references), help("Drop every clone of the transaction (including ones held by iterators or handles) before committing or aborting it.")))]
     ArcReferences(usize, usize),
 
     #[error("Failed to execute {operation} on {collection}: the current transaction is read-only.")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::read_only), help("Open the transaction with Database::writer() instead of Database::reader().")))]
     ReadOnlyTransaction {
         operation: String,
         collection: String
+    },
+
+    #[error("Invalid query cursor: {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::invalid_cursor), help("Pass back a cursor token exactly as produced by Cursor's Display impl (cursor.to_string()), for the same query.")))]
+    InvalidCursor(String),
+
+    #[error("Invalid JSON pointer {0:?}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::invalid_pointer), help("JSON pointers are empty or start with '/', such as \"/profile/email\". Write '/' and '~' inside a field name as ~1 and ~0.")))]
+    InvalidPointer(String),
+
+    #[error("Invalid time bucket width: {0:?}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::invalid_bucket), help("Time buckets must be a whole number of milliseconds, at least one.")))]
+    InvalidBucket(Duration),
+
+    #[error("Missing required option: {0}")]
+    UninitializedField(String),
+
+    #[error("Database format version {found} is newer than the newest supported version ({supported})")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::unsupported_format), help("This file was written by a newer version of scarf; upgrade scarf to open it.")))]
+    UnsupportedFormat {
+        found: u32,
+        supported: u32
+    },
+
+    #[error("Database format version {found} must be upgraded to {current}, but format upgrades are disabled")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::upgrade_required), help("Enable upgrades with Database::builder().upgrade_format(true), ideally with backup_before_upgrade(true).")))]
+    UpgradeRequired {
+        found: u32,
+        current: u32
+    },
+
+    #[error("No format upgrade registered from version {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::missing_upgrade), help("The upgrade chain has a gap; this is a scarf bug, please report it along with the stored format version.")))]
+    MissingUpgrade(u32),
+
+    #[error("Cannot {0} an in-memory database")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::not_file_backed), help("Snapshots, backups and restores copy the database file, so open the database with Database::open(path).")))]
+    NotFileBacked(String),
+
+    #[error("Database handle is unusable: {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::unusable), help("A restore closed the database file and couldn't reopen it or the original. Repair the file, then open it again with Database::open.")))]
+    Unusable(String),
+
+    #[error("No snapshot directory is configured for this database")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::snapshots_not_configured), help("Configure a directory with Database::builder().snapshots(SnapshotConfig::new(dir)).")))]
+    SnapshotsNotConfigured,
+
+    #[error("Unknown snapshot {0}")]
+    UnknownSnapshot(String),
+
+    #[error("Cannot restore while {0} transaction(s) are open")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::transactions_active), help("Drop every open transaction, iterator and traversal; Database::active_transactions() lists what is still open.")))]
+    TransactionsActive(usize),
+
+    #[error("Collection {name} is registered as {registered}, not {requested}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::collection_conflict), help("Each collection name can only be registered to one document type; rename one of the collections.")))]
+    CollectionConflict {
+        name: String,
+        registered: String,
+        requested: String
+    },
+
+    #[error("Document type {type_name} is already registered as collection {name}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::type_already_registered), help("A document type can only be registered under one name; use Database::collection(name) for ad-hoc handles.")))]
+    TypeAlreadyRegistered {
+        type_name: String,
+        name: String
+    },
+
+    #[error("No collection is registered for {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::unregistered_collection), help("Call Database::register::<T>(name) at startup before Database::get_collection::<T>().")))]
+    UnregisteredCollection(String),
+
+    #[error("Collection {0} already exists")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::collection_exists), help("Drop the existing collection with Database::drop_collection first, or pick another name.")))]
+    CollectionExists(String),
+
+    #[error("Unknown index {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::unknown_index), help("Only keys returned by Document::index_keys() can be queried.")))]
+    UnknownIndex(String),
+
+    #[error("Collection {collection} has index tables {stored:?}, but its Document declares {declared:?}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::index_mismatch), help("Run Collection::rebuild_indexes() on {collection} to build {declared:?} from the stored documents and drop the other index tables.")))]
+    IndexMismatch {
+        collection: String,
+        stored: Vec<String>,
+        declared: Vec<String>
+    },
+
+    #[error("A query on {0} needs its documents, so it can't be answered from the indexes alone")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::unindexed_query), help("Query::index_keys reads index tables only: give the query an eq, prefix or order_by condition and move filter predicates elsewhere, or use Query::keys.")))]
+    UnindexedQuery(String),
+
+    #[error("Query parameter {0} has no value")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::unbound_parameter), help("Bind every Query::eq_param name with Params::bind and run the query through Query::prepare.")))]
+    UnboundParameter(String),
+
+    #[error("Callback {hook} panicked: {message}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::hook_panicked), help("The write transaction the callback ran in was rolled back and can't be committed. Fix the panic and retry the write.")))]
+    HookPanicked {
+        hook: String,
+        message: String
+    },
+
+    #[error("Index {0} has no sketch")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::unsketched_index), help("Declare the index with IndexSpec::sketched(), then run Collection::rebuild_indexes to build its sketch from the stored documents.")))]
+    UnsketchedIndex(String),
+
+    #[error("Index {0} has no bitmap")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::unbitmapped_index), help("Declare the index with IndexSpec::bitmap(), then run Collection::rebuild_indexes to build its bitmaps from the stored documents.")))]
+    UnbitmappedIndex(String),
+
+    #[error("Collection {0} has used every bitmap row")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::bitmap_rows_exhausted), help("Bitmap rows of deleted documents aren't reused until Collection::rebuild_indexes renumbers them.")))]
+    BitmapRowsExhausted(String),
+
+    #[error("Index {0} is not ordered")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::unordered_index), help("Range scans, prefix matches and ordering need an IndexSpec::ordered() index on a collection using IndexKeyFormat::Raw. Run Collection::rebuild_indexes after changing an index's spec.")))]
+    UnorderedIndex(String),
+
+    #[error("A document with id {id} already exists in {collection}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::document_exists), help("Use Collection::upsert or Collection::replace to overwrite an existing document.")))]
+    DocumentExists {
+        collection: String,
+        id: String
+    },
+
+    #[error("Index {index} of {collection} is unique and already holds {value}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::duplicate_key), help("Remove or change the document in {collection} that holds {value} first, or drop IndexSpec::unique from index {index}.")))]
+    DuplicateKey {
+        collection: String,
+        index: String,
+        value: String
+    },
+
+    #[error("Field {field} of document {id} in {collection} is not {expected}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::field_type), help("Field operations address fields of the serialized document by dot path, like Document::path_indexes.")))]
+    FieldType {
+        collection: String,
+        id: String,
+        field: String,
+        expected: &'static str
+    },
+
+    #[error("Document {id} in {collection} is locked by {owner} until {expires_at}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::document_locked), help("Wait until {expires_at} for the lock to expire, or have {owner} release it with Collection::unlock.")))]
+    DocumentLocked {
+        collection: String,
+        id: String,
+        owner: String,
+        expires_at: chrono::DateTime<chrono::Utc>
+    },
+
+    #[error("A document written to {collection} has no id at {pointer}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::missing_id), help("Every document of a JsonCollection needs a value at its id pointer. Open the collection with the pointer its documents use, like \"/id\".")))]
+    MissingId {
+        collection: String,
+        pointer: String
+    },
+
+    #[error("Modifying document {id} in {collection} changed its primary key")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::primary_key_changed), help("Changing a primary key is an insert plus a delete; do that explicitly instead of mutating the id.")))]
+    PrimaryKeyChanged {
+        collection: String,
+        id: String
+    },
+
+    #[error("No document with id {id} exists in {collection}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::document_not_found), help("Use Collection::upsert to insert the document when it is missing.")))]
+    DocumentNotFound {
+        collection: String,
+        id: String
+    },
+
+    #[error("Golden file {} differs at line {line}: expected {expected:?}, got {actual:?}", path.display())]
+    #[cfg_attr(feature = "miette", diagnostic(code(scarf::golden_mismatch), help("If the encoding change is intentional, rerun with SCARF_UPDATE_GOLDEN=1 to rewrite the golden file.")))]
+    GoldenMismatch {
+        path: std::path::PathBuf,
+        line: usize,
+        expected: String,
+        actual: String
     }
 }
 
 impl Error {
+    /// The underlying storage error, if this came from redb's storage layer (IO failures, corruption, ...).
+    pub fn storage_error(&self) -> Option<&redb::StorageError> {
+        match self {
+            Self::Storage(e)
+            | Self::Commit(redb::CommitError::Storage(e))
+            | Self::Table(redb::TableError::Storage(e))
+            | Self::Database(redb::DatabaseError::Storage(e))
+            | Self::Savepoint(redb::SavepointError::Storage(e))
+            | Self::Compaction(redb::CompactionError::Storage(e)) => Some(e),
+            Self::Transaction(e) => match e.as_ref() {
+                redb::TransactionError::Storage(e) => Some(e),
+                _ => None
+            },
+            _ => None
+        }
+    }
+
+    /// The underlying IO error, whether it came from scarf's own file handling or from redb's storage
+    /// layer, so callers can react to e.g. [std::io::ErrorKind::StorageFull].
+    pub fn io_error(&self) -> Option<&std::io::Error> {
+        match (self, self.storage_error()) {
+            (Self::Io(e), _) => Some(e),
+            (Self::Redb(e), _) => match e.as_ref() {
+                redb::Error::Io(e) => Some(e),
+                _ => None
+            },
+            (_, Some(redb::StorageError::Io(e))) => Some(e),
+            _ => None
+        }
+    }
+
     pub fn unknown_table(name: impl AsRef<str>) -> Self {
         Self::UnknownTableName(name.as_ref().to_string())
     }
@@ -38,57 +286,27 @@ impl Error {
     }
 }
 
-impl<T> From<std::sync::PoisonError<T>> for Error {
-    fn from(value: std::sync::PoisonError<T>) -> Self {
-        Self::Poison(value.to_string())
-    }
-}
-
-impl From<redb::CommitError> for Error {
-    fn from(value: redb::CommitError) -> Self {
-        Self::Redb(value.into())
-    }
-}
-
-impl From<redb::CompactionError> for Error {
-    fn from(value: redb::CompactionError) -> Self {
-        Self::Redb(value.into())
+impl From<redb::Error> for Error {
+    fn from(value: redb::Error) -> Self {
+        Self::Redb(Box::new(value))
     }
 }
 
-impl From<redb::DatabaseError> for Error {
-    fn from(value: redb::DatabaseError) -> Self {
-        Self::Redb(value.into())
-    }
-}
-
-impl From<redb::SavepointError> for Error {
-    fn from(value: redb::SavepointError) -> Self {
-        Self::Redb(value.into())
-    }
-}
-
-impl From<redb::StorageError> for Error {
-    fn from(value: redb::StorageError) -> Self {
-        Self::Redb(value.into())
+impl<T> From<std::sync::PoisonError<T>> for Error {
+    fn from(value: std::sync::PoisonError<T>) -> Self {
+        Self::Poison(value.to_string())
     }
 }
 
-impl From<redb::TableError> for Error {
-    fn from(value: redb::TableError) -> Self {
-        Self::Redb(value.into())
+impl From<derive_builder::UninitializedFieldError> for Error {
+    fn from(value: derive_builder::UninitializedFieldError) -> Self {
+        Self::UninitializedField(value.field_name().to_string())
     }
 }
 
 impl From<redb::TransactionError> for Error {
     fn from(value: redb::TransactionError) -> Self {
-        Self::Redb(value.into())
-    }
-}
-
-impl From<redb::UpgradeError> for Error {
-    fn from(value: redb::UpgradeError) -> Self {
-        Self::Redb(value.into())
+        Self::Transaction(Box::new(value))
     }
 }
 
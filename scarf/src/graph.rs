@@ -0,0 +1,300 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque}, hash::Hash, marker::PhantomData
+};
+
+use redb::{MultimapTableDefinition, ReadableMultimapTable};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::{with_table, Database, Transaction}, document::OwnedKey, tracking::ChangeKind
+};
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Outgoing,
+    Incoming
+}
+
+/// Directed edges between node keys, indexed by both source and target.
+#[derive(Debug)]
+pub struct Edges<N: OwnedKey + Hash + Eq> {
+    database: Database,
+    name: String,
+    node_type: PhantomData<N>
+}
+
+impl<N: OwnedKey + Hash + Eq> Clone for Edges<N> {
+    fn clone(&self) -> Self {
+        Self {
+            database: self.database.clone(),
+            name: self.name.clone(),
+            node_type: PhantomData
+        }
+    }
+}
+
+impl<N: OwnedKey + Hash + Eq> Edges<N> {
+    pub(crate) fn new(db: Database, name: String) -> Self {
+        Self {
+            database: db,
+            name,
+            node_type: PhantomData
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn table_name(&self, direction: Direction) -> String {
+        match direction {
+            Direction::Outgoing => format!("edges/{}/out", self.name()),
+            Direction::Incoming => format!("edges/{}/in", self.name())
+        }
+    }
+
+    /// Adds an edge from `from` to `to`, returning `false` if it already existed.
+    pub fn add(&self, from: &N, to: &N) -> crate::Result<bool> {
+        let txn = self.database.begin_write("add_edge", self.table_name(Direction::Outgoing))?;
+        let added = self.add_in(&txn, from, to)?;
+        txn.commit()?;
+        Ok(added)
+    }
+
+    pub fn add_in(&self, txn: &Transaction, from: &N, to: &N) -> crate::Result<bool> {
+        let (out_name, in_name) = (self.table_name(Direction::Outgoing), self.table_name(Direction::Incoming));
+        let guard = txn.write_guard("add_edge", &out_name)?;
+        let existed = guard.open_multimap_table(MultimapTableDefinition::<N, N>::new(&out_name))?.insert(from, to)?;
+        guard.open_multimap_table(MultimapTableDefinition::<N, N>::new(&in_name))?.insert(to, from)?;
+        if !existed {
+            let bytes = N::as_bytes(from).as_ref().len() + N::as_bytes(to).as_ref().len();
+            txn.record_change(&out_name, ChangeKind::Insert, bytes)?;
+            txn.record_change(&in_name, ChangeKind::Insert, bytes)?;
+        }
+        Ok(!existed)
+    }
+
+    /// Removes the edge from `from` to `to`, returning `false` if it didn't exist.
+    pub fn remove(&self, from: &N, to: &N) -> crate::Result<bool> {
+        let txn = self.database.begin_write("remove_edge", self.table_name(Direction::Outgoing))?;
+        let removed = self.remove_in(&txn, from, to)?;
+        txn.commit()?;
+        Ok(removed)
+    }
+
+    pub fn remove_in(&self, txn: &Transaction, from: &N, to: &N) -> crate::Result<bool> {
+        let (out_name, in_name) = (self.table_name(Direction::Outgoing), self.table_name(Direction::Incoming));
+        let guard = txn.write_guard("remove_edge", &out_name)?;
+        let existed = guard.open_multimap_table(MultimapTableDefinition::<N, N>::new(&out_name))?.remove(from, to)?;
+        guard.open_multimap_table(MultimapTableDefinition::<N, N>::new(&in_name))?.remove(to, from)?;
+        if existed {
+            txn.record_change(&out_name, ChangeKind::Delete, 0)?;
+            txn.record_change(&in_name, ChangeKind::Delete, 0)?;
+        }
+        Ok(existed)
+    }
+
+    /// Removes every edge touching `node`, returning the number of edges removed.
+    pub fn remove_node(&self, node: &N) -> crate::Result<usize> {
+        let txn = self.database.begin_write("remove_node", self.table_name(Direction::Outgoing))?;
+        let removed = self.remove_node_in(&txn, node)?;
+        txn.commit()?;
+        Ok(removed)
+    }
+
+    pub fn remove_node_in(&self, txn: &Transaction, node: &N) -> crate::Result<usize> {
+        let (out_name, in_name) = (self.table_name(Direction::Outgoing), self.table_name(Direction::Incoming));
+        let guard = txn.write_guard("remove_node", &out_name)?;
+        let mut outgoing = guard.open_multimap_table(MultimapTableDefinition::<N, N>::new(&out_name))?;
+        let mut incoming = guard.open_multimap_table(MultimapTableDefinition::<N, N>::new(&in_name))?;
+        let mut removed = 0;
+        for target in outgoing.remove_all(node)? {
+            incoming.remove(target?.value(), node)?;
+            txn.record_change(&out_name, ChangeKind::Delete, 0)?;
+            txn.record_change(&in_name, ChangeKind::Delete, 0)?;
+            removed += 1;
+        }
+        for source in incoming.remove_all(node)? {
+            outgoing.remove(source?.value(), node)?;
+            txn.record_change(&out_name, ChangeKind::Delete, 0)?;
+            txn.record_change(&in_name, ChangeKind::Delete, 0)?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    pub fn contains(&self, from: &N, to: &N) -> crate::Result<bool> {
+        Ok(self.neighbors(from, Direction::Outgoing)?.contains(to))
+    }
+
+    pub fn outgoing(&self, node: &N) -> crate::Result<Vec<N>> {
+        self.neighbors(node, Direction::Outgoing)
+    }
+
+    pub fn incoming(&self, node: &N) -> crate::Result<Vec<N>> {
+        self.neighbors(node, Direction::Incoming)
+    }
+
+    pub fn neighbors(&self, node: &N, direction: Direction) -> crate::Result<Vec<N>> {
+        self.neighbors_in(&self.database.begin_read("neighbors", self.table_name(direction))?, node, direction)
+    }
+
+    pub fn neighbors_in(&self, txn: &Transaction, node: &N, direction: Direction) -> crate::Result<Vec<N>> {
+        let name = self.table_name(direction);
+        with_table!(multimap txn, MultimapTableDefinition::<N, N>::new(&name), table => {
+            let mut results = Vec::new();
+            for value in table.get(node)? {
+                results.push(value?.value());
+            }
+            Ok(results)
+        }, Ok(Vec::new()))
+    }
+
+    /// Breadth-first traversal from `start` (inclusive), reading from a single read transaction.
+    pub fn bfs(&self, start: N, direction: Direction) -> crate::Result<Traversal<N>> {
+        Traversal::new(self.clone(), start, direction, TraversalOrder::BreadthFirst)
+    }
+
+    /// Depth-first (preorder) traversal from `start` (inclusive), reading from a single read transaction.
+    pub fn dfs(&self, start: N, direction: Direction) -> crate::Result<Traversal<N>> {
+        Traversal::new(self.clone(), start, direction, TraversalOrder::DepthFirst)
+    }
+
+    /// Finds a path with the fewest edges from `from` to `to` following outgoing edges.
+    pub fn shortest_path(&self, from: &N, to: &N) -> crate::Result<Option<Vec<N>>> {
+        let txn = self.database.begin_read("shortest_path", self.table_name(Direction::Outgoing))?;
+        let mut parents: HashMap<N, N> = HashMap::new();
+        let mut visited = HashSet::from([from.clone()]);
+        let mut queue = VecDeque::from([from.clone()]);
+
+        while let Some(node) = queue.pop_front() {
+            if &node == to {
+                let mut path = vec![node];
+                while let Some(parent) = path.last().and_then(|last| parents.get(last)) {
+                    path.push(parent.clone());
+                }
+                path.reverse();
+                return Ok(Some(path));
+            }
+            for neighbor in self.neighbors_in(&txn, &node, Direction::Outgoing)? {
+                if visited.insert(neighbor.clone()) {
+                    parents.insert(neighbor.clone(), node.clone());
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TraversalOrder {
+    BreadthFirst,
+    DepthFirst
+}
+
+/// Lazy BFS/DFS iterator over an [Edges] graph. Each node is yielded at most once.
+pub struct Traversal<N: OwnedKey + Hash + Eq> {
+    edges: Edges<N>,
+    transaction: Transaction,
+    direction: Direction,
+    order: TraversalOrder,
+    pending: VecDeque<N>,
+    visited: HashSet<N>
+}
+
+impl<N: OwnedKey + Hash + Eq> Traversal<N> {
+    fn new(edges: Edges<N>, start: N, direction: Direction, order: TraversalOrder) -> crate::Result<Self> {
+        Ok(Self {
+            transaction: edges.database.begin_read("traverse", edges.table_name(direction))?,
+            edges,
+            direction,
+            order,
+            pending: VecDeque::from([start]),
+            visited: HashSet::new()
+        })
+    }
+}
+
+impl<N: OwnedKey + Hash + Eq> Iterator for Traversal<N> {
+    type Item = crate::Result<N>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let node = match self.order {
+                TraversalOrder::BreadthFirst => self.pending.pop_front(),
+                TraversalOrder::DepthFirst => self.pending.pop_back()
+            }?;
+            if !self.visited.insert(node.clone()) {
+                continue;
+            }
+
+            let neighbors = match self.edges.neighbors_in(&self.transaction, &node, self.direction) {
+                Ok(neighbors) => neighbors,
+                Err(e) => return Some(Err(e))
+            };
+            let unvisited = neighbors.into_iter().filter(|neighbor| !self.visited.contains(neighbor));
+            match self.order {
+                TraversalOrder::BreadthFirst => self.pending.extend(unvisited),
+                TraversalOrder::DepthFirst => {
+                    let mut unvisited: Vec<N> = unvisited.collect();
+                    unvisited.reverse();
+                    self.pending.extend(unvisited);
+                }
+            }
+            return Some(Ok(node));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(edges: &[(u64, u64)]) -> crate::Result<Edges<u64>> {
+        let graph = Database::open_in_memory()?.edges::<u64>("graph");
+        for (from, to) in edges {
+            graph.add(from, to)?;
+        }
+        Ok(graph)
+    }
+
+    fn visit(traversal: Traversal<u64>) -> crate::Result<Vec<u64>> {
+        traversal.collect()
+    }
+
+    #[test]
+    fn traversals_visit_each_node_of_a_cycle_once() -> crate::Result<()> {
+        let graph = graph(&[(1, 2), (1, 5), (2, 3), (2, 4), (3, 1), (3, 4), (5, 4), (9, 1)])?;
+        assert_eq!(visit(graph.bfs(1, Direction::Outgoing)?)?, [1, 2, 5, 3, 4]);
+        assert_eq!(visit(graph.dfs(1, Direction::Outgoing)?)?, [1, 2, 3, 4, 5]);
+        assert_eq!(visit(graph.bfs(4, Direction::Incoming)?)?, [4, 2, 3, 5, 1, 9]);
+        assert_eq!(visit(graph.dfs(4, Direction::Outgoing)?)?, [4]);
+        assert_eq!(graph.shortest_path(&3, &5)?, Some(vec![3, 1, 5]));
+        assert_eq!(graph.shortest_path(&1, &1)?, Some(vec![1]));
+        Ok(())
+    }
+
+    #[test]
+    fn unreachable_nodes_have_no_path() -> crate::Result<()> {
+        let graph = graph(&[(1, 2), (2, 3), (3, 1), (9, 1)])?;
+        assert!(!visit(graph.bfs(1, Direction::Outgoing)?)?.contains(&9));
+        assert!(!visit(graph.dfs(1, Direction::Outgoing)?)?.contains(&9));
+        assert_eq!(graph.shortest_path(&1, &9)?, None);
+        assert_eq!(graph.shortest_path(&1, &7)?, None);
+        assert_eq!(graph.shortest_path(&9, &3)?, Some(vec![9, 1, 2, 3]));
+        Ok(())
+    }
+
+    #[test]
+    fn equal_length_paths_resolve_to_the_lowest_keys() -> crate::Result<()> {
+        // Neighbors come back in key order, so the tie goes to the path through 2 whichever edge came first.
+        for edges in [[(1, 5), (5, 4), (1, 2), (2, 4)], [(1, 2), (2, 4), (1, 5), (5, 4)]] {
+            let graph = graph(&edges)?;
+            assert_eq!(graph.shortest_path(&1, &4)?, Some(vec![1, 2, 4]));
+            assert_eq!(visit(graph.bfs(1, Direction::Outgoing)?)?, [1, 2, 5, 4]);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,335 @@
+use redb::{MultimapTableDefinition, MultimapTableHandle, ReadableMultimapTable, ReadableTable, ReadableTableMetadata, TableDefinition};
+use serde_json::Value;
+
+use crate::{
+    database::{with_table, Database, Transaction}, document::encode_ordered_value, tracking::ChangeKind, Error
+};
+
+/// A collection of untyped [serde_json::Value] documents, for persisting API responses without defining a
+/// [Document](crate::document::Document) type. Documents are keyed by the value at a JSON pointer and can be
+/// indexed on further pointers chosen at runtime, such as `/address/city`.
+///
+/// An index entry holds the whole value at its pointer, so arrays aren't split into one entry per element.
+/// Numbers keep their JSON type: `1` and `1.0` are different keys.
+#[derive(Clone, Debug)]
+pub struct JsonCollection {
+    database: Database,
+    name: String,
+    id_pointer: String,
+    indexes: Vec<String>
+}
+
+impl JsonCollection {
+    pub(crate) fn new(database: Database, name: String, id_pointer: String) -> Self {
+        Self {
+            database,
+            name,
+            id_pointer,
+            indexes: Vec::new()
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn id_pointer(&self) -> String {
+        self.id_pointer.clone()
+    }
+
+    /// Indexes documents on the value at JSON pointer `pointer`; documents without one aren't indexed.
+    /// Documents stored before the index was added are missing from it until [JsonCollection::rebuild_indexes].
+    pub fn index(mut self, pointer: impl AsRef<str>) -> Self {
+        if !self.indexes.iter().any(|index| index == pointer.as_ref()) {
+            self.indexes.push(pointer.as_ref().to_string());
+        }
+        self
+    }
+
+    pub fn indexes(&self) -> Vec<String> {
+        self.indexes.clone()
+    }
+
+    fn main_table_name(&self) -> String {
+        format!("json/{}", self.name)
+    }
+
+    fn index_prefix(&self) -> String {
+        format!("json/{}/index", self.name)
+    }
+
+    fn index_table_name(&self, pointer: &str) -> String {
+        format!("{}{pointer}", self.index_prefix())
+    }
+
+    fn key_of(&self, document: &Value) -> crate::Result<Vec<u8>> {
+        let id = document.pointer(&self.id_pointer).ok_or_else(|| Error::MissingId { collection: self.name(), pointer: self.id_pointer() })?;
+        encode_key(id)
+    }
+
+    pub fn len(&self) -> crate::Result<u64> {
+        self.len_in(&self.database.begin_read("json_len", self.main_table_name())?)
+    }
+
+    pub fn len_in(&self, txn: &Transaction) -> crate::Result<u64> {
+        let name = self.main_table_name();
+        with_table!(txn, TableDefinition::<&[u8], &[u8]>::new(&name), table => {
+            Ok(table.len()?)
+        }, Ok(0))
+    }
+
+    pub fn is_empty(&self) -> crate::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    pub fn get(&self, id: impl Into<Value>) -> crate::Result<Option<Value>> {
+        self.get_in(&self.database.begin_read("json_get", self.main_table_name())?, id)
+    }
+
+    pub fn get_in(&self, txn: &Transaction, id: impl Into<Value>) -> crate::Result<Option<Value>> {
+        self.get_encoded(txn, &encode_key(&id.into())?)
+    }
+
+    fn get_encoded(&self, txn: &Transaction, key: &[u8]) -> crate::Result<Option<Value>> {
+        let name = self.main_table_name();
+        with_table!(txn, TableDefinition::<&[u8], &[u8]>::new(&name), table => {
+            match table.get(key)? {
+                Some(value) => Ok(Some(rmp_serde::from_slice::<Value>(value.value())?)),
+                None => Ok(None)
+            }
+        }, Ok(None))
+    }
+
+    /// Stores a new document. Fails with [Error::DocumentExists] if its id is taken and with
+    /// [Error::MissingId] if it has no value at the id pointer.
+    pub fn insert(&self, document: &Value) -> crate::Result<()> {
+        let txn = self.database.begin_write("json_insert", self.main_table_name())?;
+        self.insert_in(&txn, document)?;
+        txn.commit()
+    }
+
+    pub fn insert_in(&self, txn: &Transaction, document: &Value) -> crate::Result<()> {
+        let key = self.key_of(document)?;
+        if self.get_encoded(txn, &key)?.is_some() {
+            let id = document.pointer(&self.id_pointer).map(Value::to_string).unwrap_or_default();
+            return Err(Error::DocumentExists { collection: self.name(), id });
+        }
+        self.write(txn, &key, None, Some(document))
+    }
+
+    /// Stores the document, replacing and returning any document with the same id.
+    pub fn upsert(&self, document: &Value) -> crate::Result<Option<Value>> {
+        let txn = self.database.begin_write("json_upsert", self.main_table_name())?;
+        let previous = self.upsert_in(&txn, document)?;
+        txn.commit()?;
+        Ok(previous)
+    }
+
+    pub fn upsert_in(&self, txn: &Transaction, document: &Value) -> crate::Result<Option<Value>> {
+        let key = self.key_of(document)?;
+        let previous = self.get_encoded(txn, &key)?;
+        self.write(txn, &key, previous.as_ref(), Some(document))?;
+        Ok(previous)
+    }
+
+    pub fn delete(&self, id: impl Into<Value>) -> crate::Result<Option<Value>> {
+        let txn = self.database.begin_write("json_delete", self.main_table_name())?;
+        let deleted = self.delete_in(&txn, id)?;
+        txn.commit()?;
+        Ok(deleted)
+    }
+
+    pub fn delete_in(&self, txn: &Transaction, id: impl Into<Value>) -> crate::Result<Option<Value>> {
+        let key = encode_key(&id.into())?;
+        let previous = self.get_encoded(txn, &key)?;
+        if previous.is_some() {
+            self.write(txn, &key, previous.as_ref(), None)?;
+        }
+        Ok(previous)
+    }
+
+    /// Documents whose value at indexed pointer `pointer` equals `value`, in id order. Fails with
+    /// [Error::UnknownIndex] unless `pointer` was added with [JsonCollection::index].
+    pub fn find_by(&self, pointer: impl AsRef<str>, value: impl Into<Value>) -> crate::Result<Vec<Value>> {
+        self.find_by_in(&self.database.begin_read("json_find_by", self.main_table_name())?, pointer, value)
+    }
+
+    pub fn find_by_in(&self, txn: &Transaction, pointer: impl AsRef<str>, value: impl Into<Value>) -> crate::Result<Vec<Value>> {
+        if !self.indexes.iter().any(|index| index == pointer.as_ref()) {
+            return Err(Error::UnknownIndex(pointer.as_ref().to_string()));
+        }
+        let (name, value) = (self.index_table_name(pointer.as_ref()), encode_key(&value.into())?);
+        let keys: crate::Result<Vec<Vec<u8>>> = with_table!(multimap txn, MultimapTableDefinition::<&[u8], &[u8]>::new(&name), table => {
+            let mut keys = Vec::new();
+            for key in table.get(value.as_slice())? {
+                keys.push(key?.value().to_vec());
+            }
+            Ok(keys)
+        }, Ok(Vec::new()));
+
+        let mut documents = Vec::new();
+        for key in keys? {
+            documents.extend(self.get_encoded(txn, &key)?);
+        }
+        Ok(documents)
+    }
+
+    /// Drops every index table of the collection and rebuilds the ones currently declared from the stored
+    /// documents, returning the number of index entries written.
+    pub fn rebuild_indexes(&self) -> crate::Result<usize> {
+        let txn = self.database.begin_write("json_rebuild_indexes", self.main_table_name())?;
+        let written = self.rebuild_indexes_in(&txn)?;
+        txn.commit()?;
+        Ok(written)
+    }
+
+    pub fn rebuild_indexes_in(&self, txn: &Transaction) -> crate::Result<usize> {
+        let main_name = self.main_table_name();
+        let documents: crate::Result<Vec<(Vec<u8>, Value)>> = with_table!(txn, TableDefinition::<&[u8], &[u8]>::new(&main_name), table => {
+            let mut documents = Vec::new();
+            for entry in table.iter()? {
+                let (key, value) = entry?;
+                documents.push((key.value().to_vec(), rmp_serde::from_slice::<Value>(value.value())?));
+            }
+            Ok(documents)
+        }, Ok(Vec::new()));
+        let documents = documents?;
+
+        let prefix = format!("{}/", self.index_prefix());
+        let guard = txn.write_guard("json_rebuild_indexes", &main_name)?;
+        let stale: Vec<_> = guard.list_multimap_tables()?.filter(|handle| handle.name().starts_with(&prefix)).collect();
+        let mut changes = Vec::new();
+        for handle in stale {
+            if guard.delete_multimap_table(handle.clone())? {
+                changes.push((handle.name().to_string(), ChangeKind::Delete, 0));
+            }
+        }
+        for pointer in &self.indexes {
+            let index_name = self.index_table_name(pointer);
+            let mut index = guard.open_multimap_table(MultimapTableDefinition::<&[u8], &[u8]>::new(&index_name))?;
+            for (key, document) in &documents {
+                if let Some(value) = document.pointer(pointer) {
+                    let value = encode_key(value)?;
+                    index.insert(value.as_slice(), key.as_slice())?;
+                    changes.push((index_name.clone(), ChangeKind::Insert, value.len() + key.len()));
+                }
+            }
+        }
+        drop(guard);
+
+        let written = changes.iter().filter(|(_, kind, _)| *kind == ChangeKind::Insert).count();
+        for (table, kind, bytes) in changes {
+            txn.record_change(table, kind, bytes)?;
+        }
+        Ok(written)
+    }
+
+    /// Replaces the document stored under `key`, `previous`, with `next` (deleting it if `None`), keeping every
+    /// index in step.
+    fn write(&self, txn: &Transaction, key: &[u8], previous: Option<&Value>, next: Option<&Value>) -> crate::Result<()> {
+        let main_name = self.main_table_name();
+        let guard = txn.write_guard("json_write", &main_name)?;
+        let mut changes = Vec::new();
+        let mut main = guard.open_table(TableDefinition::<&[u8], &[u8]>::new(&main_name))?;
+        match next {
+            Some(document) => {
+                let encoded = rmp_serde::to_vec(document)?;
+                main.insert(key, encoded.as_slice())?;
+                let kind = if previous.is_some() { ChangeKind::Update } else { ChangeKind::Insert };
+                changes.push((main_name.clone(), kind, key.len() + encoded.len()));
+            },
+            None => {
+                main.remove(key)?;
+                changes.push((main_name.clone(), ChangeKind::Delete, 0));
+            }
+        }
+
+        for pointer in &self.indexes {
+            let (old, new) = (previous.and_then(|document| document.pointer(pointer)), next.and_then(|document| document.pointer(pointer)));
+            if old == new {
+                continue;
+            }
+            let index_name = self.index_table_name(pointer);
+            let mut index = guard.open_multimap_table(MultimapTableDefinition::<&[u8], &[u8]>::new(&index_name))?;
+            if let Some(old) = old {
+                index.remove(encode_key(old)?.as_slice(), key)?;
+                changes.push((index_name.clone(), ChangeKind::Delete, 0));
+            }
+            if let Some(new) = new {
+                let value = encode_key(new)?;
+                index.insert(value.as_slice(), key)?;
+                changes.push((index_name.clone(), ChangeKind::Insert, value.len() + key.len()));
+            }
+        }
+        drop(main);
+        drop(guard);
+
+        for (table, kind, bytes) in changes {
+            txn.record_change(table, kind, bytes)?;
+        }
+        Ok(())
+    }
+}
+
+/// The table key of a JSON id or index value, in the order-preserving encoding of ordered indexes.
+fn encode_key(value: &Value) -> crate::Result<Vec<u8>> {
+    encode_ordered_value(&rmpv::ext::to_value(value)?)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn people(db: &Database) -> JsonCollection {
+        db.json_collection("people", "/id").index("/address/city")
+    }
+
+    #[test]
+    fn documents_round_trip_and_index_on_pointers() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let people = people(&db);
+        people.insert(&json!({"id": 1, "name": "Ada", "address": {"city": "London"}}))?;
+        people.insert(&json!({"id": 2, "name": "Grace", "address": {"city": "New York"}}))?;
+        people.insert(&json!({"id": "guest", "name": "Anon"}))?;
+
+        assert_eq!(people.len()?, 3);
+        assert_eq!(people.get(1)?, Some(json!({"id": 1, "name": "Ada", "address": {"city": "London"}})));
+        assert_eq!(people.get("guest")?.and_then(|person| person.get("name").cloned()), Some(json!("Anon")));
+        assert_eq!(people.find_by("/address/city", "London")?.len(), 1);
+
+        let previous = people.upsert(&json!({"id": 2, "name": "Grace", "address": {"city": "London"}}))?;
+        assert_eq!(previous.and_then(|person| person.pointer("/address/city").cloned()), Some(json!("New York")));
+        assert!(people.find_by("/address/city", "New York")?.is_empty());
+        assert_eq!(people.find_by("/address/city", "London")?.len(), 2);
+
+        assert!(people.delete(1)?.is_some());
+        assert_eq!(people.find_by("/address/city", "London")?, [json!({"id": 2, "name": "Grace", "address": {"city": "London"}})]);
+        assert!(matches!(people.find_by("/name", "Grace"), Err(Error::UnknownIndex(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn writes_need_a_free_id() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let people = people(&db);
+        people.insert(&json!({"id": 1}))?;
+        assert!(matches!(people.insert(&json!({"id": 1})), Err(Error::DocumentExists { .. })));
+        assert!(matches!(people.insert(&json!({"name": "Nobody"})), Err(Error::MissingId { .. })));
+        assert_eq!(people.len()?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn added_indexes_cover_documents_after_a_rebuild() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        people(&db).insert(&json!({"id": 1, "name": "Ada", "address": {"city": "London"}}))?;
+        let people = people(&db).index("/name");
+        assert!(people.find_by("/name", "Ada")?.is_empty());
+        assert_eq!(people.rebuild_indexes()?, 2);
+        assert_eq!(people.find_by("/name", "Ada")?.len(), 1);
+        Ok(())
+    }
+}
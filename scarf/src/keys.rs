@@ -0,0 +1,137 @@
+use std::{
+    cmp::Ordering, ops::{BitAnd, BitOr, Sub}
+};
+
+use crate::document::OwnedKey;
+
+fn compare<K: OwnedKey>(a: &K, b: &K) -> Ordering {
+    K::compare(K::as_bytes(a).as_ref(), K::as_bytes(b).as_ref())
+}
+
+/// A set of primary keys kept in redb key order, so set operations are a single sorted merge.
+///
+/// Built from index lookups such as [crate::database::Collection::keys_where], combined with `&`, `|` and `-`,
+/// and resolved with [crate::database::Collection::get_many] once the filter is final.
+#[derive(Clone, Debug)]
+pub struct KeySet<K: OwnedKey> {
+    keys: Vec<K>
+}
+
+impl<K: OwnedKey> Default for KeySet<K> {
+    fn default() -> Self {
+        Self { keys: Vec::new() }
+    }
+}
+
+impl<K: OwnedKey> KeySet<K> {
+    /// Wraps keys that are already sorted and free of duplicates, as read from a redb table.
+    pub(crate) fn from_sorted(keys: Vec<K>) -> Self {
+        Self { keys }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.keys.binary_search_by(|probe| compare(probe, key)).is_ok()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, K> {
+        self.keys.iter()
+    }
+
+    pub fn into_vec(self) -> Vec<K> {
+        self.keys
+    }
+
+    fn merge(self, other: Self, keep_left: bool, keep_both: bool, keep_right: bool) -> Self {
+        let (mut left, mut right) = (self.keys.into_iter().peekable(), other.keys.into_iter().peekable());
+        let mut keys = Vec::new();
+        loop {
+            let order = match (left.peek(), right.peek()) {
+                (Some(a), Some(b)) => compare(a, b),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => break
+            };
+            match order {
+                Ordering::Less => keys.extend(left.next().filter(|_| keep_left)),
+                Ordering::Greater => keys.extend(right.next().filter(|_| keep_right)),
+                Ordering::Equal => {
+                    right.next();
+                    keys.extend(left.next().filter(|_| keep_both));
+                }
+            }
+        }
+        Self { keys }
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        self.merge(other, true, true, true)
+    }
+
+    pub fn intersection(self, other: Self) -> Self {
+        self.merge(other, false, true, false)
+    }
+
+    /// Keys in `self` that aren't in `other`.
+    pub fn difference(self, other: Self) -> Self {
+        self.merge(other, true, false, false)
+    }
+}
+
+impl<K: OwnedKey> FromIterator<K> for KeySet<K> {
+    fn from_iter<I: IntoIterator<Item = K>>(iter: I) -> Self {
+        let mut keys: Vec<K> = iter.into_iter().collect();
+        keys.sort_by(compare);
+        keys.dedup_by(|a, b| compare(a, b).is_eq());
+        Self { keys }
+    }
+}
+
+impl<K: OwnedKey> IntoIterator for KeySet<K> {
+    type Item = K;
+    type IntoIter = std::vec::IntoIter<K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.keys.into_iter()
+    }
+}
+
+impl<'a, K: OwnedKey> IntoIterator for &'a KeySet<K> {
+    type Item = &'a K;
+    type IntoIter = std::slice::Iter<'a, K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.keys.iter()
+    }
+}
+
+impl<K: OwnedKey> BitAnd for KeySet<K> {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        self.intersection(other)
+    }
+}
+
+impl<K: OwnedKey> BitOr for KeySet<K> {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        self.union(other)
+    }
+}
+
+impl<K: OwnedKey> Sub for KeySet<K> {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        self.difference(other)
+    }
+}
@@ -1,5 +1,29 @@
+// Public APIs report failures through `crate::Result` rather than panicking.
+#![deny(clippy::unwrap_used, clippy::expect_used, clippy::panic, clippy::unreachable, clippy::todo, clippy::unimplemented, clippy::indexing_slicing)]
+
+pub mod backup;
+pub mod bitmap;
+pub mod bloom;
 pub mod database;
 pub mod error;
 pub mod document;
+pub mod erased;
+pub mod graph;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod keys;
+pub mod log;
+pub mod meta;
+pub mod multi_get;
+pub mod options;
+pub mod query;
+mod registry;
+pub mod relation;
+pub mod sketch;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod timeseries;
+pub mod tracking;
+pub mod tree;
 
-pub use error::{Error, Result};
\ No newline at end of file
+pub use error::{Error, Result};
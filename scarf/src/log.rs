@@ -0,0 +1,439 @@
+use std::{
+    collections::VecDeque, marker::PhantomData, thread, time::Duration
+};
+
+use chrono::{DateTime, Utc};
+use redb::{ReadableTable, TableDefinition};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    database::{with_table, Database, Transaction}, tracking::ChangeKind
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LogOptions {
+    /// Number of entries written to a segment before a new one is started.
+    pub segment_entries: u64,
+    pub max_segments: Option<usize>,
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        Self {
+            segment_entries: 4096,
+            max_segments: None,
+            max_bytes: None,
+            max_age: None
+        }
+    }
+}
+
+impl LogOptions {
+    pub fn segment_entries(mut self, entries: u64) -> Self {
+        self.segment_entries = entries.max(1);
+        self
+    }
+
+    pub fn max_segments(mut self, segments: usize) -> Self {
+        self.max_segments = Some(segments);
+        self
+    }
+
+    pub fn max_bytes(mut self, bytes: u64) -> Self {
+        self.max_bytes = Some(bytes);
+        self
+    }
+
+    pub fn max_age(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Segment {
+    pub id: u64,
+    pub first_seq: u64,
+    pub last_seq: u64,
+    pub entries: u64,
+    pub bytes: u64,
+    pub created_at: DateTime<Utc>,
+    pub last_write: DateTime<Utc>
+}
+
+/// An append-only sequence of entries, split into segment tables so old data can be pruned by
+/// deleting whole tables.
+///
+/// Sequence numbers start at 0 and are never reused. The newest segment is never pruned, so the
+/// sequence survives even when every other segment has expired.
+#[derive(Debug)]
+pub struct Log<T: Serialize + DeserializeOwned> {
+    database: Database,
+    name: String,
+    options: LogOptions,
+    entry_type: PhantomData<T>
+}
+
+impl<T: Serialize + DeserializeOwned> Clone for Log<T> {
+    fn clone(&self) -> Self {
+        Self {
+            database: self.database.clone(),
+            name: self.name.clone(),
+            options: self.options.clone(),
+            entry_type: PhantomData
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Log<T> {
+    pub(crate) fn new(db: Database, name: String) -> Self {
+        Self {
+            database: db,
+            name,
+            options: LogOptions::default(),
+            entry_type: PhantomData
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn with_options(mut self, options: LogOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn options(&self) -> LogOptions {
+        self.options.clone()
+    }
+
+    fn segments_table_name(&self) -> String {
+        format!("logs/{}/segments", self.name())
+    }
+
+    fn segment_table_name(&self, id: u64) -> String {
+        format!("logs/{}/segments/{}", self.name(), id)
+    }
+
+    pub fn append(&self, entry: T) -> crate::Result<u64> {
+        let txn = self.database.begin_write("append", self.segments_table_name())?;
+        let seq = self.append_in(&txn, [entry])?;
+        txn.commit()?;
+        Ok(seq.unwrap_or_default())
+    }
+
+    /// Appends every entry in one write transaction, returning the sequence number of the last one.
+    pub fn append_many(&self, entries: impl IntoIterator<Item = T>) -> crate::Result<Option<u64>> {
+        let txn = self.database.begin_write("append_many", self.segments_table_name())?;
+        let seq = self.append_in(&txn, entries)?;
+        txn.commit()?;
+        Ok(seq)
+    }
+
+    /// Appends every entry within `txn`. Segments over the configured limits are pruned whenever an append
+    /// starts a new segment, the only time one closes; [Log::prune] applies [LogOptions::max_age] in between.
+    pub fn append_in(&self, txn: &Transaction, entries: impl IntoIterator<Item = T>) -> crate::Result<Option<u64>> {
+        let segments_name = self.segments_table_name();
+        let now = Utc::now();
+        let (mut last_seq, mut rolled_over) = (None, false);
+        {
+            let guard = txn.write_guard("append", &segments_name)?;
+            let mut segments = guard.open_table(TableDefinition::<u64, &[u8]>::new(&segments_name))?;
+            let mut current = match segments.last()? {
+                Some((_, value)) => Some(rmp_serde::from_slice::<Segment>(value.value())?),
+                None => None
+            };
+
+            for entry in entries {
+                let mut segment = match current.take() {
+                    Some(segment) if segment.entries < self.options.segment_entries => segment,
+                    previous => {
+                        rolled_over |= previous.is_some();
+                        let (id, seq) = previous.map(|s| (s.id + 1, s.last_seq + 1)).unwrap_or((0, 0));
+                        Segment {
+                            id,
+                            first_seq: seq,
+                            last_seq: seq,
+                            entries: 0,
+                            bytes: 0,
+                            created_at: now,
+                            last_write: now
+                        }
+                    }
+                };
+                let seq = if segment.entries == 0 { segment.first_seq } else { segment.last_seq + 1 };
+                let encoded = rmp_serde::to_vec_named(&entry)?;
+
+                let segment_name = self.segment_table_name(segment.id);
+                guard.open_table(TableDefinition::<u64, &[u8]>::new(&segment_name))?.insert(seq, encoded.as_slice())?;
+                txn.record_change(&segment_name, ChangeKind::Insert, encoded.len())?;
+
+                segment.last_seq = seq;
+                segment.entries += 1;
+                segment.bytes += encoded.len() as u64;
+                segment.last_write = now;
+                let encoded_segment = rmp_serde::to_vec_named(&segment)?;
+                let kind = match segments.insert(segment.id, encoded_segment.as_slice())? {
+                    Some(_) => ChangeKind::Update,
+                    None => ChangeKind::Insert
+                };
+                txn.record_change(&segments_name, kind, encoded_segment.len())?;
+                last_seq = Some(seq);
+                current = Some(segment);
+            }
+        }
+
+        if rolled_over {
+            self.prune_in(txn, now)?;
+        }
+        Ok(last_seq)
+    }
+
+    pub fn segments(&self) -> crate::Result<Vec<Segment>> {
+        self.segments_in(&self.database.begin_read("segments", self.segments_table_name())?)
+    }
+
+    fn segments_in(&self, txn: &Transaction) -> crate::Result<Vec<Segment>> {
+        let name = self.segments_table_name();
+        with_table!(txn, TableDefinition::<u64, &[u8]>::new(&name), table => {
+            let mut results = Vec::new();
+            for entry in table.iter()? {
+                results.push(rmp_serde::from_slice::<Segment>(entry?.1.value())?);
+            }
+            Ok(results)
+        }, Ok(Vec::new()))
+    }
+
+    pub fn first_seq(&self) -> crate::Result<Option<u64>> {
+        Ok(self.segments()?.first().map(|segment| segment.first_seq))
+    }
+
+    pub fn last_seq(&self) -> crate::Result<Option<u64>> {
+        Ok(self.segments()?.last().map(|segment| segment.last_seq))
+    }
+
+    /// Returns up to `limit` entries with sequence numbers greater than or equal to `seq`.
+    pub fn read_from(&self, seq: u64, limit: usize) -> crate::Result<Vec<(u64, T)>> {
+        let txn = self.database.begin_read("read_from", self.segments_table_name())?;
+        let mut results = Vec::new();
+        for segment in self.segments_in(&txn)? {
+            if results.len() >= limit {
+                break;
+            }
+            if segment.last_seq < seq {
+                continue;
+            }
+            let name = self.segment_table_name(segment.id);
+            with_table!(&txn, TableDefinition::<u64, &[u8]>::new(&name), table => {
+                for entry in table.range(seq..)?.take(limit - results.len()) {
+                    let (key, value) = entry?;
+                    results.push((key.value(), rmp_serde::from_slice::<T>(value.value())?));
+                }
+                crate::Result::Ok(())
+            }, Ok(()))?;
+        }
+        Ok(results)
+    }
+
+    /// Returns the newest `n` entries, oldest first.
+    pub fn tail(&self, n: usize) -> crate::Result<Vec<(u64, T)>> {
+        let txn = self.database.begin_read("tail", self.segments_table_name())?;
+        let mut results = Vec::new();
+        for segment in self.segments_in(&txn)?.into_iter().rev() {
+            if results.len() >= n {
+                break;
+            }
+            let name = self.segment_table_name(segment.id);
+            with_table!(&txn, TableDefinition::<u64, &[u8]>::new(&name), table => {
+                for entry in table.iter()?.rev().take(n - results.len()) {
+                    let (key, value) = entry?;
+                    results.push((key.value(), rmp_serde::from_slice::<T>(value.value())?));
+                }
+                crate::Result::Ok(())
+            }, Ok(()))?;
+        }
+        results.reverse();
+        Ok(results)
+    }
+
+    /// Follows the log from its current end, blocking until new entries are appended.
+    pub fn follow(&self) -> crate::Result<Follow<T>> {
+        let next = self.last_seq()?.map(|seq| seq + 1).unwrap_or_default();
+        Ok(self.follow_from(next))
+    }
+
+    pub fn follow_from(&self, seq: u64) -> Follow<T> {
+        Follow {
+            log: self.clone(),
+            next: seq,
+            buffer: VecDeque::new(),
+            poll_interval: Duration::from_millis(100)
+        }
+    }
+
+    /// Removes closed segments that exceed the configured limits, returning the number removed.
+    pub fn prune(&self) -> crate::Result<usize> {
+        let txn = self.database.begin_write("prune", self.segments_table_name())?;
+        let removed = self.prune_in(&txn, Utc::now())?;
+        txn.commit()?;
+        Ok(removed)
+    }
+
+    fn prune_in(&self, txn: &Transaction, now: DateTime<Utc>) -> crate::Result<usize> {
+        let segments = self.segments_in(txn)?;
+        let closed = segments.len().saturating_sub(1);
+        let mut total_bytes: u64 = segments.iter().map(|segment| segment.bytes).sum();
+        let mut remaining = segments.len();
+        let mut expired = Vec::new();
+
+        for segment in segments.iter().take(closed) {
+            let over_count = self.options.max_segments.is_some_and(|max| remaining > max);
+            let over_bytes = self.options.max_bytes.is_some_and(|max| total_bytes > max);
+            let over_age = self.options.max_age.is_some_and(|max| {
+                now.signed_duration_since(segment.last_write).to_std().unwrap_or_default() > max
+            });
+            if !(over_count || over_bytes || over_age) {
+                break;
+            }
+            total_bytes -= segment.bytes;
+            remaining -= 1;
+            expired.push(segment.id);
+        }
+
+        if !expired.is_empty() {
+            let segments_name = self.segments_table_name();
+            let guard = txn.write_guard("prune", &segments_name)?;
+            let mut table = guard.open_table(TableDefinition::<u64, &[u8]>::new(&segments_name))?;
+            for id in &expired {
+                let segment_name = self.segment_table_name(*id);
+                guard.delete_table(TableDefinition::<u64, &[u8]>::new(&segment_name))?;
+                table.remove(id)?;
+                txn.record_change(&segments_name, ChangeKind::Delete, 0)?;
+            }
+        }
+        Ok(expired.len())
+    }
+}
+
+/// Blocking iterator over entries appended to a [Log], polling for new entries once caught up.
+pub struct Follow<T: Serialize + DeserializeOwned> {
+    log: Log<T>,
+    next: u64,
+    buffer: VecDeque<(u64, T)>,
+    poll_interval: Duration
+}
+
+impl<T: Serialize + DeserializeOwned> Follow<T> {
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Returns the next entry if one is available without waiting.
+    pub fn try_next(&mut self) -> crate::Result<Option<(u64, T)>> {
+        if self.buffer.is_empty() {
+            self.buffer.extend(self.log.read_from(self.next, 256)?);
+        }
+        let entry = self.buffer.pop_front();
+        if let Some((seq, _)) = &entry {
+            self.next = seq + 1;
+        }
+        Ok(entry)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Iterator for Follow<T> {
+    type Item = crate::Result<(u64, T)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.try_next() {
+                Ok(Some(entry)) => return Some(Ok(entry)),
+                Ok(None) => thread::sleep(self.poll_interval),
+                Err(e) => return Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(options: LogOptions) -> crate::Result<Log<String>> {
+        Ok(Database::open_in_memory()?.log::<String>("events").with_options(options))
+    }
+
+    fn entries(count: u64) -> impl Iterator<Item = String> {
+        (0..count).map(|n| format!("event {n}"))
+    }
+
+    fn seqs(entries: Vec<(u64, String)>) -> Vec<u64> {
+        entries.into_iter().map(|(seq, _)| seq).collect()
+    }
+
+    #[test]
+    fn appends_number_entries_across_segments() -> crate::Result<()> {
+        let events = log(LogOptions::default().segment_entries(3))?;
+        assert_eq!(events.append("first".to_string())?, 0);
+        assert_eq!(events.append_many(entries(7))?, Some(7));
+        assert_eq!(events.append_many(entries(0))?, None);
+
+        let segments = events.segments()?;
+        let bounds: Vec<_> = segments.iter().map(|segment| (segment.id, segment.first_seq, segment.last_seq, segment.entries)).collect();
+        assert_eq!(bounds, [(0, 0, 2, 3), (1, 3, 5, 3), (2, 6, 7, 2)]);
+        assert_eq!((events.first_seq()?, events.last_seq()?), (Some(0), Some(7)));
+
+        let read = events.read_from(2, 4)?;
+        assert_eq!(seqs(read.clone()), [2, 3, 4, 5]);
+        assert_eq!(read.first().map(|(_, entry)| entry.as_str()), Some("event 1"));
+        assert_eq!(seqs(events.read_from(5, 100)?), [5, 6, 7]);
+        assert!(events.read_from(8, 100)?.is_empty());
+        assert_eq!(seqs(events.tail(4)?), [4, 5, 6, 7]);
+        assert_eq!(seqs(events.tail(100)?), (0..=7).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn retention_prunes_closed_segments_on_rollover() -> crate::Result<()> {
+        let events = log(LogOptions::default().segment_entries(2).max_segments(2))?;
+        events.append_many(entries(4))?;
+        assert_eq!(events.segments()?.len(), 2);
+        events.append_many(entries(3))?;
+        assert_eq!(events.segments()?.iter().map(|segment| segment.id).collect::<Vec<_>>(), [2, 3]);
+        assert_eq!(events.first_seq()?, Some(4));
+        assert_eq!(seqs(events.read_from(0, 100)?), [4, 5, 6]);
+
+        let sized = log(LogOptions::default().segment_entries(2).max_bytes(1))?;
+        sized.append_many(entries(5))?;
+        assert_eq!(sized.segments()?.len(), 1, "every closed segment is over the byte limit");
+        assert_eq!(sized.last_seq()?, Some(4));
+        Ok(())
+    }
+
+    #[test]
+    fn appends_within_a_segment_leave_pruning_alone() -> crate::Result<()> {
+        let events = log(LogOptions::default().segment_entries(3))?;
+        events.append_many(entries(8))?;
+        let strict = events.clone().with_options(LogOptions::default().segment_entries(3).max_segments(1));
+        strict.append("within the last segment".to_string())?;
+        assert_eq!(strict.segments()?.len(), 3);
+        assert_eq!(strict.prune()?, 2);
+        assert_eq!(strict.segments()?.len(), 1);
+
+        let aged = events.clone().with_options(LogOptions::default().segment_entries(3).max_age(Duration::ZERO));
+        let ids = || -> crate::Result<Vec<u64>> { Ok(aged.segments()?.iter().map(|segment| segment.id).collect()) };
+        aged.append("opens segment 3".to_string())?;
+        assert_eq!(ids()?, [3]);
+        aged.append_many(entries(2))?;
+        thread::sleep(Duration::from_millis(5));
+        aged.append("opens segment 4".to_string())?;
+        assert_eq!(ids()?, [4]);
+        assert_eq!(aged.prune()?, 0, "the newest segment is never pruned");
+        assert_eq!(aged.last_seq()?, Some(12));
+        Ok(())
+    }
+}
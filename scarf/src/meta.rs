@@ -0,0 +1,353 @@
+use std::{fs, path::Path};
+
+use redb::{MultimapTableHandle, ReadableTable, TableDefinition};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    database::{with_table, Database, DatabaseLocation, Transaction}, document::IndexKeyFormat, options::DatabaseOptions, tracking::ChangeKind, Error
+};
+
+/// Version of the on-disk layout written by this build of scarf.
+pub const FORMAT_VERSION: u32 = 2;
+
+pub(crate) const META_TABLE: &str = "scarf/meta";
+const FORMAT_VERSION_KEY: &str = "format_version";
+
+pub(crate) fn read<T: DeserializeOwned>(txn: &Transaction, key: impl AsRef<str>) -> crate::Result<Option<T>> {
+    with_table!(txn, TableDefinition::<&str, &[u8]>::new(META_TABLE), table => {
+        match table.get(key.as_ref())? {
+            Some(value) => Ok(Some(rmp_serde::from_slice::<T>(value.value())?)),
+            None => Ok(None)
+        }
+    }, Ok(None))
+}
+
+pub(crate) fn write<T: Serialize>(txn: &Transaction, key: impl AsRef<str>, value: &T) -> crate::Result<()> {
+    let encoded = rmp_serde::to_vec_named(value)?;
+    let guard = txn.write_guard("write_meta", META_TABLE)?;
+    let kind = match guard.open_table(TableDefinition::<&str, &[u8]>::new(META_TABLE))?.insert(key.as_ref(), encoded.as_slice())? {
+        Some(_) => ChangeKind::Update,
+        None => ChangeKind::Insert
+    };
+    drop(guard);
+    txn.record_change(META_TABLE, kind, encoded.len())
+}
+
+pub(crate) fn remove(txn: &Transaction, key: impl AsRef<str>) -> crate::Result<bool> {
+    let guard = txn.write_guard("remove_meta", META_TABLE)?;
+    let removed = guard.open_table(TableDefinition::<&str, &[u8]>::new(META_TABLE))?.remove(key.as_ref())?.is_some();
+    drop(guard);
+    if removed {
+        txn.record_change(META_TABLE, ChangeKind::Delete, 0)?;
+    }
+    Ok(removed)
+}
+
+const APP_PREFIX: &str = "app/";
+
+/// Application metadata kept in scarf's meta table under `app/`, apart from every collection and included in
+/// backups. See [Database::app_meta].
+#[derive(Clone)]
+pub struct AppMeta {
+    database: Database
+}
+
+impl AppMeta {
+    pub(crate) fn new(database: Database) -> Self {
+        Self { database }
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: impl AsRef<str>) -> crate::Result<Option<T>> {
+        self.get_in(&self.database.begin_read("app_meta_get", META_TABLE)?, key)
+    }
+
+    pub fn get_in<T: DeserializeOwned>(&self, txn: &Transaction, key: impl AsRef<str>) -> crate::Result<Option<T>> {
+        read(txn, format!("{APP_PREFIX}{}", key.as_ref()))
+    }
+
+    pub fn set<T: Serialize>(&self, key: impl AsRef<str>, value: &T) -> crate::Result<()> {
+        let txn = self.database.begin_write("app_meta_set", META_TABLE)?;
+        self.set_in(&txn, key, value)?;
+        txn.commit()
+    }
+
+    pub fn set_in<T: Serialize>(&self, txn: &Transaction, key: impl AsRef<str>, value: &T) -> crate::Result<()> {
+        write(txn, format!("{APP_PREFIX}{}", key.as_ref()), value)
+    }
+
+    /// Removes `key`, returning whether it was set.
+    pub fn remove(&self, key: impl AsRef<str>) -> crate::Result<bool> {
+        let txn = self.database.begin_write("app_meta_remove", META_TABLE)?;
+        let removed = self.remove_in(&txn, key)?;
+        txn.commit()?;
+        Ok(removed)
+    }
+
+    pub fn remove_in(&self, txn: &Transaction, key: impl AsRef<str>) -> crate::Result<bool> {
+        remove(txn, format!("{APP_PREFIX}{}", key.as_ref()))
+    }
+
+    /// Every key set by the application, in order.
+    pub fn keys(&self) -> crate::Result<Vec<String>> {
+        self.keys_in(&self.database.begin_read("app_meta_keys", META_TABLE)?)
+    }
+
+    pub fn keys_in(&self, txn: &Transaction) -> crate::Result<Vec<String>> {
+        with_table!(txn, TableDefinition::<&str, &[u8]>::new(META_TABLE), table => {
+            let mut keys = Vec::new();
+            for entry in table.range(APP_PREFIX..)? {
+                match entry?.0.value().strip_prefix(APP_PREFIX) {
+                    Some(key) => keys.push(key.to_string()),
+                    None => break
+                }
+            }
+            crate::Result::Ok(keys)
+        }, Ok(Vec::new()))
+    }
+}
+
+fn index_format_key(collection: &str) -> String {
+    format!("index_format/{collection}")
+}
+
+/// The index key format of `collection`, [IndexKeyFormat::Raw] unless recorded otherwise.
+pub(crate) fn index_format(txn: &Transaction, collection: impl AsRef<str>) -> crate::Result<IndexKeyFormat> {
+    Ok(read::<IndexKeyFormat>(txn, index_format_key(collection.as_ref()))?.unwrap_or_default())
+}
+
+pub(crate) fn set_index_format(txn: &Transaction, collection: impl AsRef<str>, format: IndexKeyFormat) -> crate::Result<()> {
+    match format {
+        IndexKeyFormat::Raw => remove(txn, index_format_key(collection.as_ref())).map(|_| ()),
+        format => write(txn, index_format_key(collection.as_ref()), &format)
+    }
+}
+
+/// Chunked maintenance tasks that persist a resume cursor per collection.
+pub(crate) const REBUILD_INDEXES: &str = "rebuild_indexes";
+pub(crate) const CHECK_INDEXES: &str = "check_indexes";
+const MAINTENANCE_TASKS: [&str; 2] = [REBUILD_INDEXES, CHECK_INDEXES];
+
+fn maintenance_key(collection: &str, task: &str) -> String {
+    format!("maintenance/{collection}/{task}")
+}
+
+/// Where the unfinished chunked `task` on `collection` stopped, if one is under way.
+pub(crate) fn maintenance_cursor<T: DeserializeOwned>(txn: &Transaction, collection: &str, task: &str) -> crate::Result<Option<T>> {
+    read(txn, maintenance_key(collection, task))
+}
+
+pub(crate) fn set_maintenance_cursor<T: Serialize>(txn: &Transaction, collection: &str, task: &str, cursor: &T) -> crate::Result<()> {
+    write(txn, maintenance_key(collection, task), cursor)
+}
+
+pub(crate) fn remove_maintenance_cursor(txn: &Transaction, collection: &str, task: &str) -> crate::Result<bool> {
+    remove(txn, maintenance_key(collection, task))
+}
+
+/// Moves the maintenance cursors of `from` to collection `to`, or discards them if `to` is `None`.
+pub(crate) fn move_maintenance_cursors(txn: &Transaction, from: &str, to: Option<&str>) -> crate::Result<()> {
+    for task in MAINTENANCE_TASKS {
+        let Some(cursor) = maintenance_cursor::<rmpv::Value>(txn, from, task)? else {
+            continue;
+        };
+        remove_maintenance_cursor(txn, from, task)?;
+        if let Some(to) = to {
+            set_maintenance_cursor(txn, to, task, &cursor)?;
+        }
+    }
+    Ok(())
+}
+
+/// Marks every collection that already has index tables as using base64 index keys.
+fn mark_base64_indexes(txn: &Transaction) -> crate::Result<()> {
+    let guard = txn.write_guard("upgrade_format", META_TABLE)?;
+    let mut collections: Vec<String> = guard
+        .list_multimap_tables()?
+        .filter_map(|handle| Some(handle.name().strip_prefix("collections/")?.rsplit_once("/index/")?.0.to_string()))
+        .collect();
+    drop(guard);
+    collections.sort();
+    collections.dedup();
+    for collection in collections {
+        set_index_format(txn, collection, IndexKeyFormat::Base64)?;
+    }
+    Ok(())
+}
+
+/// A step that brings a database written with format `from` up to format `to`.
+pub(crate) struct FormatUpgrade {
+    pub from: u32,
+    pub to: u32,
+    pub description: &'static str,
+    pub apply: fn(&Transaction) -> crate::Result<()>
+}
+
+fn upgrades() -> Vec<FormatUpgrade> {
+    vec![FormatUpgrade {
+        from: 0,
+        to: 1,
+        description: "stamp format version on databases created before versioning",
+        apply: |_| Ok(())
+    }, FormatUpgrade {
+        from: 1,
+        to: 2,
+        description: "keep base64 index keys for collections indexed before raw index keys",
+        apply: mark_base64_indexes
+    }]
+}
+
+fn is_empty(txn: &Transaction) -> crate::Result<bool> {
+    match txn {
+        Transaction::Read(txn, _) => {
+            let txn = txn.read()?;
+            Ok(txn.list_tables()?.next().is_none() && txn.list_multimap_tables()?.next().is_none())
+        },
+        Transaction::Write(txn, _) => {
+            let txn = txn.lock()?;
+            Ok(txn.list_tables()?.next().is_none() && txn.list_multimap_tables()?.next().is_none())
+        }
+    }
+}
+
+pub(crate) fn format_version(db: &Database) -> crate::Result<u32> {
+    let txn = db.begin_read("format_version", META_TABLE)?;
+    Ok(read::<u32>(&txn, FORMAT_VERSION_KEY)?.unwrap_or_default())
+}
+
+fn backup_path(path: &Path, version: u32) -> std::path::PathBuf {
+    let mut name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+    name.push(format!(".format-v{version}.bak"));
+    path.with_file_name(name)
+}
+
+/// Stamps new databases with [FORMAT_VERSION] and upgrades older ones, refusing to open newer ones.
+pub(crate) fn ensure_format(db: &Database, options: &DatabaseOptions) -> crate::Result<()> {
+    let txn = db.begin_read("ensure_format", META_TABLE)?;
+    let stored = read::<u32>(&txn, FORMAT_VERSION_KEY)?;
+    let fresh = stored.is_none() && is_empty(&txn)?;
+    drop(txn);
+
+    let found = match stored {
+        Some(version) => version,
+        None if fresh => {
+            let txn = db.begin_write("ensure_format", META_TABLE)?;
+            write(&txn, FORMAT_VERSION_KEY, &FORMAT_VERSION)?;
+            return txn.commit();
+        },
+        None => 0
+    };
+
+    if found > FORMAT_VERSION {
+        return Err(Error::UnsupportedFormat { found, supported: FORMAT_VERSION });
+    }
+    if found == FORMAT_VERSION {
+        return Ok(());
+    }
+    if !options.upgrade_format {
+        return Err(Error::UpgradeRequired { found, current: FORMAT_VERSION });
+    }
+
+    if options.backup_before_upgrade && let DatabaseLocation::Filesystem(path) = db.location() {
+        fs::copy(&path, backup_path(&path, found))?;
+    }
+
+    let available = upgrades();
+    let txn = db.begin_write("upgrade_format", META_TABLE)?;
+    let mut version = found;
+    while version < FORMAT_VERSION {
+        let upgrade = available.iter().find(|upgrade| upgrade.from == version).ok_or(Error::MissingUpgrade(version))?;
+        tracing::info!(from = upgrade.from, to = upgrade.to, "upgrading database format: {}", upgrade.description);
+        (upgrade.apply)(&txn)?;
+        version = upgrade.to;
+    }
+    write(&txn, FORMAT_VERSION_KEY, &version)?;
+    txn.commit()
+}
+
+/// One kind of table in the on-disk layout. `name` is a pattern where `{...}` stands for a user-chosen name.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TableLayout {
+    pub name: String,
+    pub multimap: bool,
+    pub key: String,
+    pub value: String,
+    pub description: String
+}
+
+/// One kind of key in the meta table, whose values are named msgpack.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MetaKeyLayout {
+    pub key: String,
+    pub value: String,
+    pub description: String
+}
+
+/// A way index values are turned into index table keys, chosen per index by [crate::document::IndexSpec].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IndexEncoding {
+    pub name: String,
+    pub description: String
+}
+
+/// A machine-readable description of the on-disk layout, returned by [Database::describe_format].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FormatDescription {
+    /// The version written by this build, [FORMAT_VERSION].
+    pub format_version: u32,
+    /// The version stamped in the described database.
+    pub stored_version: u32,
+    /// How documents, log entries, samples and meta values are encoded.
+    pub value_codec: String,
+    pub tables: Vec<TableLayout>,
+    pub meta_keys: Vec<MetaKeyLayout>,
+    pub index_encodings: Vec<IndexEncoding>
+}
+
+fn table(name: &str, multimap: bool, key: &str, value: &str, description: &str) -> TableLayout {
+    TableLayout { name: name.to_string(), multimap, key: key.to_string(), value: value.to_string(), description: description.to_string() }
+}
+
+fn meta_key(key: &str, value: &str, description: &str) -> MetaKeyLayout {
+    MetaKeyLayout { key: key.to_string(), value: value.to_string(), description: description.to_string() }
+}
+
+fn index_encoding(name: &str, description: &str) -> IndexEncoding {
+    IndexEncoding { name: name.to_string(), description: description.to_string() }
+}
+
+pub(crate) fn describe(db: &Database) -> crate::Result<FormatDescription> {
+    let index_key = "index key bytes (&[u8]), or their url-safe unpadded base64 (&str) when index_format/{collection} is base64";
+    Ok(FormatDescription {
+        format_version: FORMAT_VERSION,
+        stored_version: format_version(db)?,
+        value_codec: String::from("msgpack with named struct fields (rmp_serde::to_vec_named)"),
+        tables: vec![
+            table(META_TABLE, false, "&str", "msgpack", "database metadata, see meta_keys"),
+            table("collections/{collection}", false, "primary key", "msgpack document", "documents by primary key"),
+            table("collections/{collection}/index/{index}", true, index_key, "primary key", "secondary index entries, see index_encodings"),
+            table("locks/{collection}", false, "primary key", "msgpack lock", "advisory document locks with owner and expiry"),
+            table("logs/{log}/segments", false, "u64 segment id", "msgpack segment", "segment bookkeeping of an append-only log"),
+            table("logs/{log}/segments/{segment}", false, "u64 sequence number", "msgpack entry", "entries of one log segment"),
+            table("timeseries/{series}", false, "(&str series, i64 microseconds since the unix epoch)", "msgpack sample", "raw time series points"),
+            table("timeseries/{series}/rollups/{bucket}ms", false, "(&str series, i64 bucket start in microseconds)", "msgpack rollup", "pre-aggregated buckets of a time series"),
+            table("edges/{graph}/out", true, "node key", "node key", "outgoing edges of a graph"),
+            table("edges/{graph}/in", true, "node key", "node key", "incoming edges of a graph"),
+            table("relations/{relation}/a", true, "primary key of A", "primary key of B", "forward side of a relation"),
+            table("relations/{relation}/b", true, "primary key of B", "primary key of A", "reverse side of a relation"),
+        ],
+        meta_keys: vec![
+            meta_key(FORMAT_VERSION_KEY, "u32", "format version of the database"),
+            meta_key("index_format/{collection}", "\"raw\" | \"base64\"", "index key format of a collection; absent means raw"),
+            meta_key("app/{key}", "msgpack", "application metadata, see Database::app_meta"),
+        ],
+        index_encodings: vec![
+            index_encoding("raw", "the msgpack encoding of the index value"),
+            index_encoding("truncated", "the first n bytes of the msgpack encoding followed by its 64-bit FNV-1a hash, big-endian, when longer than n bytes"),
+            index_encoding("fnv64", "64-bit FNV-1a of the msgpack encoding, big-endian"),
+            index_encoding("fnv128", "128-bit FNV-1a of the msgpack encoding, big-endian"),
+            index_encoding("custom_hash", "an application-defined hash of the msgpack encoding, such as keyed SipHash-2-4"),
+            index_encoding("multikey", "one entry per element of an array value, each stored with the index's other encoding"),
+            index_encoding("ordered", "a type tag byte (nil 0x01, bool 0x02/0x03, int 0x10, float 0x11, string 0x20, binary 0x21, array 0x30, map or ext 0x40) followed by: ints as 128-bit big-endian with the sign bit flipped; floats as f64 bits, inverted if negative and sign bit set otherwise; strings and binary with 0x00 escaped as 0x00 0xff and terminated by 0x00 0x00; arrays as their encoded elements terminated by 0x00; maps and ext as msgpack"),
+        ]
+    })
+}
@@ -0,0 +1,94 @@
+use std::{any::Any, collections::HashMap, fmt::Debug, marker::PhantomData};
+
+use crate::{
+    database::{Collection, CollectionOperation, Database, Transaction}, document::Document
+};
+
+type Lookup = Box<dyn FnOnce(&Transaction) -> crate::Result<Box<dyn Any>>>;
+
+/// Collects lookups across any number of collections and resolves them in a single read transaction,
+/// see [Database::multi_get].
+pub struct MultiGet {
+    database: Database,
+    lookups: Vec<Lookup>
+}
+
+/// Identifies one lookup queued on a [MultiGet], used to take its result from [MultiGetResults].
+pub struct Pending<T> {
+    position: usize,
+    result: PhantomData<fn() -> T>
+}
+
+impl<T> Clone for Pending<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Pending<T> {}
+
+impl<T> Debug for Pending<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pending").field("position", &self.position).finish()
+    }
+}
+
+impl MultiGet {
+    pub(crate) fn new(db: Database) -> Self {
+        Self { database: db, lookups: Vec::new() }
+    }
+
+    fn push<T: 'static>(&mut self, lookup: impl FnOnce(&Transaction) -> crate::Result<T> + 'static) -> Pending<T> {
+        self.lookups.push(Box::new(move |txn| Ok(Box::new(lookup(txn)?) as Box<dyn Any>)));
+        Pending { position: self.lookups.len() - 1, result: PhantomData }
+    }
+
+    /// Queues a lookup of `id` in `collection`.
+    pub fn get<T: Document + 'static>(&mut self, collection: &Collection<T>, id: T::PrimaryKey) -> Pending<Option<T>> {
+        let collection = collection.clone();
+        self.push(move |txn| CollectionOperation::new("multi_get", &collection, txn).get(&id))
+    }
+
+    /// Queues a lookup of every id in `collection`, resolved in the same order as `ids`.
+    pub fn get_many<T: Document + 'static>(&mut self, collection: &Collection<T>, ids: impl IntoIterator<Item = T::PrimaryKey>) -> Pending<Vec<Option<T>>> {
+        let (collection, ids): (_, Vec<_>) = (collection.clone(), ids.into_iter().collect());
+        self.push(move |txn| CollectionOperation::new("multi_get", &collection, txn).get_many(ids))
+    }
+
+    pub fn len(&self) -> usize {
+        self.lookups.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lookups.is_empty()
+    }
+
+    /// Resolves every queued lookup from one read transaction.
+    pub fn execute(self) -> crate::Result<MultiGetResults> {
+        let txn = self.database.begin_read("multi_get", "collections")?;
+        let mut results = HashMap::new();
+        for (position, lookup) in self.lookups.into_iter().enumerate() {
+            results.insert(position, lookup(&txn)?);
+        }
+        Ok(MultiGetResults { results })
+    }
+}
+
+/// The resolved lookups of a [MultiGet].
+pub struct MultiGetResults {
+    results: HashMap<usize, Box<dyn Any>>
+}
+
+impl MultiGetResults {
+    /// Takes the result of `pending`. Returns `None` if it was already taken or belongs to another [MultiGet].
+    pub fn take<T: 'static>(&mut self, pending: Pending<T>) -> Option<T> {
+        let result = self.results.remove(&pending.position)?;
+        match result.downcast::<T>() {
+            Ok(result) => Some(*result),
+            Err(result) => {
+                self.results.insert(pending.position, result);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::path::Path;
+
+use derive_builder::Builder;
+use redb::backends::InMemoryBackend;
+
+use crate::{
+    backup::{self, SnapshotConfig}, database::{Database, DatabaseLocation}, tracking::CheckpointPolicy
+};
+
+/// Settings applied when opening a [Database]. Build with [Database::builder].
+#[derive(Builder, Clone, Debug)]
+#[builder(pattern = "owned", name = "DatabaseBuilder", build_fn(name = "options", error = "crate::Error"))]
+pub struct DatabaseOptions {
+    /// Copy the database file aside before running format upgrades.
+    #[builder(default = "true")]
+    pub backup_before_upgrade: bool,
+
+    /// Upgrade older file formats on open instead of failing with [crate::Error::UpgradeRequired].
+    #[builder(default = "true")]
+    pub upgrade_format: bool,
+
+    /// Number of rotated copies of the database file to keep, taken each time it's opened. `0` disables.
+    #[builder(default)]
+    pub backup_on_open: usize,
+
+    /// Where and how often [Database::schedule_snapshots] snapshots the database.
+    #[builder(default, setter(strip_option))]
+    pub snapshots: Option<SnapshotConfig>,
+
+    /// Batch fsyncs for high-ingest workloads, see [CheckpointPolicy].
+    #[builder(default, setter(strip_option))]
+    pub ingest: Option<CheckpointPolicy>
+}
+
+impl Default for DatabaseOptions {
+    fn default() -> Self {
+        Self {
+            backup_before_upgrade: true,
+            upgrade_format: true,
+            backup_on_open: 0,
+            snapshots: None,
+            ingest: None
+        }
+    }
+}
+
+impl DatabaseBuilder {
+    pub fn open(self, path: impl AsRef<Path>) -> crate::Result<Database> {
+        let options = self.options()?;
+        backup::rotate(path.as_ref(), options.backup_on_open)?;
+        let db = redb::Database::create(path.as_ref())?;
+        Database::initialize(db, DatabaseLocation::file(path), options)
+    }
+
+    pub fn open_in_memory(self) -> crate::Result<Database> {
+        let options = self.options()?;
+        let db = redb::Database::builder().create_with_backend(InMemoryBackend::new())?;
+        Database::initialize(db, DatabaseLocation::memory(), options)
+    }
+}
@@ -0,0 +1,756 @@
+use std::{
+    collections::{HashMap, HashSet}, fmt::{self, Display}, ops::{Bound, RangeBounds}, str::FromStr
+};
+
+use base64::prelude::*;
+use redb::{Key, ReadableTable, TableDefinition, Value};
+
+use crate::{
+    database::{with_table, Collection, CollectionOperation, Transaction}, document::{decode_key, index_names, index_spec, Document}, keys::KeySet, Error
+};
+
+type Predicate<T> = Box<dyn Fn(&T) -> bool>;
+
+/// Encoded primary keys to leave out of a [Query], looked up once per run.
+type Exclusion<T> = Box<dyn Fn(&CollectionOperation<T>) -> crate::Result<HashSet<Vec<u8>>>>;
+
+/// A candidate read from the index tables: its encoded [Query::order_by] value, if any, and primary key.
+type IndexEntry<K> = (Option<Vec<u8>>, K);
+
+/// Direction of [Query::order_by].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Order {
+    Asc,
+    Desc
+}
+
+/// A position in the results of a [Query], handed out with each [Page] and passed back through [Query::after].
+///
+/// It holds the last primary key (and [Query::order_by] value) rather than an offset, so writes between pages
+/// don't shift the next one. It round-trips through an opaque URL-safe token via [Display] and [FromStr].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cursor {
+    index: Option<Vec<u8>>,
+    key: Vec<u8>
+}
+
+impl Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut encoded = Vec::new();
+        if let Some(index) = &self.index {
+            encoded.push(1);
+            encoded.extend((index.len() as u32).to_be_bytes());
+            encoded.extend(index);
+        } else {
+            encoded.push(0);
+        }
+        encoded.extend(&self.key);
+        f.write_str(&BASE64_URL_SAFE_NO_PAD.encode(encoded))
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = Error;
+
+    fn from_str(token: &str) -> crate::Result<Self> {
+        let invalid = || Error::InvalidCursor(token.to_string());
+        let encoded = BASE64_URL_SAFE_NO_PAD.decode(token).map_err(|_| invalid())?;
+        match encoded.split_first() {
+            Some((0, key)) => Ok(Self { index: None, key: key.to_vec() }),
+            Some((1, rest)) => {
+                let (length, rest) = rest.split_first_chunk::<4>().ok_or_else(invalid)?;
+                let length = u32::from_be_bytes(*length) as usize;
+                let (index, key) = rest.split_at_checked(length).ok_or_else(invalid)?;
+                Ok(Self { index: Some(index.to_vec()), key: key.to_vec() })
+            },
+            _ => Err(invalid())
+        }
+    }
+}
+
+/// One page of [Query::page] results. `cursor` resumes after the last document, and is `None` once a page
+/// comes back short.
+#[derive(Clone, Debug)]
+pub struct Page<T> {
+    pub documents: Vec<T>,
+    pub cursor: Option<Cursor>
+}
+
+/// A typed query over a [Collection]: equality on indexed keys, a primary key range, anti-joins and in-memory filters.
+///
+/// ```ignore
+/// let adults = users.query().eq("country", "NL").filter(|user| user.age >= 18).limit(10).collect()?;
+/// ```
+///
+/// With no [Query::eq] or [Query::prefix] conditions the main table is scanned in key order; otherwise the matching keys are
+/// intersected from the index tables first and only those documents are read.
+pub struct Query<T: Document> {
+    collection: Collection<T>,
+    equals: Vec<(String, rmpv::Value)>,
+    params: Vec<(String, String)>,
+    prefixes: Vec<(String, Vec<rmpv::Value>)>,
+    range: (Bound<T::PrimaryKey>, Bound<T::PrimaryKey>),
+    filters: Vec<Predicate<T>>,
+    exclusions: Vec<Exclusion<T>>,
+    order: Option<(String, Order)>,
+    after: Option<Cursor>,
+    duplicates: bool,
+    /// Whether a condition has to be checked against documents, which rules out [Query::index_keys].
+    verified: bool,
+    skip: usize,
+    limit: Option<usize>
+}
+
+impl<T: Document> Query<T> {
+    pub(crate) fn new(collection: Collection<T>) -> Self {
+        Self {
+            collection,
+            equals: Vec::new(),
+            params: Vec::new(),
+            prefixes: Vec::new(),
+            range: (Bound::Unbounded, Bound::Unbounded),
+            filters: Vec::new(),
+            exclusions: Vec::new(),
+            order: None,
+            after: None,
+            duplicates: false,
+            verified: false,
+            skip: 0,
+            limit: None
+        }
+    }
+
+    /// Only documents whose index `key` holds `value`.
+    pub fn eq(mut self, key: impl AsRef<str>, value: impl Into<rmpv::Value>) -> Self {
+        let value = value.into();
+        self.verified |= Self::is_lossy(key.as_ref(), &value);
+        self.equals.push((key.as_ref().to_string(), value));
+        self
+    }
+
+    /// Only documents whose index `key` holds the value bound to parameter `name` when a [PreparedQuery] runs.
+    /// Running the query unprepared, or without binding `name`, fails with [Error::UnboundParameter].
+    pub fn eq_param(mut self, key: impl AsRef<str>, name: impl AsRef<str>) -> Self {
+        self.params.push((key.as_ref().to_string(), name.as_ref().to_string()));
+        self
+    }
+
+    /// Only documents whose compound index `key` starts with the values in `prefix`.
+    pub fn prefix<V: Into<rmpv::Value>>(mut self, key: impl AsRef<str>, prefix: impl IntoIterator<Item = V>) -> Self {
+        self.prefixes.push((key.as_ref().to_string(), prefix.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    /// Only documents whose primary key falls in `range`.
+    pub fn range(mut self, range: impl RangeBounds<T::PrimaryKey>) -> Self {
+        self.range = (range.start_bound().cloned(), range.end_bound().cloned());
+        self
+    }
+
+    /// Only documents for which `predicate` returns `true`, checked after decoding.
+    pub fn filter(mut self, predicate: impl Fn(&T) -> bool + 'static) -> Self {
+        self.filters.push(Box::new(predicate));
+        self.verified = true;
+        self
+    }
+
+    /// Leaves out documents whose index `key` holds any of `values`. The excluded primary keys are read from the
+    /// index once per run and checked from a hash set, before any document is decoded.
+    pub fn not_in<V: Into<rmpv::Value>>(mut self, key: impl AsRef<str>, values: impl IntoIterator<Item = V>) -> Self {
+        let (key, values): (String, Vec<rmpv::Value>) = (key.as_ref().to_string(), values.into_iter().map(Into::into).collect());
+        self.verified |= values.iter().any(|value| Self::is_lossy(&key, value));
+        self.exclusions.push(Box::new(move |operation| {
+            let mut excluded = HashSet::new();
+            for value in &values {
+                excluded.extend(operation.keys_where(&key, value)?.into_iter().map(|id| T::PrimaryKey::as_bytes(&id).as_ref().to_vec()));
+            }
+            Ok(excluded)
+        }));
+        self
+    }
+
+    /// Leaves out documents that a result of `subquery` points at through `link`, e.g. posts without comments:
+    ///
+    /// ```ignore
+    /// let quiet = posts.query().not_exists(comments.query(), |comment| comment.post_id).collect()?;
+    /// ```
+    ///
+    /// The subquery runs once per run in the same transaction, and its linked keys are checked from a hash set.
+    pub fn not_exists<U: Document + 'static>(mut self, subquery: Query<U>, link: impl Fn(&U) -> T::PrimaryKey + 'static) -> Self {
+        self.exclusions.push(Box::new(move |operation| {
+            let mut excluded = HashSet::new();
+            subquery.visit(operation.transaction(), subquery.limit, |_, _, document| {
+                excluded.insert(T::PrimaryKey::as_bytes(&link(&document)).as_ref().to_vec());
+            })?;
+            Ok(excluded)
+        }));
+        self
+    }
+
+    /// Returns documents in the order of index `key`, which must be [ordered](crate::document::IndexSpec::ordered),
+    /// reading the index table instead of sorting in memory. Documents with no entry in the index are skipped.
+    pub fn order_by(mut self, key: impl AsRef<str>, order: Order) -> Self {
+        self.order = Some((key.as_ref().to_string(), order));
+        self
+    }
+
+    /// Returns a document once per entry it has in the [Query::order_by] index instead of once at its first
+    /// entry, e.g. a post at each of its tags when ordering by a [multikey](crate::document::IndexSpec::multikey)
+    /// index. Equal values within one document's array still count once. Has no effect without [Query::order_by].
+    pub fn allow_duplicates(mut self) -> Self {
+        self.duplicates = true;
+        self
+    }
+
+    /// Only documents after `cursor`, as returned with a previous [Page] of the same query.
+    pub fn after(mut self, cursor: Cursor) -> Self {
+        self.after = Some(cursor);
+        self
+    }
+
+    /// Leaves out the first `skip` matches. Without [Query::filter] predicates, skipped documents are never decoded.
+    /// Ignored once [Query::after] is set, so a skipped first [Query::page] doesn't skip again on every later page.
+    pub fn skip(mut self, skip: usize) -> Self {
+        self.skip = skip;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Checks the query's index keys once, so a hot query fails here instead of on its first run, and returns
+    /// it as a [PreparedQuery] to run with different [Params] for its [Query::eq_param] conditions.
+    pub fn prepare(self) -> crate::Result<PreparedQuery<T>> {
+        let indexes = index_names::<T>();
+        let conditions = self.equals.iter().map(|(key, _)| key).chain(self.params.iter().map(|(key, _)| key));
+        let mut ordered = self.prefixes.iter().map(|(key, _)| key).chain(self.order.iter().map(|(key, _)| key));
+        for key in conditions.chain(ordered.clone()) {
+            if !indexes.contains(key) {
+                return Err(Error::UnknownIndex(key.clone()));
+            }
+        }
+        if let Some(key) = ordered.find(|key| !index_spec::<T>(key).is_ordered()) {
+            return Err(Error::UnorderedIndex(key.clone()));
+        }
+        Ok(PreparedQuery { query: self })
+    }
+
+    pub fn collect(&self) -> crate::Result<Vec<T>> {
+        self.collect_in(&self.collection.database().begin_read("query", self.collection.main_table_name())?)
+    }
+
+    pub fn collect_in(&self, txn: &Transaction) -> crate::Result<Vec<T>> {
+        self.collect_bound(txn, &Params::new())
+    }
+
+    fn collect_bound(&self, txn: &Transaction, params: &Params) -> crate::Result<Vec<T>> {
+        let mut results = Vec::new();
+        self.visit_bound(txn, self.limit, params, |_, _, document| results.push(document))?;
+        Ok(results)
+    }
+
+    /// Up to `size` matching documents, starting after [Query::after], with the [Cursor] of the next page.
+    /// `size` takes the place of [Query::limit].
+    pub fn page(&self, size: usize) -> crate::Result<Page<T>> {
+        self.page_in(&self.collection.database().begin_read("query", self.collection.main_table_name())?, size)
+    }
+
+    pub fn page_in(&self, txn: &Transaction, size: usize) -> crate::Result<Page<T>> {
+        let (mut documents, mut last) = (Vec::new(), None);
+        self.visit(txn, Some(size), |index, id, document| {
+            documents.push(document);
+            last = Some(Cursor { index: index.map(<[u8]>::to_vec), key: T::PrimaryKey::as_bytes(&id).as_ref().to_vec() });
+        })?;
+        Ok(Page { cursor: last.filter(|_| size > 0 && documents.len() == size), documents })
+    }
+
+    /// Primary keys of the matching documents, in key order.
+    pub fn keys(&self) -> crate::Result<KeySet<T::PrimaryKey>> {
+        let txn = self.collection.database().begin_read("query", self.collection.main_table_name())?;
+        let mut keys = Vec::new();
+        self.visit(&txn, self.limit, |_, id, _| keys.push(id))?;
+        Ok(match self.order {
+            Some(_) => keys.into_iter().collect(),
+            None => KeySet::from_sorted(keys)
+        })
+    }
+
+    /// Primary keys of the matching documents read from the index tables alone, without opening the main table,
+    /// for callers that only need ids. Honours [Query::skip] and [Query::limit] in result order. Fails with
+    /// [Error::UnindexedQuery] if the query has no [Query::eq], [Query::prefix] or [Query::order_by] condition, has
+    /// [Query::filter] predicates, or matches on a hashed or truncated index value, since those need the documents.
+    pub fn index_keys(&self) -> crate::Result<KeySet<T::PrimaryKey>> {
+        self.index_keys_in(&self.collection.database().begin_read("query", self.collection.main_table_name())?)
+    }
+
+    pub fn index_keys_in(&self, txn: &Transaction) -> crate::Result<KeySet<T::PrimaryKey>> {
+        if self.verified {
+            return Err(Error::UnindexedQuery(self.collection.name()));
+        }
+        if let Some(cursor) = &self.after {
+            decode_key::<T::PrimaryKey>(&cursor.key).ok_or_else(|| Error::InvalidCursor(cursor.to_string()))?;
+        }
+        let bound = self.bind(&Params::new())?;
+        let operation = CollectionOperation::new("query", &self.collection, txn);
+        let excluded = self.excluded(&operation)?;
+        let entries = self.index_entries(&operation, &bound, &excluded)?.ok_or_else(|| Error::UnindexedQuery(self.collection.name()))?;
+        let skip = if self.after.is_some() { 0 } else { self.skip };
+        Ok(entries.into_iter().skip(skip).take(self.limit.unwrap_or(usize::MAX)).map(|(_, id)| id).collect())
+    }
+
+    /// `(primary key, document)` of every match, for writes that go on to change them.
+    pub(crate) fn entries_in(&self, txn: &Transaction) -> crate::Result<Vec<(T::PrimaryKey, T)>> {
+        let mut entries = Vec::new();
+        self.visit(txn, self.limit, |_, id, document| entries.push((id, document)))?;
+        Ok(entries)
+    }
+
+    pub fn count(&self) -> crate::Result<usize> {
+        let txn = self.collection.database().begin_read("query", self.collection.main_table_name())?;
+        let mut count = 0;
+        self.visit(&txn, self.limit, |_, _, _| count += 1)?;
+        Ok(count)
+    }
+
+    /// Whether index `key` stores `value` lossily, so matches on it are verified against documents.
+    fn is_lossy(key: &str, value: &rmpv::Value) -> bool {
+        index_spec::<T>(key).stored_key(value).is_ok_and(|(_, lossy)| lossy)
+    }
+
+    fn is_excluded(excluded: &HashSet<Vec<u8>>, id: &T::PrimaryKey) -> bool {
+        !excluded.is_empty() && excluded.contains(T::PrimaryKey::as_bytes(id).as_ref())
+    }
+
+    fn matches(&self, document: &T) -> bool {
+        self.filters.iter().all(|predicate| predicate(document))
+    }
+
+    fn in_range(&self, id: &T::PrimaryKey) -> bool {
+        let order = |bound: &T::PrimaryKey| T::PrimaryKey::compare(T::PrimaryKey::as_bytes(id).as_ref(), T::PrimaryKey::as_bytes(bound).as_ref());
+        let after_start = match &self.range.0 {
+            Bound::Included(start) => order(start).is_ge(),
+            Bound::Excluded(start) => order(start).is_gt(),
+            Bound::Unbounded => true
+        };
+        after_start && self.before_end(id)
+    }
+
+    fn before_end(&self, id: &T::PrimaryKey) -> bool {
+        let order = |bound: &T::PrimaryKey| T::PrimaryKey::compare(T::PrimaryKey::as_bytes(id).as_ref(), T::PrimaryKey::as_bytes(bound).as_ref());
+        match &self.range.1 {
+            Bound::Included(end) => order(end).is_le(),
+            Bound::Excluded(end) => order(end).is_lt(),
+            Bound::Unbounded => true
+        }
+    }
+
+    /// Whether the entry at `index` (its [Query::order_by] value, if ordered) and `id` comes after [Query::after].
+    fn after_cursor(&self, index: Option<&[u8]>, id: &T::PrimaryKey) -> bool {
+        let Some(cursor) = &self.after else {
+            return true;
+        };
+        let by_key = T::PrimaryKey::compare(T::PrimaryKey::as_bytes(id).as_ref(), &cursor.key);
+        let position = match (index, &cursor.index) {
+            (Some(index), Some(last)) => index.cmp(last.as_slice()).then(by_key),
+            _ => by_key
+        };
+        match &self.order {
+            Some((_, Order::Desc)) => position.is_lt(),
+            _ => position.is_gt()
+        }
+    }
+
+    /// Whether the next match should be skipped, counting it against [Query::skip].
+    fn skipping(&self, skipped: &mut usize) -> bool {
+        let skipping = self.after.is_none() && *skipped < self.skip;
+        *skipped += usize::from(skipping);
+        skipping
+    }
+
+    /// Calls `visit` with every matching document in key order (or [Query::order_by] order), after the skip and up to the limit.
+    fn visit(&self, txn: &Transaction, limit: Option<usize>, visit: impl FnMut(Option<&[u8]>, T::PrimaryKey, T)) -> crate::Result<()> {
+        self.visit_bound(txn, limit, &Params::new(), visit)
+    }
+
+    /// [Query::visit] with the [Query::eq_param] conditions taken from `params`.
+    fn visit_bound(&self, txn: &Transaction, limit: Option<usize>, params: &Params, mut visit: impl FnMut(Option<&[u8]>, T::PrimaryKey, T)) -> crate::Result<()> {
+        let bound = self.bind(params)?;
+        let limit = limit.unwrap_or(usize::MAX);
+        if limit == 0 {
+            return Ok(());
+        }
+        let after = match &self.after {
+            Some(cursor) => Some(decode_key::<T::PrimaryKey>(&cursor.key).ok_or_else(|| Error::InvalidCursor(cursor.to_string()))?),
+            None => None
+        };
+        let operation = CollectionOperation::new("query", &self.collection, txn);
+        let (mut found, mut skipped) = (0, 0);
+        let excluded = self.excluded(&operation)?;
+        let Some(entries) = self.index_entries(&operation, &bound, &excluded)? else {
+            return self.scan(txn, limit, after, &excluded, visit);
+        };
+        for (index, id) in entries {
+            if self.filters.is_empty() && self.skipping(&mut skipped) {
+                continue;
+            }
+            if let Some(document) = operation.get(&id)?
+                && self.matches(&document)
+                && (self.filters.is_empty() || !self.skipping(&mut skipped))
+            {
+                visit(index.as_deref(), id, document);
+                found += 1;
+                if found >= limit {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The `(key, value)` conditions of [Query::eq_param], with each value taken from `params`.
+    fn bind(&self, params: &Params) -> crate::Result<Vec<(String, rmpv::Value)>> {
+        self.params.iter()
+            .map(|(key, name)| params.0.get(name).map(|value| (key.clone(), value.clone())).ok_or_else(|| Error::UnboundParameter(name.clone())))
+            .collect()
+    }
+
+    /// Encoded primary keys left out by [Query::not_in] and [Query::not_exists].
+    fn excluded(&self, operation: &CollectionOperation<T>) -> crate::Result<HashSet<Vec<u8>>> {
+        let mut excluded = HashSet::new();
+        for exclusion in &self.exclusions {
+            excluded.extend(exclusion(operation)?);
+        }
+        Ok(excluded)
+    }
+
+    /// `(order_by value, primary key)` of every candidate read from the index tables, in result order and
+    /// already narrowed by the key range, the cursor and exclusions, or `None` if the query has no index
+    /// condition and has to scan the main table.
+    fn index_entries(&self, operation: &CollectionOperation<T>, bound: &[(String, rmpv::Value)], excluded: &HashSet<Vec<u8>>) -> crate::Result<Option<Vec<IndexEntry<T::PrimaryKey>>>> {
+        // Two or more equality conditions on bitmap indexes are intersected as bitmaps first.
+        let (mut bitmapped, mut indexed): (Vec<_>, Vec<_>) = self.equals.iter().chain(bound).cloned().partition(|(key, _)| index_spec::<T>(key).bitmap);
+        if bitmapped.len() < 2 {
+            indexed.append(&mut bitmapped);
+        }
+        let conditions = (!bitmapped.is_empty()).then(|| operation.bitmap_keys(&bitmapped)).into_iter()
+            .chain(indexed.iter().map(|(key, value)| operation.keys_where(key, value)))
+            .chain(self.prefixes.iter().map(|(key, prefix)| operation.keys_with_prefix(key, prefix)));
+        let mut matched: Option<KeySet<T::PrimaryKey>> = None;
+        for keys in conditions {
+            if matched.as_ref().is_some_and(KeySet::is_empty) {
+                break;
+            }
+            let keys = keys?;
+            matched = Some(match matched {
+                Some(matched) => matched & keys,
+                None => keys
+            });
+        }
+
+        let entries: Vec<(Option<Vec<u8>>, T::PrimaryKey)> = match (&self.order, matched) {
+            (Some((key, order)), matched) => operation.ordered_entries(key, *order == Order::Desc, self.duplicates)?
+                .into_iter()
+                .filter(|(_, id)| matched.as_ref().is_none_or(|matched| matched.contains(id)))
+                .map(|(index, id)| (Some(index), id))
+                .collect(),
+            (None, Some(matched)) => matched.into_iter().map(|id| (None, id)).collect(),
+            (None, None) => return Ok(None)
+        };
+        Ok(Some(entries.into_iter().filter(|(index, id)| self.in_range(id) && self.after_cursor(index.as_deref(), id) && !Self::is_excluded(excluded, id)).collect()))
+    }
+
+    /// Visits matching documents straight from the main table, for queries without index conditions.
+    fn scan(&self, txn: &Transaction, limit: usize, after: Option<T::PrimaryKey>, excluded: &HashSet<Vec<u8>>, mut visit: impl FnMut(Option<&[u8]>, T::PrimaryKey, T)) -> crate::Result<()> {
+        let (name, mut found, mut skipped) = (self.collection.main_table_name(), 0, 0);
+        let mut range = self.range.clone();
+        if let Some(last) = after {
+            if self.in_range(&last) {
+                range.0 = Bound::Excluded(last);
+            } else if !self.before_end(&last) {
+                return Ok(());
+            }
+        }
+        with_table!(txn, TableDefinition::<T::PrimaryKey, &[u8]>::new(&name), table => {
+            for entry in table.range::<T::PrimaryKey>(range)? {
+                let (id, value) = entry?;
+                if Self::is_excluded(excluded, &id.value()) || (self.filters.is_empty() && self.skipping(&mut skipped)) {
+                    continue;
+                }
+                let document = rmp_serde::from_slice::<T>(value.value())?;
+                if self.matches(&document) && (self.filters.is_empty() || !self.skipping(&mut skipped)) {
+                    visit(None, id.value(), document);
+                    found += 1;
+                    if found >= limit {
+                        break;
+                    }
+                }
+            }
+            Ok(())
+        }, Ok(()))
+    }
+}
+
+/// Values for the [Query::eq_param] conditions of a [PreparedQuery], by parameter name.
+///
+/// ```ignore
+/// let by_country = users.query().eq_param("country", "country").limit(10).prepare()?;
+/// let dutch = by_country.run(&Params::new().bind("country", "NL"))?;
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Params(HashMap<String, rmpv::Value>);
+
+impl Params {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Binds parameter `name` to `value`, replacing an earlier binding.
+    pub fn bind(mut self, name: impl AsRef<str>, value: impl Into<rmpv::Value>) -> Self {
+        self.0.insert(name.as_ref().to_string(), value.into());
+        self
+    }
+}
+
+/// A [Query] whose index keys were checked once by [Query::prepare], run with [Params] for its
+/// [Query::eq_param] conditions.
+pub struct PreparedQuery<T: Document> {
+    query: Query<T>
+}
+
+impl<T: Document> PreparedQuery<T> {
+    /// Matching documents with every parameter bound from `params`. Fails with [Error::UnboundParameter] if
+    /// one is missing.
+    pub fn run(&self, params: &Params) -> crate::Result<Vec<T>> {
+        let collection = &self.query.collection;
+        self.run_in(&collection.database().begin_read("query", collection.main_table_name())?, params)
+    }
+
+    pub fn run_in(&self, txn: &Transaction, params: &Params) -> crate::Result<Vec<T>> {
+        self.query.collect_bound(txn, params)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::{database::Database, document::IndexSpec, testing::fixtures::{Item, Note}};
+
+    fn note(id: &str) -> Note {
+        Note { id: id.to_string(), title: format!("title {id}") }
+    }
+
+    #[test]
+    fn crafted_cursor_is_rejected() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let notes = db.collection::<Note>("notes");
+        notes.insert(&Note { id: "a".to_string(), title: "first".to_string() })?;
+        let cursor: Cursor = "AP8".parse()?;
+        assert!(matches!(notes.query().after(cursor.clone()).page(10), Err(Error::InvalidCursor(_))));
+        assert!(matches!(notes.query().order_by("title", Order::Asc).after(cursor).page(10), Err(Error::InvalidCursor(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn index_keys_never_read_documents() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let items = db.collection::<Item>("items");
+        for (id, name) in [(1, "a"), (2, "b"), (3, "a"), (4, "a")] {
+            items.insert(&Item::new(id, name, id as f64))?;
+        }
+        let txn = db.writer()?;
+        let guard = txn.write_guard("test", items.main_table_name())?;
+        let mut table = guard.open_table(TableDefinition::<u64, &[u8]>::new(&items.main_table_name()))?;
+        for id in 1..=4 {
+            table.insert(id, [0xc1].as_slice())?;
+        }
+        drop(table);
+        drop(guard);
+        txn.commit()?;
+
+        assert!(items.query().eq("name", "a").keys().is_err());
+        let keys = items.keys_matching(&items.query().eq("name", "a").not_in("score", [3.0]))?;
+        assert_eq!(keys.into_iter().collect::<Vec<_>>(), vec![1, 4]);
+        let keys = items.query().order_by("score", Order::Desc).skip(1).limit(2).index_keys()?;
+        assert_eq!(keys.into_iter().collect::<Vec<_>>(), vec![2, 3]);
+        assert!(matches!(items.query().filter(|item| item.score > 1.0).index_keys(), Err(Error::UnindexedQuery(_))));
+        assert!(matches!(items.query().range(1..3).index_keys(), Err(Error::UnindexedQuery(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn prepared_queries_run_with_bound_parameters() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let items = db.collection::<Item>("items");
+        for (id, name) in [(1, "a"), (2, "b"), (3, "a"), (4, "c")] {
+            items.insert(&Item::new(id, name, id as f64))?;
+        }
+        let by_name = items.query().eq_param("name", "name").order_by("score", Order::Desc).prepare()?;
+        let ids = |params: Params| -> crate::Result<Vec<u64>> { Ok(by_name.run(&params)?.into_iter().map(|item| item.id).collect()) };
+        assert_eq!(ids(Params::new().bind("name", "a"))?, [3, 1]);
+        assert_eq!(ids(Params::new().bind("name", "c"))?, [4]);
+        assert!(ids(Params::new().bind("name", "z"))?.is_empty());
+        assert!(matches!(ids(Params::new().bind("other", "a")), Err(Error::UnboundParameter(name)) if name == "name"));
+        assert!(matches!(items.query().eq_param("name", "name").collect(), Err(Error::UnboundParameter(_))));
+
+        assert!(matches!(items.query().eq_param("missing", "name").prepare(), Err(Error::UnknownIndex(_))));
+        assert!(matches!(items.query().order_by("tags", Order::Asc).prepare(), Err(Error::UnorderedIndex(_))));
+        Ok(())
+    }
+
+    /// Ids of every page of `query` of `size` documents, following the cursors to the end.
+    fn paged_ids(query: impl Fn() -> Query<Item>, size: usize) -> crate::Result<Vec<u64>> {
+        let (mut ids, mut cursor) = (Vec::new(), None);
+        loop {
+            let page = match cursor {
+                Some(cursor) => query().after(cursor).page(size)?,
+                None => query().page(size)?
+            };
+            assert!(page.documents.len() <= size);
+            ids.extend(page.documents.iter().map(|item| item.id));
+            match page.cursor {
+                Some(next) => cursor = Some(next),
+                None => return Ok(ids)
+            }
+        }
+    }
+
+    #[test]
+    fn pages_cover_every_document_once_in_either_order() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let items = db.collection::<Item>("items");
+        items.insert_many((0..23).map(|id| Item::new(id, &format!("name {}", id % 5), id as f64)))?;
+        let mut ascending: Vec<u64> = (0..23).collect();
+        ascending.sort_by_key(|id| (id % 5, *id));
+        let mut descending = ascending.clone();
+        descending.reverse();
+
+        assert_eq!(paged_ids(|| items.query(), 4)?, (0..23).collect::<Vec<_>>());
+        for size in [1, 4, 5, 23, 30] {
+            assert_eq!(paged_ids(|| items.query().order_by("name", Order::Asc), size)?, ascending, "ascending pages of {size}");
+            assert_eq!(paged_ids(|| items.query().order_by("name", Order::Desc), size)?, descending, "descending pages of {size}");
+        }
+        assert_eq!(paged_ids(|| items.query().order_by("score", Order::Desc).filter(|item| item.id % 2 == 0), 3)?, (0..23).rev().filter(|id| id % 2 == 0).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn writes_between_pages_dont_shift_the_next_page() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let items = db.collection::<Item>("items");
+        items.insert_many((0..6).map(|id| Item::new(id * 10, "item", id as f64)))?;
+        let first = items.query().order_by("score", Order::Asc).page(3)?;
+        items.insert(&Item::new(5, "item", 0.5))?;
+        items.delete(&30)?;
+        let second = items.query().order_by("score", Order::Asc).after(first.cursor.clone().unwrap()).page(3)?;
+        let ids: Vec<u64> = first.documents.iter().chain(&second.documents).map(|item| item.id).collect();
+        assert_eq!(ids, [0, 10, 20, 40, 50]);
+        assert!(second.cursor.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_cursor_tokens_are_rejected() -> crate::Result<()> {
+        let encode = |bytes: &[u8]| BASE64_URL_SAFE_NO_PAD.encode(bytes);
+        let truncated_length = encode(&[1, 0, 0]);
+        let overlong_index = encode(&[1, 0, 0, 0, 9, 1, 2]);
+        let unknown_tag = encode(&[2, 1]);
+        for token in ["", "!!!", "a b", truncated_length.as_str(), overlong_index.as_str(), unknown_tag.as_str()] {
+            assert!(matches!(token.parse::<Cursor>(), Err(Error::InvalidCursor(_))), "{token:?} parsed");
+        }
+
+        let db = Database::open_in_memory()?;
+        let items = db.collection::<Item>("items");
+        items.insert_many((0..3).map(|id| Item::new(id, "item", 1.0)))?;
+        let cursor = items.query().order_by("name", Order::Asc).page(1)?.cursor.unwrap();
+        assert_eq!(cursor.to_string().parse::<Cursor>()?, cursor);
+        let short_key: Cursor = encode(&[0, 1, 2, 3]).parse()?;
+        assert!(matches!(items.query().after(short_key.clone()).page(1), Err(Error::InvalidCursor(_))));
+        assert!(matches!(items.query().order_by("name", Order::Asc).after(short_key).page(1), Err(Error::InvalidCursor(_))));
+        Ok(())
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    struct Post {
+        id: u64,
+        tags: Vec<String>
+    }
+
+    impl Document for Post {
+        type PrimaryKey = u64;
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn id_field() -> String {
+            "id".to_string()
+        }
+
+        fn index_keys() -> Vec<String> {
+            vec!["tags".to_string()]
+        }
+
+        fn index_vals(&self) -> HashMap<String, rmpv::Value> {
+            HashMap::from([("tags".to_string(), rmpv::Value::Array(self.tags.iter().map(|tag| tag.as_str().into()).collect()))])
+        }
+
+        fn index_spec(_key: &str) -> IndexSpec {
+            IndexSpec::new().ordered().multikey()
+        }
+    }
+
+    #[test]
+    fn multikey_results_are_deduplicated_unless_allowed() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let posts = db.collection::<Post>("posts");
+        let post = |id, tags: &[&str]| Post { id, tags: tags.iter().map(|tag| tag.to_string()).collect() };
+        posts.insert_many([post(1, &["rust", "db", "rust"]), post(2, &["db"]), post(3, &["web", "rust"])])?;
+        let ids = |query: Query<Post>| -> crate::Result<Vec<u64>> { Ok(query.collect()?.into_iter().map(|post| post.id).collect()) };
+
+        assert_eq!(ids(posts.query().eq("tags", "rust"))?, [1, 3]);
+        assert_eq!(ids(posts.query().eq("tags", "rust").allow_duplicates())?, [1, 3]);
+        assert_eq!(ids(posts.query().order_by("tags", Order::Asc))?, [1, 2, 3]);
+        assert_eq!(ids(posts.query().order_by("tags", Order::Asc).allow_duplicates())?, [1, 2, 1, 3, 3]);
+        assert_eq!(ids(posts.query().order_by("tags", Order::Desc).allow_duplicates().eq("tags", "db"))?, [1, 2, 1]);
+
+        let first = posts.query().order_by("tags", Order::Asc).allow_duplicates().page(3)?;
+        let rest = posts.query().order_by("tags", Order::Asc).allow_duplicates().after(first.cursor.clone().unwrap()).page(3)?;
+        let paged: Vec<u64> = first.documents.iter().chain(&rest.documents).map(|post| post.id).collect();
+        assert_eq!(paged, [1, 2, 1, 3, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn anti_joins_leave_out_matching_documents() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let (posts, comments) = (db.collection::<Note>("posts"), db.collection::<Note>("comments"));
+        posts.insert_many(["a", "b", "c", "d"].map(note))?;
+        comments.insert_many([("1", "a"), ("2", "c"), ("3", "a")].map(|(id, post)| Note { id: id.to_string(), title: post.to_string() }))?;
+        let ids = |query: Query<Note>| -> crate::Result<Vec<String>> { Ok(query.collect()?.into_iter().map(|note| note.id).collect()) };
+
+        assert_eq!(ids(posts.query().not_in("title", ["title b", "title d"]))?, ["a", "c"]);
+        assert_eq!(ids(posts.query().not_exists(comments.query(), |comment| comment.title.clone()))?, ["b", "d"]);
+        let unanswered = posts.query().not_exists(comments.query().filter(|comment| comment.id != "2"), |comment| comment.title.clone());
+        assert_eq!(ids(unanswered.order_by("title", Order::Desc).skip(1))?, ["c", "b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn skip_applies_to_the_first_page_only() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let notes = db.collection::<Note>("notes");
+        notes.insert_many(["a", "b", "c", "d", "e", "f"].map(note))?;
+        let first = notes.query().skip(1).page(2)?;
+        let second = notes.query().skip(1).after(first.cursor.clone().unwrap()).page(2)?;
+        let ids: Vec<_> = first.documents.iter().chain(&second.documents).map(|note| note.id.as_str()).collect();
+        assert_eq!(ids, ["b", "c", "d", "e"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use std::{
+    any::{type_name, TypeId}, collections::HashMap, sync::{Arc, RwLock}
+};
+
+use crate::{
+    database::{Collection, Database, FieldDefaults, FieldProvider}, document::Document, erased::ErasedCollection, Error
+};
+
+#[derive(Clone, Debug)]
+struct Registration {
+    name: String,
+    type_id: TypeId,
+    type_name: &'static str,
+    open: fn(Database, String) -> Box<dyn ErasedCollection>
+}
+
+fn open_erased<T: Document + Send + Sync + 'static>(db: Database, name: String) -> Box<dyn ErasedCollection> {
+    Box::new(Collection::<T>::new(db, name))
+}
+
+/// Maps collection names to document types, so a name is only ever opened as one Rust type.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CollectionRegistry {
+    by_type: Arc<RwLock<HashMap<TypeId, Registration>>>,
+    by_name: Arc<RwLock<HashMap<String, Registration>>>,
+    /// [Collection::with_default] defaults set on registered collections, by name.
+    defaults: Arc<RwLock<HashMap<String, FieldDefaults>>>
+}
+
+impl CollectionRegistry {
+    pub(crate) fn register<T: Document + Send + Sync + 'static>(&self, name: impl AsRef<str>) -> crate::Result<()> {
+        let registration = Registration {
+            name: name.as_ref().to_string(),
+            type_id: TypeId::of::<T>(),
+            type_name: type_name::<T>(),
+            open: open_erased::<T>
+        };
+        let mut by_type = self.by_type.write()?;
+        let mut by_name = self.by_name.write()?;
+
+        if let Some(existing) = by_name.get(&registration.name) && existing.type_id != registration.type_id {
+            return Err(Error::CollectionConflict {
+                name: registration.name,
+                registered: existing.type_name.to_string(),
+                requested: registration.type_name.to_string()
+            });
+        }
+        if let Some(existing) = by_type.get(&registration.type_id) && existing.name != registration.name {
+            return Err(Error::TypeAlreadyRegistered {
+                type_name: existing.type_name.to_string(),
+                name: existing.name.clone()
+            });
+        }
+
+        by_type.insert(registration.type_id, registration.clone());
+        by_name.insert(registration.name.clone(), registration);
+        Ok(())
+    }
+
+    /// Fails with [Error::CollectionConflict] if `name` is registered to a type other than `T`.
+    pub(crate) fn ensure_available<T: 'static>(&self, name: impl AsRef<str>) -> crate::Result<()> {
+        match self.by_name.read()?.get(name.as_ref()) {
+            Some(existing) if existing.type_id != TypeId::of::<T>() => Err(Error::CollectionConflict {
+                name: name.as_ref().to_string(),
+                registered: existing.type_name.to_string(),
+                requested: type_name::<T>().to_string()
+            }),
+            _ => Ok(())
+        }
+    }
+
+    /// Moves the registration of `old` and its defaults, if any, to `new`.
+    pub(crate) fn rename(&self, old: impl AsRef<str>, new: impl AsRef<str>) -> crate::Result<()> {
+        let mut by_type = self.by_type.write()?;
+        let mut by_name = self.by_name.write()?;
+        if let Some(mut registration) = by_name.remove(old.as_ref()) {
+            registration.name = new.as_ref().to_string();
+            by_type.insert(registration.type_id, registration.clone());
+            by_name.insert(registration.name.clone(), registration);
+        }
+        let mut defaults = self.defaults.write()?;
+        if let Some(moved) = defaults.remove(old.as_ref()) {
+            defaults.insert(new.as_ref().to_string(), moved);
+        }
+        Ok(())
+    }
+
+    /// Adds a default for `field` to collection `name`, returning `false` unless `name` is registered to `T`.
+    /// Compares type names, as `T` needn't be `'static` here.
+    pub(crate) fn add_default<T>(&self, name: &str, field: &str, provider: FieldProvider) -> crate::Result<bool> {
+        if self.by_name.read()?.get(name).is_none_or(|registration| registration.type_name != type_name::<T>()) {
+            return Ok(false);
+        }
+        self.defaults.write()?.entry(name.to_string()).or_default().0.push((field.to_string(), provider));
+        Ok(true)
+    }
+
+    pub(crate) fn defaults(&self, name: &str) -> crate::Result<Vec<(String, FieldProvider)>> {
+        Ok(self.defaults.read()?.get(name).map(|defaults| defaults.0.clone()).unwrap_or_default())
+    }
+
+    pub(crate) fn name_of<T: 'static>(&self) -> crate::Result<String> {
+        self.by_type
+            .read()?
+            .get(&TypeId::of::<T>())
+            .map(|registration| registration.name.clone())
+            .ok_or_else(|| Error::UnregisteredCollection(type_name::<T>().to_string()))
+    }
+
+    pub(crate) fn erased(&self, db: &Database, name: impl AsRef<str>) -> crate::Result<Option<Box<dyn ErasedCollection>>> {
+        Ok(self.by_name.read()?.get(name.as_ref()).map(|registration| (registration.open)(db.clone(), registration.name.clone())))
+    }
+
+    pub(crate) fn all(&self, db: &Database) -> crate::Result<Vec<Box<dyn ErasedCollection>>> {
+        let by_name = self.by_name.read()?;
+        let mut registrations: Vec<&Registration> = by_name.values().collect();
+        registrations.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(registrations.into_iter().map(|registration| (registration.open)(db.clone(), registration.name.clone())).collect())
+    }
+}
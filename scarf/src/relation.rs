@@ -0,0 +1,328 @@
+use std::{
+    any::Any, collections::{BTreeMap, HashMap}, fmt::Debug, marker::PhantomData, sync::{Arc, RwLock}
+};
+
+use redb::{Key, MultimapTableDefinition, ReadableMultimapTable, Value};
+
+use crate::{
+    database::{with_table, Collection, Database, Transaction}, document::Document, tracking::ChangeKind
+};
+
+/// Runs inside the transaction that deleted from a collection, with the deleted document's primary key, or
+/// `None` if the whole collection was cleared or dropped.
+pub(crate) type DeleteHook = Arc<dyn Fn(&Database, &Transaction, Option<&dyn Any>) -> crate::Result<()> + Send + Sync>;
+
+/// Callbacks run when documents are deleted from a collection, keyed by collection name and then by owner,
+/// so helpers like [Relation::cascade] can drop whatever points at the deleted documents.
+#[derive(Clone, Default)]
+pub(crate) struct DeleteHooks {
+    hooks: Arc<RwLock<HashMap<String, BTreeMap<String, DeleteHook>>>>
+}
+
+impl Debug for DeleteHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let collections: Vec<String> = self.hooks.read().map(|hooks| hooks.keys().cloned().collect()).unwrap_or_default();
+        f.debug_struct("DeleteHooks").field("collections", &collections).finish()
+    }
+}
+
+impl DeleteHooks {
+    /// Runs `hook` on every delete from `collection`, replacing any hook `owner` registered there before.
+    pub(crate) fn register(&self, collection: impl AsRef<str>, owner: impl AsRef<str>, hook: DeleteHook) -> crate::Result<()> {
+        self.hooks.write()?.entry(collection.as_ref().to_string()).or_default().insert(owner.as_ref().to_string(), hook);
+        Ok(())
+    }
+
+    pub(crate) fn run(&self, db: &Database, txn: &Transaction, collection: impl AsRef<str>, key: Option<&dyn Any>) -> crate::Result<()> {
+        let hooks: Vec<DeleteHook> = self.hooks.read()?.get(collection.as_ref()).map(|hooks| hooks.values().cloned().collect()).unwrap_or_default();
+        for hook in hooks {
+            hook(db, txn, key)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the hooks of collection `old`, if any, to `new`.
+    pub(crate) fn rename(&self, old: impl AsRef<str>, new: impl AsRef<str>) -> crate::Result<()> {
+        let mut hooks = self.hooks.write()?;
+        if let Some(moved) = hooks.remove(old.as_ref()) {
+            hooks.insert(new.as_ref().to_string(), moved);
+        }
+        Ok(())
+    }
+}
+
+/// A many-to-many join between the primary keys of two document types.
+///
+/// Links are stored twice, once per direction, so both `of_a` and `of_b` are single multimap
+/// lookups. After [Relation::cascade], deleting a document from either side removes its links.
+#[derive(Debug)]
+pub struct Relation<A: Document, B: Document> {
+    database: Database,
+    name: String,
+    types: PhantomData<(A, B)>
+}
+
+impl<A: Document, B: Document> Clone for Relation<A, B> {
+    fn clone(&self) -> Self {
+        Self {
+            database: self.database.clone(),
+            name: self.name.clone(),
+            types: PhantomData
+        }
+    }
+}
+
+impl<A: Document, B: Document> Relation<A, B> {
+    pub(crate) fn new(db: Database, name: String) -> Self {
+        Self {
+            database: db,
+            name,
+            types: PhantomData
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn forward_table_name(&self) -> String {
+        format!("relations/{}/a", self.name())
+    }
+
+    fn reverse_table_name(&self) -> String {
+        format!("relations/{}/b", self.name())
+    }
+
+    /// Removes links automatically whenever a document is deleted from `a` or `b`, in the same transaction as
+    /// the delete. Clearing or dropping either collection removes every link. Calling this again for the same
+    /// relation replaces the earlier registration on each collection.
+    pub fn cascade(&self, a: &Collection<A>, b: &Collection<B>) -> crate::Result<()>
+    where
+        A: 'static,
+        B: 'static
+    {
+        let hooks = self.database.delete_hooks();
+        let name = self.name();
+        hooks.register(a.name(), self.forward_table_name(), Arc::new(move |db: &Database, txn: &Transaction, key: Option<&dyn Any>| {
+            let relation = db.relation::<A, B>(&name);
+            match key {
+                Some(key) => key.downcast_ref::<A::PrimaryKey>().map_or(Ok(()), |a| relation.forget_a_in(txn, a).map(drop)),
+                None => relation.clear_in(txn)
+            }
+        }))?;
+        let name = self.name();
+        hooks.register(b.name(), self.reverse_table_name(), Arc::new(move |db: &Database, txn: &Transaction, key: Option<&dyn Any>| {
+            let relation = db.relation::<A, B>(&name);
+            match key {
+                Some(key) => key.downcast_ref::<B::PrimaryKey>().map_or(Ok(()), |b| relation.forget_b_in(txn, b).map(drop)),
+                None => relation.clear_in(txn)
+            }
+        }))
+    }
+
+    /// Links `a` to `b`, returning `false` if they were already linked.
+    pub fn link(&self, a: &A::PrimaryKey, b: &B::PrimaryKey) -> crate::Result<bool> {
+        let txn = self.database.begin_write("link", self.forward_table_name())?;
+        let linked = self.link_in(&txn, a, b)?;
+        txn.commit()?;
+        Ok(linked)
+    }
+
+    pub fn link_in(&self, txn: &Transaction, a: &A::PrimaryKey, b: &B::PrimaryKey) -> crate::Result<bool> {
+        let (forward_name, reverse_name) = (self.forward_table_name(), self.reverse_table_name());
+        let guard = txn.write_guard("link", &forward_name)?;
+        let existed = guard.open_multimap_table(MultimapTableDefinition::<A::PrimaryKey, B::PrimaryKey>::new(&forward_name))?.insert(a, b)?;
+        guard.open_multimap_table(MultimapTableDefinition::<B::PrimaryKey, A::PrimaryKey>::new(&reverse_name))?.insert(b, a)?;
+        if !existed {
+            let bytes = A::PrimaryKey::as_bytes(a).as_ref().len() + B::PrimaryKey::as_bytes(b).as_ref().len();
+            txn.record_change(&forward_name, ChangeKind::Insert, bytes)?;
+            txn.record_change(&reverse_name, ChangeKind::Insert, bytes)?;
+        }
+        Ok(!existed)
+    }
+
+    /// Removes the link between `a` and `b`, returning `false` if they weren't linked.
+    pub fn unlink(&self, a: &A::PrimaryKey, b: &B::PrimaryKey) -> crate::Result<bool> {
+        let txn = self.database.begin_write("unlink", self.forward_table_name())?;
+        let unlinked = self.unlink_in(&txn, a, b)?;
+        txn.commit()?;
+        Ok(unlinked)
+    }
+
+    pub fn unlink_in(&self, txn: &Transaction, a: &A::PrimaryKey, b: &B::PrimaryKey) -> crate::Result<bool> {
+        let (forward_name, reverse_name) = (self.forward_table_name(), self.reverse_table_name());
+        let guard = txn.write_guard("unlink", &forward_name)?;
+        let existed = guard.open_multimap_table(MultimapTableDefinition::<A::PrimaryKey, B::PrimaryKey>::new(&forward_name))?.remove(a, b)?;
+        guard.open_multimap_table(MultimapTableDefinition::<B::PrimaryKey, A::PrimaryKey>::new(&reverse_name))?.remove(b, a)?;
+        if existed {
+            txn.record_change(&forward_name, ChangeKind::Delete, 0)?;
+            txn.record_change(&reverse_name, ChangeKind::Delete, 0)?;
+        }
+        Ok(existed)
+    }
+
+    pub fn is_linked(&self, a: &A::PrimaryKey, b: &B::PrimaryKey) -> crate::Result<bool> {
+        let name = self.forward_table_name();
+        let target = B::PrimaryKey::as_bytes(b);
+        with_table!(multimap &self.database.begin_read("is_linked", self.forward_table_name())?, MultimapTableDefinition::<A::PrimaryKey, B::PrimaryKey>::new(&name), table => {
+            for value in table.get(a)? {
+                let value = value?;
+                if B::PrimaryKey::compare(B::PrimaryKey::as_bytes(&value.value()).as_ref(), target.as_ref()).is_eq() {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }, Ok(false))
+    }
+
+    /// Returns every `B` key linked to `a`, in key order.
+    pub fn of_a(&self, a: &A::PrimaryKey) -> crate::Result<Vec<B::PrimaryKey>> {
+        self.of_a_in(&self.database.begin_read("of_a", self.forward_table_name())?, a)
+    }
+
+    pub fn of_a_in(&self, txn: &Transaction, a: &A::PrimaryKey) -> crate::Result<Vec<B::PrimaryKey>> {
+        let name = self.forward_table_name();
+        with_table!(multimap txn, MultimapTableDefinition::<A::PrimaryKey, B::PrimaryKey>::new(&name), table => {
+            let mut results = Vec::new();
+            for value in table.get(a)? {
+                results.push(value?.value());
+            }
+            Ok(results)
+        }, Ok(Vec::new()))
+    }
+
+    /// Returns every `A` key linked to `b`, in key order.
+    pub fn of_b(&self, b: &B::PrimaryKey) -> crate::Result<Vec<A::PrimaryKey>> {
+        self.of_b_in(&self.database.begin_read("of_b", self.forward_table_name())?, b)
+    }
+
+    pub fn of_b_in(&self, txn: &Transaction, b: &B::PrimaryKey) -> crate::Result<Vec<A::PrimaryKey>> {
+        let name = self.reverse_table_name();
+        with_table!(multimap txn, MultimapTableDefinition::<B::PrimaryKey, A::PrimaryKey>::new(&name), table => {
+            let mut results = Vec::new();
+            for value in table.get(b)? {
+                results.push(value?.value());
+            }
+            Ok(results)
+        }, Ok(Vec::new()))
+    }
+
+    /// Removes every link involving `a`, returning the number of links removed.
+    pub fn forget_a(&self, a: &A::PrimaryKey) -> crate::Result<usize> {
+        let txn = self.database.begin_write("forget_a", self.forward_table_name())?;
+        let removed = self.forget_a_in(&txn, a)?;
+        txn.commit()?;
+        Ok(removed)
+    }
+
+    pub fn forget_a_in(&self, txn: &Transaction, a: &A::PrimaryKey) -> crate::Result<usize> {
+        let (forward_name, reverse_name) = (self.forward_table_name(), self.reverse_table_name());
+        let guard = txn.write_guard("forget", &forward_name)?;
+        let mut forward = guard.open_multimap_table(MultimapTableDefinition::<A::PrimaryKey, B::PrimaryKey>::new(&forward_name))?;
+        let mut reverse = guard.open_multimap_table(MultimapTableDefinition::<B::PrimaryKey, A::PrimaryKey>::new(&reverse_name))?;
+        let mut removed = 0;
+        for b in forward.remove_all(a)? {
+            reverse.remove(b?.value(), a)?;
+            txn.record_change(&forward_name, ChangeKind::Delete, 0)?;
+            txn.record_change(&reverse_name, ChangeKind::Delete, 0)?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Removes every link involving `b`, returning the number of links removed.
+    pub fn forget_b(&self, b: &B::PrimaryKey) -> crate::Result<usize> {
+        let txn = self.database.begin_write("forget_b", self.forward_table_name())?;
+        let removed = self.forget_b_in(&txn, b)?;
+        txn.commit()?;
+        Ok(removed)
+    }
+
+    pub fn forget_b_in(&self, txn: &Transaction, b: &B::PrimaryKey) -> crate::Result<usize> {
+        let (forward_name, reverse_name) = (self.forward_table_name(), self.reverse_table_name());
+        let guard = txn.write_guard("forget", &forward_name)?;
+        let mut forward = guard.open_multimap_table(MultimapTableDefinition::<A::PrimaryKey, B::PrimaryKey>::new(&forward_name))?;
+        let mut reverse = guard.open_multimap_table(MultimapTableDefinition::<B::PrimaryKey, A::PrimaryKey>::new(&reverse_name))?;
+        let mut removed = 0;
+        for a in reverse.remove_all(b)? {
+            forward.remove(a?.value(), b)?;
+            txn.record_change(&forward_name, ChangeKind::Delete, 0)?;
+            txn.record_change(&reverse_name, ChangeKind::Delete, 0)?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Removes every link.
+    pub fn clear(&self) -> crate::Result<()> {
+        let txn = self.database.begin_write("clear_relation", self.forward_table_name())?;
+        self.clear_in(&txn)?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub fn clear_in(&self, txn: &Transaction) -> crate::Result<()> {
+        let (forward_name, reverse_name) = (self.forward_table_name(), self.reverse_table_name());
+        let guard = txn.write_guard("clear_relation", &forward_name)?;
+        let forward = guard.delete_multimap_table(MultimapTableDefinition::<A::PrimaryKey, B::PrimaryKey>::new(&forward_name))?;
+        let reverse = guard.delete_multimap_table(MultimapTableDefinition::<B::PrimaryKey, A::PrimaryKey>::new(&reverse_name))?;
+        drop(guard);
+        for (table, existed) in [(forward_name, forward), (reverse_name, reverse)] {
+            if existed {
+                txn.record_change(table, ChangeKind::Delete, 0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use crate::{database::Database, testing::fixtures::Note};
+
+    fn note(id: &str) -> Note {
+        Note { id: id.to_string(), title: id.to_string() }
+    }
+
+    #[test]
+    fn cascade_removes_links_of_deleted_documents() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let (posts, tags) = (db.collection::<Note>("posts"), db.collection::<Note>("tags"));
+        let relation = db.relation::<Note, Note>("post_tags");
+        relation.cascade(&posts, &tags)?;
+        for id in ["p1", "p2"] {
+            posts.insert(&note(id))?;
+        }
+        for id in ["rust", "db"] {
+            tags.insert(&note(id))?;
+        }
+        for (post, tag) in [("p1", "rust"), ("p1", "db"), ("p2", "rust")] {
+            relation.link(&post.to_string(), &tag.to_string())?;
+        }
+
+        posts.delete(&"p1".to_string())?;
+        assert_eq!(relation.of_b(&"rust".to_string())?, vec!["p2".to_string()]);
+        assert!(relation.of_b(&"db".to_string())?.is_empty());
+
+        tags.delete_where(|tag| tag.id == "rust")?;
+        assert!(relation.of_a(&"p2".to_string())?.is_empty());
+
+        relation.link(&"p2".to_string(), &"db".to_string())?;
+        tags.clear()?;
+        assert!(!relation.is_linked(&"p2".to_string(), &"db".to_string())?);
+        Ok(())
+    }
+
+    #[test]
+    fn relations_without_cascade_keep_their_links() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let posts = db.collection::<Note>("posts");
+        let relation = db.relation::<Note, Note>("post_tags");
+        posts.insert(&note("p1"))?;
+        relation.link(&"p1".to_string(), &"rust".to_string())?;
+        posts.delete(&"p1".to_string())?;
+        assert!(relation.is_linked(&"p1".to_string(), &"rust".to_string())?);
+        Ok(())
+    }
+}
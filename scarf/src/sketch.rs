@@ -0,0 +1,95 @@
+use crate::document::{fnv1a_64, mix64};
+
+/// Bits of the hash that pick a register: 2^11 one-byte registers, for a standard error of about 2.3%.
+const PRECISION: u32 = 11;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// A HyperLogLog sketch estimating how many distinct byte strings were inserted, in a fixed 2 KiB.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HyperLogLog {
+    registers: Vec<u8>
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HyperLogLog {
+    pub fn new() -> Self {
+        Self { registers: vec![0; REGISTERS] }
+    }
+
+    /// Reads a sketch written by [HyperLogLog::as_bytes], or `None` if `bytes` isn't one.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        (bytes.len() == REGISTERS).then(|| Self { registers: bytes.to_vec() })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.registers
+    }
+
+    pub fn insert(&mut self, item: &[u8]) {
+        let hash = mix64(fnv1a_64(item));
+        let register = (hash >> (64 - PRECISION)) as usize;
+        let rank = ((hash << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() as u8 + 1;
+        if let Some(current) = self.registers.get_mut(register) {
+            *current = (*current).max(rank);
+        }
+    }
+
+    /// Folds `other` in, as if every item inserted into it had been inserted here.
+    pub fn merge(&mut self, other: &Self) {
+        for (register, theirs) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(*theirs);
+        }
+    }
+
+    /// The estimated number of distinct items inserted.
+    pub fn estimate(&self) -> u64 {
+        let registers = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / registers);
+        let sum: f64 = self.registers.iter().map(|rank| 2f64.powi(-i32::from(*rank))).sum();
+        let raw = alpha * registers * registers / sum;
+        let empty = self.registers.iter().filter(|rank| **rank == 0).count();
+        // Linear counting is more accurate while many registers are still empty.
+        let estimate = match raw <= 2.5 * registers && empty > 0 {
+            true => registers * (registers / empty as f64).ln(),
+            false => raw
+        };
+        estimate.round() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_stay_close_to_distinct_counts() {
+        for distinct in [0u32, 10, 1_000, 50_000] {
+            let mut sketch = HyperLogLog::new();
+            for item in 0..distinct {
+                sketch.insert(&item.to_be_bytes());
+                sketch.insert(&item.to_be_bytes());
+            }
+            let error = (sketch.estimate() as f64 - f64::from(distinct)).abs() / f64::from(distinct.max(1));
+            assert!(error < 0.05, "estimated {} for {distinct}", sketch.estimate());
+        }
+    }
+
+    #[test]
+    fn merged_sketches_count_the_union() {
+        let (mut left, mut right) = (HyperLogLog::new(), HyperLogLog::new());
+        for item in 0u32..600 {
+            left.insert(&item.to_be_bytes());
+        }
+        for item in 400u32..1000 {
+            right.insert(&item.to_be_bytes());
+        }
+        left.merge(&right);
+        assert!(left.estimate().abs_diff(1000) < 50);
+        assert_eq!(HyperLogLog::from_bytes(left.as_bytes()), Some(left));
+    }
+}
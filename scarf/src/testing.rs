@@ -0,0 +1,475 @@
+use std::{
+    any::Any, collections::{BTreeMap, HashMap}, fs, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex}, thread, time::{Duration, Instant}
+};
+
+use redb::Value;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::{stored_indices, Collection, Database, CollectionOperation}, document::Document, Error
+};
+
+/// Set to any value to rewrite golden files instead of comparing against them.
+pub const UPDATE_GOLDEN_VAR: &str = "SCARF_UPDATE_GOLDEN";
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A file path in the system temp directory that no other call in this process will return.
+pub fn temp_path(prefix: impl AsRef<str>) -> PathBuf {
+    let unique = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos();
+    std::env::temp_dir().join(format!("{}-{}-{nanos}-{unique}.db", prefix.as_ref(), std::process::id()))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StressConfig {
+    pub readers: usize,
+    pub writers: usize,
+    pub writes_per_writer: usize,
+    /// Run against an in-memory database instead of a temporary file.
+    pub in_memory: bool
+}
+
+impl Default for StressConfig {
+    fn default() -> Self {
+        Self {
+            readers: 4,
+            writers: 2,
+            writes_per_writer: 500,
+            in_memory: false
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StressReport {
+    pub writes: u64,
+    pub reads: u64,
+    pub documents: u64,
+    pub elapsed: Duration,
+    /// Every invariant violation or error seen, empty if the run was clean.
+    pub violations: Vec<String>
+}
+
+impl StressReport {
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// Runs `config.writers` threads upserting documents from `generate(writer, iteration)` while
+/// `config.readers` threads repeatedly check index consistency inside read snapshots.
+///
+/// Afterwards every key's stored document must be the last one written for it (no lost updates), the
+/// document count must match the number of distinct keys, and the indexes must match the documents.
+pub fn stress<T, F>(config: StressConfig, generate: F) -> crate::Result<StressReport>
+where
+    T: Document + Send + Sync + 'static,
+    F: Fn(usize, usize) -> T + Send + Sync + 'static
+{
+    let path = (!config.in_memory).then(|| temp_path("scarf-stress"));
+    let db = match &path {
+        Some(path) => Database::open(path)?,
+        None => Database::open_in_memory()?
+    };
+    let report = run_stress(&db, &config, Arc::new(generate));
+    drop(db);
+    if let Some(path) = path {
+        let _ = fs::remove_file(path);
+    }
+    report
+}
+
+fn run_stress<T, F>(db: &Database, config: &StressConfig, generate: Arc<F>) -> crate::Result<StressReport>
+where
+    T: Document + Send + Sync + 'static,
+    F: Fn(usize, usize) -> T + Send + Sync + 'static
+{
+    let collection = db.collection::<T>("stress");
+    let latest: Arc<Mutex<HashMap<Vec<u8>, Vec<u8>>>> = Arc::new(Mutex::new(HashMap::new()));
+    let violations = Arc::new(Mutex::new(Vec::new()));
+    let (writes, reads) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+    let writing = Arc::new(AtomicBool::new(true));
+    let started = Instant::now();
+
+    let readers: Vec<_> = (0..config.readers)
+        .map(|_| {
+            let (collection, violations, reads, writing) = (collection.clone(), violations.clone(), reads.clone(), writing.clone());
+            thread::spawn(move || {
+                while writing.load(Ordering::Relaxed) {
+                    match collection.check_indexes() {
+                        Ok(problems) => record_violations(&violations, problems),
+                        Err(e) => record_violations(&violations, vec![format!("reader error: {e}")])
+                    }
+                    reads.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect();
+
+    let writers: Vec<_> = (0..config.writers)
+        .map(|writer| {
+            let (collection, latest, violations, writes, generate) = (collection.clone(), latest.clone(), violations.clone(), writes.clone(), generate.clone());
+            let count = config.writes_per_writer;
+            thread::spawn(move || {
+                for iteration in 0..count {
+                    if let Err(e) = write_one(&collection, &latest, generate(writer, iteration)) {
+                        record_violations(&violations, vec![format!("writer {writer} error: {e}")]);
+                        return;
+                    }
+                    writes.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect();
+
+    for (writer, handle) in writers.into_iter().enumerate() {
+        if let Err(payload) = handle.join() {
+            record_violations(&violations, vec![format!("writer {writer} panicked: {}", panic_message(payload.as_ref()))]);
+        }
+    }
+    writing.store(false, Ordering::Relaxed);
+    for (reader, handle) in readers.into_iter().enumerate() {
+        if let Err(payload) = handle.join() {
+            record_violations(&violations, vec![format!("reader {reader} panicked: {}", panic_message(payload.as_ref()))]);
+        }
+    }
+
+    let mut problems = collection.check_indexes()?;
+    let operation = CollectionOperation::new_reader("stress_check", &collection)?;
+    let stored: HashMap<Vec<u8>, Vec<u8>> = operation
+        .scan_raw()?
+        .into_iter()
+        .map(|(id, encoded)| (T::PrimaryKey::as_bytes(&id).as_ref().to_vec(), encoded))
+        .collect();
+    let latest = latest.lock()?;
+    if stored.len() != latest.len() {
+        problems.push(format!("expected {} documents, found {}", latest.len(), stored.len()));
+    }
+    for (key, expected) in latest.iter() {
+        match stored.get(key) {
+            Some(found) if found == expected => {},
+            Some(_) => problems.push(format!("lost update for key {key:?}")),
+            None => problems.push(format!("missing document for key {key:?}"))
+        }
+    }
+
+    let mut violations = violations.lock()?.clone();
+    violations.extend(problems);
+    Ok(StressReport {
+        writes: writes.load(Ordering::Relaxed),
+        reads: reads.load(Ordering::Relaxed),
+        documents: stored.len() as u64,
+        elapsed: started.elapsed(),
+        violations
+    })
+}
+
+/// Upserts `document` and records it as the latest version of its key. It's recorded while the write
+/// transaction is still open: only one can be, so the recorded order matches the commit order without a lock
+/// of our own around the write.
+fn write_one<T: Document>(collection: &Collection<T>, latest: &Mutex<HashMap<Vec<u8>, Vec<u8>>>, document: T) -> crate::Result<()> {
+    let key = T::PrimaryKey::as_bytes(&document.id()).as_ref().to_vec();
+    let encoded = rmp_serde::to_vec_named(&document)?;
+    let txn = collection.database().writer()?;
+    collection.upsert_in(&txn, &document)?;
+    latest.lock()?.insert(key, encoded);
+    txn.commit()
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload.downcast_ref::<&str>().map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
+
+fn record_violations(violations: &Mutex<Vec<String>>, problems: Vec<String>) {
+    if !problems.is_empty() && let Ok(mut violations) = violations.lock() {
+        violations.extend(problems);
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Renders the on-disk encoding of each document — primary key bytes, stored document bytes and every index
+/// key stored for it under its [crate::document::IndexSpec], one line per entry — as stable, line-based text
+/// suitable for a golden file. Index lines are sorted by index name, then by key bytes.
+pub fn golden_encoding<T: Document>(documents: &[T]) -> crate::Result<String> {
+    let mut output = String::new();
+    for (position, document) in documents.iter().enumerate() {
+        output.push_str(&format!("# document {position}\n"));
+        output.push_str(&format!("id: {}\n", hex(T::PrimaryKey::as_bytes(&document.id()).as_ref())));
+        output.push_str(&format!("document: {}\n", hex(&rmp_serde::to_vec_named(document)?)));
+        let indexes: BTreeMap<String, Vec<Vec<u8>>> = stored_indices(document)?.into_iter().collect();
+        for (key, values) in indexes {
+            for value in values {
+                output.push_str(&format!("index {key}: {}\n", hex(&value)));
+            }
+        }
+    }
+    Ok(output)
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GoldenStatus {
+    Matched,
+    Created,
+    Updated
+}
+
+/// Compares [golden_encoding] of `documents` against the golden file at `path`, failing with
+/// [crate::Error::GoldenMismatch] on the first differing line. Missing files are created, and every file is
+/// rewritten when [UPDATE_GOLDEN_VAR] is set.
+pub fn check_golden<T: Document>(path: impl AsRef<Path>, documents: &[T]) -> crate::Result<GoldenStatus> {
+    let path = path.as_ref();
+    let actual = golden_encoding(documents)?;
+    if !path.exists() || std::env::var_os(UPDATE_GOLDEN_VAR).is_some() {
+        let status = if path.exists() { GoldenStatus::Updated } else { GoldenStatus::Created };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, actual)?;
+        return Ok(status);
+    }
+
+    let expected = fs::read_to_string(path)?;
+    let (mut expected_lines, mut actual_lines) = (expected.lines(), actual.lines());
+    for line in 1.. {
+        match (expected_lines.next(), actual_lines.next()) {
+            (None, None) => break,
+            (expected, actual) if expected == actual => continue,
+            (expected, actual) => {
+                return Err(Error::GoldenMismatch {
+                    path: path.to_path_buf(),
+                    line,
+                    expected: expected.unwrap_or("<end of file>").to_string(),
+                    actual: actual.unwrap_or("<end of file>").to_string()
+                });
+            }
+        }
+    }
+    Ok(GoldenStatus::Matched)
+}
+
+/// Document types shared by the unit tests.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::document::{Document, IndexSpec};
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    pub struct Note {
+        pub id: String,
+        pub title: String
+    }
+
+    impl Document for Note {
+        type PrimaryKey = String;
+
+        fn id(&self) -> String {
+            self.id.clone()
+        }
+
+        fn id_field() -> String {
+            "id".to_string()
+        }
+
+        fn index_keys() -> Vec<String> {
+            vec!["title".to_string()]
+        }
+
+        fn index_vals(&self) -> HashMap<String, rmpv::Value> {
+            HashMap::from([("title".to_string(), self.title.clone().into())])
+        }
+
+        fn index_spec(_key: &str) -> IndexSpec {
+            IndexSpec::new().ordered()
+        }
+    }
+
+    /// A document with one index of each kind: ordered `name` and `score`, multikey `tags` and a unique,
+    /// sparse `email`.
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    pub struct Item {
+        pub id: u64,
+        pub name: String,
+        pub score: f64,
+        pub tags: Vec<String>,
+        pub email: Option<String>
+    }
+
+    impl Item {
+        pub fn new(id: u64, name: &str, score: f64) -> Self {
+            Self { id, name: name.to_string(), score, tags: Vec::new(), email: None }
+        }
+    }
+
+    impl Document for Item {
+        type PrimaryKey = u64;
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn id_field() -> String {
+            "id".to_string()
+        }
+
+        fn index_keys() -> Vec<String> {
+            vec!["name".to_string(), "score".to_string(), "tags".to_string(), "email".to_string()]
+        }
+
+        fn index_vals(&self) -> HashMap<String, rmpv::Value> {
+            HashMap::from([
+                ("name".to_string(), self.name.clone().into()),
+                ("score".to_string(), self.score.into()),
+                ("tags".to_string(), rmpv::Value::Array(self.tags.iter().map(|tag| tag.clone().into()).collect())),
+                ("email".to_string(), self.email.clone().map_or(rmpv::Value::Nil, Into::into))
+            ])
+        }
+
+        fn index_spec(key: &str) -> IndexSpec {
+            match key {
+                "name" | "score" => IndexSpec::new().ordered(),
+                "tags" => IndexSpec::new().multikey(),
+                "email" => IndexSpec::new().unique().sparse(),
+                _ => IndexSpec::new()
+            }
+        }
+    }
+
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::document::{HashWidth, IndexSpec};
+
+    const GOLDEN_ENCODING: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/golden/encoding.txt");
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    struct Address {
+        city: String
+    }
+
+    /// One index of every storage kind, so any change to how index keys are encoded shows up in the golden file.
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    struct Encoded {
+        id: u64,
+        name: String,
+        score: f64,
+        rank: i64,
+        email: Option<String>,
+        url: String,
+        tags: Vec<String>,
+        address: Address
+    }
+
+    impl Document for Encoded {
+        type PrimaryKey = u64;
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn id_field() -> String {
+            "id".to_string()
+        }
+
+        fn index_keys() -> Vec<String> {
+            ["name", "score", "rank", "email", "url", "tags"].map(String::from).to_vec()
+        }
+
+        fn index_vals(&self) -> HashMap<String, rmpv::Value> {
+            HashMap::from([
+                ("name".to_string(), self.name.clone().into()),
+                ("score".to_string(), self.score.into()),
+                ("rank".to_string(), self.rank.into()),
+                ("email".to_string(), self.email.clone().map_or(rmpv::Value::Nil, Into::into)),
+                ("url".to_string(), self.url.clone().into()),
+                ("tags".to_string(), rmpv::Value::Array(self.tags.iter().map(|tag| tag.clone().into()).collect()))
+            ])
+        }
+
+        fn index_spec(key: &str) -> IndexSpec {
+            match key {
+                "name" | "score" | "rank" => IndexSpec::new().ordered(),
+                "email" => IndexSpec::new().hashed(HashWidth::Bits64).sparse(),
+                "url" => IndexSpec::new().truncate(16),
+                "tags" => IndexSpec::new().multikey(),
+                _ => IndexSpec::new()
+            }
+        }
+
+        fn path_indexes() -> Vec<String> {
+            vec!["address.city".to_string()]
+        }
+
+        fn compound_indexes() -> Vec<(String, Vec<String>)> {
+            vec![("city_rank".to_string(), vec!["address.city".to_string(), "rank".to_string()])]
+        }
+    }
+
+    fn encoded_documents() -> Vec<Encoded> {
+        vec![
+            Encoded {
+                id: 1,
+                name: "alice".to_string(),
+                score: 2.5,
+                rank: -3,
+                email: Some("alice@example.com".to_string()),
+                url: "https://example.com/a/very/long/path".to_string(),
+                tags: vec!["red".to_string(), "blue".to_string(), "red".to_string()],
+                address: Address { city: "Oslo".to_string() }
+            },
+            Encoded {
+                id: 2,
+                name: "bob".to_string(),
+                score: -0.0,
+                rank: 40,
+                email: None,
+                url: "short".to_string(),
+                tags: Vec::new(),
+                address: Address { city: "Åre".to_string() }
+            },
+        ]
+    }
+
+    #[test]
+    fn stress_runs_report_clean_writes_and_panics() -> crate::Result<()> {
+        use crate::testing::fixtures::Note;
+
+        let config = StressConfig { readers: 2, writers: 3, writes_per_writer: 40, in_memory: true };
+        let note = |writer: usize, iteration: usize| Note { id: format!("note-{}", iteration % 10), title: format!("{writer}/{iteration}") };
+        let report = stress(config.clone(), note)?;
+        assert!(report.is_ok(), "{:?}", report.violations);
+        assert_eq!((report.writes, report.documents), (120, 10));
+
+        let report = stress(config, move |writer, iteration| {
+            assert!(writer != 1 || iteration < 5, "generator gave up");
+            note(writer, iteration)
+        })?;
+        assert_eq!(report.writes, 85);
+        assert_eq!(report.violations, ["writer 1 panicked: generator gave up"]);
+        Ok(())
+    }
+
+    #[test]
+    fn stored_encoding_matches_golden_file() -> crate::Result<()> {
+        assert!(Path::new(GOLDEN_ENCODING).exists(), "{GOLDEN_ENCODING} is missing; rerun with {UPDATE_GOLDEN_VAR}=1 to create it");
+        let status = check_golden(GOLDEN_ENCODING, &encoded_documents())?;
+        assert_ne!(status, GoldenStatus::Created);
+        Ok(())
+    }
+}
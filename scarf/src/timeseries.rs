@@ -0,0 +1,536 @@
+use std::{
+    fmt::Debug, marker::PhantomData, ops::{Bound, RangeBounds}, time::Duration
+};
+
+use chrono::{DateTime, Utc};
+use redb::{ReadableTable, TableDefinition};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    database::{with_table, Database, Transaction}, tracking::ChangeKind, Error
+};
+
+/// A value that can be stored in a [TimeSeries] and reduced into rollups.
+pub trait Sample: Serialize + DeserializeOwned + Clone + Debug {
+    fn sample_value(&self) -> f64;
+}
+
+macro_rules! numeric_sample {
+    ($($t:ty),*) => {
+        $(
+            impl Sample for $t {
+                fn sample_value(&self) -> f64 {
+                    *self as f64
+                }
+            }
+        )*
+    };
+}
+
+numeric_sample!(f64, f32, i64, i32, i16, i8, u64, u32, u16, u8);
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Rollup {
+    pub start: DateTime<Utc>,
+    pub count: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64
+}
+
+impl Rollup {
+    fn new(start: DateTime<Utc>, value: f64) -> Self {
+        Self {
+            start,
+            count: 1,
+            sum: value,
+            min: value,
+            max: value
+        }
+    }
+
+    fn push(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn avg(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum / self.count as f64
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregate {
+    Avg,
+    Sum,
+    Min,
+    Max,
+    Count,
+    First,
+    Last
+}
+
+struct Window {
+    rollup: Rollup,
+    first: f64,
+    last: f64
+}
+
+impl Window {
+    fn new(start: DateTime<Utc>, value: f64) -> Self {
+        Self {
+            rollup: Rollup::new(start, value),
+            first: value,
+            last: value
+        }
+    }
+
+    fn push(&mut self, value: f64) {
+        self.rollup.push(value);
+        self.last = value;
+    }
+
+    fn finish(&self, aggregate: Aggregate) -> (DateTime<Utc>, f64) {
+        let value = match aggregate {
+            Aggregate::Avg => self.rollup.avg(),
+            Aggregate::Sum => self.rollup.sum,
+            Aggregate::Min => self.rollup.min,
+            Aggregate::Max => self.rollup.max,
+            Aggregate::Count => self.rollup.count as f64,
+            Aggregate::First => self.first,
+            Aggregate::Last => self.last
+        };
+        (self.rollup.start, value)
+    }
+}
+
+/// Maximum ages for raw points and rollups. `None` keeps data forever.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub raw: Option<Duration>,
+    pub rollups: Option<Duration>
+}
+
+impl RetentionPolicy {
+    pub fn raw(mut self, max_age: Duration) -> Self {
+        self.raw = Some(max_age);
+        self
+    }
+
+    pub fn rollups(mut self, max_age: Duration) -> Self {
+        self.rollups = Some(max_age);
+        self
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    pub buckets_rolled_up: usize,
+    pub points_purged: usize,
+    pub rollups_purged: usize
+}
+
+pub(crate) type SeriesKey<'a> = (&'a str, i64);
+
+pub(crate) fn to_micros(timestamp: &DateTime<Utc>) -> i64 {
+    timestamp.timestamp_micros()
+}
+
+pub(crate) fn from_micros(micros: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp_micros(micros).unwrap_or(DateTime::<Utc>::MIN_UTC)
+}
+
+/// The width of `bucket` in microseconds. Buckets must be whole milliseconds, which also name the rollup
+/// tables, so two widths never share one.
+pub(crate) fn bucket_micros(bucket: Duration) -> crate::Result<i64> {
+    match i64::try_from(bucket.as_micros()) {
+        Ok(width) if bucket.as_millis() > 0 && bucket.subsec_nanos().is_multiple_of(1_000_000) => Ok(width),
+        _ => Err(Error::InvalidBucket(bucket))
+    }
+}
+
+fn micros_bounds(range: &impl RangeBounds<DateTime<Utc>>) -> (Bound<i64>, Bound<i64>) {
+    let start = match range.start_bound() {
+        Bound::Included(t) => Bound::Included(to_micros(t)),
+        Bound::Excluded(t) => Bound::Excluded(to_micros(t)),
+        Bound::Unbounded => Bound::Included(i64::MIN)
+    };
+    let end = match range.end_bound() {
+        Bound::Included(t) => Bound::Included(to_micros(t)),
+        Bound::Excluded(t) => Bound::Excluded(to_micros(t)),
+        Bound::Unbounded => Bound::Included(i64::MAX)
+    };
+    (start, end)
+}
+
+fn series_bounds(series: &str, (start, end): (Bound<i64>, Bound<i64>)) -> (Bound<SeriesKey<'_>>, Bound<SeriesKey<'_>>) {
+    (start.map(|t| (series, t)), end.map(|t| (series, t)))
+}
+
+/// A collection of numeric-ish samples keyed by `(series, timestamp)`.
+///
+/// Each series holds at most one point per timestamp (microsecond precision); recording the
+/// same timestamp twice replaces the earlier value.
+#[derive(Clone, Debug)]
+pub struct TimeSeries<T: Sample> {
+    database: Database,
+    name: String,
+    retention: RetentionPolicy,
+    rollup_buckets: Vec<Duration>,
+    sample_type: PhantomData<T>
+}
+
+impl<T: Sample> TimeSeries<T> {
+    pub(crate) fn new(db: Database, name: String) -> Self {
+        Self {
+            database: db,
+            name,
+            retention: RetentionPolicy::default(),
+            rollup_buckets: Vec::new(),
+            sample_type: PhantomData
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn with_retention(mut self, policy: RetentionPolicy) -> Self {
+        self.retention = policy;
+        self
+    }
+
+    /// Registers a rollup level that [TimeSeries::maintain] keeps up to date.
+    pub fn with_rollup(mut self, bucket: Duration) -> Self {
+        if !self.rollup_buckets.contains(&bucket) {
+            self.rollup_buckets.push(bucket);
+        }
+        self
+    }
+
+    pub fn retention(&self) -> RetentionPolicy {
+        self.retention.clone()
+    }
+
+    fn points_table_name(&self) -> String {
+        format!("timeseries/{}", self.name())
+    }
+
+    fn rollup_table_name(&self, bucket: Duration) -> String {
+        format!("timeseries/{}/rollups/{}ms", self.name(), bucket.as_millis())
+    }
+
+    pub fn record(&self, series: impl AsRef<str>, timestamp: DateTime<Utc>, value: T) -> crate::Result<()> {
+        self.record_many(series, [(timestamp, value)])
+    }
+
+    pub fn record_many(&self, series: impl AsRef<str>, points: impl IntoIterator<Item = (DateTime<Utc>, T)>) -> crate::Result<()> {
+        let txn = self.database.begin_write("record_many", self.points_table_name())?;
+        self.record_in(&txn, series, points)?;
+        txn.commit()
+    }
+
+    pub fn record_in(&self, txn: &Transaction, series: impl AsRef<str>, points: impl IntoIterator<Item = (DateTime<Utc>, T)>) -> crate::Result<()> {
+        let name = self.points_table_name();
+        let guard = txn.write_guard("record", &name)?;
+        let mut table = guard.open_table(TableDefinition::<SeriesKey, &[u8]>::new(&name))?;
+        for (timestamp, value) in points {
+            let encoded = rmp_serde::to_vec_named(&value)?;
+            let kind = match table.insert((series.as_ref(), to_micros(&timestamp)), encoded.as_slice())? {
+                Some(_) => ChangeKind::Update,
+                None => ChangeKind::Insert
+            };
+            txn.record_change(&name, kind, encoded.len())?;
+        }
+        Ok(())
+    }
+
+    pub fn range(&self, series: impl AsRef<str>, range: impl RangeBounds<DateTime<Utc>>) -> crate::Result<Vec<(DateTime<Utc>, T)>> {
+        self.range_in(&self.database.begin_read("range", self.points_table_name())?, series, range)
+    }
+
+    pub fn range_in(&self, txn: &Transaction, series: impl AsRef<str>, range: impl RangeBounds<DateTime<Utc>>) -> crate::Result<Vec<(DateTime<Utc>, T)>> {
+        let name = self.points_table_name();
+        let bounds = series_bounds(series.as_ref(), micros_bounds(&range));
+        with_table!(txn, TableDefinition::<SeriesKey, &[u8]>::new(&name), table => {
+            let mut results = Vec::new();
+            for entry in table.range::<SeriesKey>(bounds)? {
+                let (key, value) = entry?;
+                results.push((from_micros(key.value().1), rmp_serde::from_slice::<T>(value.value())?));
+            }
+            Ok(results)
+        }, Ok(Vec::new()))
+    }
+
+    pub fn latest(&self, series: impl AsRef<str>) -> crate::Result<Option<(DateTime<Utc>, T)>> {
+        let name = self.points_table_name();
+        let series = series.as_ref();
+        with_table!(&self.database.begin_read("latest", self.points_table_name())?, TableDefinition::<SeriesKey, &[u8]>::new(&name), table => {
+            match table.range::<SeriesKey>((series, i64::MIN)..=(series, i64::MAX))?.next_back() {
+                Some(entry) => {
+                    let (key, value) = entry?;
+                    Ok(Some((from_micros(key.value().1), rmp_serde::from_slice::<T>(value.value())?)))
+                },
+                None => Ok(None)
+            }
+        }, Ok(None))
+    }
+
+    /// Aggregates the raw points of `series` within `range` into `bucket`-wide windows in a single pass.
+    /// Buckets are aligned to the Unix epoch and empty buckets are omitted.
+    pub fn query_window(&self, series: impl AsRef<str>, range: impl RangeBounds<DateTime<Utc>>, bucket: Duration, aggregate: Aggregate) -> crate::Result<Vec<(DateTime<Utc>, f64)>> {
+        let width = bucket_micros(bucket)?;
+        let name = self.points_table_name();
+        let bounds = series_bounds(series.as_ref(), micros_bounds(&range));
+        with_table!(&self.database.begin_read("query_window", self.points_table_name())?, TableDefinition::<SeriesKey, &[u8]>::new(&name), table => {
+            let mut results = Vec::new();
+            let mut current: Option<Window> = None;
+            for entry in table.range::<SeriesKey>(bounds)? {
+                let (key, value) = entry?;
+                let timestamp = key.value().1;
+                let start = timestamp - timestamp.rem_euclid(width);
+                let sample = rmp_serde::from_slice::<T>(value.value())?.sample_value();
+                match current.as_mut() {
+                    Some(window) if to_micros(&window.rollup.start) == start => window.push(sample),
+                    _ => {
+                        if let Some(done) = current.replace(Window::new(from_micros(start), sample)) {
+                            results.push(done.finish(aggregate));
+                        }
+                    }
+                }
+            }
+            results.extend(current.map(|window| window.finish(aggregate)));
+            Ok(results)
+        }, Ok(Vec::new()))
+    }
+
+    /// Lists the names of every series that currently holds at least one raw point.
+    pub fn series(&self) -> crate::Result<Vec<String>> {
+        let name = self.points_table_name();
+        with_table!(&self.database.begin_read("series", self.points_table_name())?, TableDefinition::<SeriesKey, &[u8]>::new(&name), table => {
+            let mut results: Vec<String> = Vec::new();
+            let mut next = table.first()?.map(|(key, _)| key.value().0.to_string());
+            while let Some(series) = next {
+                next = match table.range::<SeriesKey>((Bound::Excluded((series.as_str(), i64::MAX)), Bound::Unbounded))?.next() {
+                    Some(entry) => Some(entry?.0.value().0.to_string()),
+                    None => None
+                };
+                results.push(series);
+            }
+            Ok(results)
+        }, Ok(Vec::new()))
+    }
+
+    /// Rolls raw points of `series` up into `bucket`-wide aggregates, returning the number of buckets written.
+    ///
+    /// Rollups are recomputed starting from the most recent stored bucket, so late points landing in
+    /// that bucket are picked up on the next run. Raw points are left in place for retention to remove.
+    pub fn downsample(&self, series: impl AsRef<str>, bucket: Duration) -> crate::Result<usize> {
+        let txn = self.database.begin_write("downsample", self.points_table_name())?;
+        let written = self.downsample_in(&txn, series.as_ref(), bucket)?;
+        txn.commit()?;
+        Ok(written)
+    }
+
+    fn downsample_in(&self, txn: &Transaction, series: &str, bucket: Duration) -> crate::Result<usize> {
+        let width = bucket_micros(bucket)?;
+        let points_name = self.points_table_name();
+        let rollup_name = self.rollup_table_name(bucket);
+        let guard = txn.write_guard("downsample", &points_name)?;
+        let points = guard.open_table(TableDefinition::<SeriesKey, &[u8]>::new(&points_name))?;
+        let mut rollups = guard.open_table(TableDefinition::<SeriesKey, &[u8]>::new(&rollup_name))?;
+
+        let resume = match rollups.range::<SeriesKey>((series, i64::MIN)..=(series, i64::MAX))?.next_back() {
+            Some(entry) => entry?.0.value().1,
+            None => i64::MIN
+        };
+
+        let mut current: Option<Rollup> = None;
+        let mut finished = Vec::new();
+        for entry in points.range::<SeriesKey>((series, resume)..=(series, i64::MAX))? {
+            let (key, value) = entry?;
+            let timestamp = key.value().1;
+            let start = timestamp - timestamp.rem_euclid(width);
+            let sample = rmp_serde::from_slice::<T>(value.value())?.sample_value();
+            match current.as_mut() {
+                Some(rollup) if to_micros(&rollup.start) == start => rollup.push(sample),
+                _ => {
+                    if let Some(done) = current.replace(Rollup::new(from_micros(start), sample)) {
+                        finished.push(done);
+                    }
+                }
+            }
+        }
+        finished.extend(current);
+
+        for rollup in &finished {
+            let encoded = rmp_serde::to_vec_named(rollup)?;
+            let kind = match rollups.insert((series, to_micros(&rollup.start)), encoded.as_slice())? {
+                Some(_) => ChangeKind::Update,
+                None => ChangeKind::Insert
+            };
+            txn.record_change(&rollup_name, kind, encoded.len())?;
+        }
+        Ok(finished.len())
+    }
+
+    pub fn rollups(&self, series: impl AsRef<str>, bucket: Duration, range: impl RangeBounds<DateTime<Utc>>) -> crate::Result<Vec<Rollup>> {
+        bucket_micros(bucket)?;
+        let name = self.rollup_table_name(bucket);
+        let bounds = series_bounds(series.as_ref(), micros_bounds(&range));
+        with_table!(&self.database.begin_read("rollups", self.points_table_name())?, TableDefinition::<SeriesKey, &[u8]>::new(&name), table => {
+            let mut results = Vec::new();
+            for entry in table.range::<SeriesKey>(bounds)? {
+                results.push(rmp_serde::from_slice::<Rollup>(entry?.1.value())?);
+            }
+            Ok(results)
+        }, Ok(Vec::new()))
+    }
+
+    /// Deletes raw points and rollups older than the configured [RetentionPolicy], relative to `now`.
+    /// Returns the number of raw points and rollups removed.
+    pub fn enforce_retention(&self, now: DateTime<Utc>) -> crate::Result<(usize, usize)> {
+        let txn = self.database.begin_write("enforce_retention", self.points_table_name())?;
+        let removed = self.enforce_retention_in(&txn, now)?;
+        txn.commit()?;
+        Ok(removed)
+    }
+
+    fn enforce_retention_in(&self, txn: &Transaction, now: DateTime<Utc>) -> crate::Result<(usize, usize)> {
+        let points_name = self.points_table_name();
+        let guard = txn.write_guard("enforce_retention", &points_name)?;
+        let cutoff = |max_age: Duration| to_micros(&now).saturating_sub(i64::try_from(max_age.as_micros()).unwrap_or(i64::MAX));
+
+        let mut points_purged = 0;
+        if let Some(max_age) = self.retention.raw {
+            let cutoff = cutoff(max_age);
+            let mut table = guard.open_table(TableDefinition::<SeriesKey, &[u8]>::new(&points_name))?;
+            for entry in table.extract_if(|(_, timestamp), _| timestamp < cutoff)? {
+                entry?;
+                txn.record_change(&points_name, ChangeKind::Delete, 0)?;
+                points_purged += 1;
+            }
+        }
+
+        let mut rollups_purged = 0;
+        if let Some(max_age) = self.retention.rollups {
+            let cutoff = cutoff(max_age);
+            for bucket in &self.rollup_buckets {
+                let rollup_name = self.rollup_table_name(*bucket);
+                let mut table = guard.open_table(TableDefinition::<SeriesKey, &[u8]>::new(&rollup_name))?;
+                for entry in table.extract_if(|(_, timestamp), _| timestamp < cutoff)? {
+                    entry?;
+                    txn.record_change(&rollup_name, ChangeKind::Delete, 0)?;
+                    rollups_purged += 1;
+                }
+            }
+        }
+        Ok((points_purged, rollups_purged))
+    }
+
+    /// Runs every registered rollup for every series, then applies retention, in one write transaction.
+    pub fn maintain(&self, now: DateTime<Utc>) -> crate::Result<MaintenanceReport> {
+        let all_series = self.series()?;
+        let txn = self.database.begin_write("maintain", self.points_table_name())?;
+        let mut report = MaintenanceReport::default();
+        for bucket in &self.rollup_buckets {
+            for series in &all_series {
+                report.buckets_rolled_up += self.downsample_in(&txn, series, *bucket)?;
+            }
+        }
+        (report.points_purged, report.rollups_purged) = self.enforce_retention_in(&txn, now)?;
+        txn.commit()?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(millis: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(millis).unwrap_or_default()
+    }
+
+    fn series() -> crate::Result<TimeSeries<f64>> {
+        Ok(Database::open_in_memory()?.timeseries::<f64>("metrics"))
+    }
+
+    #[test]
+    fn buckets_must_be_whole_milliseconds() -> crate::Result<()> {
+        let metrics = series()?;
+        for bucket in [Duration::ZERO, Duration::from_micros(999), Duration::from_micros(1_500)] {
+            assert!(matches!(metrics.downsample("cpu", bucket), Err(Error::InvalidBucket(_))));
+            assert!(matches!(metrics.query_window("cpu", .., bucket, Aggregate::Sum), Err(Error::InvalidBucket(_))));
+        }
+        assert_eq!(metrics.downsample("cpu", Duration::from_millis(1_500))?, 0);
+        assert!(metrics.rollups("cpu", Duration::from_millis(1), ..)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn rollups_aggregate_each_bucket() -> crate::Result<()> {
+        let metrics = series()?.with_rollup(Duration::from_secs(60));
+        metrics.record_many("cpu", [(at(0), 1.0), (at(10_000), 3.0), (at(59_999), 2.0), (at(60_000), 10.0), (at(125_000), 4.0)])?;
+        metrics.record("disk", at(30_000), 7.0)?;
+        let minute = Duration::from_secs(60);
+        assert_eq!(metrics.downsample("cpu", minute)?, 3);
+        let rollups = metrics.rollups("cpu", minute, ..)?;
+        assert_eq!(rollups, [
+            Rollup { start: at(0), count: 3, sum: 6.0, min: 1.0, max: 3.0 },
+            Rollup { start: at(60_000), count: 1, sum: 10.0, min: 10.0, max: 10.0 },
+            Rollup { start: at(120_000), count: 1, sum: 4.0, min: 4.0, max: 4.0 }
+        ]);
+        assert_eq!(rollups.first().map(Rollup::avg), Some(2.0));
+
+        // A late point in the newest bucket is folded in on the next run; the older buckets stay as they were.
+        metrics.record("cpu", at(179_999), 6.0)?;
+        assert_eq!(metrics.downsample("cpu", minute)?, 1);
+        assert_eq!(metrics.rollups("cpu", minute, at(120_000)..)?, [Rollup { start: at(120_000), count: 2, sum: 10.0, min: 4.0, max: 6.0 }]);
+        assert_eq!(metrics.rollups("cpu", minute, ..at(60_000))?.len(), 1);
+
+        let report = metrics.maintain(at(200_000))?;
+        assert_eq!(report.buckets_rolled_up, 2);
+        assert_eq!(metrics.rollups("disk", minute, ..)?, [Rollup { start: at(0), count: 1, sum: 7.0, min: 7.0, max: 7.0 }]);
+        Ok(())
+    }
+
+    #[test]
+    fn windows_split_points_at_bucket_boundaries() -> crate::Result<()> {
+        let metrics = series()?;
+        let second = Duration::from_secs(1);
+        metrics.record_many("cpu", [(at(-1), 1.0), (at(0), 2.0), (at(999), 3.0), (at(1_000), 4.0), (at(2_500), 5.0), (at(2_999), 6.0)])?;
+        let window = |aggregate| metrics.query_window("cpu", .., second, aggregate);
+        assert_eq!(window(Aggregate::Count)?, [(at(-1_000), 1.0), (at(0), 2.0), (at(1_000), 1.0), (at(2_000), 2.0)]);
+        assert_eq!(window(Aggregate::First)?, [(at(-1_000), 1.0), (at(0), 2.0), (at(1_000), 4.0), (at(2_000), 5.0)]);
+        assert_eq!(window(Aggregate::Last)?, [(at(-1_000), 1.0), (at(0), 3.0), (at(1_000), 4.0), (at(2_000), 6.0)]);
+        assert_eq!(window(Aggregate::Avg)?.last(), Some(&(at(2_000), 5.5)));
+        assert_eq!(window(Aggregate::Max)?.get(1), Some(&(at(0), 3.0)));
+        assert_eq!(metrics.query_window("cpu", at(0)..at(1_000), second, Aggregate::Sum)?, [(at(0), 5.0)]);
+        assert_eq!(metrics.query_window("cpu", at(0)..=at(1_000), second, Aggregate::Sum)?, [(at(0), 5.0), (at(1_000), 4.0)]);
+
+        assert_eq!(metrics.downsample("cpu", second)?, 4);
+        let starts: Vec<_> = metrics.rollups("cpu", second, ..)?.into_iter().map(|rollup| (rollup.start, rollup.count)).collect();
+        assert_eq!(starts, [(at(-1_000), 1), (at(0), 2), (at(1_000), 1), (at(2_000), 2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn retention_purges_old_points_and_rollups() -> crate::Result<()> {
+        let minute = Duration::from_secs(60);
+        let metrics = series()?.with_rollup(minute).with_retention(RetentionPolicy::default().raw(minute).rollups(Duration::from_secs(180)));
+        metrics.record_many("cpu", (0..5).map(|minutes| (at(minutes * 60_000), minutes as f64)))?;
+        let report = metrics.maintain(at(300_000))?;
+        assert_eq!(report, MaintenanceReport { buckets_rolled_up: 5, points_purged: 4, rollups_purged: 2 });
+        assert_eq!(metrics.range("cpu", ..)?, [(at(240_000), 4.0)]);
+        assert_eq!(metrics.rollups("cpu", minute, ..)?.first().map(|rollup| rollup.start), Some(at(120_000)));
+        Ok(())
+    }
+}
@@ -0,0 +1,705 @@
+use std::{
+    collections::{BTreeMap, HashSet}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Condvar, Mutex}, thread::{self, JoinHandle}, time::{Duration, Instant}
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::database::Database;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionKind {
+    Read,
+    Write
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TransactionInfo {
+    pub id: u64,
+    pub kind: TransactionKind,
+    pub operation: String,
+    pub target: Option<String>,
+    pub opened_at: DateTime<Utc>,
+    /// Who asked for this transaction and why, once set with [crate::database::Transaction::set_context].
+    pub context: Option<WriteContext>
+}
+
+impl TransactionInfo {
+    pub fn age(&self) -> Duration {
+        Utc::now().signed_duration_since(self.opened_at).to_std().unwrap_or_default()
+    }
+}
+
+/// Provenance attached to a transaction: the acting user or service, the request it serves and why it writes.
+/// It's listed with the transaction in [TransactionInfo], logged with its [CommitSummary] and readable by hooks
+/// through [crate::database::Transaction::context].
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WriteContext {
+    pub actor: Option<String>,
+    pub request_id: Option<String>,
+    pub reason: Option<String>
+}
+
+impl WriteContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn actor(mut self, actor: impl AsRef<str>) -> Self {
+        self.actor = Some(actor.as_ref().to_string());
+        self
+    }
+
+    pub fn request_id(mut self, request_id: impl AsRef<str>) -> Self {
+        self.request_id = Some(request_id.as_ref().to_string());
+        self
+    }
+
+    pub fn reason(mut self, reason: impl AsRef<str>) -> Self {
+        self.reason = Some(reason.as_ref().to_string());
+        self
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TransactionMetrics {
+    pub reads_opened: u64,
+    pub writes_opened: u64,
+    pub commits: u64,
+    pub aborts: u64
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Insert,
+    Update,
+    Delete
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TableChanges {
+    pub inserts: u64,
+    pub updates: u64,
+    pub deletes: u64,
+    pub bytes_written: u64
+}
+
+impl TableChanges {
+    fn merge(&mut self, other: &TableChanges) {
+        self.inserts += other.inserts;
+        self.updates += other.updates;
+        self.deletes += other.deletes;
+        self.bytes_written += other.bytes_written;
+    }
+}
+
+/// What a single write transaction changed, reported when it commits.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CommitSummary {
+    pub transaction: u64,
+    pub operation: String,
+    pub target: Option<String>,
+    pub context: Option<WriteContext>,
+    pub tables: BTreeMap<String, TableChanges>,
+    pub held: Duration,
+    pub commit_duration: Duration
+}
+
+impl CommitSummary {
+    pub fn totals(&self) -> TableChanges {
+        let mut totals = TableChanges::default();
+        for changes in self.tables.values() {
+            totals.merge(changes);
+        }
+        totals
+    }
+}
+
+/// What [Database::dry_run] would have written: the closure's result and the rows it changed per table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DryRun<R> {
+    pub result: R,
+    pub tables: BTreeMap<String, TableChanges>
+}
+
+impl<R> DryRun<R> {
+    pub fn totals(&self) -> TableChanges {
+        let mut totals = TableChanges::default();
+        for changes in self.tables.values() {
+            totals.merge(changes);
+        }
+        totals
+    }
+}
+
+/// Ingestion mode: commits use eventual durability, and one is made durable whenever `interval` has passed
+/// or `bytes` have been written since the last durable commit. Run [Database::schedule_checkpoints] to also
+/// checkpoint after `interval` when no further commit arrives.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CheckpointPolicy {
+    pub interval: Duration,
+    pub bytes: u64
+}
+
+impl CheckpointPolicy {
+    pub fn new(interval: Duration, bytes: u64) -> Self {
+        Self { interval, bytes }
+    }
+}
+
+impl Default for CheckpointPolicy {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), 16 * 1024 * 1024)
+    }
+}
+
+/// The newest commit known to be persisted to disk; everything committed before it survives a crash.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DurableCheckpoint {
+    pub transaction: u64,
+    pub at: DateTime<Utc>
+}
+
+#[derive(Debug)]
+struct CheckpointState {
+    policy: Option<CheckpointPolicy>,
+    last: Option<DurableCheckpoint>,
+    last_instant: Instant,
+    pending_bytes: u64,
+    /// Eventual-durability commits since the last durable one.
+    pending_commits: u64
+}
+
+impl Default for CheckpointState {
+    fn default() -> Self {
+        Self {
+            policy: None,
+            last: None,
+            last_instant: Instant::now(),
+            pending_bytes: 0,
+            pending_commits: 0
+        }
+    }
+}
+
+/// Bytes and rows written by every committed transaction sharing one operation label and target table.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OperationWrites {
+    pub operation: String,
+    pub target: Option<String>,
+    pub transactions: u64,
+    pub tables: BTreeMap<String, TableChanges>
+}
+
+impl OperationWrites {
+    pub fn bytes_written(&self) -> u64 {
+        self.tables.values().map(|changes| changes.bytes_written).sum()
+    }
+
+    /// Total bytes written per byte written to the target table, e.g. how much index and metadata
+    /// tables add on top of the main table. `None` if nothing was written to the target.
+    pub fn amplification(&self) -> Option<f64> {
+        let primary = self.tables.get(self.target.as_ref()?)?.bytes_written;
+        (primary > 0).then(|| self.bytes_written() as f64 / primary as f64)
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct WriteAmplificationReport {
+    /// Sorted by total bytes written, largest first.
+    pub operations: Vec<OperationWrites>
+}
+
+#[derive(Debug, Default)]
+struct TrackerState {
+    open: BTreeMap<u64, TransactionInfo>,
+    metrics: TransactionMetrics,
+    writes: BTreeMap<(String, Option<String>), OperationWrites>,
+    frozen: bool
+}
+
+/// Shared registry of the transactions currently open against a [crate::database::Database].
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TransactionTracker {
+    state: Arc<Mutex<TrackerState>>,
+    /// Signalled when the tracker is unfrozen or a transaction closes.
+    changed: Arc<Condvar>,
+    next_id: Arc<AtomicU64>,
+    log_commits: Arc<AtomicBool>,
+    checkpoints: Arc<Mutex<CheckpointState>>
+}
+
+impl TransactionTracker {
+    pub(crate) fn new(checkpoints: Option<CheckpointPolicy>) -> Self {
+        Self {
+            checkpoints: Arc::new(Mutex::new(CheckpointState { policy: checkpoints, ..Default::default() })),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn last_durable_checkpoint(&self) -> crate::Result<Option<DurableCheckpoint>> {
+        Ok(self.checkpoints.lock()?.last.clone())
+    }
+
+    pub(crate) fn checkpoint_policy(&self) -> crate::Result<Option<CheckpointPolicy>> {
+        Ok(self.checkpoints.lock()?.policy.clone())
+    }
+
+    /// Whether commits are waiting on a durable checkpoint whose [CheckpointPolicy::interval] has passed.
+    pub(crate) fn checkpoint_due(&self) -> crate::Result<bool> {
+        let state = self.checkpoints.lock()?;
+        Ok(state.policy.as_ref().is_some_and(|policy| state.pending_commits > 0 && state.last_instant.elapsed() >= policy.interval))
+    }
+
+    /// The id the next tracked transaction will get; every transaction opened from now on has at least this id.
+    pub(crate) fn next_id(&self) -> u64 {
+        self.next_id.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn track(&self, kind: TransactionKind, operation: impl AsRef<str>, target: Option<String>) -> crate::Result<Arc<TransactionGuard>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut state = self.state.lock()?;
+        while kind == TransactionKind::Write && state.frozen {
+            state = self.changed.wait(state)?;
+        }
+        match kind {
+            TransactionKind::Read => state.metrics.reads_opened += 1,
+            TransactionKind::Write => state.metrics.writes_opened += 1
+        }
+        state.open.insert(id, TransactionInfo {
+            id,
+            kind,
+            operation: operation.as_ref().to_string(),
+            target,
+            opened_at: Utc::now(),
+            context: None
+        });
+        Ok(Arc::new(TransactionGuard {
+            tracker: self.clone(),
+            id,
+            changes: Mutex::new(BTreeMap::new()),
+            force_durable: AtomicBool::new(false),
+            panicked: Mutex::new(None)
+        }))
+    }
+
+    /// Makes new write transactions wait until [TransactionTracker::unfreeze], then waits for open ones to close.
+    pub(crate) fn freeze(&self) -> crate::Result<()> {
+        let mut state = self.state.lock()?;
+        state.frozen = true;
+        while state.open.values().any(|info| info.kind == TransactionKind::Write) {
+            state = self.changed.wait(state)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn unfreeze(&self) -> crate::Result<()> {
+        self.state.lock()?.frozen = false;
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    pub(crate) fn is_frozen(&self) -> crate::Result<bool> {
+        Ok(self.state.lock()?.frozen)
+    }
+
+    pub(crate) fn set_commit_logging(&self, enabled: bool) {
+        self.log_commits.store(enabled, Ordering::Relaxed);
+    }
+
+    pub(crate) fn active(&self) -> crate::Result<Vec<TransactionInfo>> {
+        Ok(self.state.lock()?.open.values().cloned().collect())
+    }
+
+    pub(crate) fn metrics(&self) -> crate::Result<TransactionMetrics> {
+        Ok(self.state.lock()?.metrics.clone())
+    }
+
+    pub(crate) fn write_amplification(&self) -> crate::Result<WriteAmplificationReport> {
+        let mut operations: Vec<OperationWrites> = self.state.lock()?.writes.values().cloned().collect();
+        operations.sort_by_key(|writes| std::cmp::Reverse(writes.bytes_written()));
+        Ok(WriteAmplificationReport { operations })
+    }
+}
+
+/// Keeps a transaction listed as active until the last clone of its [crate::database::Transaction] is dropped.
+#[derive(Debug)]
+pub struct TransactionGuard {
+    tracker: TransactionTracker,
+    id: u64,
+    changes: Mutex<BTreeMap<String, TableChanges>>,
+    force_durable: AtomicBool,
+    /// The hook and message of the first callback that panicked inside this transaction.
+    panicked: Mutex<Option<(String, String)>>
+}
+
+impl TransactionGuard {
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn info(&self) -> crate::Result<Option<TransactionInfo>> {
+        Ok(self.tracker.state.lock()?.open.get(&self.id).cloned())
+    }
+
+    pub(crate) fn record_change(&self, table: impl AsRef<str>, kind: ChangeKind, bytes: usize) -> crate::Result<()> {
+        let mut changes = self.changes.lock()?;
+        let entry = changes.entry(table.as_ref().to_string()).or_default();
+        match kind {
+            ChangeKind::Insert => entry.inserts += 1,
+            ChangeKind::Update => entry.updates += 1,
+            ChangeKind::Delete => entry.deletes += 1
+        }
+        entry.bytes_written += bytes as u64;
+        Ok(())
+    }
+
+    pub(crate) fn changes(&self) -> crate::Result<BTreeMap<String, TableChanges>> {
+        Ok(self.changes.lock()?.clone())
+    }
+
+    pub(crate) fn set_context(&self, context: WriteContext) -> crate::Result<()> {
+        if let Some(info) = self.tracker.state.lock()?.open.get_mut(&self.id) {
+            info.context = Some(context);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn hook_panicked(&self, hook: &str, message: &str) -> crate::Result<()> {
+        self.panicked.lock()?.get_or_insert_with(|| (hook.to_string(), message.to_string()));
+        Ok(())
+    }
+
+    pub(crate) fn panicked(&self) -> crate::Result<Option<(String, String)>> {
+        Ok(self.panicked.lock()?.clone())
+    }
+
+    /// Makes this transaction's commit durable even when a [CheckpointPolicy] would defer it.
+    pub(crate) fn require_durable(&self) {
+        self.force_durable.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether this transaction's commit should be fsynced under the current [CheckpointPolicy].
+    pub(crate) fn wants_durable(&self) -> crate::Result<bool> {
+        let state = self.tracker.checkpoints.lock()?;
+        let Some(policy) = &state.policy else {
+            return Ok(true);
+        };
+        let bytes: u64 = self.changes.lock()?.values().map(|changes| changes.bytes_written).sum();
+        Ok(self.force_durable.load(Ordering::Relaxed)
+            || state.last_instant.elapsed() >= policy.interval
+            || state.pending_bytes + bytes >= policy.bytes)
+    }
+
+    pub(crate) fn committed(&self, commit_duration: Duration, durable: bool) -> crate::Result<()> {
+        {
+            let mut state = self.tracker.state.lock()?;
+            state.metrics.commits += 1;
+            if let Some(info) = state.open.get(&self.id).cloned() {
+                let writes = state.writes.entry((info.operation.clone(), info.target.clone())).or_insert_with(|| OperationWrites {
+                    operation: info.operation,
+                    target: info.target,
+                    ..Default::default()
+                });
+                writes.transactions += 1;
+                for (table, changes) in self.changes.lock()?.iter() {
+                    writes.tables.entry(table.clone()).or_default().merge(changes);
+                }
+            }
+        }
+        {
+            let mut checkpoints = self.tracker.checkpoints.lock()?;
+            if durable {
+                checkpoints.last = Some(DurableCheckpoint { transaction: self.id, at: Utc::now() });
+                checkpoints.last_instant = Instant::now();
+                checkpoints.pending_bytes = 0;
+                checkpoints.pending_commits = 0;
+            } else {
+                checkpoints.pending_bytes += self.changes.lock()?.values().map(|changes| changes.bytes_written).sum::<u64>();
+                checkpoints.pending_commits += 1;
+            }
+        }
+        if self.tracker.log_commits.load(Ordering::Relaxed) && let Some(info) = self.info()? {
+            let summary = CommitSummary {
+                transaction: info.id,
+                operation: info.operation.clone(),
+                target: info.target.clone(),
+                context: info.context.clone(),
+                tables: self.changes()?,
+                held: info.age(),
+                commit_duration
+            };
+            let totals = summary.totals();
+            let context = summary.context.clone().unwrap_or_default();
+            tracing::info!(
+                transaction = summary.transaction,
+                operation = summary.operation.as_str(),
+                target = summary.target.as_deref().unwrap_or(""),
+                actor = context.actor.as_deref().unwrap_or(""),
+                request_id = context.request_id.as_deref().unwrap_or(""),
+                reason = context.reason.as_deref().unwrap_or(""),
+                tables = ?summary.tables.keys().collect::<Vec<_>>(),
+                inserts = totals.inserts,
+                updates = totals.updates,
+                deletes = totals.deletes,
+                bytes_written = totals.bytes_written,
+                held_ms = summary.held.as_millis() as u64,
+                commit_ms = summary.commit_duration.as_millis() as u64,
+                "commit"
+            );
+        }
+        Ok(())
+    }
+
+    pub(crate) fn aborted(&self) -> crate::Result<()> {
+        self.tracker.state.lock()?.metrics.aborts += 1;
+        Ok(())
+    }
+}
+
+impl Drop for TransactionGuard {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.tracker.state.lock() {
+            state.open.remove(&self.id);
+        }
+        self.tracker.changed.notify_all();
+    }
+}
+
+/// Background thread that takes a durable checkpoint once the [CheckpointPolicy] interval has passed with
+/// commits still pending, so ingestion that goes quiet is persisted without waiting for the next commit. Stops
+/// when dropped.
+#[derive(Debug)]
+pub struct CheckpointScheduler {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>
+}
+
+impl CheckpointScheduler {
+    pub(crate) fn spawn(db: Database, interval: Duration) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handle = thread::spawn(move || {
+            loop {
+                thread::park_timeout(interval);
+                if stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                if !db.tracker().checkpoint_due().unwrap_or(false) {
+                    continue;
+                }
+                match db.checkpoint() {
+                    Ok(checkpoint) => tracing::debug!(transaction = checkpoint.map(|checkpoint| checkpoint.transaction), "took scheduled checkpoint"),
+                    Err(e) => tracing::warn!(error = %e, "scheduled checkpoint failed")
+                }
+            }
+        });
+        Self {
+            stop,
+            handle: Some(handle)
+        }
+    }
+
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for CheckpointScheduler {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Background thread that warns (through `tracing`) about read transactions held longer than a threshold.
+///
+/// Long-lived readers pin old pages, which blocks compaction and grows the file. Each transaction is
+/// reported once, and stays in [Watchdog::flagged] until it closes. The thread stops when the handle is dropped.
+#[derive(Debug)]
+pub struct Watchdog {
+    stop: Arc<AtomicBool>,
+    flagged: Arc<Mutex<Vec<TransactionInfo>>>,
+    handle: Option<JoinHandle<()>>
+}
+
+impl Watchdog {
+    pub(crate) fn spawn(tracker: TransactionTracker, threshold: Duration, interval: Duration) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let flagged = Arc::new(Mutex::new(Vec::new()));
+        let still_open = flagged.clone();
+        let handle = thread::spawn(move || {
+            let mut reported = HashSet::new();
+            while !stopped.load(Ordering::Relaxed) {
+                if let Ok(active) = tracker.active() {
+                    reported.retain(|id| active.iter().any(|info| info.id == *id));
+                    for info in active.iter().filter(|info| info.kind == TransactionKind::Read) {
+                        let age = info.age();
+                        if age > threshold && reported.insert(info.id) {
+                            tracing::warn!(
+                                transaction = info.id,
+                                operation = info.operation.as_str(),
+                                target = info.target.as_deref().unwrap_or(""),
+                                age_ms = age.as_millis() as u64,
+                                "read transaction held longer than {:?}",
+                                threshold
+                            );
+                        }
+                    }
+                    if let Ok(mut flagged) = still_open.lock() {
+                        *flagged = active.into_iter().filter(|info| reported.contains(&info.id)).collect();
+                    }
+                }
+                thread::park_timeout(interval);
+            }
+        });
+        Self {
+            stop,
+            flagged,
+            handle: Some(handle)
+        }
+    }
+
+    /// The read transactions still open that have been reported as held past the threshold.
+    pub fn flagged(&self) -> crate::Result<Vec<TransactionInfo>> {
+        Ok(self.flagged.lock()?.clone())
+    }
+
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{database::UpsertResult, testing::fixtures::Note};
+
+    #[test]
+    fn scheduled_checkpoint_persists_quiet_ingest() -> crate::Result<()> {
+        let db = Database::builder().ingest(CheckpointPolicy::new(Duration::from_millis(50), u64::MAX)).open_in_memory()?;
+        let durable = db.last_durable_checkpoint()?;
+        db.collection::<Note>("notes").insert(&Note { id: "a".to_string(), title: "first".to_string() })?;
+        assert_eq!(db.last_durable_checkpoint()?, durable);
+
+        let scheduler = db.schedule_checkpoints()?;
+        thread::sleep(Duration::from_millis(300));
+        scheduler.stop();
+        assert_ne!(db.last_durable_checkpoint()?, durable);
+        assert!(!db.tracker().checkpoint_due()?);
+        Ok(())
+    }
+
+    #[test]
+    fn write_context_reaches_listings_and_hooks() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let notes = db.collection::<Note>("notes");
+        let seen = Arc::new(Mutex::new(None));
+        let hook_seen = seen.clone();
+        db.delete_hooks().register("notes", "test", Arc::new(move |_, txn, _| {
+            *hook_seen.lock()? = txn.context()?;
+            Ok(())
+        }))?;
+        notes.insert(&Note { id: "a".to_string(), title: "first".to_string() })?;
+
+        let context = WriteContext::new().actor("admin").request_id("req-1").reason("cleanup");
+        let txn = db.writer_with(context.clone())?;
+        assert_eq!(db.active_transactions()?.into_iter().map(|info| info.context).collect::<Vec<_>>(), [Some(context.clone())]);
+        notes.delete_in(&txn, &"a".to_string())?;
+        assert_eq!(*seen.lock()?, Some(context));
+        txn.commit()?;
+        assert_eq!(db.writer()?.context()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn dry_runs_report_changes_without_writing() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let notes = db.collection::<Note>("notes");
+        let note = |id: &str| Note { id: id.to_string(), title: format!("title {id}") };
+        notes.insert(&note("a"))?;
+        let commits = db.transaction_metrics()?.commits;
+
+        let preview = db.dry_run(|txn| {
+            notes.insert_in(txn, &note("b"))?;
+            let replaced = notes.upsert_in(txn, &Note { title: "renamed".to_string(), ..note("a") })?;
+            Ok(matches!(replaced, UpsertResult::Replaced(_)))
+        })?;
+        assert!(preview.result);
+        let main = preview.tables.get("collections/notes").cloned().unwrap_or_default();
+        assert_eq!((main.inserts, main.updates, main.deletes), (1, 1, 0));
+        assert!(preview.tables.keys().any(|table| table.starts_with("collections/notes/index/")));
+        assert_eq!(notes.count()?, 1);
+        assert_eq!(notes.get(&"a".to_string())?, Some(note("a")));
+
+        assert!(db.dry_run(|txn| notes.insert_in(txn, &note("a"))).is_err());
+        assert_eq!(db.transaction_metrics()?.commits, commits);
+        Ok(())
+    }
+
+    #[test]
+    fn freeze_holds_new_writers_until_unfreeze() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let notes = db.collection::<Note>("notes");
+        db.freeze()?;
+        assert!(db.tracker().is_frozen()?);
+
+        let writer = thread::spawn({
+            let notes = notes.clone();
+            move || notes.insert(&Note { id: "a".to_string(), title: "first".to_string() })
+        });
+        thread::sleep(Duration::from_millis(100));
+        assert!(!writer.is_finished());
+        assert_eq!(notes.count()?, 0);
+
+        db.unfreeze()?;
+        writer.join().unwrap()?;
+        assert_eq!(notes.count()?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn watchdog_flags_long_held_readers() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let watchdog = db.watchdog(Duration::from_millis(50), Duration::from_millis(10));
+        let quick = db.reader()?;
+        drop(quick);
+        let held = db.reader()?;
+        thread::sleep(Duration::from_millis(200));
+        let flagged = watchdog.flagged()?;
+        assert_eq!(flagged.len(), 1);
+        assert_eq!(flagged.first().map(|info| info.kind), Some(TransactionKind::Read));
+        assert!(flagged.iter().all(|info| info.age() >= Duration::from_millis(50)));
+
+        drop(held);
+        thread::sleep(Duration::from_millis(100));
+        assert!(watchdog.flagged()?.is_empty());
+        watchdog.stop();
+        Ok(())
+    }
+}
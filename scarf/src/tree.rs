@@ -0,0 +1,173 @@
+use std::collections::{HashSet, VecDeque};
+
+use redb::Value;
+
+use crate::{
+    database::{Collection, Transaction}, document::Document
+};
+
+/// Parent/child navigation over a [Collection] whose documents name their parent's primary key in index
+/// `parent`, see [Collection::tree]. Roots leave the index value nil or out.
+///
+/// The tree is an adjacency list kept by the `parent` index itself, so every write keeps it current without
+/// a separate path or closure table: [Tree::children] is one index lookup, [Tree::descendants] one per
+/// visited node, and [Tree::ancestors] one document read per level. Cycles are cut at the first node visited
+/// twice.
+#[derive(Debug)]
+pub struct Tree<T: Document> {
+    collection: Collection<T>,
+    parent: String
+}
+
+impl<T: Document> Clone for Tree<T> {
+    fn clone(&self) -> Self {
+        Self {
+            collection: self.collection.clone(),
+            parent: self.parent.clone()
+        }
+    }
+}
+
+impl<T: Document> Tree<T> {
+    pub(crate) fn new(collection: Collection<T>, parent: String) -> Self {
+        Self { collection, parent }
+    }
+
+    /// Documents whose parent is `key`, in primary key order.
+    pub fn children(&self, key: &T::PrimaryKey) -> crate::Result<Vec<T>> {
+        self.children_in(&self.begin_read("children")?, key)
+    }
+
+    pub fn children_in(&self, txn: &Transaction, key: &T::PrimaryKey) -> crate::Result<Vec<T>> {
+        self.collection.find_by_in(txn, &self.parent, rmpv::ext::to_value(key)?)
+    }
+
+    /// Every document below `key`, breadth-first: its children, then their children, and so on.
+    pub fn descendants(&self, key: &T::PrimaryKey) -> crate::Result<Vec<T>> {
+        self.descendants_in(&self.begin_read("descendants")?, key)
+    }
+
+    pub fn descendants_in(&self, txn: &Transaction, key: &T::PrimaryKey) -> crate::Result<Vec<T>> {
+        let (mut descendants, mut seen) = (Vec::new(), HashSet::from([Self::encoded(key)]));
+        let mut pending = VecDeque::from([key.clone()]);
+        while let Some(next) = pending.pop_front() {
+            for child in self.children_in(txn, &next)? {
+                let id = child.id();
+                if seen.insert(Self::encoded(&id)) {
+                    pending.push_back(id);
+                    descendants.push(child);
+                }
+            }
+        }
+        Ok(descendants)
+    }
+
+    /// The parent of `key`, its parent, and so on up to the root, nearest first. Stops early at a parent that
+    /// isn't stored.
+    pub fn ancestors(&self, key: &T::PrimaryKey) -> crate::Result<Vec<T>> {
+        self.ancestors_in(&self.begin_read("ancestors")?, key)
+    }
+
+    pub fn ancestors_in(&self, txn: &Transaction, key: &T::PrimaryKey) -> crate::Result<Vec<T>> {
+        let (mut ancestors, mut seen) = (Vec::new(), HashSet::from([Self::encoded(key)]));
+        let mut next = match self.collection.get_in(txn, key)? {
+            Some(document) => self.parent_of(&document)?,
+            None => None
+        };
+        while let Some(parent) = next.filter(|parent| seen.insert(Self::encoded(parent))) {
+            let Some(document) = self.collection.get_in(txn, &parent)? else {
+                break;
+            };
+            next = self.parent_of(&document)?;
+            ancestors.push(document);
+        }
+        Ok(ancestors)
+    }
+
+    /// The primary key `document` names as its parent, or `None` for a root.
+    fn parent_of(&self, document: &T) -> crate::Result<Option<T::PrimaryKey>> {
+        match document.index_vals().remove(&self.parent) {
+            None | Some(rmpv::Value::Nil) => Ok(None),
+            Some(parent) => Ok(Some(rmpv::ext::from_value::<T::PrimaryKey>(parent)?))
+        }
+    }
+
+    fn encoded(key: &T::PrimaryKey) -> Vec<u8> {
+        T::PrimaryKey::as_bytes(key).as_ref().to_vec()
+    }
+
+    fn begin_read(&self, operation: &str) -> crate::Result<Transaction> {
+        self.collection.database().begin_read(operation, self.collection.main_table_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::{database::Database, document::Document};
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    struct Category {
+        id: String,
+        parent: Option<String>
+    }
+
+    impl Document for Category {
+        type PrimaryKey = String;
+
+        fn id(&self) -> String {
+            self.id.clone()
+        }
+
+        fn id_field() -> String {
+            "id".to_string()
+        }
+
+        fn index_keys() -> Vec<String> {
+            vec!["parent".to_string()]
+        }
+
+        fn index_vals(&self) -> HashMap<String, rmpv::Value> {
+            HashMap::from([("parent".to_string(), self.parent.clone().map_or(rmpv::Value::Nil, Into::into))])
+        }
+    }
+
+    fn ids(categories: Vec<Category>) -> Vec<String> {
+        categories.into_iter().map(|category| category.id).collect()
+    }
+
+    #[test]
+    fn trees_follow_the_parent_index() -> crate::Result<()> {
+        let db = Database::open_in_memory()?;
+        let categories = db.collection::<Category>("categories");
+        let category = |id: &str, parent: Option<&str>| Category { id: id.to_string(), parent: parent.map(str::to_string) };
+        categories.insert_many([
+            category("root", None),
+            category("books", Some("root")),
+            category("music", Some("root")),
+            category("fiction", Some("books")),
+            category("poetry", Some("books")),
+            category("sonnets", Some("poetry"))
+        ])?;
+        let tree = categories.tree("parent");
+        let key = |id: &str| id.to_string();
+
+        assert_eq!(ids(tree.children(&key("root"))?), ["books", "music"]);
+        assert_eq!(ids(tree.descendants(&key("root"))?), ["books", "music", "fiction", "poetry", "sonnets"]);
+        assert_eq!(ids(tree.ancestors(&key("sonnets"))?), ["poetry", "books", "root"]);
+        assert!(tree.ancestors(&key("root"))?.is_empty());
+        assert!(tree.children(&key("sonnets"))?.is_empty());
+
+        categories.upsert(&category("poetry", Some("music")))?;
+        assert_eq!(ids(tree.children(&key("music"))?), ["poetry"]);
+        assert_eq!(ids(tree.ancestors(&key("sonnets"))?), ["poetry", "music", "root"]);
+
+        categories.upsert(&category("root", Some("sonnets")))?;
+        assert_eq!(ids(tree.ancestors(&key("sonnets"))?), ["poetry", "music", "root"]);
+        assert_eq!(ids(tree.descendants(&key("music"))?), ["poetry", "sonnets", "root", "books", "fiction"]);
+        Ok(())
+    }
+}